@@ -6,6 +6,8 @@ BIN_PATH="/usr/local/"  # Path where the binary is installed
 SERVICE_NAME="xcontroller" # The name of the systemd service (e.g. "my_service")
 SERVICE_FILE="/etc/systemd/system/$SERVICE_NAME.service"  # Path to the systemd service file
 TEMP_DIR="/tmp/xcontroller"  # Temporary directory for downloading the binary
+CONFIG_DIR="/etc/xcontroller"  # Directory of the configuration file
+CONFIG_FILE="$CONFIG_DIR/xcontroller.toml"  # Configuration file read by the service
 
 # Parameters for the binary
 WEBSOCKET_PORT=$1
@@ -50,7 +52,24 @@ if [ $? -ne 0 ]; then
   exit 1
 fi
 
-# 4. Install or Update the service
+# 4. Write the configuration, other settings in an existing file are kept
+echo "Writing the configuration to $CONFIG_FILE..."
+sudo mkdir -p $CONFIG_DIR
+if [ -f $CONFIG_FILE ]; then
+  sudo sed -i '/^\(ws_port\|serial_port\|baud_rate\|test_mode\) *=/d' $CONFIG_FILE
+  EXISTING=$(cat $CONFIG_FILE)
+else
+  EXISTING=""
+fi
+sudo tee $CONFIG_FILE > /dev/null <<EOL
+ws_port = $WEBSOCKET_PORT
+serial_port = "$SERIAL_PORT"
+baud_rate = $BAUDRATE
+test_mode = $TEST_MODE
+$EXISTING
+EOL
+
+# 5. Install or Update the service
 echo "Ensuring the service is installed/updated..."
 
 if [ ! -f $SERVICE_FILE ]; then
@@ -63,7 +82,7 @@ Description=xcontroller
 After=network.target
 
 [Service]
-ExecStart=$BIN_PATH/$SERVICE_NAME -- $WEBSOCKET_PORT $SERIAL_PORT $BAUDRATE $TEST_MODE
+ExecStart=$BIN_PATH/$SERVICE_NAME --config $CONFIG_FILE
 Restart=always
 User=root  # Adjust this to the user you want the service to run as
 Group=root  # Optional, set if needed
@@ -81,7 +100,7 @@ else
   echo "Service updated!"
 fi
 
-# 5. Start the service
+# 6. Start the service
 echo "Starting the service..."
 sudo systemctl start $SERVICE_NAME
 if [ $? -ne 0 ]; then
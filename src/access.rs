@@ -0,0 +1,190 @@
+use log::warn;
+use std::io::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::structs::{AccessConfig, RateLimit};
+
+/**
+ * Decides which connections are accepted by the listener
+ * Each accepted client holds a permit until its connection ends
+ */
+pub struct AccessControl {
+    config: AccessConfig,
+    clients: Arc<Semaphore>,
+}
+
+impl AccessControl {
+    pub fn new(config: AccessConfig) -> Self {
+        let permits = match config.max_clients {
+            0 => Semaphore::MAX_PERMITS,
+            max_clients => max_clients,
+        };
+
+        AccessControl {
+            config,
+            clients: Arc::new(Semaphore::new(permits)),
+        }
+    }
+
+    /**
+     * Check a new connection against the address lists and the client limit
+     * @param peer: SocketAddr, address of the client
+     * @return Result<OwnedSemaphorePermit, Error>, permit to keep for the connection lifetime
+     */
+    pub fn admit(&self, peer: SocketAddr) -> Result<OwnedSemaphorePermit, Error> {
+        let result = check_ip(&self.config, peer.ip()).and_then(|_| {
+            self.clients.clone().try_acquire_owned().map_err(|_| {
+                Error::other(format!(
+                    "Maximum of {} clients reached",
+                    self.config.max_clients
+                ))
+            })
+        });
+
+        if let Err(e) = &result {
+            warn!("Rejected connection from {} | {}", peer, e);
+        }
+        result
+    }
+}
+
+/**
+ * Check an address against the deny and allow lists
+ * @param config: &AccessConfig, access configuration
+ * @param ip: IpAddr, address of the client
+ * @return Result<(), Error>, the reason when the address is rejected
+ */
+pub fn check_ip(config: &AccessConfig, ip: IpAddr) -> Result<(), Error> {
+    let ip = ip.to_canonical();
+
+    if config.deny.iter().any(|net| net.contains(&ip)) {
+        return Err(Error::other(format!("Address {} is denied", ip)));
+    }
+
+    if !config.allow.is_empty() && !config.allow.iter().any(|net| net.contains(&ip)) {
+        return Err(Error::other(format!("Address {} is not allowed", ip)));
+    }
+
+    Ok(())
+}
+
+/**
+ * Check the Origin header of a handshake
+ * Clients that are not browsers do not send an origin and are accepted
+ * @param allowed_origins: &[String], accepted origins, empty accepts any
+ * @param origin: Option<&str>, Origin header of the request
+ * @return bool
+ */
+pub fn origin_allowed(allowed_origins: &[String], origin: Option<&str>) -> bool {
+    match origin {
+        None => true,
+        Some(_) if allowed_origins.is_empty() => true,
+        Some(origin) => allowed_origins.iter().any(|allowed| {
+            allowed == "*" || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)
+        }),
+    }
+}
+
+/// Token bucket limiting the messages of a client
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: &RateLimit) -> Self {
+        let burst = f64::from(limit.burst.max(1));
+
+        RateLimiter {
+            rate: limit.messages_per_second,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    /**
+     * Take a token for a new message
+     * @return bool, false when the client sends faster than allowed
+     */
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now())
+    }
+
+    fn allow_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_check_ip() {
+        let config = AccessConfig {
+            allow: vec!["192.168.1.0/24".parse().unwrap()],
+            deny: vec!["192.168.1.66/32".parse().unwrap()],
+            ..AccessConfig::default()
+        };
+
+        assert!(check_ip(&config, "192.168.1.20".parse().unwrap()).is_ok());
+        assert!(check_ip(&config, "::ffff:192.168.1.20".parse().unwrap()).is_ok());
+        assert!(check_ip(&config, "192.168.1.66".parse().unwrap()).is_err());
+        assert!(check_ip(&config, "10.0.0.1".parse().unwrap()).is_err());
+        assert!(check_ip(&AccessConfig::default(), "10.0.0.1".parse().unwrap()).is_ok());
+    }
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed = vec!["http://printer.local/".to_string()];
+
+        assert!(origin_allowed(&allowed, Some("http://printer.local")));
+        assert!(origin_allowed(&allowed, None));
+        assert!(!origin_allowed(&allowed, Some("http://evil.example")));
+        assert!(origin_allowed(&[], Some("http://evil.example")));
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let access = AccessControl::new(AccessConfig {
+            max_clients: 1,
+            ..AccessConfig::default()
+        });
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        let permit = access.admit(peer).unwrap();
+        assert!(access.admit(peer).is_err());
+        drop(permit);
+        assert!(access.admit(peer).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(&RateLimit {
+            messages_per_second: 2.0,
+            burst: 3,
+        });
+        let start = limiter.last;
+
+        assert!((0..3).all(|_| limiter.allow_at(start)));
+        assert!(!limiter.allow_at(start));
+        assert!(limiter.allow_at(start + Duration::from_millis(500)));
+        assert!(!limiter.allow_at(start + Duration::from_millis(500)));
+    }
+}
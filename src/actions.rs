@@ -0,0 +1,215 @@
+use crate::structs::{ActionCommand, GcodeState, HostPrompt};
+
+static ACTION_PREFIX: &str = "//action:";
+// Feedrate of the Z moves when parking, mm/min
+static PARK_FEEDRATE: u32 = 600;
+
+/// What a streamed job does on an action command of the firmware
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reaction {
+    /// park is false when the firmware already moved the nozzle away, as M600 does
+    Pause {
+        park: bool,
+        reason: String,
+    },
+    Resume,
+    Cancel,
+}
+
+/**
+ * Read an action command sent by the firmware
+ * @param line: &str, line received from the printer
+ * @return Option<ActionCommand>, None for the other lines
+ */
+pub fn parse_action(line: &str) -> Option<ActionCommand> {
+    let command = line.trim().strip_prefix(ACTION_PREFIX)?.trim();
+    if command.is_empty() {
+        return None;
+    }
+
+    let (action, detail) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    Some(ActionCommand {
+        action: action.trim_end_matches(':').to_lowercase(),
+        detail: detail.trim().to_string(),
+    })
+}
+
+/// Pause caused by the filament sensor, Marlin sends the reason after the action
+pub fn is_runout(action: &ActionCommand) -> bool {
+    action.action == "out_of_filament" || action.detail.contains("filament_runout")
+}
+
+/**
+ * Reaction of a streamed job to an action command
+ * @param action: &ActionCommand
+ * @return Option<Reaction>, None for the actions the job does not follow
+ */
+pub fn reaction(action: &ActionCommand) -> Option<Reaction> {
+    let reason = if is_runout(action) {
+        "Filament runout"
+    } else {
+        "Paused by the printer"
+    }
+    .to_string();
+
+    match action.action.as_str() {
+        "pause" | "out_of_filament" => Some(Reaction::Pause { park: true, reason }),
+        "paused" => Some(Reaction::Pause {
+            park: false,
+            reason,
+        }),
+        "resume" | "resumed" => Some(Reaction::Resume),
+        "cancel" => Some(Reaction::Cancel),
+        _ => None,
+    }
+}
+
+/**
+ * Build the host prompt from the prompt_* actions
+ * @param prompt: &mut Option<HostPrompt>, dialog of the printer
+ * @param action: &ActionCommand
+ * @return bool, true when the clients have to be told, the dialog was shown or closed
+ */
+pub fn apply_prompt(prompt: &mut Option<HostPrompt>, action: &ActionCommand) -> bool {
+    match action.action.as_str() {
+        "prompt_begin" => {
+            *prompt = Some(HostPrompt {
+                message: action.detail.clone(),
+                buttons: Vec::new(),
+                shown: false,
+            });
+            false
+        }
+        "prompt_button" | "prompt_choice" => {
+            if let Some(prompt) = prompt.as_mut() {
+                prompt.buttons.push(action.detail.clone());
+            }
+            false
+        }
+        "prompt_show" => match prompt.as_mut() {
+            Some(prompt) => {
+                prompt.shown = true;
+                true
+            }
+            None => false,
+        },
+        "prompt_end" => prompt.take().is_some_and(|prompt| prompt.shown),
+        _ => false,
+    }
+}
+
+/**
+ * Commands moving the nozzle up or down while a job is paused
+ * @param gcode: &GcodeState, modes restored after the move
+ * @param lift_mm: f64, negative to come back down
+ * @return Vec<String>
+ */
+pub fn park_commands(gcode: &GcodeState, lift_mm: f64) -> Vec<String> {
+    let mut commands = vec![
+        "G91".to_string(),
+        format!("G1 Z{} F{}", lift_mm, PARK_FEEDRATE),
+    ];
+    // G90 and G91 also set the extrusion mode, M82 and M83 come after them
+    if !gcode.relative_moves {
+        commands.push("G90".to_string());
+    }
+    commands.push(
+        if gcode.relative_extrusion {
+            "M83"
+        } else {
+            "M82"
+        }
+        .to_string(),
+    );
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(parse_action("ok T:210.0 /210.0"), None);
+        assert_eq!(parse_action("//action:"), None);
+
+        let action = parse_action("//action:pause filament_runout 0\n").unwrap();
+        assert_eq!(action.action, "pause");
+        assert_eq!(action.detail, "filament_runout 0");
+        assert_eq!(
+            reaction(&action),
+            Some(Reaction::Pause {
+                park: true,
+                reason: "Filament runout".to_string()
+            })
+        );
+
+        let paused = parse_action("//action:paused").unwrap();
+        assert_eq!(
+            reaction(&paused),
+            Some(Reaction::Pause {
+                park: false,
+                reason: "Paused by the printer".to_string()
+            })
+        );
+        assert!(is_runout(
+            &parse_action("//action:out_of_filament T0").unwrap()
+        ));
+        assert_eq!(
+            reaction(&parse_action("//action:resumed").unwrap()),
+            Some(Reaction::Resume)
+        );
+        assert_eq!(
+            reaction(&parse_action("//action:cancel").unwrap()),
+            Some(Reaction::Cancel)
+        );
+        assert_eq!(
+            reaction(&parse_action("//action:notification Hi").unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_apply_prompt() {
+        let mut prompt = None;
+        let lines = [
+            "//action:prompt_end",
+            "//action:prompt_begin FilamentRunout T0",
+            "//action:prompt_button Purge More",
+            "//action:prompt_button Continue",
+        ];
+        for line in lines {
+            assert!(!apply_prompt(&mut prompt, &parse_action(line).unwrap()));
+        }
+        assert!(!prompt.as_ref().unwrap().shown);
+
+        assert!(apply_prompt(
+            &mut prompt,
+            &parse_action("//action:prompt_show").unwrap()
+        ));
+        let shown = prompt.clone().unwrap();
+        assert_eq!(shown.message, "FilamentRunout T0");
+        assert_eq!(shown.buttons, vec!["Purge More", "Continue"]);
+
+        assert!(apply_prompt(
+            &mut prompt,
+            &parse_action("//action:prompt_end").unwrap()
+        ));
+        assert_eq!(prompt, None);
+    }
+
+    #[test]
+    fn test_park_commands() {
+        let gcode = GcodeState {
+            relative_extrusion: true,
+            ..GcodeState::default()
+        };
+        assert_eq!(
+            park_commands(&gcode, 5.0),
+            vec!["G91", "G1 Z5 F600", "G90", "M83"]
+        );
+        assert_eq!(park_commands(&gcode, -5.0)[1], "G1 Z-5 F600");
+    }
+}
@@ -0,0 +1,351 @@
+use log::{error, info};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tungstenite::http::HeaderMap;
+
+use crate::gcode::GcodeCommand;
+use crate::structs::MessageType;
+
+/// Client permissions, each role includes the ones before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Operator => write!(f, "operator"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(Error::other(format!("Unknown role \"{}\"", s))),
+        }
+    }
+}
+
+/// Stored token, only the SHA-256 hash of the secret is kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenEntry {
+    pub name: String,
+    pub role: Role,
+    pub hash: String,
+    pub created: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenStore {
+    pub tokens: Vec<TokenEntry>,
+}
+
+impl TokenStore {
+    /**
+     * Load the tokens file, a missing file is an empty store
+     * @param path: &Path, tokens file
+     * @return Result<TokenStore, Error>
+     */
+    pub fn load(path: &Path) -> Result<TokenStore, Error> {
+        if !path.exists() {
+            return Ok(TokenStore::default());
+        }
+
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(Error::other)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let data = serde_json::to_string_pretty(self).map_err(Error::other)?;
+        fs::write(path, data)
+    }
+
+    /**
+     * Create a new token, the secret is only returned here
+     * @param name: &str, unique name of the token
+     * @param role: Role, permissions of the token
+     * @return Result<String, Error>, the secret to hand to the client
+     */
+    pub fn mint(&mut self, name: &str, role: Role) -> Result<String, Error> {
+        if self.tokens.iter().any(|token| token.name == name) {
+            return Err(Error::other(format!("Token \"{}\" already exists", name)));
+        }
+
+        let mut secret = [0u8; 32];
+        rand::rng().fill_bytes(&mut secret);
+        let token = format!("xc_{}", to_hex(&secret));
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+
+        self.tokens.push(TokenEntry {
+            name: name.to_string(),
+            role,
+            hash: hash_token(&token),
+            created,
+        });
+
+        Ok(token)
+    }
+
+    pub fn revoke(&mut self, name: &str) -> Result<(), Error> {
+        let count = self.tokens.len();
+        self.tokens.retain(|token| token.name != name);
+
+        if self.tokens.len() == count {
+            return Err(Error::other(format!("Token \"{}\" not found", name)));
+        }
+
+        Ok(())
+    }
+
+    /**
+     * Find the token matching a secret
+     * @param token: &str, secret sent by the client
+     * @return Option<&TokenEntry>, None when the token is unknown or revoked
+     */
+    pub fn authenticate(&self, token: &str) -> Option<&TokenEntry> {
+        let hash = hash_token(token.trim());
+        self.tokens
+            .iter()
+            .find(|entry| constant_time_eq(entry.hash.as_bytes(), hash.as_bytes()))
+    }
+}
+
+/**
+ * Authenticate a token against the tokens file
+ * @param tokens_file: &str, path of the tokens file
+ * @param token: &str, secret sent by the client
+ * @return Option<(String, Role)>, name and role of the token
+ */
+pub fn authenticate(tokens_file: &str, token: &str) -> Option<(String, Role)> {
+    match TokenStore::load(Path::new(tokens_file)) {
+        Ok(store) => store
+            .authenticate(token)
+            .map(|entry| (entry.name.clone(), entry.role)),
+        Err(e) => {
+            error!("Failed to load tokens file {} | {}", tokens_file, e);
+            None
+        }
+    }
+}
+
+/**
+ * Token sent with a request
+ * Either "Authorization: Bearer <token>" or the "token" query parameter for browsers
+ * @param headers: &HeaderMap, request headers
+ * @param query: Option<&str>, query string of the request
+ * @return Option<String>, token if present
+ */
+pub fn request_token(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+    let header = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    header.or_else(|| {
+        query?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token=").map(|token| token.to_string()))
+    })
+}
+
+/**
+ * Role needed to send a message
+ * Read only commands can be sent by viewers through GCommand
+ * @param message_type: &MessageType, type of the message
+ * @param command: Option<&GcodeCommand>, parsed command of a GCommand message
+ * @return Option<Role>, None when no authentication is needed
+ */
+pub fn required_role(message_type: &MessageType, command: Option<&GcodeCommand>) -> Option<Role> {
+    match message_type {
+        MessageType::Auth => None,
+        MessageType::GCommand => match command {
+            Some(command) if is_query(command) => Some(Role::Viewer),
+            _ => Some(Role::Operator),
+        },
+        MessageType::EepromRead
+        | MessageType::EepromBackups
+        | MessageType::BedMeshList
+        | MessageType::ControlStatus
+        | MessageType::PrinterStatus
+        | MessageType::SerialPorts
+        | MessageType::PrinterList
+        | MessageType::History
+        | MessageType::HistoryStats
+        | MessageType::Queue
+        | MessageType::Objects => Some(Role::Viewer),
+        MessageType::BedMesh
+        | MessageType::ControlAcquire
+        | MessageType::ControlRelease
+        | MessageType::EmergencyStop
+        | MessageType::QueueUpdate
+        | MessageType::PromptAnswer
+        | MessageType::ObjectCancel
+        | MessageType::CheckpointResume
+        | MessageType::CheckpointDiscard => Some(Role::Operator),
+        MessageType::SerialConfig
+        | MessageType::Terminal
+        | MessageType::Unsafe
+        | MessageType::EepromApply
+        | MessageType::EepromRestore
+        | MessageType::ControlTakeover => Some(Role::Admin),
+    }
+}
+
+/// Commands that only report state
+fn is_query(command: &GcodeCommand) -> bool {
+    match (command.letter, command.number) {
+        // M27 S<seconds> turns on the SD status auto-report
+        ('M', 27) => !command.has_param('S'),
+        ('M', 20 | 31 | 33 | 105 | 114 | 115 | 119 | 503) => true,
+        _ => false,
+    }
+}
+
+/**
+ * Handle the "token" subcommand
+ * xcontroller token mint <name> <role> | revoke <name> | list
+ * @param args: &[String], arguments after "token"
+ * @param tokens_file: &str, path of the tokens file
+ * @return Result<(), Error>
+ */
+pub fn token_command(args: &[String], tokens_file: &str) -> Result<(), Error> {
+    let path = Path::new(tokens_file);
+    let mut store = TokenStore::load(path)?;
+
+    match args.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+        ["mint", name, role] => {
+            let token = store.mint(name, role.parse()?)?;
+            store.save(path)?;
+            info!("Token \"{}\" minted with role {}", name, role);
+            println!("{}", token);
+        }
+        ["revoke", name] => {
+            store.revoke(name)?;
+            store.save(path)?;
+            info!("Token \"{}\" revoked", name);
+            println!("Token \"{}\" revoked", name);
+        }
+        ["list"] => {
+            for token in &store.tokens {
+                println!("{}\t{}\t{}", token.name, token.role, token.created);
+            }
+        }
+        _ => return Err(Error::other(
+            "Usage: xcontroller token mint <name> <viewer|operator|admin> | revoke <name> | list",
+        )),
+    }
+
+    Ok(())
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcode::parse_gcode;
+
+    #[test]
+    fn test_mint_and_authenticate() {
+        let mut store = TokenStore::default();
+        let token = store.mint("dashboard", Role::Operator).unwrap();
+
+        assert!(token.starts_with("xc_"));
+        assert!(!store.tokens[0].hash.contains(&token));
+        assert_eq!(store.authenticate(&token).unwrap().role, Role::Operator);
+        assert!(store.authenticate("xc_invalid").is_none());
+        assert!(store.mint("dashboard", Role::Admin).is_err());
+    }
+
+    #[test]
+    fn test_revoke() {
+        let mut store = TokenStore::default();
+        let token = store.mint("old", Role::Admin).unwrap();
+
+        store.revoke("old").unwrap();
+        assert!(store.authenticate(&token).is_none());
+        assert!(store.revoke("old").is_err());
+    }
+
+    #[test]
+    fn test_token_store_file() {
+        let path =
+            std::env::temp_dir().join(format!("xcontroller_tokens_{}.json", std::process::id()));
+        let mut store = TokenStore::default();
+        let token = store.mint("cli", Role::Viewer).unwrap();
+        store.save(&path).unwrap();
+
+        let (name, role) = authenticate(path.to_str().unwrap(), &token).unwrap();
+        assert_eq!(name, "cli");
+        assert_eq!(role, Role::Viewer);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_required_role() {
+        let query = parse_gcode("M105").unwrap();
+        let motion = parse_gcode("G28").unwrap();
+
+        assert_eq!(
+            required_role(&MessageType::GCommand, Some(&query)),
+            Some(Role::Viewer)
+        );
+        assert_eq!(
+            required_role(&MessageType::GCommand, Some(&motion)),
+            Some(Role::Operator)
+        );
+        assert_eq!(
+            required_role(&MessageType::GCommand, Some(&parse_gcode("M27").unwrap())),
+            Some(Role::Viewer)
+        );
+        assert_eq!(
+            required_role(
+                &MessageType::GCommand,
+                Some(&parse_gcode("M27 S5").unwrap())
+            ),
+            Some(Role::Operator)
+        );
+        assert_eq!(required_role(&MessageType::Unsafe, None), Some(Role::Admin));
+        assert_eq!(required_role(&MessageType::Auth, None), None);
+        assert_eq!(
+            required_role(&MessageType::CheckpointResume, None),
+            Some(Role::Operator)
+        );
+        assert!(Role::Admin > Role::Operator && Role::Operator > Role::Viewer);
+    }
+}
@@ -1,51 +1,588 @@
-use crate::structs::Config;
-use log::warn;
-
-pub fn get_configuration(args: Vec<String>) -> Config {
-    // Set defaults in case arguments are not provided
-    let mut configuration = Config {
-        test_mode: false,
-        serial_port: "/dev/ttyUSB0".to_string(),
-        baud_rate: 115200,
-        ws_port: "9002".to_string(),
+use log::info;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use toml_edit::{DocumentMut, InlineTable, Item, TableLike, Value};
+
+use crate::discovery::AUTO_PORT;
+use crate::structs::{Config, PrinterConfig, SafetyLimits, SerialSettings};
+
+static CONFIG_FILE: &str = "./xcontroller.toml";
+// Used when the TOML file is missing, older versions were configured with JSON
+static LEGACY_CONFIG_FILE: &str = "./xcontroller.json";
+static SUBCOMMANDS: &[&str] = &["token", "ports", "config"];
+// Name of the printer built from the top level settings
+pub static DEFAULT_PRINTER: &str = "default";
+
+/// Parsed command line, values are validated when the configuration is loaded
+#[derive(Debug, Default, PartialEq)]
+pub struct CliArgs {
+    /// Subcommand and its arguments, e.g. ["token", "list"]
+    pub command: Vec<String>,
+    pub config: Option<String>,
+    pub ws_port: Option<String>,
+    pub serial_port: Option<String>,
+    pub baud: Option<String>,
+    pub test_mode: Option<String>,
+    /// Started with the four positional params of older versions
+    pub legacy_positional: bool,
+}
+
+/**
+ * Parse the command line
+ * Flags: --config <file>, --ws-port <port>, --serial-port <port>, --baud <rate>, --test-mode [true|false]
+ * The positional form "<ws_port> <serial_port> <baud_rate> <test_mode>" is still accepted
+ * @param args: &[String], arguments including the program name
+ * @return Result<CliArgs, Error>
+ */
+pub fn parse_args(args: &[String]) -> Result<CliArgs, Error> {
+    let mut cli = CliArgs::default();
+    let mut positional = Vec::new();
+    let mut iter = args.iter().skip(1).peekable();
+
+    while let Some(arg) = iter.next() {
+        // Older docs start the params with a bare "--"
+        if arg == "--" {
+            continue;
+        }
+
+        let Some(flag) = arg.strip_prefix("--") else {
+            positional.push(arg.clone());
+            continue;
+        };
+
+        let (name, inline) = match flag.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (flag, None),
+        };
+
+        let slot = match name {
+            "config" => &mut cli.config,
+            "ws-port" => &mut cli.ws_port,
+            "serial-port" => &mut cli.serial_port,
+            "baud" => &mut cli.baud,
+            "test-mode" => &mut cli.test_mode,
+            _ => return Err(Error::other(format!("Unknown flag --{}", name))),
+        };
+
+        let value = match inline {
+            Some(value) => value,
+            // A bare --test-mode enables it
+            None if name == "test-mode" => match iter.peek().map(|value| value.as_str()) {
+                Some("true" | "false") => iter.next().cloned().unwrap_or_default(),
+                _ => "true".to_string(),
+            },
+            None => iter
+                .next()
+                .cloned()
+                .ok_or_else(|| Error::other(format!("Missing value for --{}", name)))?,
+        };
+        *slot = Some(value);
+    }
+
+    match positional.first().map(String::as_str) {
+        None => {}
+        Some(command) if SUBCOMMANDS.contains(&command) => cli.command = positional,
+        Some(_) if positional.len() == 4 => {
+            let mut values = positional.into_iter();
+            cli.ws_port = cli.ws_port.or(values.next());
+            cli.serial_port = cli.serial_port.or(values.next());
+            cli.baud = cli.baud.or(values.next());
+            cli.test_mode = cli.test_mode.or(values.next());
+            cli.legacy_positional = true;
+        }
+        Some(_) => {
+            return Err(Error::other(format!(
+                "Unexpected arguments {:?}, use --ws-port, --serial-port, --baud and --test-mode",
+                positional
+            )))
+        }
+    }
+
+    Ok(cli)
+}
+
+/**
+ * Layer the configuration: defaults, TOML file, XCONTROLLER_* environment variables then flags
+ * @param cli: &CliArgs, parsed command line
+ * @param env: &dyn Fn(&str) -> Option<String>, environment lookup
+ * @return Result<Config, Error>, every invalid value is listed in the error
+ */
+pub fn load_configuration(
+    cli: &CliArgs,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<Config, Error> {
+    let explicit = cli.config.clone().or_else(|| env("XCONTROLLER_CONFIG"));
+
+    let mut configuration = match &explicit {
+        Some(path) if !Path::new(path).exists() => {
+            return Err(Error::other(format!("Config file {} not found", path)));
+        }
+        Some(path) => read_config_file(Path::new(path))?,
+        None if Path::new(CONFIG_FILE).exists() => read_config_file(Path::new(CONFIG_FILE))?,
+        None if Path::new(LEGACY_CONFIG_FILE).exists() => {
+            read_config_file(Path::new(LEGACY_CONFIG_FILE))?
+        }
+        None => Config {
+            config_file: CONFIG_FILE.to_string(),
+            ..Config::default()
+        },
     };
 
-    if args.len() > 4 {
-        let ws_port = args[1].clone();
-        let serial_port = args[2].clone();
-        let baudrate = args[3].clone();
-        let test_arg = args[4].clone();
-
-        configuration = Config {
-            test_mode: matches!(test_arg.to_lowercase().as_str(), "true"),
-            baud_rate: match baudrate.parse::<u32>() {
-                Ok(br) => br,
-                Err(_) => {
-                    warn!("Failed to parse baudrate. Using default baudrate 115200");
-                    115200
+    let mut errors = Vec::new();
+    let mut serial_override = false;
+    let overrides = [
+        (&cli.ws_port, "XCONTROLLER_WS_PORT"),
+        (&cli.serial_port, "XCONTROLLER_SERIAL_PORT"),
+        (&cli.baud, "XCONTROLLER_BAUD"),
+        (&cli.test_mode, "XCONTROLLER_TEST_MODE"),
+    ];
+
+    for (flag, variable) in overrides {
+        let Some(value) = flag.clone().or_else(|| env(variable)) else {
+            continue;
+        };
+        let value = value.trim();
+
+        match variable {
+            "XCONTROLLER_WS_PORT" => match value.parse() {
+                Ok(ws_port) => configuration.ws_port = ws_port,
+                Err(_) => errors.push(format!("Invalid websocket port \"{}\"", value)),
+            },
+            "XCONTROLLER_SERIAL_PORT" => {
+                configuration.serial_port = value.to_string();
+                serial_override = true;
+            }
+            "XCONTROLLER_BAUD" => {
+                match value.parse() {
+                    Ok(baud_rate) => configuration.baud_rate = baud_rate,
+                    Err(_) => errors.push(format!("Invalid baud rate \"{}\"", value)),
                 }
+                serial_override = true;
+            }
+            _ => match value.to_lowercase().as_str() {
+                "true" | "1" => configuration.test_mode = true,
+                "false" | "0" => configuration.test_mode = false,
+                _ => errors.push(format!("Invalid test mode \"{}\"", value)),
             },
-            serial_port,
-            ws_port,
-        };
+        }
+    }
+
+    // The top level serial settings are not used once printers are listed
+    if serial_override && !configuration.printers.is_empty() {
+        errors.push(
+            "--serial-port, --baud, XCONTROLLER_SERIAL_PORT and XCONTROLLER_BAUD do not apply \
+             when [[printers]] are configured, set serial_port and baud_rate on each printer"
+                .to_string(),
+        );
+    }
+
+    errors.extend(validate(&configuration));
+    if !errors.is_empty() {
+        return Err(Error::other(format!(
+            "Invalid configuration:\n  {}",
+            errors.join("\n  ")
+        )));
+    }
+
+    Ok(configuration)
+}
+
+/**
+ * Check values that would only fail once the server is running
+ * @param configuration: &Config, configuration to check
+ * @return Vec<String>, one message per invalid value
+ */
+fn validate(configuration: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    let checks = [
+        (configuration.ws_port == 0, "ws_port must not be 0"),
+        (
+            configuration.serial_port.trim().is_empty(),
+            "serial_port must not be empty",
+        ),
+        (
+            configuration.timeouts.port_ms == 0 || configuration.timeouts.response_ms == 0,
+            "timeouts must be greater than 0",
+        ),
+        (
+            configuration.access.rate_limit.messages_per_second <= 0.0,
+            "access.rate_limit.messages_per_second must be greater than 0",
+        ),
+        (
+            configuration.tls.enabled
+                && (configuration.tls.cert_path.is_empty()
+                    || configuration.tls.key_path.is_empty()),
+            "tls.cert_path and tls.key_path are required when TLS is enabled",
+        ),
+        (
+            configuration.auth.enabled && configuration.auth.tokens_file.is_empty(),
+            "auth.tokens_file is required when authentication is enabled",
+        ),
+        (
+            configuration.http.enabled
+                && (configuration.http.port == 0
+                    || configuration.http.port == configuration.ws_port),
+            "http.port must not be 0 or the ws_port",
+        ),
+        (
+            configuration.jobs.files_dir.trim().is_empty(),
+            "jobs.files_dir must not be empty",
+        ),
+        (
+            configuration.mqtt.enabled
+                && (configuration.mqtt.host.trim().is_empty() || configuration.mqtt.port == 0),
+            "mqtt.host must not be empty and mqtt.port not 0",
+        ),
+        (
+            configuration.mqtt.enabled
+                && (configuration.mqtt.topic_prefix.trim().is_empty()
+                    || configuration.mqtt.topic_prefix.contains(['#', '+'])),
+            "mqtt.topic_prefix must not be empty or contain wildcards",
+        ),
+        (
+            configuration.mqtt.enabled && configuration.mqtt.telemetry_secs == 0,
+            "mqtt.telemetry_secs must not be 0",
+        ),
+        (
+            configuration.queue.check_secs == 0,
+            "queue.check_secs must not be 0",
+        ),
+        (
+            configuration.recovery.enabled
+                && (configuration.recovery.interval_secs == 0
+                    || configuration.recovery.lift_mm < 0.0),
+            "recovery.interval_secs must not be 0 and recovery.lift_mm not negative",
+        ),
+        (
+            !configuration.actions.park_lift_mm.is_finite()
+                || configuration.actions.park_lift_mm < 0.0,
+            "actions.park_lift_mm must not be negative",
+        ),
+    ];
+
+    for (invalid, message) in checks {
+        if invalid {
+            errors.push(message.to_string());
+        }
+    }
+
+    errors.extend(validate_safety("safety", &configuration.safety));
+    for (name, limits) in &configuration.profiles {
+        errors.extend(validate_safety(&format!("profiles.{}", name), limits));
     }
+    errors.extend(validate_printers(configuration));
 
-    configuration
+    errors
+}
+
+fn validate_safety(section: &str, safety: &SafetyLimits) -> Vec<String> {
+    let volume = &safety.build_volume;
+    let feedrate = &safety.max_feedrate;
+
+    let checks = [
+        (
+            safety.max_hotend_temp <= 0.0 || safety.max_bed_temp <= 0.0,
+            "temperatures must be greater than 0",
+        ),
+        (
+            volume.min_x >= volume.max_x
+                || volume.min_y >= volume.max_y
+                || volume.min_z >= volume.max_z,
+            "build_volume minimums must be lower than maximums",
+        ),
+        (
+            [feedrate.x, feedrate.y, feedrate.z, feedrate.e]
+                .iter()
+                .any(|value| *value <= 0.0),
+            "max_feedrate values must be greater than 0",
+        ),
+    ];
+
+    checks
+        .into_iter()
+        .filter(|(invalid, _)| *invalid)
+        .map(|(_, message)| format!("{} {}", section, message))
+        .collect()
+}
+
+fn validate_printers(configuration: &Config) -> Vec<String> {
+    let mut errors = Vec::new();
+    let mut names = Vec::new();
+    let mut ports = Vec::new();
+
+    for printer in &configuration.printers {
+        let name = printer.name.trim();
+        if name.is_empty() || name.contains('/') {
+            errors.push(format!("Invalid printer name \"{}\"", printer.name));
+        } else if names.contains(&name) {
+            errors.push(format!("Printer {} is defined twice", name));
+        }
+        names.push(name);
+
+        let port = printer.serial_port.trim();
+        if port.is_empty() {
+            errors.push(format!("Printer {} has no serial_port", name));
+        } else if port != AUTO_PORT && ports.contains(&port) {
+            errors.push(format!("Port {} is used by several printers", port));
+        }
+        ports.push(port);
+
+        if printer
+            .timeouts
+            .is_some_and(|timeouts| timeouts.port_ms == 0 || timeouts.response_ms == 0)
+        {
+            errors.push(format!("Printer {} timeouts must be greater than 0", name));
+        }
+
+        if let Some(profile) = &printer.profile {
+            if !configuration.profiles.contains_key(profile) {
+                errors.push(format!("Printer {} uses unknown profile {}", name, profile));
+            }
+        }
+    }
+
+    errors
+}
+
+/**
+ * Printers managed by this instance
+ * Without printers entries, a single printer named "default" uses the top level settings
+ * @param configuration: &Config, loaded configuration
+ * @return Vec<PrinterConfig>, in configuration order
+ */
+pub fn printer_configs(configuration: &Config) -> Vec<PrinterConfig> {
+    if configuration.printers.is_empty() {
+        return vec![PrinterConfig {
+            name: DEFAULT_PRINTER.to_string(),
+            serial_port: configuration.serial_port.clone(),
+            baud_rate: configuration.baud_rate,
+            timeouts: None,
+            profile: None,
+        }];
+    }
+
+    configuration.printers.clone()
+}
+
+/**
+ * Safety limits of a printer, its profile or the top level limits
+ * @param configuration: &Config, loaded configuration
+ * @param printer: &PrinterConfig, printer entry
+ * @return SafetyLimits
+ */
+pub fn printer_safety(configuration: &Config, printer: &PrinterConfig) -> SafetyLimits {
+    printer
+        .profile
+        .as_ref()
+        .and_then(|profile| configuration.profiles.get(profile))
+        .unwrap_or(&configuration.safety)
+        .clone()
+}
+
+/**
+ * Handle the "config" subcommand, print the effective configuration as TOML
+ * @param configuration: &Config, loaded configuration
+ * @return Result<(), Error>
+ */
+pub fn config_command(configuration: &Config) -> Result<(), Error> {
+    let toml = toml::to_string_pretty(configuration).map_err(Error::other)?;
+    println!(
+        "# Effective configuration, file: {}",
+        configuration.config_file
+    );
+    println!("{}", toml);
+    Ok(())
+}
+
+/**
+ * Store the serial settings in the configuration file, other settings are kept
+ * The printers entry with the same name is updated, otherwise the top level settings
+ * @param config_file: &str, file the configuration was loaded from
+ * @param printer: &str, name of the printer
+ * @param settings: &SerialSettings, settings to store
+ * @return Result<(), Error>
+ */
+pub fn persist_serial_settings(
+    config_file: &str,
+    printer: &str,
+    settings: &SerialSettings,
+) -> Result<(), Error> {
+    let path = Path::new(config_file);
+    let data = if is_json(path) {
+        serial_settings_json(path, printer, settings)?
+    } else {
+        serial_settings_toml(path, printer, settings)?
+    };
+
+    fs::write(path, data)?;
+    info!("Serial settings stored in {}", path.display());
+    Ok(())
+}
+
+/// Comments and key order of the file are kept, only the serial values change
+fn serial_settings_toml(
+    path: &Path,
+    printer: &str,
+    settings: &SerialSettings,
+) -> Result<String, Error> {
+    let mut file = if path.exists() {
+        fs::read_to_string(path)?
+            .parse::<DocumentMut>()
+            .map_err(Error::other)?
+    } else {
+        DocumentMut::new()
+    };
+
+    let entry = file
+        .get_mut("printers")
+        .and_then(|printers| printers.as_array_of_tables_mut())
+        .and_then(|printers| {
+            printers
+                .iter_mut()
+                .find(|entry| entry.get("name").and_then(|name| name.as_str()) == Some(printer))
+        });
+    let table: &mut dyn TableLike = match entry {
+        Some(entry) => entry,
+        None => file.as_table_mut(),
+    };
+
+    set_toml_value(table, "serial_port", settings.serial_port.as_str().into());
+    set_toml_value(table, "baud_rate", i64::from(settings.baud_rate).into());
+
+    if !table
+        .get("timeouts")
+        .is_some_and(|timeouts| timeouts.is_table_like())
+    {
+        table.insert("timeouts", Item::Value(InlineTable::new().into()));
+    }
+    let timeouts = table
+        .get_mut("timeouts")
+        .and_then(|timeouts| timeouts.as_table_like_mut())
+        .ok_or_else(|| Error::other("Invalid timeouts"))?;
+    set_toml_value(
+        timeouts,
+        "port_ms",
+        (settings.timeouts.port_ms as i64).into(),
+    );
+    set_toml_value(
+        timeouts,
+        "response_ms",
+        (settings.timeouts.response_ms as i64).into(),
+    );
+
+    Ok(file.to_string())
+}
+
+/// Replace a value in place, the comments around it are kept
+fn set_toml_value(table: &mut dyn TableLike, key: &str, mut value: Value) {
+    match table.get_mut(key) {
+        Some(Item::Value(current)) => {
+            *value.decor_mut() = current.decor().clone();
+            *current = value;
+        }
+        _ => {
+            table.insert(key, Item::Value(value));
+        }
+    }
+}
+
+fn serial_settings_json(
+    path: &Path,
+    printer: &str,
+    settings: &SerialSettings,
+) -> Result<String, Error> {
+    let mut file = if path.exists() {
+        serde_json::from_str(&fs::read_to_string(path)?).map_err(Error::other)?
+    } else {
+        serde_json::json!({})
+    };
+
+    let has_entry = file["printers"]
+        .as_array()
+        .is_some_and(|printers| printers.iter().any(|entry| entry["name"] == printer));
+    let target = if has_entry {
+        file["printers"]
+            .as_array_mut()
+            .and_then(|printers| printers.iter_mut().find(|entry| entry["name"] == printer))
+    } else {
+        Some(&mut file)
+    };
+
+    let object = target
+        .and_then(|target| target.as_object_mut())
+        .ok_or_else(|| Error::other(format!("{} is not a JSON object", path.display())))?;
+    object.insert(
+        "serial_port".to_string(),
+        settings.serial_port.clone().into(),
+    );
+    object.insert("baud_rate".to_string(), settings.baud_rate.into());
+    object.insert(
+        "timeouts".to_string(),
+        serde_json::to_value(settings.timeouts).map_err(Error::other)?,
+    );
+
+    serde_json::to_string_pretty(&file).map_err(Error::other)
+}
+
+/**
+ * Read a configuration file, TOML or JSON for files ending with .json
+ * @param path: &Path, configuration file
+ * @return Result<Config, Error>
+ */
+fn read_config_file(path: &Path) -> Result<Config, Error> {
+    let data = fs::read_to_string(path).map_err(|e| {
+        Error::other(format!(
+            "Failed to read config file {} | {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let mut configuration: Config = if is_json(path) {
+        serde_json::from_str(&data).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&data).map_err(|e| e.to_string())
+    }
+    .map_err(|e| Error::other(format!("Invalid config file {} | {}", path.display(), e)))?;
+
+    info!("Loaded config file {}", path.display());
+    configuration.config_file = path.display().to_string();
+    Ok(configuration)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::structs::SerialTimeouts;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    fn temp_file(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("xcontroller_{}_{}", std::process::id(), name))
+    }
 
     #[test]
     fn test_get_configuration_defaults() {
         let args: Vec<String> = vec![];
-        let config = get_configuration(args);
+        let config = load_configuration(&parse_args(&args).unwrap(), &no_env).unwrap();
 
-        assert_eq!(config.test_mode, false);
+        assert!(!config.test_mode);
         assert_eq!(config.serial_port, "/dev/ttyUSB0");
         assert_eq!(config.baud_rate, 115200);
-        assert_eq!(config.ws_port, "9002");
+        assert_eq!(config.ws_port, 9002);
     }
 
     #[test]
@@ -57,12 +594,12 @@ mod tests {
             "9600".to_string(),
             "true".to_string(),
         ];
-        let config = get_configuration(args);
+        let config = load_configuration(&parse_args(&args).unwrap(), &no_env).unwrap();
 
-        assert_eq!(config.test_mode, true);
+        assert!(config.test_mode);
         assert_eq!(config.serial_port, "/dev/ttyS0");
         assert_eq!(config.baud_rate, 9600);
-        assert_eq!(config.ws_port, "8080");
+        assert_eq!(config.ws_port, 8080);
     }
 
     #[test]
@@ -74,11 +611,249 @@ mod tests {
             "invalid_baudrate".to_string(),
             "false".to_string(),
         ];
-        let config = get_configuration(args);
+        let error = load_configuration(&parse_args(&args).unwrap(), &no_env).unwrap_err();
 
-        assert_eq!(config.test_mode, false);
-        assert_eq!(config.serial_port, "/dev/ttyS0");
-        assert_eq!(config.baud_rate, 115200); // Default baud rate
-        assert_eq!(config.ws_port, "8080");
+        assert!(error.to_string().contains("Invalid baud rate"));
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let cli = parse_args(&args(&[
+            "xcontroller",
+            "--ws-port=8080",
+            "--serial-port",
+            "/dev/ttyACM0",
+            "--test-mode",
+        ]))
+        .unwrap();
+        assert_eq!(cli.ws_port.as_deref(), Some("8080"));
+        assert_eq!(cli.serial_port.as_deref(), Some("/dev/ttyACM0"));
+        assert_eq!(cli.test_mode.as_deref(), Some("true"));
+
+        let cli = parse_args(&args(&[
+            "xcontroller",
+            "token",
+            "list",
+            "--config",
+            "a.toml",
+        ]))
+        .unwrap();
+        assert_eq!(cli.command, args(&["token", "list"]));
+        assert_eq!(cli.config.as_deref(), Some("a.toml"));
+
+        assert!(parse_args(&args(&["xcontroller", "8080", "/dev/ttyS0"])).is_err());
+        assert!(parse_args(&args(&["xcontroller", "--baud"])).is_err());
+        assert!(parse_args(&args(&["xcontroller", "--unknown", "1"])).is_err());
+    }
+
+    #[test]
+    fn test_configuration_layers() {
+        let path = temp_file("layers.toml");
+        fs::write(
+            &path,
+            "ws_port = 7000\nserial_port = \"/dev/ttyACM0\"\nbaud_rate = 250000\n",
+        )
+        .unwrap();
+
+        let cli = parse_args(&args(&[
+            "xcontroller",
+            "--config",
+            path.to_str().unwrap(),
+            "--baud",
+            "57600",
+        ]))
+        .unwrap();
+        let env = |name: &str| match name {
+            "XCONTROLLER_WS_PORT" => Some("7100".to_string()),
+            "XCONTROLLER_BAUD" => Some("9600".to_string()),
+            _ => None,
+        };
+
+        let config = load_configuration(&cli, &env).unwrap();
+        assert_eq!(config.serial_port, "/dev/ttyACM0");
+        assert_eq!(config.ws_port, 7100);
+        assert_eq!(config.baud_rate, 57600);
+        assert_eq!(config.config_file, path.display().to_string());
+
+        fs::write(
+            &path,
+            "[[printers]]\nname = \"mk3\"\nserial_port = \"/dev/ttyACM0\"\n",
+        )
+        .unwrap();
+        let error = load_configuration(&cli, &no_env).unwrap_err();
+        assert!(error.to_string().contains("[[printers]]"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_config_file_safety() {
+        let path = temp_file("safety.toml");
+        fs::write(
+            &path,
+            "[safety]\nmax_hotend_temp = 300\n\n[safety.build_volume]\nmax_x = 350\n",
+        )
+        .unwrap();
+
+        let config = read_config_file(&path).unwrap();
+        assert_eq!(config.safety.max_hotend_temp, 300.0);
+        assert_eq!(config.safety.max_bed_temp, 120.0);
+        assert_eq!(config.safety.build_volume.max_x, 350.0);
+        assert_eq!(config.safety.build_volume.max_y, 220.0);
+
+        fs::write(&path, "[safety]\nmax_hotend_temp = \"hot\"\n").unwrap();
+        assert!(read_config_file(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        assert!(validate(&config).is_empty());
+
+        config.ws_port = 0;
+        config.safety.build_volume.min_x = 500.0;
+        assert_eq!(validate(&config).len(), 2);
+    }
+
+    #[test]
+    fn test_config_hides_secrets() {
+        let config: Config =
+            toml::from_str("[mqtt]\nusername = \"xcontroller\"\npassword = \"secret\"\n").unwrap();
+        assert_eq!(config.mqtt.password.as_deref(), Some("secret"));
+
+        let printed = toml::to_string_pretty(&config).unwrap();
+        assert!(printed.contains("xcontroller"));
+        assert!(!printed.contains("secret"));
+
+        // The configuration logged at startup
+        let logged = format!("{:?}", config);
+        assert!(logged.contains("password: Some(\"***\")"));
+        assert!(!logged.contains("secret"));
+    }
+
+    #[test]
+    fn test_printer_configs() {
+        let mut config = Config::default();
+        let printers = printer_configs(&config);
+        assert_eq!(printers.len(), 1);
+        assert_eq!(printers[0].name, DEFAULT_PRINTER);
+        assert_eq!(printers[0].serial_port, "/dev/ttyUSB0");
+
+        config = toml::from_str(
+            r#"
+            [profiles.large.build_volume]
+            max_x = 400
+
+            [[printers]]
+            name = "mk3"
+            serial_port = "/dev/ttyACM0"
+
+            [[printers]]
+            name = "voron"
+            serial_port = "/dev/ttyACM1"
+            baud_rate = 250000
+            profile = "large"
+            "#,
+        )
+        .unwrap();
+        assert!(validate(&config).is_empty());
+
+        let printers = printer_configs(&config);
+        assert_eq!(printers.len(), 2);
+        assert_eq!(printers[1].baud_rate, 250000);
+        assert_eq!(
+            printer_safety(&config, &printers[0]).build_volume.max_x,
+            220.0
+        );
+        assert_eq!(
+            printer_safety(&config, &printers[1]).build_volume.max_x,
+            400.0
+        );
+
+        config.printers[1].name = "mk3".to_string();
+        config.printers[1].serial_port = "/dev/ttyACM0".to_string();
+        config.printers[1].profile = Some("small".to_string());
+        assert_eq!(validate(&config).len(), 3);
+    }
+
+    #[test]
+    fn test_persist_printer_serial_settings() {
+        let settings = SerialSettings {
+            serial_port: "/dev/ttyACM5".to_string(),
+            baud_rate: 250000,
+            timeouts: SerialTimeouts::default(),
+        };
+        let path = temp_file("printers.toml");
+        fs::write(
+            &path,
+            "serial_port = \"/dev/ttyUSB0\"\n\n[[printers]]\nname = \"mk3\"\nserial_port = \"/dev/ttyACM0\"\n\n[[printers]]\nname = \"voron\"\nserial_port = \"/dev/ttyACM1\"\n",
+        )
+        .unwrap();
+
+        persist_serial_settings(path.to_str().unwrap(), "voron", &settings).unwrap();
+
+        let config = read_config_file(&path).unwrap();
+        assert_eq!(config.serial_port, "/dev/ttyUSB0");
+        assert_eq!(config.printers[0].serial_port, "/dev/ttyACM0");
+        assert_eq!(config.printers[1].serial_port, "/dev/ttyACM5");
+        assert_eq!(config.printers[1].baud_rate, 250000);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_serial_settings_keeps_comments() {
+        let settings = SerialSettings {
+            serial_port: "/dev/ttyACM0".to_string(),
+            baud_rate: 250000,
+            timeouts: SerialTimeouts {
+                port_ms: 500,
+                response_ms: 200,
+            },
+        };
+        let path = temp_file("comments.toml");
+        fs::write(
+            &path,
+            "# Printer on the desk\nbaud_rate = 115200 # fastest stable rate\nserial_port = \"/dev/ttyUSB0\"\n\n# Slow firmware\n[timeouts]\nport_ms = 1000\n\n[auth]\nenabled = true\n",
+        )
+        .unwrap();
+
+        persist_serial_settings(path.to_str().unwrap(), DEFAULT_PRINTER, &settings).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# Printer on the desk\nbaud_rate = 250000 # fastest stable rate\nserial_port = \"/dev/ttyACM0\"\n\n# Slow firmware\n[timeouts]\nport_ms = 500\nresponse_ms = 200\n\n[auth]\nenabled = true\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_serial_settings() {
+        let settings = SerialSettings {
+            serial_port: "/dev/ttyACM0".to_string(),
+            baud_rate: 250000,
+            timeouts: SerialTimeouts::default(),
+        };
+
+        for name in ["serial.toml", "serial.json"] {
+            let path = temp_file(name);
+            let existing = if name.ends_with(".json") {
+                r#"{ "auth": { "enabled": true } }"#
+            } else {
+                "[auth]\nenabled = true\n"
+            };
+            fs::write(&path, existing).unwrap();
+
+            persist_serial_settings(path.to_str().unwrap(), DEFAULT_PRINTER, &settings).unwrap();
+
+            let config = read_config_file(&path).unwrap();
+            assert!(config.auth.enabled);
+            assert_eq!(config.serial_port, "/dev/ttyACM0");
+            assert_eq!(config.baud_rate, 250000);
+
+            fs::remove_file(&path).unwrap();
+        }
     }
 }
@@ -0,0 +1,235 @@
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::structs::{ControlHolder, ControlStatus};
+
+/**
+ * Exclusive control of the printer
+ * While a client holds the lock the other clients are read only observers
+ * The lock is released by its holder, on disconnect or after a period without commands
+ */
+pub struct ControlLock {
+    timeout: Duration,
+    holder: Mutex<Option<(ControlHolder, Instant)>>,
+}
+
+impl ControlLock {
+    pub fn new(timeout: Duration) -> Self {
+        ControlLock {
+            timeout,
+            holder: Mutex::new(None),
+        }
+    }
+
+    /**
+     * Take the lock if it is free or already held by the client
+     * @param client_id: u64, id of the connection
+     * @param name: &str, token name of the client
+     * @param peer: SocketAddr, address of the client
+     * @return Result<ControlStatus, Error>, error when another client holds the lock
+     */
+    pub fn acquire(
+        &self,
+        client_id: u64,
+        name: &str,
+        peer: SocketAddr,
+    ) -> Result<ControlStatus, Error> {
+        let mut holder = self.lock();
+        self.expire_locked(&mut holder);
+
+        match holder.as_mut() {
+            Some((current, activity)) if current.client_id == client_id => {
+                *activity = Instant::now();
+                Ok(status(Some(current.clone()), "acquired"))
+            }
+            Some((current, _)) => Err(held_by(current)),
+            None => {
+                let new_holder = new_holder(client_id, name, peer);
+                *holder = Some((new_holder.clone(), Instant::now()));
+                Ok(status(Some(new_holder), "acquired"))
+            }
+        }
+    }
+
+    /**
+     * Take the lock even when another client holds it, reserved to admins
+     * @param client_id: u64, id of the connection
+     * @param name: &str, token name of the client
+     * @param peer: SocketAddr, address of the client
+     * @return ControlStatus
+     */
+    pub fn takeover(&self, client_id: u64, name: &str, peer: SocketAddr) -> ControlStatus {
+        let new_holder = new_holder(client_id, name, peer);
+        *self.lock() = Some((new_holder.clone(), Instant::now()));
+        status(Some(new_holder), "takeover")
+    }
+
+    /**
+     * Release the lock held by the client
+     * @param client_id: u64, id of the connection
+     * @return Result<ControlStatus, Error>, error when the client does not hold the lock
+     */
+    pub fn release(&self, client_id: u64) -> Result<ControlStatus, Error> {
+        let mut holder = self.lock();
+        self.expire_locked(&mut holder);
+
+        match holder.as_ref() {
+            Some((current, _)) if current.client_id == client_id => {
+                *holder = None;
+                Ok(status(None, "released"))
+            }
+            Some((current, _)) => Err(held_by(current)),
+            None => Err(Error::other("Control is not held")),
+        }
+    }
+
+    /**
+     * Check that a client may send commands and renew its lock
+     * @param client_id: u64, id of the connection
+     * @return Result<(), Error>, the reason when another client holds the lock
+     */
+    pub fn check(&self, client_id: u64) -> Result<(), Error> {
+        let mut holder = self.lock();
+        self.expire_locked(&mut holder);
+
+        match holder.as_mut() {
+            Some((current, activity)) if current.client_id == client_id => {
+                *activity = Instant::now();
+                Ok(())
+            }
+            Some((current, _)) => Err(held_by(current)),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Release the lock of a closed connection
+     * @param client_id: u64, id of the connection
+     * @return Option<ControlStatus>, new status when the client was holding the lock
+     */
+    pub fn disconnect(&self, client_id: u64) -> Option<ControlStatus> {
+        let mut holder = self.lock();
+
+        match holder.as_ref() {
+            Some((current, _)) if current.client_id == client_id => {
+                *holder = None;
+                Some(status(None, "disconnected"))
+            }
+            _ => None,
+        }
+    }
+
+    /**
+     * Release the lock when its holder has been idle for too long
+     * @return Option<ControlStatus>, new status when the lock expired
+     */
+    pub fn expire(&self) -> Option<ControlStatus> {
+        let mut holder = self.lock();
+        self.expire_locked(&mut holder)
+    }
+
+    pub fn status(&self) -> ControlStatus {
+        let mut holder = self.lock();
+        self.expire_locked(&mut holder);
+        status(
+            holder.as_ref().map(|(current, _)| current.clone()),
+            "status",
+        )
+    }
+
+    fn expire_locked(
+        &self,
+        holder: &mut Option<(ControlHolder, Instant)>,
+    ) -> Option<ControlStatus> {
+        match holder {
+            Some((_, activity)) if activity.elapsed() >= self.timeout => {
+                *holder = None;
+                Some(status(None, "timeout"))
+            }
+            _ => None,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<(ControlHolder, Instant)>> {
+        self.holder.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn new_holder(client_id: u64, name: &str, peer: SocketAddr) -> ControlHolder {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    ControlHolder {
+        client_id,
+        name: name.to_string(),
+        peer: peer.to_string(),
+        since,
+    }
+}
+
+fn status(holder: Option<ControlHolder>, reason: &str) -> ControlStatus {
+    ControlStatus {
+        holder,
+        reason: reason.to_string(),
+    }
+}
+
+fn held_by(holder: &ControlHolder) -> Error {
+    Error::other(format!(
+        "Control is held by {} ({}), you are an observer",
+        holder.name, holder.peer
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5000".parse().unwrap()
+    }
+
+    #[test]
+    fn test_acquire_and_release() {
+        let control = ControlLock::new(Duration::from_secs(60));
+
+        assert!(control.check(2).is_ok());
+        control.acquire(1, "alice", peer()).unwrap();
+        assert!(control.acquire(1, "alice", peer()).is_ok());
+        assert!(control.acquire(2, "bob", peer()).is_err());
+        assert!(control.check(1).is_ok());
+        assert!(control.check(2).is_err());
+        assert!(control.release(2).is_err());
+
+        control.release(1).unwrap();
+        assert!(control.check(2).is_ok());
+        assert!(control.release(1).is_err());
+    }
+
+    #[test]
+    fn test_takeover_and_disconnect() {
+        let control = ControlLock::new(Duration::from_secs(60));
+
+        control.acquire(1, "alice", peer()).unwrap();
+        let status = control.takeover(2, "admin", peer());
+        assert_eq!(status.holder.unwrap().client_id, 2);
+        assert!(control.check(1).is_err());
+
+        assert!(control.disconnect(1).is_none());
+        assert_eq!(control.disconnect(2).unwrap().reason, "disconnected");
+        assert!(control.status().holder.is_none());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let control = ControlLock::new(Duration::ZERO);
+
+        control.acquire(1, "alice", peer()).unwrap();
+        assert_eq!(control.expire().unwrap().reason, "timeout");
+        assert!(control.check(2).is_ok());
+    }
+}
@@ -0,0 +1,231 @@
+use log::{debug, info};
+use serialport::{available_ports, SerialPortType};
+use std::io::Error;
+use std::thread;
+use std::time::Duration;
+
+use crate::parser::m115;
+use crate::serialcom::{open_port, send_command};
+use crate::structs::{DetectedPrinter, PortDescription, SerialTimeouts};
+
+/// Value of Config.serial_port probing every port
+pub static AUTO_PORT: &str = "auto";
+/// Value of Config.baud_rate probing every baud rate
+pub static AUTO_BAUD: u32 = 0;
+
+// Most common rates first, 250000 is used by many CH340 and STM32 boards
+static BAUD_RATES: &[u32] = &[115200, 250000, 230400, 57600, 500000, 1000000, 38400, 19200];
+static PROBE_ATTEMPTS: u32 = 3;
+
+/**
+ * List the serial ports of the host
+ * @return Result<Vec<PortDescription>, Error>, ports with their USB details
+ */
+pub fn list_ports() -> Result<Vec<PortDescription>, Error> {
+    let ports = available_ports().map_err(Error::from)?;
+
+    Ok(ports
+        .into_iter()
+        .map(|port| match port.port_type {
+            SerialPortType::UsbPort(usb) => PortDescription {
+                port_name: port.port_name,
+                port_type: "usb".to_string(),
+                vid: Some(usb.vid),
+                pid: Some(usb.pid),
+                serial_number: usb.serial_number,
+                manufacturer: usb.manufacturer,
+                product: usb.product,
+            },
+            port_type => PortDescription {
+                port_name: port.port_name,
+                port_type: match port_type {
+                    SerialPortType::PciPort => "pci",
+                    SerialPortType::BluetoothPort => "bluetooth",
+                    _ => "unknown",
+                }
+                .to_string(),
+                vid: None,
+                pid: None,
+                serial_number: None,
+                manufacturer: None,
+                product: None,
+            },
+        })
+        .collect())
+}
+
+/**
+ * Ports and baud rates to try, USB ports first
+ * @param serial_port: &str, configured port or "auto"
+ * @param baud_rate: u32, configured baud rate or 0 for auto
+ * @param ports: &[PortDescription], ports of the host
+ * @return Vec<(String, u32)>
+ */
+pub fn candidates(
+    serial_port: &str,
+    baud_rate: u32,
+    ports: &[PortDescription],
+) -> Vec<(String, u32)> {
+    let port_names: Vec<String> = if serial_port == AUTO_PORT {
+        let mut ports: Vec<&PortDescription> = ports.iter().collect();
+        ports.sort_by_key(|port| port.port_type != "usb");
+        ports.iter().map(|port| port.port_name.clone()).collect()
+    } else {
+        vec![serial_port.to_string()]
+    };
+
+    let baud_rates: Vec<u32> = if baud_rate == AUTO_BAUD {
+        BAUD_RATES.to_vec()
+    } else {
+        vec![baud_rate]
+    };
+
+    port_names
+        .iter()
+        .flat_map(|port| baud_rates.iter().map(move |baud| (port.clone(), *baud)))
+        .collect()
+}
+
+/**
+ * Check if a firmware answers M115 on a port
+ * Opening the port resets most boards, so the first attempts may go unanswered
+ * @param port_name: &str, port to probe
+ * @param baud_rate: u32, baud rate to probe
+ * @return Option<DetectedPrinter>
+ */
+pub fn probe(port_name: &str, baud_rate: u32) -> Option<DetectedPrinter> {
+    let timeouts = SerialTimeouts::default();
+    let mut port = open_port(
+        port_name,
+        baud_rate,
+        Duration::from_millis(timeouts.port_ms),
+    )
+    .inspect_err(|e| debug!("Probe {} failed | {}", port_name, e))
+    .ok()?;
+
+    for _ in 0..PROBE_ATTEMPTS {
+        let response = send_command(
+            &mut port,
+            "M115",
+            Duration::from_millis(timeouts.response_ms),
+        )
+        .ok()?;
+        let info = m115(response);
+        if !info.firmware_name.is_empty() {
+            return Some(DetectedPrinter {
+                port_name: port_name.to_string(),
+                baud_rate,
+                firmware_name: info.firmware_name,
+                firmware_version: info.firmware_version,
+            });
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    debug!("No firmware on {} at {}", port_name, baud_rate);
+    None
+}
+
+/**
+ * Find the printer when the port or the baud rate is set to auto
+ * @param serial_port: &str, configured port or "auto"
+ * @param baud_rate: u32, configured baud rate or 0 for auto
+ * @return Result<DetectedPrinter, Error>
+ */
+pub fn detect(serial_port: &str, baud_rate: u32) -> Result<DetectedPrinter, Error> {
+    let ports = if serial_port == AUTO_PORT {
+        list_ports()?
+    } else {
+        Vec::new()
+    };
+
+    let detected = candidates(serial_port, baud_rate, &ports)
+        .iter()
+        .find_map(|(port, baud)| probe(port, *baud))
+        .ok_or_else(|| {
+            Error::new(
+                std::io::ErrorKind::NotFound,
+                "No firmware answered M115 on the candidate ports",
+            )
+        })?;
+
+    info!(
+        "Detected {} {} on {} at {}",
+        detected.firmware_name, detected.firmware_version, detected.port_name, detected.baud_rate
+    );
+    Ok(detected)
+}
+
+/**
+ * Handle the "ports" subcommand
+ * xcontroller ports | ports probe
+ * @param args: &[String], arguments after "ports"
+ * @return Result<(), Error>
+ */
+pub fn ports_command(args: &[String]) -> Result<(), Error> {
+    match args.iter().map(String::as_str).collect::<Vec<&str>>()[..] {
+        [] => {
+            for port in list_ports()? {
+                match (port.vid, port.pid) {
+                    (Some(vid), Some(pid)) => println!(
+                        "{}\t{}\t{:04x}:{:04x}\t{}\t{}\t{}",
+                        port.port_name,
+                        port.port_type,
+                        vid,
+                        pid,
+                        port.manufacturer.unwrap_or_default(),
+                        port.product.unwrap_or_default(),
+                        port.serial_number.unwrap_or_default()
+                    ),
+                    _ => println!("{}\t{}", port.port_name, port.port_type),
+                }
+            }
+        }
+        ["probe"] => {
+            let detected = detect(AUTO_PORT, AUTO_BAUD)?;
+            println!(
+                "{}\t{}\t{} {}",
+                detected.port_name,
+                detected.baud_rate,
+                detected.firmware_name,
+                detected.firmware_version
+            );
+        }
+        _ => return Err(Error::other("Usage: xcontroller ports [probe]")),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(port_name: &str, port_type: &str) -> PortDescription {
+        PortDescription {
+            port_name: port_name.to_string(),
+            port_type: port_type.to_string(),
+            vid: None,
+            pid: None,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let ports = vec![port("/dev/ttyS0", "pci"), port("/dev/ttyACM0", "usb")];
+
+        let auto = candidates(AUTO_PORT, AUTO_BAUD, &ports);
+        assert_eq!(auto.len(), 2 * BAUD_RATES.len());
+        assert_eq!(auto[0], ("/dev/ttyACM0".to_string(), 115200));
+        assert_eq!(auto[1], ("/dev/ttyACM0".to_string(), 250000));
+
+        assert_eq!(
+            candidates("/dev/ttyUSB0", 250000, &ports),
+            vec![("/dev/ttyUSB0".to_string(), 250000)]
+        );
+        assert_eq!(candidates(AUTO_PORT, 115200, &ports).len(), 2);
+    }
+}
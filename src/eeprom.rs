@@ -0,0 +1,337 @@
+use log::{error, info, warn};
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::parser::{m503, setting_selector};
+use crate::printer::data_dir;
+use crate::structs::{EepromApplyResult, EepromBackup, PrinterSettings};
+
+static BACKUP_DIR: &str = "./backups/eeprom";
+
+// Values are reported with two decimals by the firmware
+static TOLERANCE: f32 = 0.005;
+
+// Setting commands printed by M503, commands acting on the machine such as M104,
+// M303 or M502 are refused
+static SETTING_COMMANDS: &[&str] = &[
+    "M92", "M145", "M149", "M200", "M201", "M203", "M204", "M205", "M206", "M207", "M208", "M209",
+    "M217", "M218", "M301", "M304", "M412", "M413", "M420", "M425", "M569", "M603", "M665", "M666",
+    "M851", "M900", "M906", "M913", "M914",
+];
+
+/**
+ * Read the current settings from the printer
+ * @param send: FnMut(&str) -> Result<String, Error>, send a command and return the response
+ * @return Result<PrinterSettings, Error>, settings reported by M503
+ */
+pub fn read_settings<F>(send: &mut F) -> Result<PrinterSettings, Error>
+where
+    F: FnMut(&str) -> Result<String, Error>,
+{
+    let response =
+        send("M503").map_err(|e| Error::other(format!("Failed to read settings | {}", e)))?;
+    let settings = m503(response);
+
+    if settings.commands.is_empty() {
+        return Err(Error::other("No settings reported by the firmware"));
+    }
+
+    Ok(settings)
+}
+
+/**
+ * Compute the minimal set of commands turning current settings into desired settings
+ * Only the parameters that differ are sent
+ * @param current: &PrinterSettings, settings reported by the printer
+ * @param desired: &PrinterSettings, settings requested by the client
+ * @return Vec<String>, commands to send
+ */
+pub fn diff_settings(current: &PrinterSettings, desired: &PrinterSettings) -> Vec<String> {
+    let mut commands = Vec::new();
+
+    for (key, params) in &desired.commands {
+        let current_params = current.commands.get(key);
+
+        let changed: Vec<String> = params
+            .iter()
+            .filter(|(letter, value)| {
+                current_params
+                    .and_then(|p| p.get(letter))
+                    .is_none_or(|current| (current - *value).abs() > TOLERANCE)
+            })
+            .map(|(letter, value)| format!("{}{}", letter, value))
+            .collect();
+
+        if !changed.is_empty() {
+            commands.push(format!("{} {}", key, changed.join(" ")));
+        }
+    }
+
+    commands
+}
+
+/**
+ * Compare the settings read back from the printer with the desired ones
+ * @param actual: &PrinterSettings, settings reported after applying
+ * @param desired: &PrinterSettings, settings requested by the client
+ * @return Vec<String>, parameters that did not take effect
+ */
+pub fn verify_settings(actual: &PrinterSettings, desired: &PrinterSettings) -> Vec<String> {
+    diff_settings(actual, desired)
+}
+
+/**
+ * Apply desired settings, verify them and optionally persist with M500
+ * A backup of the settings before and after the change is stored on the host
+ * @param send: FnMut(&str) -> Result<String, Error>, send a command and return the response
+ * @param printer: &str, name of the printer, backups are stored per printer
+ * @param desired: &PrinterSettings, settings requested by the client
+ * @param persist: bool, store the settings in EEPROM
+ * @return Result<EepromApplyResult, Error>, applied commands and verification result
+ */
+pub fn apply_settings<F>(
+    send: &mut F,
+    printer: &str,
+    desired: &PrinterSettings,
+    persist: bool,
+) -> Result<EepromApplyResult, Error>
+where
+    F: FnMut(&str) -> Result<String, Error>,
+{
+    validate_settings(desired)?;
+
+    let current = read_settings(send)?;
+    let commands = diff_settings(&current, desired);
+    let mut result = EepromApplyResult::default();
+
+    if commands.is_empty() {
+        info!("Settings already up to date");
+        return Ok(result);
+    }
+
+    // Snapshot the known state before touching anything
+    save_backup(printer, &current)?;
+
+    for command in &commands {
+        send(command).map_err(|e| Error::other(format!("Failed to send {} | {}", command, e)))?;
+    }
+
+    let applied = read_settings(send)?;
+    result.mismatches = verify_settings(&applied, desired);
+    result.commands = commands;
+
+    if !result.mismatches.is_empty() {
+        warn!("Settings not applied | {:?}", result.mismatches);
+    } else if persist {
+        send("M500").map_err(|e| Error::other(format!("Failed to store settings | {}", e)))?;
+        result.persisted = true;
+    }
+
+    result.backup_version = Some(save_backup(printer, &applied)?.version);
+
+    Ok(result)
+}
+
+/**
+ * Restore a backup version onto the printer
+ * @param send: FnMut(&str) -> Result<String, Error>, send a command and return the response
+ * @param printer: &str, name of the printer
+ * @param version: u32, backup version to restore
+ * @param persist: bool, store the settings in EEPROM
+ * @return Result<EepromApplyResult, Error>, applied commands and verification result
+ */
+pub fn restore_backup<F>(
+    send: &mut F,
+    printer: &str,
+    version: u32,
+    persist: bool,
+) -> Result<EepromApplyResult, Error>
+where
+    F: FnMut(&str) -> Result<String, Error>,
+{
+    let backup = load_backup(printer, version)?;
+    apply_settings(send, printer, &backup.settings, persist)
+}
+
+/**
+ * List the backups stored on the host
+ * @param printer: &str, name of the printer
+ * @return Result<Vec<EepromBackup>, Error>, backups ordered by version
+ */
+pub fn list_backups(printer: &str) -> Result<Vec<EepromBackup>, Error> {
+    list_backups_in(&data_dir(BACKUP_DIR, printer))
+}
+
+fn list_backups_in(dir: &Path) -> Result<Vec<EepromBackup>, Error> {
+    let mut backups = Vec::new();
+
+    if !dir.exists() {
+        return Ok(backups);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            match fs::read_to_string(&path)
+                .ok()
+                .and_then(|data| serde_json::from_str::<EepromBackup>(&data).ok())
+            {
+                Some(backup) => backups.push(backup),
+                None => error!("Failed to read backup {}", path.display()),
+            }
+        }
+    }
+
+    backups.sort_by_key(|backup| backup.version);
+    Ok(backups)
+}
+
+fn load_backup(printer: &str, version: u32) -> Result<EepromBackup, Error> {
+    list_backups(printer)?
+        .into_iter()
+        .find(|backup| backup.version == version)
+        .ok_or_else(|| Error::other(format!("Backup version {} not found", version)))
+}
+
+fn save_backup(printer: &str, settings: &PrinterSettings) -> Result<EepromBackup, Error> {
+    save_backup_in(&data_dir(BACKUP_DIR, printer), settings)
+}
+
+fn save_backup_in(dir: &Path, settings: &PrinterSettings) -> Result<EepromBackup, Error> {
+    fs::create_dir_all(dir)?;
+
+    let version = list_backups_in(dir)?
+        .last()
+        .map_or(1, |backup| backup.version + 1);
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    let backup = EepromBackup {
+        version,
+        timestamp,
+        settings: settings.clone(),
+    };
+
+    let path = dir.join(format!("v{:04}_{}.json", version, timestamp));
+    let data = serde_json::to_string_pretty(&backup).map_err(Error::other)?;
+    fs::write(&path, data)?;
+
+    info!("Settings backup saved | {}", path.display());
+    Ok(backup)
+}
+
+/// Only setting commands reported by M503 can be applied
+fn validate_settings(settings: &PrinterSettings) -> Result<(), Error> {
+    for (key, params) in &settings.commands {
+        let mut parts = key.split_whitespace();
+        let command = parts.next().unwrap_or_default();
+        let selector = parts.next();
+
+        let valid_command = SETTING_COMMANDS.contains(&command);
+        let valid_selector = match (selector, setting_selector(command)) {
+            (None, _) => true,
+            (Some(selector), Some(letter)) => selector.starts_with(letter),
+            (Some(_), None) => false,
+        };
+
+        if !valid_command || !valid_selector || parts.next().is_some() {
+            return Err(Error::other(format!("Invalid setting command {}", key)));
+        }
+
+        if params.keys().any(|letter| !letter.is_ascii_uppercase()) {
+            return Err(Error::other(format!("Invalid parameter for {}", key)));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::SettingParams;
+    use std::collections::BTreeMap;
+
+    fn settings(entries: &[(&str, &[(char, f32)])]) -> PrinterSettings {
+        let mut commands = BTreeMap::new();
+        for (key, params) in entries {
+            commands.insert(
+                key.to_string(),
+                params.iter().copied().collect::<SettingParams>(),
+            );
+        }
+        PrinterSettings { commands }
+    }
+
+    #[test]
+    fn test_diff_settings_only_changed_params() {
+        let current = settings(&[
+            ("M92", &[('X', 80.0), ('Y', 80.0), ('E', 93.0)]),
+            ("M301", &[('P', 21.73), ('I', 1.54), ('D', 76.55)]),
+        ]);
+        let desired = settings(&[
+            ("M92", &[('X', 80.0), ('E', 95.5)]),
+            ("M301", &[('P', 21.73), ('I', 1.54), ('D', 76.55)]),
+            ("M145 S0", &[('H', 210.0)]),
+        ]);
+
+        let commands = diff_settings(&current, &desired);
+        assert_eq!(commands, vec!["M145 S0 H210", "M92 E95.5"]);
+    }
+
+    #[test]
+    fn test_diff_settings_tolerance() {
+        let current = settings(&[("M900", &[('K', 0.08)])]);
+        let desired = settings(&[("M900", &[('K', 0.0801)])]);
+        assert!(diff_settings(&current, &desired).is_empty());
+    }
+
+    #[test]
+    fn test_apply_settings_unchanged() {
+        let desired = settings(&[("M92", &[('X', 80.0)])]);
+        let mut sent = Vec::new();
+        let mut send = |cmd: &str| -> Result<String, Error> {
+            sent.push(cmd.to_string());
+            Ok("echo:  M92 X80.00 Y80.00\nok".to_string())
+        };
+
+        let result = apply_settings(&mut send, "test", &desired, true).unwrap();
+        assert!(result.commands.is_empty());
+        assert!(!result.persisted);
+        assert_eq!(sent, vec!["M503"]);
+    }
+
+    #[test]
+    fn test_validate_settings() {
+        assert!(validate_settings(&settings(&[("M145 S1", &[('H', 240.0)])])).is_ok());
+        assert!(validate_settings(&settings(&[("M92 S1", &[('X', 80.0)])])).is_err());
+        assert!(validate_settings(&settings(&[("G28", &[('X', 0.0)])])).is_err());
+        assert!(validate_settings(&settings(&[("M92", &[('x', 80.0)])])).is_err());
+        // M-codes that are not settings
+        assert!(validate_settings(&settings(&[("M104", &[('S', 300.0)])])).is_err());
+        assert!(validate_settings(&settings(&[("M502", &[])])).is_err());
+        assert!(validate_settings(&settings(&[("M303", &[('E', 0.0)])])).is_err());
+    }
+
+    #[test]
+    fn test_backup_versions() {
+        let dir = std::env::temp_dir().join(format!("xcontroller_eeprom_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let first = save_backup_in(&dir, &settings(&[("M92", &[('X', 80.0)])])).unwrap();
+        let second = save_backup_in(&dir, &settings(&[("M92", &[('X', 81.0)])])).unwrap();
+        let backups = list_backups_in(&dir).unwrap();
+
+        assert_eq!(first.version, 1);
+        assert_eq!(second.version, 2);
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[1].settings.commands["M92"][&'X'], 81.0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use log::{error, warn};
+use std::io::Error;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::serialcom::emergency_write;
+use crate::state::{ManagedPrinter, ServerState};
+use crate::structs::{EmergencyStopEvent, PrinterInfo, PrinterState};
+
+/**
+ * Commands written for an emergency stop
+ * With an emergency parser the firmware handles them as soon as they are received,
+ * M410 stops the moves in the planner before M112 kills the printer.
+ * Without it they wait behind the buffered commands and M410 brings nothing.
+ * @param info: Option<&PrinterInfo>, capabilities reported by M115
+ * @return Vec<&str>
+ */
+pub fn emergency_commands(info: Option<&PrinterInfo>) -> Vec<&'static str> {
+    if has_emergency_parser(info) {
+        vec!["M410", "M112"]
+    } else {
+        vec!["M112"]
+    }
+}
+
+fn has_emergency_parser(info: Option<&PrinterInfo>) -> bool {
+    info.is_some_and(|info| info.emergency_parser == 1)
+}
+
+/**
+ * Stop the printer immediately
+ * The commands are written without waiting for the command in progress,
+ * the running job is cancelled, the printer is marked as halted and the event is broadcast to every client
+ * @param state: &ServerState, shared state
+ * @param target: &ManagedPrinter, printer to stop, the other printers keep running
+ * @param peer: SocketAddr, client that triggered the stop
+ * @param name: &str, token name of the client
+ * @return Result<EmergencyStopEvent, Error>
+ */
+pub fn emergency_stop(
+    state: &ServerState,
+    target: &ManagedPrinter,
+    peer: SocketAddr,
+    name: &str,
+) -> Result<EmergencyStopEvent, Error> {
+    let printer = &target.printer;
+    let info = printer.info();
+    let commands = emergency_commands(info.as_ref());
+    let emergency_parser = has_emergency_parser(info.as_ref());
+
+    // Cancelled before the printer is halted, the job would fail on the line in progress
+    if let Some(job) = target.job.halt() {
+        warn!(
+            "Job {} on {} cancelled by the emergency stop",
+            job.file, target.name
+        );
+    }
+    // Marked before writing so no other command is sent in the meantime
+    printer.set_state(
+        PrinterState::Halted,
+        Some(format!("Emergency stop from {}", peer)),
+    );
+    error!(
+        "Emergency stop of {} from {} ({}) | {:?}",
+        target.name, peer, name, commands
+    );
+
+    // The worker writes them on its connection, without connection the port is opened here
+    if printer.port().is_some() {
+        printer.send_urgent(&commands)?;
+    } else {
+        let settings = printer.settings();
+        emergency_write(&commands, &settings.serial_port, settings.baud_rate)?;
+    }
+
+    let warning = (!emergency_parser).then(|| {
+        let warning = "Firmware has no emergency parser, M112 runs after the buffered commands";
+        warn!("{}", warning);
+        warning.to_string()
+    });
+
+    let event = EmergencyStopEvent {
+        triggered_by: name.to_string(),
+        peer: peer.to_string(),
+        commands: commands.iter().map(|cmd| cmd.to_string()).collect(),
+        emergency_parser,
+        warning,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs(),
+    };
+    state.broadcast_event(&target.name, "EmergencyStop", &event);
+
+    Ok(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobFile;
+    use crate::printer::{OkPort, Printer};
+    use crate::structs::{Config, HistoryConfig, JobState, RecoveryConfig};
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_emergency_commands() {
+        let mut info = PrinterInfo::default();
+        assert_eq!(emergency_commands(None), vec!["M112"]);
+        assert_eq!(emergency_commands(Some(&info)), vec!["M112"]);
+
+        info.emergency_parser = 1;
+        assert_eq!(emergency_commands(Some(&info)), vec!["M410", "M112"]);
+    }
+
+    #[test]
+    fn test_emergency_stop_cancels_job() {
+        let configuration = Config {
+            serial_port: "/dev/xcontroller-emergency-test".to_string(),
+            history: HistoryConfig {
+                enabled: false,
+                ..HistoryConfig::default()
+            },
+            recovery: RecoveryConfig {
+                enabled: false,
+                ..RecoveryConfig::default()
+            },
+            ..Config::default()
+        };
+        let mut state = ServerState::new(&configuration);
+        let written = Arc::new(AtomicUsize::new(0));
+        state.printers[0].printer = Printer::serving(OkPort::new(written.clone()));
+        let target = &state.printers[0];
+
+        let path = std::env::temp_dir().join(format!(
+            "xcontroller_emergency_{}.gcode",
+            std::process::id()
+        ));
+        fs::write(&path, "G1 X1 Y1\n".repeat(2000)).unwrap();
+        let job_file = JobFile::open(&path).unwrap();
+        target
+            .job
+            .start(
+                &target.printer,
+                job_file,
+                vec!["M84".to_string()],
+                "dashboard",
+            )
+            .unwrap();
+        while target.job.status().unwrap().lines_sent < 10 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let peer = "127.0.0.1:9000".parse().unwrap();
+        let event = emergency_stop(&state, target, peer, "tester").unwrap();
+        assert_eq!(event.commands, vec!["M112"]);
+        while target.job.status().unwrap().finished.is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        fs::remove_file(&path).unwrap();
+
+        let job = target.job.status().unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert_eq!(job.error.as_deref(), Some("Emergency stop"));
+        assert_eq!(target.printer.state(), PrinterState::Halted);
+        // The lines of the job and M112, the cancel commands are not sent to the halted printer
+        for _ in 0..100 {
+            if written.load(Ordering::SeqCst) as u64 > job.lines_sent {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(written.load(Ordering::SeqCst) as u64, job.lines_sent + 1);
+    }
+}
@@ -0,0 +1,176 @@
+use log::info;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::structs::FileInfo;
+
+static EXTENSIONS: &[&str] = &["gcode", "gco", "g"];
+
+/**
+ * List the G-code files of the library
+ * @param dir: &str, directory of the library
+ * @return Result<Vec<FileInfo>, Error>, files ordered by name
+ */
+pub fn list_files(dir: &str) -> Result<Vec<FileInfo>, Error> {
+    let mut files = Vec::new();
+
+    if !Path::new(dir).exists() {
+        return Ok(files);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if path.is_file() && validate_name(name).is_ok() {
+            files.push(file_info(&path)?);
+        }
+    }
+
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/**
+ * Path of a file of the library
+ * @param dir: &str, directory of the library
+ * @param name: &str, file name without directories
+ * @return Result<PathBuf, Error>, NotFound when the file does not exist
+ */
+pub fn file_path(dir: &str, name: &str) -> Result<PathBuf, Error> {
+    validate_name(name)?;
+
+    let path = Path::new(dir).join(name);
+    if !path.is_file() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("File {} not found", name),
+        ));
+    }
+    Ok(path)
+}
+
+/**
+ * Path where an upload is written before it is moved to the library
+ * A partial upload never shows up in the listing
+ * @param dir: &str, directory of the library
+ * @param name: &str, file name without directories
+ * @return Result<(PathBuf, PathBuf), Error>, temporary and final path
+ */
+pub fn upload_paths(dir: &str, name: &str) -> Result<(PathBuf, PathBuf), Error> {
+    validate_name(name)?;
+    fs::create_dir_all(dir)?;
+
+    let dir = Path::new(dir);
+    Ok((dir.join(format!(".{}.part", name)), dir.join(name)))
+}
+
+/**
+ * Move a completed upload into the library, replacing a file with the same name
+ * @param temporary: &Path, file the upload was written to
+ * @param path: &Path, final path
+ * @return Result<FileInfo, Error>
+ */
+pub fn complete_upload(temporary: &Path, path: &Path) -> Result<FileInfo, Error> {
+    fs::rename(temporary, path)?;
+    let info = file_info(path)?;
+    info!("Stored {} ({} bytes)", info.name, info.size);
+    Ok(info)
+}
+
+/**
+ * Delete a file of the library
+ * @param dir: &str, directory of the library
+ * @param name: &str, file name without directories
+ * @return Result<(), Error>
+ */
+pub fn delete_file(dir: &str, name: &str) -> Result<(), Error> {
+    let path = file_path(dir, name)?;
+    fs::remove_file(path)?;
+    info!("Deleted {}", name);
+    Ok(())
+}
+
+/// Plain G-code file names, anything that could leave the library is refused
+fn validate_name(name: &str) -> Result<(), Error> {
+    let extension = Path::new(name)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(['/', '\\', '\0'])
+        || name.contains("..")
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid file name \"{}\"", name),
+        ));
+    }
+
+    if !extension.is_some_and(|extension| EXTENSIONS.contains(&extension.as_str())) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is not a G-code file ({})", name, EXTENSIONS.join(", ")),
+        ));
+    }
+
+    Ok(())
+}
+
+fn file_info(path: &Path) -> Result<FileInfo, Error> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    Ok(FileInfo {
+        name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        size: metadata.len(),
+        modified,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("benchy.gcode").is_ok());
+        assert!(validate_name("Part 2.GCO").is_ok());
+        assert!(validate_name("../etc/passwd.gcode").is_err());
+        assert!(validate_name(".hidden.gcode").is_err());
+        assert!(validate_name("model.stl").is_err());
+        assert!(validate_name("").is_err());
+    }
+
+    #[test]
+    fn test_library() {
+        let dir = std::env::temp_dir().join(format!("xcontroller_files_{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+
+        let (temporary, path) = upload_paths(dir, "cube.gcode").unwrap();
+        fs::write(&temporary, "G28\nG1 X10\n").unwrap();
+        assert!(list_files(dir).unwrap().is_empty());
+
+        let info = complete_upload(&temporary, &path).unwrap();
+        assert_eq!(info.size, 11);
+        assert_eq!(list_files(dir).unwrap(), vec![info]);
+        assert_eq!(file_path(dir, "cube.gcode").unwrap(), path);
+
+        delete_file(dir, "cube.gcode").unwrap();
+        let error = file_path(dir, "cube.gcode").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,761 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Single word of a command, e.g. X10.5 or a bare flag such as X in "G28 X"
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GcodeParam {
+    pub letter: char,
+    pub value: Option<f64>,
+}
+
+/// Parsed G-code line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GcodeCommand {
+    pub line_number: Option<u32>,
+    pub letter: char,
+    pub number: u32,
+    pub subcode: Option<u32>,
+    pub params: Vec<GcodeParam>,
+    /// Free text argument of commands such as M23 or M117
+    pub text: Option<String>,
+    pub comment: Option<String>,
+    pub checksum: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GcodeError {
+    Empty,
+    MultipleLines,
+    UnexpectedCharacter(char, usize),
+    InvalidCommand(String),
+    UnknownCommand(String),
+    InvalidParameter {
+        command: String,
+        param: char,
+    },
+    DuplicateParameter {
+        command: String,
+        param: char,
+    },
+    MissingValue {
+        command: String,
+        param: char,
+    },
+    InvalidValue {
+        command: String,
+        param: char,
+        value: String,
+    },
+    InvalidChecksum(String),
+    ChecksumMismatch {
+        expected: u8,
+        received: u8,
+    },
+}
+
+impl fmt::Display for GcodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GcodeError::Empty => write!(f, "Empty command"),
+            GcodeError::MultipleLines => write!(f, "Only one command per message is allowed"),
+            GcodeError::UnexpectedCharacter(c, pos) => {
+                write!(f, "Unexpected character '{}' at position {}", c, pos)
+            }
+            GcodeError::InvalidCommand(word) => write!(f, "Invalid command word \"{}\"", word),
+            GcodeError::UnknownCommand(command) => write!(f, "Unknown command {}", command),
+            GcodeError::InvalidParameter { command, param } => {
+                write!(f, "Parameter {} is not supported by {}", param, command)
+            }
+            GcodeError::DuplicateParameter { command, param } => {
+                write!(f, "Parameter {} is repeated in {}", param, command)
+            }
+            GcodeError::MissingValue { command, param } => {
+                write!(f, "Parameter {} of {} requires a value", param, command)
+            }
+            GcodeError::InvalidValue {
+                command,
+                param,
+                value,
+            } => write!(
+                f,
+                "Invalid value \"{}\" for {} in {}",
+                value, param, command
+            ),
+            GcodeError::InvalidChecksum(value) => write!(f, "Invalid checksum \"{}\"", value),
+            GcodeError::ChecksumMismatch { expected, received } => write!(
+                f,
+                "Checksum mismatch, expected {} but received {}",
+                expected, received
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GcodeError {}
+
+/**
+ * Supported commands and their parameters
+ * Uppercase letters require a value, lowercase letters are flags with an optional value,
+ * "*" accepts any parameter and "$" takes the rest of the line as text
+ */
+static COMMANDS: &[(&str, &str)] = &[
+    ("G0", "XYZEFS"),
+    ("G1", "XYZEFS"),
+    ("G2", "XYZEFIJRPS"),
+    ("G3", "XYZEFIJRPS"),
+    ("G4", "PS"),
+    ("G5", "XYZEFIJPQS"),
+    ("G6", "ABCDEIRS"),
+    ("G10", "S"),
+    ("G11", ""),
+    ("G12", "PRSTxyz"),
+    ("G17", ""),
+    ("G18", ""),
+    ("G19", ""),
+    ("G20", ""),
+    ("G21", ""),
+    ("G26", "BCDEFHIKLOPQRSUXYZ"),
+    ("G27", "P"),
+    ("G28", "Rlxyz"),
+    ("G29", "*"),
+    ("G30", "CEXY"),
+    ("G31", ""),
+    ("G32", ""),
+    ("G33", "CEFPTV"),
+    ("G34", "AEIT"),
+    ("G35", "S"),
+    ("G38.2", "XYZF"),
+    ("G38.3", "XYZF"),
+    ("G38.4", "XYZF"),
+    ("G38.5", "XYZF"),
+    ("G42", "FIJ"),
+    ("G53", ""),
+    ("G54", ""),
+    ("G55", ""),
+    ("G56", ""),
+    ("G57", ""),
+    ("G58", ""),
+    ("G59", ""),
+    ("G59.1", ""),
+    ("G59.2", ""),
+    ("G59.3", ""),
+    ("G60", "SQ"),
+    ("G61", "FSxyze"),
+    ("G76", "bp"),
+    ("G80", ""),
+    ("G90", ""),
+    ("G91", ""),
+    ("G92", "XYZE"),
+    ("G425", "BTUV"),
+    ("M0", "$"),
+    ("M1", "$"),
+    ("M3", "IOS"),
+    ("M4", "IOS"),
+    ("M5", ""),
+    ("M7", ""),
+    ("M8", ""),
+    ("M9", ""),
+    ("M10", ""),
+    ("M11", ""),
+    ("M16", "$"),
+    ("M17", "xyze"),
+    ("M18", "Sxyze"),
+    ("M19", "*"),
+    ("M20", "flt"),
+    ("M21", "psu"),
+    ("M22", ""),
+    ("M23", "$"),
+    ("M24", "ST"),
+    ("M25", ""),
+    ("M26", "S"),
+    ("M27", "Sc"),
+    ("M28", "$"),
+    ("M29", ""),
+    ("M30", "$"),
+    ("M31", ""),
+    ("M32", "$"),
+    ("M33", "$"),
+    ("M34", "SF"),
+    ("M42", "IMPST"),
+    ("M43", "*"),
+    ("M48", "CELPRSVXY"),
+    ("M73", "CPQRS"),
+    ("M75", "$"),
+    ("M76", ""),
+    ("M77", ""),
+    ("M78", "s"),
+    ("M80", "S"),
+    ("M81", ""),
+    ("M82", ""),
+    ("M83", ""),
+    ("M84", "Sxyze"),
+    ("M85", "S"),
+    ("M86", "*"),
+    ("M87", ""),
+    ("M92", "XYZET"),
+    ("M100", "*"),
+    ("M102", "*"),
+    ("M104", "SBFIT"),
+    ("M105", "RT"),
+    ("M106", "SPTI"),
+    ("M107", "P"),
+    ("M108", ""),
+    ("M109", "SRBFIT"),
+    ("M110", "N"),
+    ("M111", "S"),
+    ("M112", ""),
+    ("M113", "S"),
+    ("M114", "der"),
+    ("M115", ""),
+    ("M117", "$"),
+    ("M118", "$"),
+    ("M119", ""),
+    ("M120", ""),
+    ("M121", ""),
+    ("M122", "*"),
+    ("M123", "S"),
+    ("M125", "LPXYZ"),
+    ("M126", "P"),
+    ("M127", "P"),
+    ("M128", "P"),
+    ("M129", "P"),
+    ("M140", "SI"),
+    ("M141", "S"),
+    ("M143", "S"),
+    ("M145", "SHBF"),
+    ("M149", "cfk"),
+    ("M150", "RUBWPIKS"),
+    ("M154", "S"),
+    ("M155", "S"),
+    ("M163", "SP"),
+    ("M164", "S"),
+    ("M165", "ABCDHI"),
+    ("M166", "AZIJST"),
+    ("M190", "SRI"),
+    ("M191", "SR"),
+    ("M192", "RS"),
+    ("M193", "S"),
+    ("M200", "DLST"),
+    ("M201", "XYZETFS"),
+    ("M203", "XYZET"),
+    ("M204", "PRTS"),
+    ("M205", "BEJSTXYZ"),
+    ("M206", "XYZ"),
+    ("M207", "FSWZ"),
+    ("M208", "FRSW"),
+    ("M209", "S"),
+    ("M211", "Sxyz"),
+    ("M217", "*"),
+    ("M218", "TXYZ"),
+    ("M220", "Sbr"),
+    ("M221", "ST"),
+    ("M226", "PS"),
+    ("M240", "*"),
+    ("M250", "C"),
+    ("M255", "S"),
+    ("M256", "B"),
+    ("M260", "ABRS"),
+    ("M261", "ABS"),
+    ("M282", "P"),
+    ("M290", "PSXYZ"),
+    ("M300", "PS"),
+    ("M301", "PIDCFLE"),
+    ("M302", "PS"),
+    ("M303", "CDESU"),
+    ("M304", "PID"),
+    ("M305", "*"),
+    ("M306", "*"),
+    ("M350", "XYZEBS"),
+    ("M351", "XYZEBS"),
+    ("M355", "PS"),
+    ("M360", ""),
+    ("M361", ""),
+    ("M362", ""),
+    ("M363", ""),
+    ("M364", ""),
+    ("M380", "S"),
+    ("M381", "S"),
+    ("M400", ""),
+    ("M401", "hrs"),
+    ("M402", "r"),
+    ("M403", "EF"),
+    ("M404", "W"),
+    ("M405", "D"),
+    ("M406", ""),
+    ("M407", ""),
+    ("M410", ""),
+    ("M412", "SHRD"),
+    ("M413", "Spr"),
+    ("M420", "SZCLtv"),
+    ("M421", "IJXYZQn"),
+    ("M422", "SRWXY"),
+    ("M423", "*"),
+    ("M425", "FSxyz"),
+    ("M428", ""),
+    ("M430", "*"),
+    ("M486", "STCPUA"),
+    ("M493", "*"),
+    ("M500", ""),
+    ("M501", ""),
+    ("M502", ""),
+    ("M503", "sc"),
+    ("M504", ""),
+    ("M510", "*"),
+    ("M511", "*"),
+    ("M512", "*"),
+    ("M524", ""),
+    ("M540", "S"),
+    ("M569", "*"),
+    ("M575", "PB"),
+    ("M592", "ABC"),
+    ("M593", "*"),
+    ("M600", "BELRTUXYZ"),
+    ("M603", "LTU"),
+    ("M605", "SXR"),
+    ("M665", "*"),
+    ("M666", "*"),
+    ("M672", "rs"),
+    ("M701", "LTZ"),
+    ("M702", "LTZ"),
+    ("M710", "*"),
+    ("M808", "L"),
+    ("M810", "$"),
+    ("M811", "$"),
+    ("M812", "$"),
+    ("M813", "$"),
+    ("M814", "$"),
+    ("M815", "$"),
+    ("M816", "$"),
+    ("M817", "$"),
+    ("M818", "$"),
+    ("M819", "$"),
+    ("M851", "XYZ"),
+    ("M852", "*"),
+    ("M860", "*"),
+    ("M861", "*"),
+    ("M862", "*"),
+    ("M863", "*"),
+    ("M864", "*"),
+    ("M865", "*"),
+    ("M866", "*"),
+    ("M867", "*"),
+    ("M868", "*"),
+    ("M869", "*"),
+    ("M871", "*"),
+    ("M876", "SP"),
+    ("M900", "KLST"),
+    ("M906", "*"),
+    ("M907", "*"),
+    ("M908", "*"),
+    ("M909", "*"),
+    ("M910", "*"),
+    ("M911", "*"),
+    ("M912", "*"),
+    ("M913", "*"),
+    ("M914", "*"),
+    ("M915", "*"),
+    ("M916", "*"),
+    ("M917", "*"),
+    ("M918", "*"),
+    ("M919", "*"),
+    ("M928", "$"),
+    ("M951", "*"),
+    ("M993", "*"),
+    ("M994", "*"),
+    ("M995", "*"),
+    ("M997", ""),
+    ("M999", "S"),
+    ("M7219", "*"),
+    ("T0", "SF"),
+    ("T1", "SF"),
+    ("T2", "SF"),
+    ("T3", "SF"),
+    ("T4", "SF"),
+    ("T5", "SF"),
+    ("T6", "SF"),
+    ("T7", "SF"),
+    ("T8", "SF"),
+    ("T9", "SF"),
+];
+
+impl GcodeCommand {
+    /// Normalised command word, e.g. "G1" or "G38.2"
+    pub fn word(&self) -> String {
+        match self.subcode {
+            Some(subcode) => format!("{}{}.{}", self.letter, self.number, subcode),
+            None => format!("{}{}", self.letter, self.number),
+        }
+    }
+
+    /// Value of a parameter, None if absent or used as a flag
+    pub fn param(&self, letter: char) -> Option<f64> {
+        self.params
+            .iter()
+            .find(|param| param.letter == letter)
+            .and_then(|param| param.value)
+    }
+
+    pub fn has_param(&self, letter: char) -> bool {
+        self.params.iter().any(|param| param.letter == letter)
+    }
+}
+
+/// Normalised line as sent to the printer, comments are dropped
+impl fmt::Display for GcodeCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut line = String::new();
+
+        if let Some(line_number) = self.line_number {
+            line.push_str(&format!("N{} ", line_number));
+        }
+
+        line.push_str(&self.word());
+
+        for param in &self.params {
+            match param.value {
+                Some(value) => line.push_str(&format!(" {}{}", param.letter, value)),
+                None => line.push_str(&format!(" {}", param.letter)),
+            }
+        }
+
+        if let Some(text) = &self.text {
+            line.push(' ');
+            line.push_str(text);
+        }
+
+        if self.line_number.is_some() {
+            let checksum = checksum(&line);
+            line.push_str(&format!("*{}", checksum));
+        }
+
+        write!(f, "{}", line)
+    }
+}
+
+/**
+ * Marlin line checksum, XOR of every byte before '*'
+ * @param line: &str, line including its N word
+ * @return u8, checksum
+ */
+pub fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |acc, byte| acc ^ byte)
+}
+
+/**
+ * Tokenize and validate a single G-code line
+ * Case and leading zeros are normalised, e.g. "g01x10" becomes G1 X10
+ * @param line: &str, raw line from the client
+ * @return Result<GcodeCommand, GcodeError>, parsed command or the reason it was rejected
+ */
+pub fn parse_gcode(line: &str) -> Result<GcodeCommand, GcodeError> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.contains(['\r', '\n']) {
+        return Err(GcodeError::MultipleLines);
+    }
+
+    // Split off the comment, either "; comment" or "(comment)"
+    let (code, comment) = match line.find([';', '(']) {
+        Some(pos) => {
+            let comment = line[pos + 1..].trim_end_matches(')').trim().to_string();
+            (&line[..pos], Some(comment))
+        }
+        None => (line, None),
+    };
+
+    // Split off and verify the checksum
+    let (code, checksum_value) = match code.rfind('*') {
+        Some(pos) => {
+            let value = code[pos + 1..].trim();
+            let received = value
+                .parse::<u8>()
+                .map_err(|_| GcodeError::InvalidChecksum(value.to_string()))?;
+            let expected = checksum(&code[..pos]);
+            if expected != received {
+                return Err(GcodeError::ChecksumMismatch { expected, received });
+            }
+            (&code[..pos], Some(received))
+        }
+        None => (code, None),
+    };
+
+    let mut lexer = Lexer::new(code);
+
+    let mut word = lexer.next_word()?.ok_or(GcodeError::Empty)?;
+    let mut line_number = None;
+
+    if word.letter == 'N' {
+        let value = word
+            .value
+            .ok_or_else(|| GcodeError::InvalidCommand("N".to_string()))?;
+        line_number = Some(
+            value
+                .parse::<u32>()
+                .map_err(|_| GcodeError::InvalidCommand(format!("N{}", value)))?,
+        );
+        word = lexer.next_word()?.ok_or(GcodeError::Empty)?;
+    }
+
+    let raw_word = format!("{}{}", word.letter, word.value.clone().unwrap_or_default());
+    if !matches!(word.letter, 'G' | 'M' | 'T') {
+        return Err(GcodeError::InvalidCommand(raw_word));
+    }
+
+    let value = word
+        .value
+        .ok_or_else(|| GcodeError::InvalidCommand(raw_word.clone()))?;
+    let (number, subcode) = match value.split_once('.') {
+        Some((number, subcode)) => (number, Some(subcode)),
+        None => (value.as_str(), None),
+    };
+
+    let number = number
+        .parse::<u32>()
+        .map_err(|_| GcodeError::InvalidCommand(raw_word.clone()))?;
+    let subcode = match subcode {
+        Some(subcode) => Some(
+            subcode
+                .parse::<u32>()
+                .map_err(|_| GcodeError::InvalidCommand(raw_word.clone()))?,
+        ),
+        None => None,
+    };
+
+    let mut command = GcodeCommand {
+        line_number,
+        letter: word.letter,
+        number,
+        subcode,
+        params: Vec::new(),
+        text: None,
+        comment,
+        checksum: checksum_value,
+    };
+
+    let word_name = command.word();
+    let spec = COMMANDS
+        .iter()
+        .find(|(name, _)| *name == word_name)
+        .map(|(_, spec)| *spec)
+        .ok_or_else(|| GcodeError::UnknownCommand(word_name.clone()))?;
+
+    if spec == "$" {
+        let text = lexer.rest().trim();
+        if !text.is_empty() {
+            command.text = Some(text.to_string());
+        }
+        return Ok(command);
+    }
+
+    while let Some(word) = lexer.next_word()? {
+        let letter = word.letter;
+
+        let allowed_value = spec.contains(letter);
+        let allowed_flag = spec.contains(letter.to_ascii_lowercase());
+        if spec != "*" && !allowed_value && !allowed_flag {
+            return Err(GcodeError::InvalidParameter {
+                command: word_name,
+                param: letter,
+            });
+        }
+
+        if command.has_param(letter) {
+            return Err(GcodeError::DuplicateParameter {
+                command: word_name,
+                param: letter,
+            });
+        }
+
+        let value = match word.value {
+            Some(value) => Some(value.parse::<f64>().map_err(|_| GcodeError::InvalidValue {
+                command: word_name.clone(),
+                param: letter,
+                value: value.clone(),
+            })?),
+            None if allowed_value => {
+                return Err(GcodeError::MissingValue {
+                    command: word_name,
+                    param: letter,
+                })
+            }
+            None => None,
+        };
+
+        command.params.push(GcodeParam { letter, value });
+    }
+
+    Ok(command)
+}
+
+struct Word {
+    letter: char,
+    value: Option<String>,
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(input: &'a str) -> Self {
+        Lexer { input, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    /// Next letter with its value, words may be glued together as in "G1X10Y5"
+    fn next_word(&mut self) -> Result<Option<Word>, GcodeError> {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+
+        let letter = match self.peek() {
+            None => return Ok(None),
+            Some(c) if c.is_ascii_alphabetic() => c.to_ascii_uppercase(),
+            Some(c) => return Err(GcodeError::UnexpectedCharacter(c, self.pos)),
+        };
+        self.pos += 1;
+
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_digit() || c == '.' || c == '-' || c == '+')
+        {
+            self.pos += 1;
+        }
+
+        if let Some(c) = self.peek() {
+            if !c.is_whitespace() && !c.is_ascii_alphabetic() {
+                return Err(GcodeError::UnexpectedCharacter(c, self.pos));
+            }
+        }
+
+        let value = &self.input[start..self.pos];
+        Ok(Some(Word {
+            letter,
+            value: (!value.is_empty()).then(|| value.to_string()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_normalises_case_and_zeros() {
+        let command = parse_gcode("g01x10.50 y-5").unwrap();
+        assert_eq!(command.letter, 'G');
+        assert_eq!(command.number, 1);
+        assert_eq!(
+            command.params,
+            vec![
+                GcodeParam {
+                    letter: 'X',
+                    value: Some(10.5)
+                },
+                GcodeParam {
+                    letter: 'Y',
+                    value: Some(-5.0)
+                }
+            ]
+        );
+        assert_eq!(command.to_string(), "G1 X10.5 Y-5");
+    }
+
+    #[test]
+    fn test_parse_subcode_and_comment() {
+        let command = parse_gcode("G38.2 Z-10 F100 ; probe down").unwrap();
+        assert_eq!(command.subcode, Some(2));
+        assert_eq!(command.comment.as_deref(), Some("probe down"));
+        assert_eq!(command.to_string(), "G38.2 Z-10 F100");
+    }
+
+    #[test]
+    fn test_parse_unicode_whitespace() {
+        let command = parse_gcode("G1\u{a0}X10\u{2003}Y5").unwrap();
+        assert_eq!(command.to_string(), "G1 X10 Y5");
+        assert!(parse_gcode("G1 X10\u{a0}").is_ok());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let command = parse_gcode("G28 X Y").unwrap();
+        assert!(command.has_param('X'));
+        assert_eq!(command.params[0].value, None);
+        assert_eq!(command.to_string(), "G28 X Y");
+    }
+
+    #[test]
+    fn test_parse_text_command() {
+        let command = parse_gcode("M117 Hello World").unwrap();
+        assert_eq!(command.text.as_deref(), Some("Hello World"));
+        assert_eq!(command.to_string(), "M117 Hello World");
+    }
+
+    #[test]
+    fn test_parse_checksum() {
+        let command = parse_gcode("N10 G1 X5*84").unwrap();
+        assert_eq!(command.line_number, Some(10));
+        assert_eq!(command.checksum, Some(84));
+        assert_eq!(command.to_string(), "N10 G1 X5*84");
+
+        assert_eq!(
+            parse_gcode("N10 G1 X5*12"),
+            Err(GcodeError::ChecksumMismatch {
+                expected: 84,
+                received: 12
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_gcode(""), Err(GcodeError::Empty));
+        assert_eq!(parse_gcode("   ; comment"), Err(GcodeError::Empty));
+        assert_eq!(
+            parse_gcode("G1 X10 Y"),
+            Err(GcodeError::MissingValue {
+                command: "G1".to_string(),
+                param: 'Y'
+            })
+        );
+        assert_eq!(
+            parse_gcode("M105 X1"),
+            Err(GcodeError::InvalidParameter {
+                command: "M105".to_string(),
+                param: 'X'
+            })
+        );
+        assert_eq!(
+            parse_gcode("G1 X1 X2"),
+            Err(GcodeError::DuplicateParameter {
+                command: "G1".to_string(),
+                param: 'X'
+            })
+        );
+        assert_eq!(
+            parse_gcode("M9999"),
+            Err(GcodeError::UnknownCommand("M9999".to_string()))
+        );
+        assert_eq!(
+            parse_gcode("X10"),
+            Err(GcodeError::InvalidCommand("X10".to_string()))
+        );
+        assert!(matches!(
+            parse_gcode("G1 X1-2"),
+            Err(GcodeError::InvalidValue { .. })
+        ));
+        assert_eq!(
+            parse_gcode("G1 X10 & Y5"),
+            Err(GcodeError::UnexpectedCharacter('&', 7))
+        );
+        assert_eq!(parse_gcode("G28\nM112"), Err(GcodeError::MultipleLines));
+    }
+}
@@ -0,0 +1,357 @@
+use log::info;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, Row};
+use std::io::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::structs::{FileStats, HistoryEntry, HistoryFilter, HistoryStats, JobState, JobStatus};
+
+// Entries returned by a query without limit
+static DEFAULT_LIMIT: u32 = 100;
+
+static SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS jobs (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        printer TEXT NOT NULL,
+        file TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        started INTEGER NOT NULL,
+        finished INTEGER NOT NULL,
+        duration INTEGER NOT NULL,
+        outcome TEXT NOT NULL,
+        filament_used REAL NOT NULL,
+        error TEXT,
+        started_by TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS jobs_started ON jobs (started);
+    CREATE INDEX IF NOT EXISTS jobs_file ON jobs (file);
+";
+
+/**
+ * Finished jobs of every printer, stored in an SQLite database on the host
+ */
+pub struct History {
+    connection: Mutex<Connection>,
+}
+
+impl History {
+    /**
+     * Open the database, the table is created on the first start
+     * @param path: &str, file of the database
+     * @return Result<History, Error>
+     */
+    pub fn open(path: &str) -> Result<Self, Error> {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+        let history = History::with_connection(Connection::open(path).map_err(Error::other)?)?;
+        info!("Print history stored in {}", path);
+        Ok(history)
+    }
+
+    fn with_connection(connection: Connection) -> Result<Self, Error> {
+        connection.execute_batch(SCHEMA).map_err(Error::other)?;
+        Ok(History {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /**
+     * Store a finished job
+     * @param printer: &str, name of the printer
+     * @param job: &JobStatus, job with its final state
+     * @return Result<i64, Error>, id of the entry
+     */
+    pub fn record(&self, printer: &str, job: &JobStatus) -> Result<i64, Error> {
+        let finished = job.finished.unwrap_or(job.started);
+        let connection = lock(&self.connection);
+        connection
+            .execute(
+                "INSERT INTO jobs (printer, file, hash, started, finished, duration, outcome,
+                    filament_used, error, started_by)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    printer,
+                    job.file,
+                    job.hash,
+                    job.started as i64,
+                    finished as i64,
+                    finished.saturating_sub(job.started) as i64,
+                    format!("{:?}", job.state),
+                    job.filament_used,
+                    job.error,
+                    job.started_by,
+                ],
+            )
+            .map_err(Error::other)?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    /**
+     * Jobs matching the filter, the most recent first
+     * @param filter: &HistoryFilter
+     * @return Result<Vec<HistoryEntry>, Error>
+     */
+    pub fn query(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>, Error> {
+        let (condition, mut values) = conditions(filter);
+        values.push(Value::Integer(filter.limit.unwrap_or(DEFAULT_LIMIT).into()));
+        values.push(Value::Integer(filter.offset.unwrap_or(0).into()));
+
+        let connection = lock(&self.connection);
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT id, printer, file, hash, started, finished, duration, outcome,
+                    filament_used, error, started_by
+                 FROM jobs {} ORDER BY started DESC, id DESC LIMIT ? OFFSET ?",
+                condition
+            ))
+            .map_err(Error::other)?;
+        let entries = statement
+            .query_map(params_from_iter(values), entry)
+            .map_err(Error::other)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::other)?;
+        Ok(entries)
+    }
+
+    /**
+     * Totals of the jobs matching the filter, limit and offset are ignored
+     * @param filter: &HistoryFilter
+     * @return Result<HistoryStats, Error>, files sorted by number of jobs
+     */
+    pub fn stats(&self, filter: &HistoryFilter) -> Result<HistoryStats, Error> {
+        let (condition, values) = conditions(filter);
+        let connection = lock(&self.connection);
+
+        let (jobs, completed, cancelled, failed, duration, filament_used) = connection
+            .query_row(
+                &format!(
+                    "SELECT COUNT(*),
+                        COALESCE(SUM(outcome = 'Completed'), 0),
+                        COALESCE(SUM(outcome = 'Cancelled'), 0),
+                        COALESCE(SUM(outcome = 'Failed'), 0),
+                        COALESCE(SUM(duration), 0),
+                        COALESCE(SUM(filament_used), 0.0)
+                     FROM jobs {}",
+                    condition
+                ),
+                params_from_iter(values.iter()),
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)? as u64,
+                        row.get::<_, i64>(1)? as u64,
+                        row.get::<_, i64>(2)? as u64,
+                        row.get::<_, i64>(3)? as u64,
+                        row.get::<_, i64>(4)? as u64,
+                        row.get::<_, f64>(5)?,
+                    ))
+                },
+            )
+            .map_err(Error::other)?;
+
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT file, COUNT(*), SUM(outcome = 'Completed'), SUM(duration)
+                 FROM jobs {} GROUP BY file ORDER BY COUNT(*) DESC, file",
+                condition
+            ))
+            .map_err(Error::other)?;
+        let files = statement
+            .query_map(params_from_iter(values.iter()), |row| {
+                let jobs = row.get::<_, i64>(1)? as u64;
+                let completed = row.get::<_, i64>(2)? as u64;
+                Ok(FileStats {
+                    file: row.get(0)?,
+                    jobs,
+                    completed,
+                    print_hours: hours(row.get::<_, i64>(3)? as u64),
+                    success_rate: rate(completed, jobs),
+                })
+            })
+            .map_err(Error::other)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::other)?;
+
+        Ok(HistoryStats {
+            jobs,
+            completed,
+            cancelled,
+            failed,
+            print_hours: hours(duration),
+            filament_used,
+            success_rate: rate(completed, jobs),
+            files,
+        })
+    }
+}
+
+/**
+ * WHERE clause of a filter with its parameters
+ * @param filter: &HistoryFilter
+ * @return (String, Vec<Value>), empty clause when nothing is filtered
+ */
+fn conditions(filter: &HistoryFilter) -> (String, Vec<Value>) {
+    let mut clauses = Vec::new();
+    let mut values = Vec::new();
+
+    if let Some(printer) = &filter.printer {
+        clauses.push("printer = ?");
+        values.push(Value::Text(printer.clone()));
+    }
+    if let Some(file) = &filter.file {
+        clauses.push("file = ?");
+        values.push(Value::Text(file.clone()));
+    }
+    if let Some(outcome) = filter.outcome {
+        clauses.push("outcome = ?");
+        values.push(Value::Text(format!("{:?}", outcome)));
+    }
+    if let Some(since) = filter.since {
+        clauses.push("started >= ?");
+        values.push(Value::Integer(since as i64));
+    }
+    if let Some(until) = filter.until {
+        clauses.push("started < ?");
+        values.push(Value::Integer(until as i64));
+    }
+
+    if clauses.is_empty() {
+        return (String::new(), values);
+    }
+    (format!("WHERE {}", clauses.join(" AND ")), values)
+}
+
+fn entry(row: &Row) -> rusqlite::Result<HistoryEntry> {
+    let outcome: String = row.get(7)?;
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        printer: row.get(1)?,
+        file: row.get(2)?,
+        hash: row.get(3)?,
+        started: row.get::<_, i64>(4)? as u64,
+        finished: row.get::<_, i64>(5)? as u64,
+        duration: row.get::<_, i64>(6)? as u64,
+        // Rows are only written by record, an unknown outcome means the job did not finish
+        outcome: serde_json::from_value(serde_json::Value::String(outcome))
+            .unwrap_or(JobState::Failed),
+        filament_used: row.get(8)?,
+        error: row.get(9)?,
+        started_by: row.get(10)?,
+    })
+}
+
+fn hours(seconds: u64) -> f64 {
+    seconds as f64 / 3600.0
+}
+
+fn rate(completed: u64, jobs: u64) -> f64 {
+    if jobs == 0 {
+        return 0.0;
+    }
+    completed as f64 / jobs as f64
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(file: &str, state: JobState, started: u64, duration: u64) -> JobStatus {
+        JobStatus {
+            file: file.to_string(),
+            state,
+            position: 100,
+            size: 100,
+            progress: 100.0,
+            lines_sent: 10,
+            run_id: started,
+            started,
+            finished: Some(started + duration),
+            error: (state == JobState::Failed).then(|| "Printer is Halted".to_string()),
+            hash: "ab12".to_string(),
+            filament_used: 1500.0,
+            started_by: "dashboard".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_record_and_query() {
+        let history = History::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        history
+            .record("mk3", &job("cube.gcode", JobState::Completed, 1000, 3600))
+            .unwrap();
+        history
+            .record("mk3", &job("benchy.gcode", JobState::Failed, 2000, 600))
+            .unwrap();
+        history
+            .record("ender", &job("cube.gcode", JobState::Cancelled, 3000, 60))
+            .unwrap();
+
+        let all = history.query(&HistoryFilter::default()).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].printer, "ender");
+        assert_eq!(all[1].error.as_deref(), Some("Printer is Halted"));
+        assert_eq!(all[2].duration, 3600);
+        assert_eq!(all[2].outcome, JobState::Completed);
+
+        let filter = HistoryFilter {
+            printer: Some("mk3".to_string()),
+            since: Some(1500),
+            ..HistoryFilter::default()
+        };
+        let entries = history.query(&filter).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file, "benchy.gcode");
+
+        let page = HistoryFilter {
+            limit: Some(1),
+            offset: Some(1),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(history.query(&page).unwrap()[0].file, "benchy.gcode");
+    }
+
+    #[test]
+    fn test_stats() {
+        let history = History::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        let empty = history.stats(&HistoryFilter::default()).unwrap();
+        assert_eq!(empty.jobs, 0);
+        assert_eq!(empty.success_rate, 0.0);
+
+        for (file, state) in [
+            ("cube.gcode", JobState::Completed),
+            ("cube.gcode", JobState::Completed),
+            ("cube.gcode", JobState::Cancelled),
+            ("benchy.gcode", JobState::Failed),
+        ] {
+            history
+                .record("mk3", &job(file, state, 1000, 1800))
+                .unwrap();
+        }
+
+        let stats = history.stats(&HistoryFilter::default()).unwrap();
+        assert_eq!(stats.jobs, 4);
+        assert_eq!(stats.completed, 2);
+        assert_eq!(stats.cancelled, 1);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(stats.print_hours, 2.0);
+        assert_eq!(stats.filament_used, 6000.0);
+        assert_eq!(stats.success_rate, 0.5);
+        assert_eq!(stats.files[0].file, "cube.gcode");
+        assert_eq!(stats.files[0].jobs, 3);
+        assert!((stats.files[0].success_rate - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(stats.files[1].success_rate, 0.0);
+
+        let failed = HistoryFilter {
+            outcome: Some(JobState::Failed),
+            ..HistoryFilter::default()
+        };
+        assert_eq!(history.stats(&failed).unwrap().jobs, 1);
+    }
+}
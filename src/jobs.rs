@@ -0,0 +1,1014 @@
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+use crate::actions::{park_commands, reaction, Reaction};
+use crate::files::file_path;
+use crate::history::History;
+use crate::objects::{is_move, rejoin_commands, JobObjects};
+use crate::printer::Printer;
+use crate::recovery::{resume_commands, CheckpointStore};
+use crate::structs::{
+    ActionsConfig, GcodeState, JobCheckpoint, JobState, JobStatus, MessageSender, ObjectList,
+    PrinterState,
+};
+use crate::wscom::json_response;
+
+// Interval at which a paused job checks whether it was resumed or cancelled
+static PAUSE_CHECK: u64 = 200;
+// Progress in percent between two JobStatus broadcasts
+static BROADCAST_STEP: f64 = 1.0;
+
+struct JobShared {
+    printer: String,
+    status: Mutex<Option<JobStatus>>,
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+    /// Set by an emergency stop, the job ends cancelled without the cancel commands
+    halted: AtomicBool,
+    events: broadcast::Sender<MessageSender>,
+    history: Option<Arc<History>>,
+    checkpoints: Option<CheckpointStore>,
+    /// Checkpoint of an interrupted job, offered until it is resumed or discarded
+    pending: Mutex<Option<JobCheckpoint>>,
+    actions: ActionsConfig,
+    /// Objects labelled in the file of the job
+    objects: Mutex<JobObjects>,
+}
+
+/// Where the streaming starts, the beginning of the file or a checkpoint
+#[derive(Default)]
+struct Resume {
+    gcode: GcodeState,
+    line: u64,
+    /// Sent before the first line of the file
+    commands: Vec<String>,
+}
+
+/// Place of the file a checkpoint resumes from
+struct ResumePoint {
+    position: u64,
+    line: u64,
+    lines_sent: u64,
+    gcode: GcodeState,
+    current_object: Option<usize>,
+}
+
+/**
+ * File of a job, opened, hashed and scanned for objects before the job starts
+ * Reading a large file takes a while, it is done without holding the job status
+ */
+pub struct JobFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    hash: String,
+    objects: JobObjects,
+}
+
+impl JobFile {
+    /**
+     * Open a file of the library, blocks until the whole file is read
+     * @param path: &Path, file of the library
+     * @return Result<JobFile, Error>
+     */
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Ok(JobFile {
+            path: path.to_path_buf(),
+            file: File::open(path)?,
+            size: fs::metadata(path)?.len(),
+            hash: file_hash(path)?,
+            objects: JobObjects::scan(BufReader::new(File::open(path)?))?,
+        })
+    }
+
+    /// Open a file on a blocking thread, for the async handlers
+    pub async fn read(path: PathBuf) -> Result<Self, Error> {
+        tokio::task::spawn_blocking(move || JobFile::open(&path))
+            .await
+            .map_err(Error::other)?
+    }
+}
+
+/**
+ * Streams G-code files to a printer, one job at a time
+ * Each line waits for the "ok" of the firmware before the next one is sent
+ */
+#[derive(Clone)]
+pub struct JobRunner {
+    shared: Arc<JobShared>,
+}
+
+impl JobRunner {
+    /**
+     * @param printer: &str, name of the printer, set on the broadcast JobStatus
+     * @param events: broadcast::Sender<MessageSender>, channel receiving the job progress
+     * @param history: Option<Arc<History>>, stores the finished jobs when set
+     * @param checkpoints: Option<CheckpointStore>, checkpoints of the running job when set
+     * @param actions: ActionsConfig, reaction to the action commands of the firmware
+     */
+    pub fn new(
+        printer: &str,
+        events: broadcast::Sender<MessageSender>,
+        history: Option<Arc<History>>,
+        checkpoints: Option<CheckpointStore>,
+        actions: ActionsConfig,
+    ) -> Self {
+        let pending = checkpoints.as_ref().and_then(CheckpointStore::load);
+        JobRunner {
+            shared: Arc::new(JobShared {
+                printer: printer.to_string(),
+                status: Mutex::new(None),
+                paused: AtomicBool::new(false),
+                cancelled: AtomicBool::new(false),
+                halted: AtomicBool::new(false),
+                events,
+                history,
+                checkpoints,
+                pending: Mutex::new(pending),
+                actions,
+                objects: Mutex::new(JobObjects::default()),
+            }),
+        }
+    }
+
+    /// Current or last job
+    pub fn status(&self) -> Option<JobStatus> {
+        lock(&self.shared.status).clone()
+    }
+
+    /// Objects of the current or last job
+    pub fn objects(&self) -> ObjectList {
+        lock(&self.shared.objects).list()
+    }
+
+    /**
+     * Stop printing an object, the other objects of the job continue
+     * A job printed from the SD card is told with M486, the firmware skips the object
+     * @param printer: &Printer, printer of the job
+     * @param id: Option<usize>, object to skip, the one being printed when None
+     * @return Result<ObjectList, Error>, NotFound without job or for an unknown object
+     */
+    pub async fn cancel_object(
+        &self,
+        printer: &Printer,
+        id: Option<usize>,
+    ) -> Result<ObjectList, Error> {
+        let streaming = self.status().is_some_and(|job| is_active(job.state));
+        if !streaming {
+            if !matches!(
+                printer.state(),
+                PrinterState::Printing | PrinterState::Paused
+            ) {
+                return Err(no_job());
+            }
+            let command = match id {
+                Some(id) => format!("M486 P{}", id),
+                None => "M486 C".to_string(),
+            };
+            warn!(
+                "Printer {} skips an object | {}",
+                self.shared.printer, command
+            );
+            printer.send_async(&command).await?;
+            return Ok(ObjectList::default());
+        }
+
+        let object = lock(&self.shared.objects).cancel(id)?;
+        warn!(
+            "Printer {} skips object {} {}",
+            self.shared.printer, object.id, object.name
+        );
+        self.shared.broadcast_objects();
+        Ok(self.objects())
+    }
+
+    /// Interrupted job that can be resumed
+    pub fn checkpoint(&self) -> Option<JobCheckpoint> {
+        lock(&self.shared.pending).clone()
+    }
+
+    /**
+     * Give up the interrupted job
+     * @return Result<JobCheckpoint, Error>, NotFound without checkpoint
+     */
+    pub fn discard_checkpoint(&self) -> Result<JobCheckpoint, Error> {
+        self.shared.take_pending().ok_or_else(no_checkpoint)
+    }
+
+    /**
+     * Start streaming a file
+     * @param printer: &Printer, printer receiving the file, it has to be operational
+     * @param job_file: JobFile, file of the library opened by JobFile::open or JobFile::read
+     * @param cancel_commands: Vec<String>, sent when the job is cancelled
+     * @param started_by: &str, name of the client, stored in the history
+     * @return Result<JobStatus, Error>, ResourceBusy when a job is running or the printer is not ready
+     */
+    pub fn start(
+        &self,
+        printer: &Printer,
+        job_file: JobFile,
+        cancel_commands: Vec<String>,
+        started_by: &str,
+    ) -> Result<JobStatus, Error> {
+        self.start_run(
+            printer,
+            job_file,
+            cancel_commands,
+            started_by,
+            rand::random(),
+        )
+    }
+
+    /**
+     * Start streaming a file under a run id chosen by the caller
+     * The queue stores the id before the job starts, to recognise the job once it finished
+     * @param run_id: u64, set on the JobStatus
+     * @return Result<JobStatus, Error>, as start
+     */
+    pub fn start_run(
+        &self,
+        printer: &Printer,
+        job_file: JobFile,
+        cancel_commands: Vec<String>,
+        started_by: &str,
+        run_id: u64,
+    ) -> Result<JobStatus, Error> {
+        let mut status = lock(&self.shared.status);
+        if let Some(job) = status.as_ref().filter(|job| is_active(job.state)) {
+            return Err(Error::new(
+                ErrorKind::ResourceBusy,
+                format!("Job {} is already running", job.file),
+            ));
+        }
+
+        check_operational(printer)?;
+
+        let name = job_file
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        // A new job gives up the interrupted one
+        self.shared.take_pending();
+        let job = JobStatus {
+            file: name.clone(),
+            state: JobState::Printing,
+            position: 0,
+            size: job_file.size,
+            progress: 0.0,
+            lines_sent: 0,
+            run_id,
+            started: now(),
+            finished: None,
+            error: None,
+            hash: job_file.hash,
+            filament_used: 0.0,
+            started_by: started_by.to_string(),
+        };
+        *status = Some(job.clone());
+        drop(status);
+        *lock(&self.shared.objects) = job_file.objects;
+
+        info!("Printer {} starts {}", self.shared.printer, name);
+        self.spawn(
+            printer,
+            &name,
+            job_file.file,
+            Resume::default(),
+            cancel_commands,
+        );
+        Ok(job)
+    }
+
+    /**
+     * File of the interrupted job, opened with JobFile before the job is recovered
+     * @param files_dir: &str, library of the file
+     * @return Result<PathBuf, Error>, NotFound without checkpoint
+     */
+    pub fn checkpoint_file(&self, files_dir: &str) -> Result<PathBuf, Error> {
+        let checkpoint = self.checkpoint().ok_or_else(no_checkpoint)?;
+        file_path(files_dir, &checkpoint.file)
+    }
+
+    /**
+     * Resume the interrupted job from its checkpoint
+     * The printer heats up, homes X and Y, restores Z and E with G92 and the streaming continues
+     * @param printer: &Printer, printer of the job, it has to be operational
+     * @param job_file: JobFile, file given by checkpoint_file
+     * @param cancel_commands: Vec<String>, sent when the job is cancelled
+     * @param lift_mm: f64, raised before X and Y are homed
+     * @return Result<JobStatus, Error>, InvalidData when the file changed since the checkpoint
+     */
+    pub fn recover(
+        &self,
+        printer: &Printer,
+        job_file: JobFile,
+        cancel_commands: Vec<String>,
+        lift_mm: f64,
+    ) -> Result<JobStatus, Error> {
+        let mut status = lock(&self.shared.status);
+        if let Some(job) = status.as_ref().filter(|job| is_active(job.state)) {
+            return Err(Error::new(
+                ErrorKind::ResourceBusy,
+                format!("Job {} is already running", job.file),
+            ));
+        }
+        check_operational(printer)?;
+
+        let checkpoint = self.checkpoint().ok_or_else(no_checkpoint)?;
+        if job_file.hash != checkpoint.hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} changed since the checkpoint", checkpoint.file),
+            ));
+        }
+        let mut file = job_file.file;
+        file.seek(SeekFrom::Start(checkpoint.position))?;
+        let mut objects = job_file.objects;
+        objects.restore(&checkpoint.cancelled_objects, checkpoint.current_object);
+
+        let job = JobStatus {
+            file: checkpoint.file.clone(),
+            state: JobState::Printing,
+            position: checkpoint.position,
+            size: checkpoint.size,
+            progress: percent(checkpoint.position, checkpoint.size),
+            lines_sent: checkpoint.lines_sent,
+            run_id: checkpoint.run_id,
+            started: checkpoint.started,
+            finished: None,
+            error: None,
+            hash: checkpoint.hash.clone(),
+            filament_used: checkpoint.gcode.filament_used,
+            started_by: checkpoint.started_by.clone(),
+        };
+        *status = Some(job.clone());
+        drop(status);
+        *lock(&self.shared.pending) = None;
+        *lock(&self.shared.objects) = objects;
+
+        info!(
+            "Printer {} resumes {} at line {}",
+            self.shared.printer, checkpoint.file, checkpoint.line
+        );
+        let resume = Resume {
+            commands: resume_commands(&checkpoint, lift_mm),
+            gcode: checkpoint.gcode.clone(),
+            line: checkpoint.line,
+        };
+        self.spawn(printer, &checkpoint.file, file, resume, cancel_commands);
+        Ok(job)
+    }
+
+    fn spawn(
+        &self,
+        printer: &Printer,
+        name: &str,
+        file: File,
+        resume: Resume,
+        cancel_commands: Vec<String>,
+    ) {
+        self.shared.paused.store(false, Ordering::SeqCst);
+        self.shared.cancelled.store(false, Ordering::SeqCst);
+        self.shared.halted.store(false, Ordering::SeqCst);
+        // Actions received before the job do not concern it
+        printer.take_actions();
+        printer.set_state(PrinterState::Printing, Some(format!("Printing {}", name)));
+        self.shared.broadcast();
+        self.shared.broadcast_objects();
+
+        let shared = self.shared.clone();
+        let printer = printer.clone();
+        thread::spawn(move || stream(shared, printer, file, resume, cancel_commands));
+    }
+
+    /**
+     * Stop sending lines, the moves already queued by the firmware are finished
+     * @param printer: &Printer, printer of the job
+     * @return Result<JobStatus, Error>
+     */
+    pub fn pause(&self, printer: &Printer) -> Result<JobStatus, Error> {
+        self.shared.pause(printer, None)
+    }
+
+    pub fn resume(&self, printer: &Printer) -> Result<JobStatus, Error> {
+        self.shared.resume(printer)
+    }
+
+    /**
+     * Stop the job, the cancel commands are sent once the line in progress is acknowledged
+     * @return Result<JobStatus, Error>, the job until the streaming stopped
+     */
+    pub fn cancel(&self) -> Result<JobStatus, Error> {
+        let status = lock(&self.shared.status);
+        match status.as_ref().filter(|job| is_active(job.state)) {
+            Some(job) => {
+                self.shared.cancelled.store(true, Ordering::SeqCst);
+                Ok(job.clone())
+            }
+            None => Err(no_job()),
+        }
+    }
+
+    /**
+     * Cancel the job for an emergency stop
+     * Nothing more is sent, the line in progress fails once the printer is halted
+     * @return Option<JobStatus>, the job stopped, None without running job
+     */
+    pub fn halt(&self) -> Option<JobStatus> {
+        let status = lock(&self.shared.status);
+        let job = status.as_ref().filter(|job| is_active(job.state))?;
+        self.shared.halted.store(true, Ordering::SeqCst);
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        Some(job.clone())
+    }
+}
+
+impl JobShared {
+    /**
+     * @param printer: &Printer, printer of the job
+     * @param reason: Option<&str>, why the printer asked for the pause
+     * @return Result<JobStatus, Error>
+     */
+    fn pause(&self, printer: &Printer, reason: Option<&str>) -> Result<JobStatus, Error> {
+        let job = self.transition(JobState::Printing, JobState::Paused)?;
+        self.paused.store(true, Ordering::SeqCst);
+        let detail = match reason {
+            Some(reason) => format!("Paused {} | {}", job.file, reason),
+            None => format!("Paused {}", job.file),
+        };
+        printer.set_state(PrinterState::Paused, Some(detail));
+        Ok(job)
+    }
+
+    fn resume(&self, printer: &Printer) -> Result<JobStatus, Error> {
+        let job = self.transition(JobState::Paused, JobState::Printing)?;
+        self.paused.store(false, Ordering::SeqCst);
+        printer.set_state(
+            PrinterState::Printing,
+            Some(format!("Printing {}", job.file)),
+        );
+        Ok(job)
+    }
+
+    fn transition(&self, from: JobState, to: JobState) -> Result<JobStatus, Error> {
+        let mut status = lock(&self.status);
+        let job = status.as_mut().ok_or_else(no_job)?;
+        if job.state != from {
+            return Err(Error::new(
+                ErrorKind::ResourceBusy,
+                format!("Job {} is {:?}", job.file, job.state),
+            ));
+        }
+
+        job.state = to;
+        let job = job.clone();
+        drop(status);
+
+        self.broadcast();
+        Ok(job)
+    }
+
+    /**
+     * Follow the action commands the firmware sent since the last line
+     * A pause requested by the firmware lifts the nozzle, it comes back down before the next line
+     * @param printer: &Printer, printer of the job
+     * @param gcode: &GcodeState, modes restored after parking
+     * @param parked: &mut bool, set while the nozzle is lifted
+     * @return Result<(), Error>, the reason when the park moves failed
+     */
+    fn react(&self, printer: &Printer, gcode: &GcodeState, parked: &mut bool) -> Result<(), Error> {
+        let actions = printer.take_actions();
+        if !self.actions.enabled {
+            return Ok(());
+        }
+
+        for action in actions {
+            match reaction(&action) {
+                Some(Reaction::Pause { park, reason }) => {
+                    warn!("Printer {} asks for a pause | {}", self.printer, reason);
+                    let paused = self.pause(printer, Some(&reason)).is_ok();
+                    if paused && park && !*parked && self.actions.park_lift_mm > 0.0 {
+                        park_commands(gcode, self.actions.park_lift_mm)
+                            .iter()
+                            .try_for_each(|command| printer.send_line(command).map(drop))?;
+                        *parked = true;
+                    }
+                }
+                Some(Reaction::Resume) => {
+                    info!("Printer {} asks to resume", self.printer);
+                    let _ = self.resume(printer);
+                }
+                Some(Reaction::Cancel) => {
+                    warn!("Printer {} asks to cancel the job", self.printer);
+                    self.cancelled.store(true, Ordering::SeqCst);
+                }
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn broadcast(&self) {
+        let Some(job) = lock(&self.status).clone() else {
+            return;
+        };
+
+        let mut message = json_response("JobStatus", Ok(job));
+        message.printer = Some(self.printer.clone());
+        // No receiver only means that no client is connected
+        let _ = self.events.send(message);
+    }
+
+    fn broadcast_objects(&self) {
+        let mut message = json_response("ObjectList", Ok(lock(&self.objects).list()));
+        message.printer = Some(self.printer.clone());
+        let _ = self.events.send(message);
+    }
+
+    fn update<F: FnOnce(&mut JobStatus)>(&self, change: F) {
+        if let Some(job) = lock(&self.status).as_mut() {
+            change(job);
+        }
+    }
+
+    fn take_pending(&self) -> Option<JobCheckpoint> {
+        let pending = lock(&self.pending).take();
+        if let Some(store) = &self.checkpoints {
+            store.clear();
+        }
+        pending
+    }
+
+    /**
+     * Current place of the streaming
+     * @param line: u64, lines of the file read
+     * @param gcode: &GcodeState, modes set by the lines sent
+     * @return ResumePoint
+     */
+    fn resume_point(&self, line: u64, gcode: &GcodeState) -> ResumePoint {
+        let (position, lines_sent) = lock(&self.status)
+            .as_ref()
+            .map_or((0, 0), |job| (job.position, job.lines_sent));
+        ResumePoint {
+            position,
+            line,
+            lines_sent,
+            gcode: gcode.clone(),
+            current_object: lock(&self.objects).current(),
+        }
+    }
+
+    /**
+     * Write the progress of the job to disk
+     * @param point: &ResumePoint, where a resumed job starts
+     * @return Option<JobCheckpoint>, the checkpoint written
+     */
+    fn save_checkpoint(&self, point: &ResumePoint) -> Option<JobCheckpoint> {
+        let store = self.checkpoints.as_ref()?;
+        let job = lock(&self.status).clone()?;
+        let checkpoint = JobCheckpoint {
+            file: job.file,
+            hash: job.hash,
+            position: point.position,
+            line: point.line,
+            lines_sent: point.lines_sent,
+            size: job.size,
+            started: job.started,
+            started_by: job.started_by,
+            gcode: point.gcode.clone(),
+            saved: now(),
+            cancelled_objects: lock(&self.objects).cancelled(),
+            current_object: point.current_object,
+            run_id: job.run_id,
+        };
+        match store.save(&checkpoint) {
+            Ok(()) => Some(checkpoint),
+            Err(e) => {
+                warn!("Failed to write the checkpoint of {} | {}", self.printer, e);
+                None
+            }
+        }
+    }
+}
+
+/// Worker thread of a job, runs until the file is sent, cancelled or the printer fails
+fn stream(
+    shared: Arc<JobShared>,
+    printer: Printer,
+    file: File,
+    resume: Resume,
+    cancel_commands: Vec<String>,
+) {
+    let mut reader = BufReader::new(file);
+    let mut buffer = Vec::new();
+    let mut broadcast_at = 0.0;
+    let mut gcode = resume.gcode;
+    let mut line_number = resume.line;
+    let mut saved_at = Instant::now();
+    // Start of the layer being printed, the lines buffered by the firmware are sent again
+    let mut layer: Option<ResumePoint> = None;
+    let mut parked = false;
+    // Set once a move of a cancelled object was not sent
+    let mut skipped = false;
+
+    let prepared = resume
+        .commands
+        .iter()
+        .try_for_each(|command| printer.send_line(command).map(drop));
+
+    let outcome = if let Err(e) = prepared {
+        Err(e)
+    } else {
+        loop {
+            if let Err(e) = shared.react(&printer, &gcode, &mut parked) {
+                break Err(e);
+            }
+            if shared.paused.load(Ordering::SeqCst) && !shared.cancelled.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(PAUSE_CHECK));
+                continue;
+            }
+            if shared.cancelled.load(Ordering::SeqCst) {
+                break Ok(JobState::Cancelled);
+            }
+            if parked {
+                let unparked = park_commands(&gcode, -shared.actions.park_lift_mm)
+                    .iter()
+                    .try_for_each(|command| printer.send_line(command).map(drop));
+                if let Err(e) = unparked {
+                    break Err(e);
+                }
+                parked = false;
+            }
+
+            // An emergency stop or a lost connection ends the job
+            let state = printer.state();
+            if !matches!(state, PrinterState::Printing | PrinterState::Paused) {
+                break Err(Error::other(format!("Printer is {:?}", state)));
+            }
+
+            buffer.clear();
+            let count = match reader.read_until(b'\n', &mut buffer) {
+                Ok(0) => break Ok(JobState::Completed),
+                Ok(count) => count as u64,
+                Err(e) => break Err(e),
+            };
+
+            let line = String::from_utf8_lossy(&buffer);
+            if is_layer_change(&line) {
+                let point = shared.resume_point(line_number, &gcode);
+                if shared
+                    .checkpoints
+                    .as_ref()
+                    .is_some_and(|store| saved_at.elapsed() >= store.interval)
+                {
+                    saved_at = Instant::now();
+                    shared.save_checkpoint(&point);
+                }
+                layer = Some(point);
+            }
+            let command = strip_comment(&line);
+            let skip = lock(&shared.objects).follow(&line);
+            if skip {
+                // Followed all the same, the next object starts from where the file expects
+                gcode.track(command);
+                skipped |= is_move(command);
+            } else if !command.is_empty() {
+                if skipped && is_move(command) {
+                    let rejoined = rejoin_commands(&gcode)
+                        .iter()
+                        .try_for_each(|command| printer.send_line(command).map(drop));
+                    if let Err(e) = rejoined {
+                        break Err(e);
+                    }
+                    skipped = false;
+                }
+                if let Err(e) = printer.send_line(command) {
+                    break Err(e);
+                }
+                gcode.track(command);
+            }
+            line_number += 1;
+
+            let mut progress = 0.0;
+            shared.update(|job| {
+                job.position += count;
+                job.lines_sent += u64::from(!command.is_empty() && !skip);
+                job.progress = percent(job.position, job.size);
+                job.filament_used = gcode.filament_used;
+                progress = job.progress;
+            });
+            if progress - broadcast_at >= BROADCAST_STEP {
+                broadcast_at = progress;
+                shared.broadcast();
+            }
+            // Files without layer comments are resumed after the last line sent
+            if layer.is_none()
+                && shared
+                    .checkpoints
+                    .as_ref()
+                    .is_some_and(|store| saved_at.elapsed() >= store.interval)
+            {
+                saved_at = Instant::now();
+                shared.save_checkpoint(&shared.resume_point(line_number, &gcode));
+            }
+        }
+    };
+
+    let halted = shared.halted.load(Ordering::SeqCst);
+    let (state, reason) = match outcome {
+        _ if halted => (JobState::Cancelled, Some("Emergency stop".to_string())),
+        Ok(state) => (state, None),
+        Err(e) => {
+            error!("Job failed on {} | {}", shared.printer, e);
+            (JobState::Failed, Some(e.to_string()))
+        }
+    };
+
+    if state == JobState::Cancelled && !halted {
+        warn!("Job cancelled on {}", shared.printer);
+        for command in &cancel_commands {
+            if let Err(e) = printer.send_line(command) {
+                error!("Failed to send {} after cancel | {}", command, e);
+            }
+        }
+    }
+
+    shared.update(|job| {
+        job.state = state;
+        job.finished = Some(now());
+        job.error = reason;
+    });
+    if matches!(
+        printer.state(),
+        PrinterState::Printing | PrinterState::Paused
+    ) {
+        printer.set_state(PrinterState::Operational, Some(format!("Job {:?}", state)));
+    }
+    info!("Job {:?} on {}", state, shared.printer);
+    shared.broadcast();
+
+    // A failed job can be resumed from the start of the layer it was printing
+    if state == JobState::Failed {
+        let point = layer.unwrap_or_else(|| shared.resume_point(line_number, &gcode));
+        if let Some(checkpoint) = shared.save_checkpoint(&point) {
+            warn!(
+                "Job {} on {} can be resumed from its checkpoint",
+                checkpoint.file, shared.printer
+            );
+            *lock(&shared.pending) = Some(checkpoint);
+        }
+    } else if let Some(store) = &shared.checkpoints {
+        store.clear();
+    }
+
+    if let (Some(history), Some(job)) = (&shared.history, lock(&shared.status).clone()) {
+        if let Err(e) = history.record(&shared.printer, &job) {
+            error!("Failed to record the job in the history | {}", e);
+        }
+    }
+}
+
+/**
+ * Fingerprint of a file, identifies the same print under another name
+ * @param path: &Path
+ * @return Result<String, Error>, SHA-256 in hex
+ */
+fn file_hash(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/**
+ * Command part of a file line
+ * @param line: &str, line of the file
+ * @return &str, empty for comments and blank lines
+ */
+fn strip_comment(line: &str) -> &str {
+    line.split(';').next().unwrap_or("").trim()
+}
+
+/// Comments written by the slicers before the first line of a layer
+fn is_layer_change(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with(";LAYER_CHANGE") || line.starts_with(";LAYER:")
+}
+
+fn percent(position: u64, size: u64) -> f64 {
+    if size == 0 {
+        return 100.0;
+    }
+    (position as f64 * 100.0 / size as f64).min(100.0)
+}
+
+fn check_operational(printer: &Printer) -> Result<(), Error> {
+    let state = printer.state();
+    if state != PrinterState::Operational {
+        return Err(Error::new(
+            ErrorKind::ResourceBusy,
+            format!("Printer is {:?}", state),
+        ));
+    }
+    Ok(())
+}
+
+fn is_active(state: JobState) -> bool {
+    matches!(state, JobState::Printing | JobState::Paused)
+}
+
+fn no_job() -> Error {
+    Error::new(ErrorKind::NotFound, "No job is running")
+}
+
+fn no_checkpoint() -> Error {
+    Error::new(ErrorKind::NotFound, "No interrupted job to resume")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer::OkPort;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(strip_comment("G1 X10 ; move\n"), "G1 X10");
+        assert_eq!(strip_comment(";LAYER:2\n"), "");
+        assert_eq!(strip_comment("  \r\n"), "");
+        assert_eq!(strip_comment("M117 Hello"), "M117 Hello");
+    }
+
+    #[test]
+    fn test_is_layer_change() {
+        assert!(is_layer_change(";LAYER_CHANGE\n"));
+        assert!(is_layer_change(";LAYER:2\n"));
+        assert!(!is_layer_change(";LAYER_COUNT:120\n"));
+        assert!(!is_layer_change("G1 Z0.4 ; layer change\n"));
+    }
+
+    #[test]
+    fn test_job_transitions() {
+        let runner = JobRunner::new(
+            "mk3",
+            broadcast::channel(8).0,
+            None,
+            None,
+            ActionsConfig::default(),
+        );
+        assert_eq!(runner.cancel().unwrap_err().kind(), ErrorKind::NotFound);
+
+        *lock(&runner.shared.status) = Some(JobStatus {
+            file: "cube.gcode".to_string(),
+            state: JobState::Printing,
+            position: 50,
+            size: 200,
+            progress: percent(50, 200),
+            lines_sent: 3,
+            run_id: 1,
+            started: now(),
+            finished: None,
+            error: None,
+            hash: String::new(),
+            filament_used: 0.0,
+            started_by: "dashboard".to_string(),
+        });
+        assert_eq!(runner.status().unwrap().progress, 25.0);
+
+        runner
+            .shared
+            .transition(JobState::Printing, JobState::Paused)
+            .unwrap();
+        assert!(runner
+            .shared
+            .transition(JobState::Printing, JobState::Paused)
+            .is_err());
+        assert_eq!(runner.cancel().unwrap().state, JobState::Paused);
+        assert!(runner.shared.cancelled.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_cancel_while_streaming() {
+        let path =
+            std::env::temp_dir().join(format!("xcontroller_job_{}.gcode", std::process::id()));
+        fs::write(&path, "G1 X1 Y1\n".repeat(2000)).unwrap();
+        let written = Arc::new(AtomicUsize::new(0));
+        let printer = Printer::serving(OkPort::new(written.clone()));
+        let runner = JobRunner::new(
+            "mk3",
+            broadcast::channel(64).0,
+            None,
+            None,
+            ActionsConfig::default(),
+        );
+
+        let job_file = JobFile::open(&path).unwrap();
+        runner
+            .start(&printer, job_file, vec!["M84".to_string()], "dashboard")
+            .unwrap();
+        while runner.status().unwrap().lines_sent < 10 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        runner.cancel().unwrap();
+        while is_active(runner.status().unwrap().state) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        fs::remove_file(&path).unwrap();
+
+        let job = runner.status().unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert!(job.lines_sent < 2000);
+        // The lines sent and the cancel command
+        assert_eq!(written.load(Ordering::SeqCst) as u64, job.lines_sent + 1);
+    }
+
+    #[test]
+    fn test_checkpoint_at_layer_start() {
+        let id = std::process::id();
+        let path = std::env::temp_dir().join(format!("xcontroller_layers_{}.gcode", id));
+        let first = ";LAYER_CHANGE\nG1 Z0.2\nG1 X5 Y5 E1\n";
+        let second = ";LAYER_CHANGE\nG1 Z0.4\n";
+        fs::write(
+            &path,
+            format!("{}{}{}", first, second, "G1 X1 Y1 E1\n".repeat(2000)),
+        )
+        .unwrap();
+        let store = std::env::temp_dir().join(format!("xcontroller_layers_{}.json", id));
+        let printer = Printer::serving(OkPort::new(Arc::new(AtomicUsize::new(0))));
+        let runner = JobRunner::new(
+            "mk3",
+            broadcast::channel(64).0,
+            None,
+            Some(CheckpointStore::at(store.clone(), Duration::ZERO)),
+            ActionsConfig::default(),
+        );
+
+        runner
+            .start(
+                &printer,
+                JobFile::open(&path).unwrap(),
+                Vec::new(),
+                "dashboard",
+            )
+            .unwrap();
+        while runner.status().unwrap().lines_sent < 20 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        // The connection is lost in the middle of the second layer
+        printer.set_state(PrinterState::Error, None);
+        while is_active(runner.status().unwrap().state) {
+            thread::sleep(Duration::from_millis(5));
+        }
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&store).unwrap();
+
+        assert_eq!(runner.status().unwrap().state, JobState::Failed);
+        let checkpoint = runner.checkpoint().unwrap();
+        assert_eq!(checkpoint.position, first.len() as u64);
+        assert_eq!(checkpoint.line, 3);
+        assert_eq!(checkpoint.lines_sent, 2);
+        assert_eq!(checkpoint.gcode.z, 0.2);
+        assert_eq!((checkpoint.gcode.x, checkpoint.gcode.y), (5.0, 5.0));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_sd_object() {
+        let written = Arc::new(AtomicUsize::new(0));
+        let printer = Printer::serving(OkPort::new(written.clone()));
+        let runner = JobRunner::new(
+            "mk3",
+            broadcast::channel(64).0,
+            None,
+            None,
+            ActionsConfig::default(),
+        );
+
+        assert!(runner.cancel_object(&printer, Some(1)).await.is_err());
+        assert_eq!(written.load(Ordering::SeqCst), 0);
+
+        // Printing from the SD card, the firmware is told to skip the object
+        printer.set_state(PrinterState::Printing, None);
+        let objects = runner.cancel_object(&printer, Some(1)).await.unwrap();
+        assert!(objects.objects.is_empty());
+        assert_eq!(written.load(Ordering::SeqCst), 1);
+    }
+}
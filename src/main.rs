@@ -1,30 +1,93 @@
-use log::{error, info};
+use log::{error, info, warn};
 use simplelog::*;
 use std::env;
 use std::fs::{self, File};
+use std::sync::Arc;
+use std::time::Duration;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::net::TcpListener;
 
+mod access;
+mod actions;
+mod auth;
 mod commands;
 mod configuration;
+mod control;
+mod discovery;
+mod eeprom;
+mod emergency;
+mod files;
+mod gcode;
+mod history;
+mod jobs;
+mod mesh;
+mod metrics;
+mod moonraker;
+mod mqtt;
+mod objects;
+mod octoprint;
 mod parser;
+mod printer;
+mod queue;
+mod recovery;
+mod responses;
+mod rest;
+mod safety;
 mod serialcom;
+mod state;
 mod structs;
+mod tls;
 mod wscom;
 
-use crate::configuration::get_configuration;
+use crate::access::AccessControl;
+use crate::auth::token_command;
+use crate::configuration::{config_command, load_configuration, parse_args};
+use crate::discovery::ports_command;
+use crate::mqtt::run_mqtt;
+use crate::queue::run_queue;
+use crate::rest::serve_rest;
+use crate::state::ServerState;
 use crate::structs::{Config, MessageType, MessageWS};
-use crate::wscom::accept_connection;
+use crate::tls::tls_acceptor;
+use crate::wscom::{accept_connection, HANDSHAKE_TIMEOUT};
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = env::args().collect();
+
+    // Defaults < config file < XCONTROLLER_* variables < flags
+    let cli = parse_args(&args).unwrap_or_else(|e| exit_with_error(e));
+    let configuration = load_configuration(&cli, &|name| env::var(name).ok())
+        .unwrap_or_else(|e| exit_with_error(e));
+
+    let command = cli.command.first().map(String::as_str);
+    let result = match command {
+        // Manage authentication tokens, e.g. "xcontroller token mint <name> <role>"
+        Some("token") => token_command(&cli.command[1..], &configuration.auth.tokens_file),
+        // List serial ports, "xcontroller ports probe" also looks for a firmware
+        Some("ports") => ports_command(&cli.command[1..]),
+        // Print the effective configuration
+        Some("config") => config_command(&configuration),
+        _ => Ok(()),
+    };
+    if command.is_some() {
+        if let Err(e) = result {
+            exit_with_error(e);
+        }
+        return;
+    }
+
     setup_logs().expect("Failed to setup logs");
 
     info!("Starting xcontroller...");
 
-    // Set config from start params
-    let args: Vec<String> = env::args().collect();
-    let configuration = get_configuration(args);
+    if cli.legacy_positional {
+        warn!("Positional start params are deprecated, use --ws-port, --serial-port, --baud and --test-mode");
+    }
+
+    if !configuration.auth.enabled {
+        warn!("Authentication is disabled, every client has admin rights");
+    }
 
     let addr = format!("0.0.0.0:{}", configuration.ws_port);
 
@@ -35,23 +98,104 @@ async fn main() {
         .await
         .expect("TCP fail to open connection");
 
+    // Plain ws:// is kept for local development when TLS is disabled
+    let tls = if configuration.tls.enabled {
+        let acceptor = tls_acceptor(&configuration.tls).expect("Failed to setup TLS");
+        info!(
+            "Serving wss:// with certificate {}",
+            configuration.tls.cert_path
+        );
+        Some(acceptor)
+    } else {
+        None
+    };
+
+    let access = AccessControl::new(configuration.access.clone());
+    let state = Arc::new(ServerState::new(&configuration));
+
+    if configuration.http.enabled {
+        let rest = serve_rest(configuration.clone(), state.clone(), tls.clone());
+        tokio::spawn(async move {
+            if let Err(e) = rest.await {
+                error!("REST API stopped | {}", e);
+            }
+        });
+    }
+
+    if configuration.mqtt.enabled {
+        tokio::spawn(run_mqtt(configuration.clone(), state.clone()));
+    }
+
+    tokio::spawn(run_queue(configuration.clone(), state.clone()));
+
+    // Release the control lock of idle holders
+    let expire_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            for managed in &expire_state.printers {
+                if let Some(status) = managed.control.expire() {
+                    info!("Control lock of {} released after timeout", managed.name);
+                    expire_state.broadcast_control(&managed.name, &status);
+                }
+            }
+        }
+    });
+
     // Start serial connection and listen for incoming connections
     while let Ok((stream, _)) = listener.accept().await {
         let peer = stream
             .peer_addr()
             .expect("Connected peers should have an address");
 
+        // Rejected connections are dropped before the handshake
+        let Ok(permit) = access.admit(peer) else {
+            continue;
+        };
+
         let cloned_configuration = configuration.clone();
+        let tls = tls.clone();
+        let state = state.clone();
 
         // Spawn a new thread for each connection for async handling
         tokio::spawn(async move {
-            if let Err(e) = accept_connection(peer, stream, cloned_configuration).await {
+            let _permit = permit;
+            let result = match tls {
+                Some(acceptor) => {
+                    let handshake = tokio::time::timeout(
+                        Duration::from_secs(HANDSHAKE_TIMEOUT),
+                        acceptor.accept(stream),
+                    );
+                    match handshake.await {
+                        Ok(Ok(stream)) => {
+                            accept_connection(peer, stream, cloned_configuration, state).await
+                        }
+                        Ok(Err(e)) => {
+                            error!("TLS handshake failed from {}: {}", peer, e);
+                            return;
+                        }
+                        Err(_) => {
+                            warn!("TLS handshake timed out from {}", peer);
+                            return;
+                        }
+                    }
+                }
+                None => accept_connection(peer, stream, cloned_configuration, state).await,
+            };
+
+            if let Err(e) = result {
                 error!("Connection error from {}: {}", peer, e);
             }
         });
     }
 }
 
+fn exit_with_error(error: std::io::Error) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
+
 fn setup_logs() -> Result<(), std::io::Error> {
     // setup logs folder
     if !std::path::Path::new("./logs").exists() {
@@ -0,0 +1,247 @@
+use log::{error, info};
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::parser::{linspace, m420};
+use crate::printer::data_dir;
+use crate::structs::{BedMesh, BedMeshReport, BedMeshRequest, MeshDiff, MeshStats};
+
+static MESH_DIR: &str = "./meshes";
+
+/**
+ * Read the mesh from the printer, store it and compare it with a previous mesh
+ * @param send: FnMut(&str) -> Result<String, Error>, send a command and return the response
+ * @param printer: &str, name of the printer, meshes are stored per printer
+ * @param request: &BedMeshRequest, report command, bounds and mesh to compare with
+ * @return Result<BedMeshReport, Error>, mesh with its stats and diff
+ */
+pub fn fetch_mesh<F>(
+    send: &mut F,
+    printer: &str,
+    request: &BedMeshRequest,
+) -> Result<BedMeshReport, Error>
+where
+    F: FnMut(&str) -> Result<String, Error>,
+{
+    let command = if request.topography {
+        "G29 T"
+    } else {
+        "M420 V"
+    };
+    let response =
+        send(command).map_err(|e| Error::other(format!("Failed to read bed mesh | {}", e)))?;
+
+    let mut mesh = m420(response);
+    if mesh.z.is_empty() {
+        return Err(Error::other("No bed mesh reported by the firmware"));
+    }
+
+    if let Some(bounds) = request.bounds {
+        mesh.x = linspace(bounds.min_x, bounds.max_x, mesh.x.len());
+        mesh.y = linspace(bounds.min_y, bounds.max_y, mesh.y.len());
+    }
+
+    mesh.timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    // Without an explicit mesh, compare with the latest one if the grid matches
+    let diff = match request.compare_to {
+        Some(timestamp) => Some(diff_meshes(&mesh, &load_mesh(printer, timestamp)?)?),
+        None => list_meshes(printer)?
+            .pop()
+            .and_then(|previous| diff_meshes(&mesh, &previous).ok()),
+    };
+
+    save_mesh(&data_dir(MESH_DIR, printer), &mesh)?;
+
+    Ok(BedMeshReport {
+        stats: mesh_stats(&mesh),
+        mesh,
+        diff,
+    })
+}
+
+/**
+ * Min, max, range and mean of the probed points
+ * @param mesh: &BedMesh, mesh to measure
+ * @return MeshStats, zeroed when no point was probed
+ */
+pub fn mesh_stats(mesh: &BedMesh) -> MeshStats {
+    let values: Vec<f32> = mesh.z.iter().flatten().flatten().copied().collect();
+
+    if values.is_empty() {
+        return MeshStats::default();
+    }
+
+    let min = values.iter().copied().fold(f32::MAX, f32::min);
+    let max = values.iter().copied().fold(f32::MIN, f32::max);
+
+    MeshStats {
+        min,
+        max,
+        range: max - min,
+        mean: values.iter().sum::<f32>() / values.len() as f32,
+    }
+}
+
+/**
+ * Difference between two meshes of the same size
+ * @param current: &BedMesh, latest mesh
+ * @param previous: &BedMesh, mesh to compare with
+ * @return Result<MeshDiff, Error>, current - previous for every point probed in both
+ */
+pub fn diff_meshes(current: &BedMesh, previous: &BedMesh) -> Result<MeshDiff, Error> {
+    let same_size = current.z.len() == previous.z.len()
+        && current
+            .z
+            .iter()
+            .zip(&previous.z)
+            .all(|(a, b)| a.len() == b.len());
+
+    if !same_size {
+        return Err(Error::other("Meshes have different grid sizes"));
+    }
+
+    let z: Vec<Vec<Option<f32>>> = current
+        .z
+        .iter()
+        .zip(&previous.z)
+        .map(|(row, previous_row)| {
+            row.iter()
+                .zip(previous_row)
+                .map(|(a, b)| Some((*a)? - (*b)?))
+                .collect()
+        })
+        .collect();
+
+    let max_delta = z
+        .iter()
+        .flatten()
+        .flatten()
+        .fold(0.0_f32, |max, delta| max.max(delta.abs()));
+
+    Ok(MeshDiff {
+        previous_timestamp: previous.timestamp,
+        z,
+        max_delta,
+    })
+}
+
+/**
+ * List the meshes stored on the host
+ * @param printer: &str, name of the printer
+ * @return Result<Vec<BedMesh>, Error>, meshes ordered by timestamp
+ */
+pub fn list_meshes(printer: &str) -> Result<Vec<BedMesh>, Error> {
+    list_meshes_in(&data_dir(MESH_DIR, printer))
+}
+
+fn list_meshes_in(dir: &Path) -> Result<Vec<BedMesh>, Error> {
+    let mut meshes = Vec::new();
+
+    if !dir.exists() {
+        return Ok(meshes);
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            match fs::read_to_string(&path)
+                .ok()
+                .and_then(|data| serde_json::from_str::<BedMesh>(&data).ok())
+            {
+                Some(mesh) => meshes.push(mesh),
+                None => error!("Failed to read mesh {}", path.display()),
+            }
+        }
+    }
+
+    meshes.sort_by_key(|mesh| mesh.timestamp);
+    Ok(meshes)
+}
+
+fn load_mesh(printer: &str, timestamp: u64) -> Result<BedMesh, Error> {
+    list_meshes(printer)?
+        .into_iter()
+        .find(|mesh| mesh.timestamp == timestamp)
+        .ok_or_else(|| Error::other(format!("Mesh {} not found", timestamp)))
+}
+
+fn save_mesh(dir: &Path, mesh: &BedMesh) -> Result<(), Error> {
+    fs::create_dir_all(dir)?;
+
+    let path = dir.join(format!("mesh_{}.json", mesh.timestamp));
+    let data = serde_json::to_string(mesh).map_err(Error::other)?;
+    fs::write(&path, data)?;
+
+    info!("Bed mesh saved | {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh(timestamp: u64, z: Vec<Vec<Option<f32>>>) -> BedMesh {
+        BedMesh {
+            kind: "bilinear".to_string(),
+            timestamp,
+            x: vec![0.0, 1.0],
+            y: vec![0.0, 1.0],
+            z,
+        }
+    }
+
+    #[test]
+    fn test_mesh_stats() {
+        let stats = mesh_stats(&mesh(
+            1,
+            vec![vec![Some(-0.2), Some(0.1)], vec![None, Some(0.4)]],
+        ));
+
+        assert_eq!(stats.min, -0.2);
+        assert_eq!(stats.max, 0.4);
+        assert!((stats.range - 0.6).abs() < 1e-6);
+        assert!((stats.mean - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_diff_meshes() {
+        let previous = mesh(1, vec![vec![Some(0.0), Some(0.1)], vec![None, Some(0.2)]]);
+        let current = mesh(
+            2,
+            vec![vec![Some(0.05), Some(0.1)], vec![Some(0.3), Some(-0.1)]],
+        );
+
+        let diff = diff_meshes(&current, &previous).unwrap();
+        assert_eq!(diff.previous_timestamp, 1);
+        assert_eq!(diff.z[1][0], None);
+        assert!((diff.max_delta - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_diff_meshes_size_mismatch() {
+        let previous = mesh(1, vec![vec![Some(0.0)]]);
+        let current = mesh(2, vec![vec![Some(0.0), Some(0.1)]]);
+        assert!(diff_meshes(&current, &previous).is_err());
+    }
+
+    #[test]
+    fn test_save_and_list_meshes() {
+        let dir = std::env::temp_dir().join(format!("xcontroller_mesh_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        save_mesh(&dir, &mesh(20, vec![vec![Some(0.1)]])).unwrap();
+        save_mesh(&dir, &mesh(10, vec![vec![Some(0.2)]])).unwrap();
+        let meshes = list_meshes_in(&dir).unwrap();
+
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].timestamp, 10);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,452 @@
+use regex::Regex;
+use std::fmt::Write;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use crate::parser::m105;
+use crate::state::ServerState;
+use crate::structs::{JobState, PrinterState, Temperatures};
+
+// Upper bounds in seconds of the command latency buckets, job lines wait for long moves
+static LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 120.0,
+];
+// Heater PWM after the temperatures, "@:" for the hotend and "B@:" for the bed
+static HOTEND_POWER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?:^|\s)@:(\d+)").unwrap());
+static BED_POWER: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"B@:(\d+)").unwrap());
+/// Reads a counter of the serial metrics
+type Counter = fn(&SerialMetrics) -> u64;
+
+static STATES: &[PrinterState] = &[
+    PrinterState::Disconnected,
+    PrinterState::Connecting,
+    PrinterState::Handshaking,
+    PrinterState::Operational,
+    PrinterState::Printing,
+    PrinterState::Paused,
+    PrinterState::Error,
+    PrinterState::Halted,
+];
+
+/// Counters of a serial connection, updated by the printer worker for every command
+#[derive(Debug, Clone, Default)]
+pub struct SerialMetrics {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub lines_sent: u64,
+    pub lines_received: u64,
+    /// Lines the firmware asked to send again after a checksum or line number error
+    pub resends: u64,
+    pub latency: Histogram,
+    /// Last temperatures reported by the firmware
+    pub temperatures: Option<Temperatures>,
+    /// Heater PWM reported after @: and B@:, 0 to 127 on Marlin
+    pub hotend_power: Option<u32>,
+    pub bed_power: Option<u32>,
+}
+
+impl SerialMetrics {
+    /**
+     * Count a command and its response
+     * @param command: &str, command sent without line ending
+     * @param response: &str, lines received
+     * @param elapsed: Duration, time until the response was complete
+     */
+    pub fn record(&mut self, command: &str, response: &str, elapsed: Duration) {
+        self.bytes_sent += command.len() as u64 + 1;
+        self.lines_sent += 1;
+        self.bytes_received += response.len() as u64;
+        self.lines_received += response.lines().count() as u64;
+        self.resends += response
+            .lines()
+            .filter(|line| line.trim_start().starts_with("Resend:"))
+            .count() as u64;
+        self.latency.observe(elapsed.as_secs_f64());
+
+        if response.contains("T:") && response.contains("B:") {
+            self.temperatures = Some(m105(response.to_string()));
+            self.hotend_power = capture(&HOTEND_POWER, response).or(self.hotend_power);
+            self.bed_power = capture(&BED_POWER, response).or(self.bed_power);
+        }
+    }
+}
+
+/// Cumulative histogram in the Prometheus format
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    /// Observations at or below each bound of LATENCY_BUCKETS
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/**
+ * Metrics of every printer in the Prometheus text format
+ * @param state: &ServerState, printers and connected clients
+ * @return String, body of /metrics
+ */
+pub fn render_metrics(state: &ServerState) -> String {
+    let mut out = String::new();
+    let printers: Vec<_> = state
+        .printers
+        .iter()
+        .map(|managed| {
+            (
+                escape(&managed.name),
+                managed.printer.state(),
+                managed.printer.metrics(),
+                managed.job.status(),
+                managed
+                    .safety
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .rejected(),
+            )
+        })
+        .collect();
+
+    family(
+        &mut out,
+        "xcontroller_printer_state",
+        "gauge",
+        "Connection state of the printer, 1 for the current one",
+    );
+    for (name, current, ..) in &printers {
+        for state in STATES {
+            let value = u8::from(state == current);
+            let _ = writeln!(
+                out,
+                "xcontroller_printer_state{{printer=\"{}\",state=\"{:?}\"}} {}",
+                name, state, value
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "xcontroller_temperature_celsius",
+        "gauge",
+        "Last temperature reported by the heater",
+    );
+    for (name, _, metrics, ..) in &printers {
+        for (heater, actual, _) in heaters(metrics.temperatures.as_ref()) {
+            let _ = writeln!(
+                out,
+                "xcontroller_temperature_celsius{{printer=\"{}\",heater=\"{}\"}} {}",
+                name, heater, actual
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "xcontroller_temperature_target_celsius",
+        "gauge",
+        "Target temperature of the heater",
+    );
+    for (name, _, metrics, ..) in &printers {
+        for (heater, _, target) in heaters(metrics.temperatures.as_ref()) {
+            let _ = writeln!(
+                out,
+                "xcontroller_temperature_target_celsius{{printer=\"{}\",heater=\"{}\"}} {}",
+                name, heater, target
+            );
+        }
+    }
+
+    family(
+        &mut out,
+        "xcontroller_heater_power",
+        "gauge",
+        "Heater PWM reported by the firmware",
+    );
+    for (name, _, metrics, ..) in &printers {
+        let powers = [("e0", metrics.hotend_power), ("bed", metrics.bed_power)];
+        for (heater, power) in powers {
+            if let Some(power) = power {
+                let _ = writeln!(
+                    out,
+                    "xcontroller_heater_power{{printer=\"{}\",heater=\"{}\"}} {}",
+                    name, heater, power
+                );
+            }
+        }
+    }
+
+    let counters: [(&str, &str, Counter); 5] = [
+        (
+            "xcontroller_serial_bytes_sent_total",
+            "Bytes written to the serial port",
+            |m| m.bytes_sent,
+        ),
+        (
+            "xcontroller_serial_bytes_received_total",
+            "Bytes read from the serial port",
+            |m| m.bytes_received,
+        ),
+        (
+            "xcontroller_serial_lines_sent_total",
+            "Lines written to the serial port",
+            |m| m.lines_sent,
+        ),
+        (
+            "xcontroller_serial_lines_received_total",
+            "Lines read from the serial port",
+            |m| m.lines_received,
+        ),
+        (
+            "xcontroller_serial_resends_total",
+            "Resend requests of the firmware",
+            |m| m.resends,
+        ),
+    ];
+    for (metric, help, value) in counters {
+        family(&mut out, metric, "counter", help);
+        for (name, _, metrics, ..) in &printers {
+            let _ = writeln!(out, "{}{{printer=\"{}\"}} {}", metric, name, value(metrics));
+        }
+    }
+
+    family(
+        &mut out,
+        "xcontroller_command_duration_seconds",
+        "histogram",
+        "Time until the response of a command was complete",
+    );
+    for (name, _, metrics, ..) in &printers {
+        let latency = &metrics.latency;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(&latency.buckets) {
+            let _ = writeln!(
+                out,
+                "xcontroller_command_duration_seconds_bucket{{printer=\"{}\",le=\"{}\"}} {}",
+                name, bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "xcontroller_command_duration_seconds_bucket{{printer=\"{}\",le=\"+Inf\"}} {}",
+            name, latency.count
+        );
+        let _ = writeln!(
+            out,
+            "xcontroller_command_duration_seconds_sum{{printer=\"{}\"}} {}",
+            name, latency.sum
+        );
+        let _ = writeln!(
+            out,
+            "xcontroller_command_duration_seconds_count{{printer=\"{}\"}} {}",
+            name, latency.count
+        );
+    }
+
+    family(
+        &mut out,
+        "xcontroller_job_progress_percent",
+        "gauge",
+        "Percentage of the file of the current or last job sent",
+    );
+    for (name, _, _, job, _) in &printers {
+        let progress = job.as_ref().map_or(0.0, |job| job.progress);
+        let _ = writeln!(
+            out,
+            "xcontroller_job_progress_percent{{printer=\"{}\"}} {}",
+            name, progress
+        );
+    }
+
+    family(
+        &mut out,
+        "xcontroller_job_active",
+        "gauge",
+        "1 while a job is printing or paused",
+    );
+    for (name, _, _, job, _) in &printers {
+        let active = job
+            .as_ref()
+            .is_some_and(|job| matches!(job.state, JobState::Printing | JobState::Paused));
+        let _ = writeln!(
+            out,
+            "xcontroller_job_active{{printer=\"{}\"}} {}",
+            name,
+            u8::from(active)
+        );
+    }
+
+    family(
+        &mut out,
+        "xcontroller_safety_rejections_total",
+        "counter",
+        "Commands rejected by the safety limits",
+    );
+    for (name, _, _, _, rejected) in &printers {
+        let _ = writeln!(
+            out,
+            "xcontroller_safety_rejections_total{{printer=\"{}\"}} {}",
+            name, rejected
+        );
+    }
+
+    family(
+        &mut out,
+        "xcontroller_websocket_clients",
+        "gauge",
+        "Connected WebSocket clients",
+    );
+    let _ = writeln!(out, "xcontroller_websocket_clients {}", state.clients());
+
+    out
+}
+
+fn family(out: &mut String, metric: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", metric, help);
+    let _ = writeln!(out, "# TYPE {} {}", metric, kind);
+}
+
+/// Heaters with their temperature and target, the extra extruders only when they are used
+fn heaters(temperatures: Option<&Temperatures>) -> Vec<(&'static str, u8, u8)> {
+    let Some(t) = temperatures else {
+        return Vec::new();
+    };
+
+    [
+        ("e0", t.e0, t.e0_set),
+        ("e1", t.e1, t.e1_set),
+        ("e2", t.e2, t.e2_set),
+        ("e3", t.e3, t.e3_set),
+        ("bed", t.bed, t.bed_set),
+    ]
+    .into_iter()
+    .filter(|(heater, actual, target)| {
+        matches!(*heater, "e0" | "bed") || *actual > 0 || *target > 0
+    })
+    .collect()
+}
+
+fn capture(re: &Regex, text: &str) -> Option<u32> {
+    re.captures(text)?.get(1)?.as_str().parse().ok()
+}
+
+/// Label values are quoted, backslashes, quotes and line feeds are escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcode::parse_gcode;
+    use crate::safety::MotionState;
+    use crate::structs::{Config, HistoryConfig};
+
+    #[test]
+    fn test_record() {
+        let mut metrics = SerialMetrics::default();
+        metrics.record(
+            "M105",
+            "ok T:210.5 /215.0 B:60.0 /60.0 @:127 B@:34\n",
+            Duration::from_millis(20),
+        );
+        metrics.record("G1 X10", "Resend: 12\nok\n", Duration::from_millis(200));
+
+        assert_eq!(metrics.bytes_sent, 12);
+        assert_eq!(metrics.lines_sent, 2);
+        assert_eq!(metrics.lines_received, 3);
+        assert_eq!(metrics.resends, 1);
+        assert_eq!(metrics.temperatures.as_ref().unwrap().e0_set, 215);
+        assert_eq!(metrics.hotend_power, Some(127));
+        assert_eq!(metrics.bed_power, Some(34));
+
+        let latency = &metrics.latency;
+        assert_eq!(latency.count, 2);
+        // 20 ms is counted from the 0.025 bucket, 200 ms from the 0.25 bucket
+        assert_eq!(latency.buckets[1], 0);
+        assert_eq!(latency.buckets[2], 1);
+        assert_eq!(latency.buckets[5], 2);
+    }
+
+    #[test]
+    fn test_heaters() {
+        assert!(heaters(None).is_empty());
+
+        let temperatures = Temperatures {
+            e0: 200,
+            e1: 25,
+            bed: 60,
+            ..Temperatures::default()
+        };
+        let names: Vec<_> = heaters(Some(&temperatures))
+            .into_iter()
+            .map(|(heater, ..)| heater)
+            .collect();
+        assert_eq!(names, vec!["e0", "e1", "bed"]);
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let configuration = Config {
+            serial_port: "/dev/xcontroller-metrics-test".to_string(),
+            history: HistoryConfig {
+                enabled: false,
+                ..HistoryConfig::default()
+            },
+            ..Config::default()
+        };
+        let state = ServerState::new(&configuration);
+        let command = parse_gcode("M104 S999").unwrap();
+        assert!(state.printers[0]
+            .safety
+            .lock()
+            .unwrap()
+            .check(&command, &MotionState::default())
+            .is_err());
+        let _client = state.client_connected();
+
+        let body = render_metrics(&state);
+        for family in [
+            "xcontroller_printer_state",
+            "xcontroller_serial_resends_total",
+            "xcontroller_command_duration_seconds",
+            "xcontroller_safety_rejections_total",
+        ] {
+            assert!(body.contains(&format!("# TYPE {} ", family)), "{}", family);
+        }
+        // Every sample is a name with its labels followed by a number
+        for sample in body.lines().filter(|line| !line.starts_with('#')) {
+            let (_, value) = sample.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", sample);
+        }
+        assert!(body
+            .contains("xcontroller_printer_state{printer=\"default\",state=\"Disconnected\"} 1"));
+        assert!(body.contains(
+            "xcontroller_command_duration_seconds_bucket{printer=\"default\",le=\"+Inf\"} 0"
+        ));
+        assert!(body.contains("xcontroller_safety_rejections_total{printer=\"default\"} 1"));
+        assert!(body.contains("xcontroller_websocket_clients 1"));
+    }
+}
@@ -0,0 +1,728 @@
+use futures::{stream::StreamExt, SinkExt};
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use crate::access::RateLimiter;
+use crate::auth::{required_role, Role};
+use crate::commands::g_command;
+use crate::files::{file_path, list_files};
+use crate::jobs::JobFile;
+use crate::parser::{m105, m114};
+use crate::printer::Printer;
+use crate::rest::status_for;
+use crate::safety::audit_rejection;
+use crate::state::{ManagedPrinter, ServerState};
+use crate::structs::{
+    AxePositions, Config, JobState, JobStatus, JsonRpcRequest, MessageType, PrinterState,
+    Temperatures,
+};
+
+// Interval between two notify_status_update of the subscribed objects
+static STATUS_INTERVAL: u64 = 1000;
+// Printer objects known by the endpoint, named like the Klipper objects
+static OBJECTS: &[&str] = &[
+    "webhooks",
+    "extruder",
+    "heater_bed",
+    "toolhead",
+    "gcode_move",
+    "print_stats",
+    "virtual_sdcard",
+];
+
+/// Error answered in the JSON-RPC error object, HTTP status codes like Moonraker
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for RpcError {
+    fn from(error: std::io::Error) -> Self {
+        RpcError::new(i64::from(status_for(error.kind()).as_u16()), error)
+    }
+}
+
+/// State of a printer read once for every requested object
+struct Snapshot {
+    state: PrinterState,
+    temperatures: Temperatures,
+    position: Option<AxePositions>,
+    homed_axes: String,
+    job: Option<JobStatus>,
+}
+
+/**
+ * Temperatures and position of a printer shared by its Moonraker clients
+ * The printer is queried at most once per STATUS_INTERVAL whatever the number of clients
+ */
+#[derive(Default)]
+pub struct StatusCache {
+    readings: tokio::sync::Mutex<Readings>,
+}
+
+#[derive(Default)]
+struct Readings {
+    temperatures: Option<(Instant, Temperatures)>,
+    position: Option<(Instant, Option<AxePositions>)>,
+}
+
+impl StatusCache {
+    async fn temperatures(&self, printer: &Printer) -> Temperatures {
+        let mut readings = self.readings.lock().await;
+        match &readings.temperatures {
+            Some((read, temperatures)) if fresh(*read) => temperatures.clone(),
+            _ => {
+                let temperatures = printer
+                    .send_async("M105")
+                    .await
+                    .map(m105)
+                    .unwrap_or_default();
+                readings.temperatures = Some((Instant::now(), temperatures.clone()));
+                temperatures
+            }
+        }
+    }
+
+    async fn position(&self, printer: &Printer) -> Option<AxePositions> {
+        let mut readings = self.readings.lock().await;
+        match &readings.position {
+            Some((read, position)) if fresh(*read) => position.clone(),
+            _ => {
+                let position = printer.send_async("M114").await.map(m114).ok();
+                readings.position = Some((Instant::now(), position.clone()));
+                position
+            }
+        }
+    }
+}
+
+/// Objects subscribed with printer.objects.subscribe, None for all attributes
+type Objects = BTreeMap<String, Option<Vec<String>>>;
+
+struct Session<'a> {
+    peer: SocketAddr,
+    configuration: &'a Config,
+    state: &'a ServerState,
+    /// Name of the token, recorded with the jobs the client starts
+    user: Option<String>,
+    role: Option<Role>,
+    printer: Option<String>,
+    client_id: u64,
+    subscriptions: Objects,
+    /// Status sent last, updates only carry the attributes that changed
+    last: Map<String, Value>,
+}
+
+/**
+ * Answer the Moonraker JSON-RPC methods used by Mainsail and Fluidd
+ * @param peer: SocketAddr, peer address
+ * @param ws_stream: WebSocketStream<S>, connection after the handshake on /websocket
+ * @param configuration: &Config, configuration for the server
+ * @param state: &ServerState, state shared between connections
+ * @param client: Option<(String, Role)>, client authenticated during the handshake
+ * @param printer: Option<String>, printer selected with /printers/<name>/websocket
+ * @return Result<(), Error>
+ */
+pub async fn moonraker_session<S>(
+    peer: SocketAddr,
+    ws_stream: WebSocketStream<S>,
+    configuration: &Config,
+    state: &ServerState,
+    client: Option<(String, Role)>,
+    printer: Option<String>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Moonraker client | {}", peer);
+    let (mut ws_write, mut ws_read) = ws_stream.split();
+    let mut rate_limiter = RateLimiter::new(&configuration.access.rate_limit);
+    let mut interval = tokio::time::interval(Duration::from_millis(STATUS_INTERVAL));
+    let (user, role) = client.unzip();
+    let mut session = Session {
+        peer,
+        configuration,
+        state,
+        user,
+        role,
+        printer,
+        client_id: state.next_client_id(),
+        subscriptions: BTreeMap::new(),
+        last: Map::new(),
+    };
+
+    loop {
+        let messages = tokio::select! {
+            msg = ws_read.next() => {
+                let msg = match msg {
+                    Some(msg) => msg?,
+                    None => break,
+                };
+                if !msg.is_text() || msg.is_empty() {
+                    continue;
+                }
+                if rate_limiter.allow() {
+                    session.handle(msg.to_text()?).await
+                } else {
+                    warn!("Rate limit exceeded by {}", peer);
+                    vec![rpc_error(Value::Null, RpcError::new(429, "Rate limit exceeded"))]
+                }
+            }
+            _ = interval.tick() => session.status_update().await.into_iter().collect(),
+        };
+
+        for message in messages {
+            ws_write
+                .send(Message::Text(message.to_string().into()))
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+impl Session<'_> {
+    /// Answer a request, followed by the notifications it caused
+    async fn handle(&mut self, text: &str) -> Vec<Value> {
+        let request = match serde_json::from_str::<JsonRpcRequest>(text) {
+            Ok(request) => request,
+            Err(e) => return vec![rpc_error(Value::Null, RpcError::new(-32700, e))],
+        };
+
+        let mut notifications = Vec::new();
+        let result = self
+            .dispatch(&request.method, &request.params, &mut notifications)
+            .await;
+        if let Err(e) = &result {
+            warn!("{} | {} failed | {}", self.peer, request.method, e.message);
+        }
+
+        // Notifications sent by the client are not answered
+        let Some(id) = request.id else {
+            return notifications;
+        };
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(e) => rpc_error(id, e),
+        };
+
+        let mut messages = vec![response];
+        messages.append(&mut notifications);
+        messages
+    }
+
+    async fn dispatch(
+        &mut self,
+        method: &str,
+        params: &Value,
+        notifications: &mut Vec<Value>,
+    ) -> Result<Value, RpcError> {
+        match method {
+            "server.info" => {
+                let state = self
+                    .target()
+                    .map_or(PrinterState::Disconnected, |target| target.printer.state());
+                Ok(json!({
+                    "klippy_connected": true,
+                    "klippy_state": klippy_state(state),
+                    "components": [],
+                    "failed_components": [],
+                    "registered_directories": ["gcodes"],
+                    "warnings": [],
+                    "moonraker_version": format!("xcontroller {}", env!("CARGO_PKG_VERSION")),
+                    "api_version": [1, 5, 0],
+                    "api_version_string": "1.5.0",
+                }))
+            }
+            "server.connection.identify" => Ok(json!({ "connection_id": self.client_id })),
+            "printer.info" => {
+                self.require(Role::Viewer)?;
+                let target = self.target()?;
+                let state = target.printer.state();
+                let firmware = target
+                    .printer
+                    .info()
+                    .map(|info| format!("{} {}", info.firmware_name, info.firmware_version))
+                    .unwrap_or_default();
+                Ok(json!({
+                    "state": klippy_state(state),
+                    "state_message": format!("Printer is {:?}", state),
+                    "hostname": target.name,
+                    "software_version": firmware,
+                }))
+            }
+            "printer.objects.list" => {
+                self.require(Role::Viewer)?;
+                Ok(json!({ "objects": OBJECTS }))
+            }
+            "printer.objects.query" | "printer.objects.subscribe" => {
+                self.require(Role::Viewer)?;
+                let objects = requested_objects(params)?;
+                let target = self.target()?;
+                let status = query_status(target, &objects).await;
+
+                // A subscription replaces the previous one
+                if method == "printer.objects.subscribe" {
+                    self.subscriptions = objects;
+                    self.last = status.clone();
+                }
+                Ok(json!({ "eventtime": eventtime(), "status": status }))
+            }
+            "printer.gcode.script" => {
+                let script = string_param(params, "script")?;
+                for line in script.lines().filter(|line| !line.trim().is_empty()) {
+                    let response = self.gcode(line).await?;
+                    notifications.push(json!({
+                        "jsonrpc": "2.0",
+                        "method": "notify_gcode_response",
+                        "params": [response.trim()],
+                    }));
+                }
+                Ok(json!("ok"))
+            }
+            "server.files.list" => {
+                self.require(Role::Viewer)?;
+                let root = params
+                    .get("root")
+                    .and_then(Value::as_str)
+                    .unwrap_or("gcodes");
+                if root != "gcodes" {
+                    return Err(RpcError::new(400, format!("Invalid root {}", root)));
+                }
+
+                let files = list_files(&self.configuration.jobs.files_dir)?;
+                Ok(files
+                    .iter()
+                    .map(|file| {
+                        json!({
+                            "path": file.name,
+                            "modified": file.modified,
+                            "size": file.size,
+                            "permissions": "rw",
+                        })
+                    })
+                    .collect())
+            }
+            "printer.print.start"
+            | "printer.print.pause"
+            | "printer.print.resume"
+            | "printer.print.cancel" => {
+                self.require(Role::Operator)?;
+                let target = self.writable_target()?;
+                match method {
+                    "printer.print.start" => {
+                        let jobs = &self.configuration.jobs;
+                        let path = file_path(&jobs.files_dir, &string_param(params, "filename")?)?;
+                        let user = self.user.as_deref().unwrap_or("anonymous");
+                        let job_file = JobFile::read(path).await?;
+                        target.job.start(
+                            &target.printer,
+                            job_file,
+                            jobs.cancel_commands.clone(),
+                            user,
+                        )?;
+                    }
+                    "printer.print.pause" => {
+                        target.job.pause(&target.printer)?;
+                    }
+                    "printer.print.resume" => {
+                        target.job.resume(&target.printer)?;
+                    }
+                    _ => {
+                        target.job.cancel()?;
+                    }
+                }
+                Ok(json!("ok"))
+            }
+            _ => Err(RpcError::new(
+                -32601,
+                format!("Method not found {}", method),
+            )),
+        }
+    }
+
+    /// Send a line of a script with the checks of GCommand messages
+    async fn gcode(&self, line: &str) -> Result<String, RpcError> {
+        let command = g_command(line).map_err(|e| RpcError::new(400, e))?;
+        let required =
+            required_role(&MessageType::GCommand, Some(&command)).unwrap_or(Role::Operator);
+        self.require(required)?;
+
+        let target = if required > Role::Viewer {
+            self.writable_target()?
+        } else {
+            self.target()?
+        };
+
+        if let Err(e) = lock(&target.safety).check(&command, &target.printer.motion()) {
+            audit_rejection(self.peer, line, &e.to_string());
+            return Err(RpcError::new(400, e));
+        }
+
+        Ok(target.printer.send_async(&command.to_string()).await?)
+    }
+
+    /// Attributes of the subscribed objects that changed since the last update
+    async fn status_update(&mut self) -> Option<Value> {
+        if self.subscriptions.is_empty() {
+            return None;
+        }
+
+        let target = self.target().ok()?;
+        let status = query_status(target, &self.subscriptions).await;
+        let changed = status_diff(&self.last, &status);
+        self.last = status;
+
+        if changed.is_empty() {
+            return None;
+        }
+        Some(json!({
+            "jsonrpc": "2.0",
+            "method": "notify_status_update",
+            "params": [changed, eventtime()],
+        }))
+    }
+
+    fn target(&self) -> Result<&ManagedPrinter, RpcError> {
+        Ok(self.state.printer(self.printer.as_deref())?)
+    }
+
+    /// Printer for a request changing it, refused while another client holds control or after an emergency stop
+    fn writable_target(&self) -> Result<&ManagedPrinter, RpcError> {
+        let target = self.target()?;
+        target
+            .control
+            .check(self.client_id)
+            .map_err(|e| RpcError::new(409, e))?;
+
+        if target.printer.state() == PrinterState::Halted {
+            return Err(RpcError::new(
+                409,
+                "Printer is halted, reset it and send M999 from the terminal",
+            ));
+        }
+        Ok(target)
+    }
+
+    fn require(&self, required: Role) -> Result<(), RpcError> {
+        match self.role {
+            None => Err(RpcError::new(401, "Missing token")),
+            Some(role) if role < required => Err(RpcError::new(
+                403,
+                format!("Permission denied, requires role {}", required),
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/**
+ * Read the printer once for the requested objects
+ * Temperatures and position are only queried when an object needs them, through the cache of the printer
+ * @param target: &ManagedPrinter, printer of the session
+ * @param objects: &Objects, objects with their attributes
+ * @return Map<String, Value>, status of the known objects
+ */
+async fn query_status(target: &ManagedPrinter, objects: &Objects) -> Map<String, Value> {
+    let state = target.printer.state();
+    let ready = accepts_queries(state);
+    let wants = |names: &[&str]| ready && names.iter().any(|name| objects.contains_key(*name));
+
+    let temperatures = if wants(&["extruder", "heater_bed"]) {
+        target.status_cache.temperatures(&target.printer).await
+    } else {
+        Temperatures::default()
+    };
+    let position = if wants(&["toolhead", "gcode_move"]) {
+        target.status_cache.position(&target.printer).await
+    } else {
+        None
+    };
+
+    let snapshot = Snapshot {
+        state,
+        temperatures,
+        position,
+        homed_axes: target.printer.homed_axes(),
+        job: target.job.status(),
+    };
+
+    objects
+        .iter()
+        .filter_map(|(name, attributes)| {
+            let status = object_status(name, &snapshot)?;
+            Some((
+                name.clone(),
+                filter_attributes(status, attributes.as_deref()),
+            ))
+        })
+        .collect()
+}
+
+/// Status of a Klipper object built from the state of xcontroller
+fn object_status(name: &str, snapshot: &Snapshot) -> Option<Value> {
+    let temperatures = &snapshot.temperatures;
+    let position = snapshot
+        .position
+        .as_ref()
+        .map_or([0.0; 4], |axes| [axes.x, axes.y, axes.z, 0.0]);
+    let job = snapshot.job.as_ref();
+    let duration = job.map_or(0, |job| {
+        job.finished
+            .unwrap_or_else(|| eventtime() as u64)
+            .saturating_sub(job.started)
+    });
+
+    let status = match name {
+        "webhooks" => json!({
+            "state": klippy_state(snapshot.state),
+            "state_message": format!("Printer is {:?}", snapshot.state),
+        }),
+        "extruder" => json!({
+            "temperature": temperatures.e0,
+            "target": temperatures.e0_set,
+            "power": 0,
+        }),
+        "heater_bed" => json!({
+            "temperature": temperatures.bed,
+            "target": temperatures.bed_set,
+            "power": 0,
+        }),
+        // Axes homed by a G28 sent through xcontroller, the homing of the firmware menu is not seen
+        "toolhead" => json!({
+            "position": position,
+            "homed_axes": snapshot.homed_axes,
+        }),
+        "gcode_move" => json!({
+            "position": position,
+            "gcode_position": position,
+        }),
+        "print_stats" => json!({
+            "filename": job.map_or("", |job| job.file.as_str()),
+            "state": print_state(job.map(|job| job.state)),
+            "print_duration": duration,
+            "total_duration": duration,
+            "filament_used": job.map_or(0.0, |job| job.filament_used),
+            "message": job.and_then(|job| job.error.as_deref()).unwrap_or(""),
+        }),
+        "virtual_sdcard" => json!({
+            "file_path": job.map(|job| job.file.as_str()),
+            "progress": job.map_or(0.0, |job| job.progress / 100.0),
+            "is_active": job.is_some_and(|job| job.state == JobState::Printing),
+            "file_position": job.map_or(0, |job| job.position),
+            "file_size": job.map_or(0, |job| job.size),
+        }),
+        _ => return None,
+    };
+    Some(status)
+}
+
+/// Keep the requested attributes of an object, all of them when None
+fn filter_attributes(status: Value, attributes: Option<&[String]>) -> Value {
+    match (status, attributes) {
+        (Value::Object(fields), Some(attributes)) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(field, _)| attributes.contains(field))
+                .collect(),
+        ),
+        (status, _) => status,
+    }
+}
+
+/**
+ * Attributes that changed between two status
+ * @param previous: &Map<String, Value>, status sent last
+ * @param current: &Map<String, Value>, status just read
+ * @return Map<String, Value>, changed attributes by object, empty when nothing changed
+ */
+fn status_diff(previous: &Map<String, Value>, current: &Map<String, Value>) -> Map<String, Value> {
+    let mut changed = Map::new();
+    for (name, status) in current {
+        let Value::Object(fields) = status else {
+            continue;
+        };
+
+        let before = previous.get(name).and_then(Value::as_object);
+        let fields: Map<String, Value> = fields
+            .iter()
+            .filter(|(field, value)| before.and_then(|before| before.get(*field)) != Some(*value))
+            .map(|(field, value)| (field.clone(), value.clone()))
+            .collect();
+        if !fields.is_empty() {
+            changed.insert(name.clone(), Value::Object(fields));
+        }
+    }
+    changed
+}
+
+/// Objects of a query, {"objects": {"extruder": null, "toolhead": ["position"]}}
+fn requested_objects(params: &Value) -> Result<Objects, RpcError> {
+    let objects = params
+        .get("objects")
+        .cloned()
+        .ok_or_else(|| RpcError::new(400, "objects is required"))?;
+    serde_json::from_value(objects).map_err(|e| RpcError::new(400, e))
+}
+
+fn string_param(params: &Value, name: &str) -> Result<String, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .map(|value| value.to_string())
+        .ok_or_else(|| RpcError::new(400, format!("{} is required", name)))
+}
+
+fn rpc_error(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": error.code, "message": error.message },
+        "id": id,
+    })
+}
+
+/// Klippy state shown by the interfaces
+fn klippy_state(state: PrinterState) -> &'static str {
+    match state {
+        PrinterState::Operational | PrinterState::Printing | PrinterState::Paused => "ready",
+        PrinterState::Disconnected | PrinterState::Connecting | PrinterState::Handshaking => {
+            "startup"
+        }
+        PrinterState::Error => "error",
+        PrinterState::Halted => "shutdown",
+    }
+}
+
+fn print_state(state: Option<JobState>) -> &'static str {
+    match state {
+        None => "standby",
+        Some(JobState::Printing) => "printing",
+        Some(JobState::Paused) => "paused",
+        Some(JobState::Completed) => "complete",
+        Some(JobState::Cancelled) => "cancelled",
+        Some(JobState::Failed) => "error",
+    }
+}
+
+fn accepts_queries(state: PrinterState) -> bool {
+    matches!(
+        state,
+        PrinterState::Operational | PrinterState::Printing | PrinterState::Paused
+    )
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Readings of another client are reused until the next status interval
+fn fresh(read: Instant) -> bool {
+    read.elapsed() < Duration::from_millis(STATUS_INTERVAL)
+}
+
+fn eventtime() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_status() {
+        let snapshot = Snapshot {
+            state: PrinterState::Printing,
+            temperatures: Temperatures {
+                e0: 210,
+                e0_set: 215,
+                ..Temperatures::default()
+            },
+            position: Some(AxePositions {
+                x: 10.0,
+                y: 20.0,
+                z: 0.2,
+            }),
+            homed_axes: String::new(),
+            job: Some(JobStatus {
+                file: "cube.gcode".to_string(),
+                state: JobState::Printing,
+                position: 50,
+                size: 200,
+                progress: 25.0,
+                lines_sent: 3,
+                run_id: 1,
+                started: 0,
+                finished: Some(60),
+                error: None,
+                hash: String::new(),
+                filament_used: 120.5,
+                started_by: "mainsail".to_string(),
+            }),
+        };
+
+        let extruder = object_status("extruder", &snapshot).unwrap();
+        assert_eq!(extruder["target"], 215);
+        let sdcard = object_status("virtual_sdcard", &snapshot).unwrap();
+        assert_eq!(sdcard["progress"], 0.25);
+        let stats = object_status("print_stats", &snapshot).unwrap();
+        assert_eq!(stats["state"], "printing");
+        assert_eq!(stats["print_duration"], 60);
+        assert_eq!(stats["filament_used"], 120.5);
+        assert!(object_status("bed_mesh", &snapshot).is_none());
+
+        let toolhead = object_status("toolhead", &snapshot).unwrap();
+        let attributes = vec!["homed_axes".to_string()];
+        assert_eq!(
+            filter_attributes(toolhead, Some(&attributes)),
+            json!({ "homed_axes": "" })
+        );
+    }
+
+    #[test]
+    fn test_status_diff() {
+        let previous = json!({
+            "extruder": { "temperature": 200, "target": 215 },
+            "print_stats": { "state": "printing" },
+        });
+        let current = json!({
+            "extruder": { "temperature": 205, "target": 215 },
+            "print_stats": { "state": "printing" },
+        });
+        let changed = status_diff(previous.as_object().unwrap(), current.as_object().unwrap());
+        assert_eq!(
+            Value::Object(changed),
+            json!({ "extruder": { "temperature": 205 } })
+        );
+        assert!(status_diff(current.as_object().unwrap(), current.as_object().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_requested_objects() {
+        let params = json!({ "objects": { "extruder": null, "toolhead": ["position"] } });
+        let objects = requested_objects(&params).unwrap();
+        assert_eq!(objects["extruder"], None);
+        assert_eq!(objects["toolhead"], Some(vec!["position".to_string()]));
+        assert_eq!(requested_objects(&json!({})).unwrap_err().code, 400);
+        assert_eq!(klippy_state(PrinterState::Halted), "shutdown");
+    }
+}
@@ -0,0 +1,467 @@
+use log::{error, info, warn};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::commands::g_command;
+use crate::parser::{m105, m114};
+use crate::responses::registry;
+use crate::safety::audit_rejection;
+use crate::state::{ManagedPrinter, ServerState};
+use crate::structs::{CommandResponse, Config, MqttConfig, PrinterState};
+
+// MQTT commands never hold the control lock, client ids start at 1
+static MQTT_CLIENT: u64 = 0;
+static RECONNECT_DELAY: u64 = 5;
+static KEEP_ALIVE: u64 = 30;
+
+/**
+ * Bridge the printers to an MQTT broker
+ * Publishes the state, temperatures, position and job of each printer and sends the G-code
+ * received on <topic_prefix>/<printer>/command
+ * @param configuration: Config, configuration of the server
+ * @param state: Arc<ServerState>, state shared with the WebSocket connections
+ */
+pub async fn run_mqtt(configuration: Config, state: Arc<ServerState>) {
+    let mqtt = configuration.mqtt.clone();
+    let mut options = MqttOptions::new(&mqtt.client_id, &mqtt.host, mqtt.port);
+    options.set_keep_alive(Duration::from_secs(KEEP_ALIVE));
+    if let Some(username) = &mqtt.username {
+        options.set_credentials(username, mqtt.password.clone().unwrap_or_default());
+    }
+    // The broker marks the bridge offline when the connection is lost
+    options.set_last_will(LastWill::new(
+        availability_topic(&mqtt),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    info!("MQTT bridge connecting to {}:{}", mqtt.host, mqtt.port);
+
+    tokio::spawn(forward_events(client.clone(), mqtt.clone(), state.clone()));
+    tokio::spawn(publish_telemetry(
+        client.clone(),
+        mqtt.clone(),
+        state.clone(),
+    ));
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("MQTT bridge connected to {}:{}", mqtt.host, mqtt.port);
+                tokio::spawn(announce(client.clone(), mqtt.clone(), state.clone()));
+            }
+            Ok(Event::Incoming(Packet::Publish(message))) => {
+                let Some(printer) = command_printer(&mqtt, &message.topic) else {
+                    continue;
+                };
+                let printer = printer.to_string();
+                let command = String::from_utf8_lossy(&message.payload).to_string();
+                let client = client.clone();
+                let mqtt = mqtt.clone();
+                let state = state.clone();
+                let source = format!("mqtt {}", mqtt.host);
+
+                tokio::spawn(async move {
+                    let response = match state.printer(Some(&printer)) {
+                        Ok(target) => serde_json::to_string(
+                            &handle_command(target, &command, &source)
+                                .await
+                                .map_err(|e| json!({ "command": command, "error": e.to_string() })),
+                        ),
+                        Err(e) => serde_json::to_string(&json!({ "error": e.to_string() })),
+                    };
+                    let response = response.expect("Failed to serialize message into JSON");
+                    publish(&client, topic(&mqtt, &printer, "response"), false, response).await;
+                });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection failed | {}", e);
+                tokio::time::sleep(Duration::from_secs(RECONNECT_DELAY)).await;
+            }
+        }
+    }
+}
+
+/**
+ * Send a G-code received on the command topic, with the validation of GCommand messages
+ * @param target: &ManagedPrinter, printer of the topic
+ * @param command: &str, payload of the message
+ * @param source: &str, broker the command came from, written to the audit log
+ * @return Result<CommandResponse, Error>
+ */
+async fn handle_command(
+    target: &ManagedPrinter,
+    command: &str,
+    source: &str,
+) -> Result<CommandResponse, Error> {
+    let command = command.trim();
+    let parsed = g_command(command).map_err(Error::other)?;
+
+    target.control.check(MQTT_CLIENT).map_err(Error::other)?;
+    if target.printer.state() == PrinterState::Halted {
+        return Err(Error::other(
+            "Printer is halted, reset it and send M999 from the terminal",
+        ));
+    }
+
+    if let Err(e) = lock(&target.safety).check(&parsed, &target.printer.motion()) {
+        audit_rejection(source, command, &e.to_string());
+        return Err(e);
+    }
+
+    let cmd = parsed.to_string();
+    let response = target.printer.send_async(&cmd).await?;
+    let decoded = registry().parse(&cmd, response.clone());
+    Ok(CommandResponse {
+        command: cmd,
+        response,
+        parsed: decoded,
+    })
+}
+
+/// Subscribe to the command topics and publish the retained state after each connection
+async fn announce(client: AsyncClient, mqtt: MqttConfig, state: Arc<ServerState>) {
+    publish(
+        &client,
+        availability_topic(&mqtt),
+        true,
+        "online".to_string(),
+    )
+    .await;
+
+    for managed in &state.printers {
+        let command_topic = topic(&mqtt, &managed.name, "command");
+        if let Err(e) = client.subscribe(&command_topic, QoS::AtLeastOnce).await {
+            error!("MQTT subscribe to {} failed | {}", command_topic, e);
+        }
+
+        if mqtt.discovery {
+            for (topic, payload) in discovery_messages(&mqtt, &managed.name) {
+                publish(&client, topic, true, payload).await;
+            }
+        }
+
+        let status = json!({ "state": managed.printer.state() }).to_string();
+        publish(&client, topic(&mqtt, &managed.name, "state"), true, status).await;
+        if let Some(job) = managed.job.status() {
+            let job = serde_json::to_string(&job).expect("Failed to serialize message into JSON");
+            publish(&client, topic(&mqtt, &managed.name, "job"), true, job).await;
+        }
+    }
+}
+
+/// Publish the connection state and job progress broadcast by the printers
+async fn forward_events(client: AsyncClient, mqtt: MqttConfig, state: Arc<ServerState>) {
+    let mut events = state.events.subscribe();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(count)) => {
+                warn!("MQTT bridge missed {} broadcast messages", count);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let name = match event.message_type.as_str() {
+            "PrinterStatus" => "state",
+            "JobStatus" => "job",
+            _ => continue,
+        };
+        if let Some(printer) = &event.printer {
+            publish(&client, topic(&mqtt, printer, name), true, event.message).await;
+        }
+    }
+}
+
+/// Query the temperatures and position of the connected printers
+async fn publish_telemetry(client: AsyncClient, mqtt: MqttConfig, state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(mqtt.telemetry_secs));
+    loop {
+        interval.tick().await;
+        for managed in &state.printers {
+            if !matches!(
+                managed.printer.state(),
+                PrinterState::Operational | PrinterState::Printing | PrinterState::Paused
+            ) {
+                continue;
+            }
+
+            if let Ok(response) = managed.printer.send_async("M105").await {
+                let temperatures = serde_json::to_string(&m105(response))
+                    .expect("Failed to serialize message into JSON");
+                let topic = topic(&mqtt, &managed.name, "temperatures");
+                publish(&client, topic, true, temperatures).await;
+            }
+            if let Ok(response) = managed.printer.send_async("M114").await {
+                let position = serde_json::to_string(&m114(response))
+                    .expect("Failed to serialize message into JSON");
+                let topic = topic(&mqtt, &managed.name, "position");
+                publish(&client, topic, true, position).await;
+            }
+        }
+    }
+}
+
+async fn publish(client: &AsyncClient, topic: String, retain: bool, payload: String) {
+    if let Err(e) = client
+        .publish(&topic, QoS::AtLeastOnce, retain, payload)
+        .await
+    {
+        error!("MQTT publish to {} failed | {}", topic, e);
+    }
+}
+
+/**
+ * Home Assistant discovery payloads of a printer, one sensor per value
+ * @param mqtt: &MqttConfig, topic and discovery prefixes
+ * @param printer: &str, name of the printer
+ * @return Vec<(String, String)>, retained config topics with their payload
+ */
+fn discovery_messages(mqtt: &MqttConfig, printer: &str) -> Vec<(String, String)> {
+    let node = node_id(printer);
+    // key, name, topic, value template, unit
+    let sensors = [
+        ("state", "State", "state", "{{ value_json.state }}", None),
+        (
+            "hotend",
+            "Hotend temperature",
+            "temperatures",
+            "{{ value_json.e0 }}",
+            Some("°C"),
+        ),
+        (
+            "hotend_target",
+            "Hotend target",
+            "temperatures",
+            "{{ value_json.e0_set }}",
+            Some("°C"),
+        ),
+        (
+            "bed",
+            "Bed temperature",
+            "temperatures",
+            "{{ value_json.bed }}",
+            Some("°C"),
+        ),
+        (
+            "bed_target",
+            "Bed target",
+            "temperatures",
+            "{{ value_json.bed_set }}",
+            Some("°C"),
+        ),
+        (
+            "job_progress",
+            "Job progress",
+            "job",
+            "{{ value_json.progress | round(1) }}",
+            Some("%"),
+        ),
+        (
+            "job_state",
+            "Job state",
+            "job",
+            "{{ value_json.state }}",
+            None,
+        ),
+    ];
+
+    sensors
+        .into_iter()
+        .map(|(key, name, state_topic, template, unit)| {
+            let mut payload = json!({
+                "name": name,
+                "unique_id": format!("{}_{}", node, key),
+                "object_id": format!("{}_{}", node, key),
+                "state_topic": topic(mqtt, printer, state_topic),
+                "value_template": template,
+                "availability_topic": availability_topic(mqtt),
+                "device": {
+                    "identifiers": [node],
+                    "name": printer,
+                    "manufacturer": "xcontroller",
+                },
+            });
+            if let Some(unit) = unit {
+                payload["unit_of_measurement"] = json!(unit);
+                payload["state_class"] = json!("measurement");
+                if unit == "°C" {
+                    payload["device_class"] = json!("temperature");
+                }
+            }
+
+            (
+                format!("{}/sensor/{}/{}/config", mqtt.discovery_prefix, node, key),
+                payload.to_string(),
+            )
+        })
+        .collect()
+}
+
+fn topic(mqtt: &MqttConfig, printer: &str, name: &str) -> String {
+    format!("{}/{}/{}", mqtt.topic_prefix, printer, name)
+}
+
+fn availability_topic(mqtt: &MqttConfig) -> String {
+    format!("{}/status", mqtt.topic_prefix)
+}
+
+/// Printer of a command topic, <topic_prefix>/<printer>/command
+fn command_printer<'a>(mqtt: &MqttConfig, topic: &'a str) -> Option<&'a str> {
+    topic
+        .strip_prefix(&mqtt.topic_prefix)?
+        .strip_prefix('/')?
+        .strip_suffix("/command")
+        .filter(|printer| !printer.is_empty() && !printer.contains('/'))
+}
+
+/// Home Assistant only accepts [a-zA-Z0-9_-] in the node id
+fn node_id(printer: &str) -> String {
+    let printer: String = printer
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("xcontroller_{}", printer)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::HistoryConfig;
+    use bytes::BytesMut;
+    use rumqttc::{ConnAck, ConnectReturnCode, PubAck, Publish, SubAck, SubscribeReasonCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_topics() {
+        let mqtt = MqttConfig::default();
+        assert_eq!(topic(&mqtt, "mk3", "job"), "xcontroller/mk3/job");
+        assert_eq!(
+            command_printer(&mqtt, "xcontroller/mk3/command"),
+            Some("mk3")
+        );
+        assert_eq!(command_printer(&mqtt, "xcontroller/mk3/job"), None);
+        assert_eq!(command_printer(&mqtt, "other/mk3/command"), None);
+        assert_eq!(node_id("Voron 2.4"), "xcontroller_Voron_2_4");
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let messages = discovery_messages(&MqttConfig::default(), "mk3");
+        let (topic, payload) = &messages[1];
+        assert_eq!(topic, "homeassistant/sensor/xcontroller_mk3/hotend/config");
+
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["state_topic"], "xcontroller/mk3/temperatures");
+        assert_eq!(payload["device_class"], "temperature");
+        assert_eq!(payload["availability_topic"], "xcontroller/status");
+    }
+
+    /// Packets sent by the bridge to a minimal in-process broker
+    async fn read_packet(stream: &mut TcpStream, buffer: &mut BytesMut) -> rumqttc::Packet {
+        loop {
+            match rumqttc::Packet::read(buffer, 1024 * 1024) {
+                Ok(packet) => return packet,
+                Err(rumqttc::Error::InsufficientBytes(_)) => {}
+                Err(e) => panic!("Invalid packet {:?}", e),
+            }
+            assert!(stream.read_buf(buffer).await.unwrap() > 0, "Bridge closed");
+        }
+    }
+
+    async fn write_packet(stream: &mut TcpStream, packet: rumqttc::Packet) {
+        let mut buffer = BytesMut::new();
+        packet.write(&mut buffer, 1024 * 1024).unwrap();
+        stream.write_all(&buffer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bridge_with_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let configuration = Config {
+            serial_port: "/dev/xcontroller-mqtt-test".to_string(),
+            mqtt: MqttConfig {
+                enabled: true,
+                host: "127.0.0.1".to_string(),
+                port: listener.local_addr().unwrap().port(),
+                ..MqttConfig::default()
+            },
+            history: HistoryConfig {
+                enabled: false,
+                ..HistoryConfig::default()
+            },
+            ..Config::default()
+        };
+        let state = Arc::new(ServerState::new(&configuration));
+        tokio::spawn(run_mqtt(configuration, state));
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = BytesMut::new();
+        let rumqttc::Packet::Connect(connect) = read_packet(&mut stream, &mut buffer).await else {
+            panic!("Expected CONNECT");
+        };
+        assert_eq!(connect.client_id, "xcontroller");
+        let ack = ConnAck::new(ConnectReturnCode::Success, false);
+        write_packet(&mut stream, rumqttc::Packet::ConnAck(ack)).await;
+
+        let mut published = Vec::new();
+        let mut subscribed = false;
+        let mut responses = Vec::new();
+        while responses.len() < 2 {
+            match read_packet(&mut stream, &mut buffer).await {
+                rumqttc::Packet::Publish(publish) => {
+                    let ack = PubAck::new(publish.pkid);
+                    write_packet(&mut stream, rumqttc::Packet::PubAck(ack)).await;
+                    if publish.topic == "xcontroller/default/response" {
+                        responses.push(String::from_utf8_lossy(&publish.payload).to_string());
+                    }
+                    published.push(publish.topic);
+                }
+                rumqttc::Packet::Subscribe(subscribe) => {
+                    assert_eq!(subscribe.filters[0].path, "xcontroller/default/command");
+                    let codes = vec![SubscribeReasonCode::Success(QoS::AtLeastOnce)];
+                    let ack = SubAck::new(subscribe.pkid, codes);
+                    write_packet(&mut stream, rumqttc::Packet::SubAck(ack)).await;
+                    subscribed = true;
+
+                    // Invalid G-code is refused, valid G-code needs a connected printer
+                    for command in ["rm -rf /", "M105"] {
+                        let topic = "xcontroller/default/command";
+                        let publish = Publish::new(topic, QoS::AtMostOnce, command);
+                        write_packet(&mut stream, rumqttc::Packet::Publish(publish)).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        assert!(subscribed);
+        assert!(published.contains(&"xcontroller/status".to_string()));
+        assert!(published.contains(&"xcontroller/default/state".to_string()));
+        assert!(published
+            .contains(&"homeassistant/sensor/xcontroller_default/hotend/config".to_string()));
+        assert!(responses.iter().all(|response| response.contains("error")));
+        assert!(responses
+            .iter()
+            .any(|response| response.contains("Disconnected") || response.contains("Connecting")));
+    }
+}
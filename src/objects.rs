@@ -0,0 +1,488 @@
+use std::io::{BufRead, Error, ErrorKind};
+
+use crate::gcode::parse_gcode;
+use crate::structs::{GcodeState, ObjectBounds, ObjectList, PrintObject};
+
+// Objects created by M486 T, a bogus count must not fill the memory
+static MAX_OBJECTS: i64 = 4096;
+
+/// How the slicer labelled the objects, the first one found is used
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Labels {
+    /// M486 T, S and A, Marlin
+    M486,
+    /// EXCLUDE_OBJECT_DEFINE, START and END, Klipper
+    ExcludeObject,
+    /// "; printing object" comments, PrusaSlicer and OrcaSlicer
+    Comment,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum LabelEvent {
+    /// Number of objects announced by M486 T
+    Count(usize),
+    Start {
+        index: Option<usize>,
+        name: Option<String>,
+    },
+    /// Name given to the current object by M486 A
+    Name(String),
+    End,
+    Define {
+        name: String,
+        polygon: Vec<(f64, f64)>,
+    },
+}
+
+/**
+ * Objects of a streamed file and the one being printed
+ * Moves of a cancelled object are not sent, the other commands are
+ */
+#[derive(Debug, Clone, Default)]
+pub struct JobObjects {
+    labels: Option<Labels>,
+    objects: Vec<PrintObject>,
+    /// Position in objects of the object being printed
+    current: Option<usize>,
+}
+
+impl JobObjects {
+    fn with_labels(labels: Labels) -> Self {
+        JobObjects {
+            labels: Some(labels),
+            ..JobObjects::default()
+        }
+    }
+
+    /**
+     * Read the object labels of a file and the area covered by their extrusions
+     * @param reader: R, file from its beginning
+     * @return Result<JobObjects, Error>, without objects when the file is not labelled
+     */
+    pub fn scan<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut found = [
+            JobObjects::with_labels(Labels::M486),
+            JobObjects::with_labels(Labels::ExcludeObject),
+            JobObjects::with_labels(Labels::Comment),
+        ];
+        let mut gcode = GcodeState::default();
+
+        for line in reader.split(b'\n') {
+            let line = String::from_utf8_lossy(&line?).to_string();
+            if let Some((labels, event)) = label(&line) {
+                if let Some(objects) = found
+                    .iter_mut()
+                    .find(|objects| objects.labels == Some(labels))
+                {
+                    objects.apply(event);
+                }
+                continue;
+            }
+
+            let (x, y, extruded) = (gcode.x, gcode.y, gcode.filament_used);
+            gcode.track(line.split(';').next().unwrap_or("").trim());
+            if gcode.filament_used <= extruded {
+                continue;
+            }
+            for objects in found.iter_mut() {
+                if let Some(object) = objects.current.map(|index| &mut objects.objects[index]) {
+                    extend(&mut object.bounds, x, y);
+                    extend(&mut object.bounds, gcode.x, gcode.y);
+                }
+            }
+        }
+
+        let mut objects = found
+            .into_iter()
+            .find(|objects| !objects.objects.is_empty())
+            .unwrap_or_default();
+        objects.objects.sort_by_key(|object| object.id);
+        objects.current = None;
+        Ok(objects)
+    }
+
+    pub fn list(&self) -> ObjectList {
+        ObjectList {
+            objects: self.objects.clone(),
+            current: self.current(),
+        }
+    }
+
+    /**
+     * Follow the labels of a streamed line
+     * @param line: &str, line of the file, comments included
+     * @return bool, true when the line is not sent, a move of a cancelled object or a Klipper label
+     */
+    pub fn follow(&mut self, line: &str) -> bool {
+        if let Some((labels, event)) = label(line) {
+            if Some(labels) == self.labels {
+                self.apply(event);
+            }
+            // Marlin does not know the Klipper labels
+            return labels == Labels::ExcludeObject;
+        }
+
+        let cancelled = self
+            .current
+            .is_some_and(|index| self.objects[index].cancelled);
+        cancelled && is_move(line)
+    }
+
+    /**
+     * Skip an object for the rest of the job
+     * @param id: Option<usize>, the current object when None
+     * @return Result<PrintObject, Error>, NotFound for an unknown id or outside of an object
+     */
+    pub fn cancel(&mut self, id: Option<usize>) -> Result<PrintObject, Error> {
+        let index = match id {
+            Some(id) => self
+                .objects
+                .iter()
+                .position(|object| object.id == id)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Unknown object {}", id)))?,
+            None => self
+                .current
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "No object is being printed"))?,
+        };
+
+        let object = &mut self.objects[index];
+        object.cancelled = true;
+        Ok(object.clone())
+    }
+
+    /// Ids of the cancelled objects, stored in the checkpoints
+    pub fn cancelled(&self) -> Vec<usize> {
+        self.objects
+            .iter()
+            .filter(|object| object.cancelled)
+            .map(|object| object.id)
+            .collect()
+    }
+
+    /// Id of the object being printed
+    pub fn current(&self) -> Option<usize> {
+        self.current.map(|index| self.objects[index].id)
+    }
+
+    /**
+     * Restore the objects of a job interrupted in the middle of the file
+     * @param cancelled: &[usize], ids of the objects skipped before
+     * @param current: Option<usize>, id of the object printed at the line where the job continues
+     */
+    pub fn restore(&mut self, cancelled: &[usize], current: Option<usize>) {
+        for object in &mut self.objects {
+            object.cancelled = cancelled.contains(&object.id);
+        }
+        self.current =
+            current.and_then(|id| self.objects.iter().position(|object| object.id == id));
+    }
+
+    fn apply(&mut self, event: LabelEvent) {
+        match event {
+            LabelEvent::Count(count) => {
+                for id in 0..count {
+                    self.object(Some(id), None);
+                }
+            }
+            LabelEvent::Start { index, name } => {
+                self.current = Some(self.object(index, name));
+            }
+            LabelEvent::Name(name) => {
+                if let Some(index) = self.current {
+                    self.objects[index].name = name;
+                }
+            }
+            LabelEvent::End => self.current = None,
+            LabelEvent::Define { name, polygon } => {
+                let index = self.object(None, Some(name));
+                for (x, y) in polygon {
+                    extend(&mut self.objects[index].bounds, x, y);
+                }
+            }
+        }
+    }
+
+    /**
+     * Find an object by M486 index or by name, added on its first label
+     * @return usize, position in objects
+     */
+    fn object(&mut self, index: Option<usize>, name: Option<String>) -> usize {
+        let position = match (index, &name) {
+            (Some(id), _) => self.objects.iter().position(|object| object.id == id),
+            (None, Some(name)) => self.objects.iter().position(|object| &object.name == name),
+            (None, None) => None,
+        };
+
+        match position {
+            Some(position) => {
+                if let (Some(name), Some(_)) = (name, index) {
+                    self.objects[position].name = name;
+                }
+                position
+            }
+            None => {
+                let id = index.unwrap_or(self.objects.len());
+                self.objects.push(PrintObject {
+                    id,
+                    name: name.unwrap_or_else(|| format!("Object {}", id)),
+                    bounds: None,
+                    cancelled: false,
+                });
+                self.objects.len() - 1
+            }
+        }
+    }
+}
+
+/**
+ * Commands bringing the printer to where the file expects it after skipped moves
+ * @param gcode: &GcodeState, modes and position followed from the file, skipped moves included
+ * @return Vec<String>, sent before the first move after a cancelled object
+ */
+pub fn rejoin_commands(gcode: &GcodeState) -> Vec<String> {
+    let feedrate = gcode
+        .feedrate
+        .map(|feedrate| format!(" F{}", feedrate))
+        .unwrap_or_default();
+    let mut commands = vec![
+        "G90".to_string(),
+        format!("G0 X{} Y{} Z{}{}", gcode.x, gcode.y, gcode.z, feedrate),
+    ];
+
+    // G90 and G91 also set the extrusion mode, M82 and M83 come after them
+    if gcode.relative_moves {
+        commands.push("G91".to_string());
+    }
+    if gcode.relative_extrusion {
+        commands.push("M83".to_string());
+    } else {
+        commands.push("M82".to_string());
+        commands.push(format!("G92 E{}", gcode.e));
+    }
+    commands
+}
+
+/**
+ * Object label of a line
+ * @param line: &str, line of the file, comments included
+ * @return Option<(Labels, LabelEvent)>, None for the other lines
+ */
+fn label(line: &str) -> Option<(Labels, LabelEvent)> {
+    let line = line.trim();
+
+    if let Some(comment) = line.strip_prefix(';') {
+        let comment = comment.trim();
+        if let Some(name) = comment.strip_prefix("printing object ") {
+            return Some((
+                Labels::Comment,
+                LabelEvent::Start {
+                    index: None,
+                    name: Some(name.trim().to_string()),
+                },
+            ));
+        }
+        return comment
+            .starts_with("stop printing object")
+            .then_some((Labels::Comment, LabelEvent::End));
+    }
+
+    let code = line.split(';').next().unwrap_or("").trim();
+    let upper = code.to_uppercase();
+    if let Some(arguments) = upper.strip_prefix("EXCLUDE_OBJECT_") {
+        let name = klipper_argument(code, "NAME").unwrap_or_default();
+        let event = if arguments.starts_with("DEFINE") {
+            let polygon = klipper_argument(code, "POLYGON")
+                .map(|polygon| polygon_points(&polygon))
+                .unwrap_or_default();
+            LabelEvent::Define { name, polygon }
+        } else if arguments.starts_with("START") {
+            LabelEvent::Start {
+                index: None,
+                name: Some(name),
+            }
+        } else if arguments.starts_with("END") {
+            LabelEvent::End
+        } else {
+            return None;
+        };
+        return Some((Labels::ExcludeObject, event));
+    }
+
+    if upper.split_whitespace().next() != Some("M486") {
+        return None;
+    }
+    // Names are free text, they can not go through the G-code parser
+    let arguments = code[4..].trim();
+    let (arguments, name) = match arguments.find(['A', 'a']) {
+        Some(position) => (
+            &arguments[..position],
+            Some(
+                arguments[position + 1..]
+                    .trim()
+                    .trim_matches('"')
+                    .to_string(),
+            ),
+        ),
+        None => (arguments, None),
+    };
+    let value = |letter: char| {
+        arguments
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix([letter, letter.to_ascii_lowercase()]))
+            .and_then(|value| value.parse::<i64>().ok())
+    };
+
+    let event = match (value('T'), value('S'), name) {
+        (Some(count), _, _) => LabelEvent::Count(count.clamp(0, MAX_OBJECTS) as usize),
+        (None, Some(index), _) if index < 0 => LabelEvent::End,
+        (None, Some(index), name) => LabelEvent::Start {
+            index: Some(index as usize),
+            name,
+        },
+        (None, None, Some(name)) => LabelEvent::Name(name),
+        // P, U and C are cancellations sent by the host
+        (None, None, None) => return None,
+    };
+    Some((Labels::M486, event))
+}
+
+/// Value of NAME=value in a Klipper command, quotes removed
+fn klipper_argument(code: &str, key: &str) -> Option<String> {
+    let upper = code.to_uppercase();
+    let start = upper.find(&format!(" {}=", key))? + key.len() + 2;
+    let rest = &code[start..];
+    let value = if rest.starts_with('[') {
+        // Polygons contain no space in the slicer output, only up to the closing bracket matters
+        let end = rest.rfind(']').map_or(rest.len(), |end| end + 1);
+        &rest[..end]
+    } else {
+        rest.split_whitespace().next().unwrap_or("")
+    };
+    Some(value.trim_matches(['"', '\'']).to_string())
+}
+
+/// Points of a POLYGON=[[x,y],[x,y]] argument
+fn polygon_points(polygon: &str) -> Vec<(f64, f64)> {
+    polygon
+        .split(']')
+        .filter_map(|point| {
+            let (x, y) = point.trim_start_matches([',', '[']).split_once(',')?;
+            Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// G0 to G3, the position has to be right before they are sent
+pub fn is_move(line: &str) -> bool {
+    parse_gcode(line.split(';').next().unwrap_or(""))
+        .is_ok_and(|command| command.letter == 'G' && command.number <= 3)
+}
+
+fn extend(bounds: &mut Option<ObjectBounds>, x: f64, y: f64) {
+    let bounds = bounds.get_or_insert(ObjectBounds {
+        min_x: x,
+        min_y: y,
+        max_x: x,
+        max_y: y,
+    });
+    bounds.min_x = bounds.min_x.min(x);
+    bounds.min_y = bounds.min_y.min(y);
+    bounds.max_x = bounds.max_x.max(x);
+    bounds.max_y = bounds.max_y.max(y);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    static LABELLED: &str = "M486 T2
+G1 X0 Y0 Z0.2 F3000
+M486 S0 A\"cube\"
+; printing object cube id:0 copy 0
+G1 X10 Y10 E1
+G1 X20 Y10 E2
+; stop printing object cube id:0 copy 0
+M486 S1
+M486 A\"cylinder\"
+G1 X50 Y50
+G1 X60 Y55 E3
+M486 S-1
+";
+
+    #[test]
+    fn test_scan() {
+        let objects = JobObjects::scan(Cursor::new(LABELLED)).unwrap().list();
+        assert_eq!(objects.objects.len(), 2);
+        assert_eq!(objects.objects[0].name, "cube");
+        assert_eq!(
+            objects.objects[0].bounds,
+            Some(ObjectBounds {
+                min_x: 0.0,
+                min_y: 0.0,
+                max_x: 20.0,
+                max_y: 10.0,
+            })
+        );
+        assert_eq!(objects.objects[1].name, "cylinder");
+        assert_eq!(objects.objects[1].bounds.unwrap().min_x, 50.0);
+
+        let klipper = "EXCLUDE_OBJECT_DEFINE NAME=part_1 CENTER=5,5 POLYGON=[[0,0],[10,0],[10,12]]
+EXCLUDE_OBJECT_START NAME=part_1
+G1 X4 Y4 E1
+EXCLUDE_OBJECT_END NAME=part_1
+";
+        let objects = JobObjects::scan(Cursor::new(klipper)).unwrap().list();
+        assert_eq!(objects.objects[0].name, "part_1");
+        assert_eq!(objects.objects[0].bounds.unwrap().max_y, 12.0);
+
+        let comments = "; printing object a\nG1 X1 E1\n; stop printing object a\n";
+        let objects = JobObjects::scan(Cursor::new(comments)).unwrap().list();
+        assert_eq!(objects.objects[0].id, 0);
+        assert_eq!(objects.objects[0].name, "a");
+
+        let objects = JobObjects::scan(Cursor::new("M486 T4000000000\n")).unwrap();
+        assert_eq!(objects.list().objects.len(), MAX_OBJECTS as usize);
+    }
+
+    #[test]
+    fn test_follow() {
+        let mut objects = JobObjects::scan(Cursor::new(LABELLED)).unwrap();
+        assert_eq!(
+            objects.cancel(None).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        objects.cancel(Some(1)).unwrap();
+        assert_eq!(objects.cancelled(), vec![1]);
+
+        let skipped: Vec<&str> = LABELLED
+            .lines()
+            .filter(|line| objects.follow(line))
+            .collect();
+        assert_eq!(skipped, vec!["G1 X50 Y50", "G1 X60 Y55 E3"]);
+        assert_eq!(objects.list().current, None);
+        assert!(objects.follow("EXCLUDE_OBJECT_END NAME=part_1"));
+
+        // A job resumed in the middle of the cylinder keeps skipping it
+        let mut resumed = JobObjects::scan(Cursor::new(LABELLED)).unwrap();
+        resumed.restore(&[1], Some(1));
+        assert_eq!(resumed.current(), Some(1));
+        assert!(resumed.follow("G1 X60 Y55 E3"));
+    }
+
+    #[test]
+    fn test_rejoin_commands() {
+        let gcode = GcodeState {
+            x: 60.0,
+            y: 55.0,
+            z: 0.2,
+            e: 3.0,
+            feedrate: Some(1800.0),
+            ..GcodeState::default()
+        };
+        assert_eq!(
+            rejoin_commands(&gcode),
+            vec!["G90", "G0 X60 Y55 Z0.2 F1800", "M82", "G92 E3"]
+        );
+    }
+}
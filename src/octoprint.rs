@@ -0,0 +1,566 @@
+use axum::extract::{Multipart, Path, RawQuery, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::get;
+use axum::{Json, Router};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::auth::{request_token, Role};
+use crate::files::{file_path, list_files};
+use crate::jobs::JobFile;
+use crate::parser::m105;
+use crate::rest::{authorize_token, check_writable, store_upload, ApiError, ApiResult, ApiState};
+use crate::state::ManagedPrinter;
+use crate::structs::{
+    FileInfo, JobState, OctoPrintFile, OctoPrintFiles, OctoPrintFlags, OctoPrintJob,
+    OctoPrintJobCommand, OctoPrintJobInfo, OctoPrintPrinter, OctoPrintProgress, OctoPrintState,
+    OctoPrintTemperature, OctoPrintUpload, OctoPrintVersion, PrinterState,
+};
+
+// Version announced to the slicers, the endpoints follow its API
+static OCTOPRINT_VERSION: &str = "1.10.0";
+static ORIGIN: &str = "local";
+
+/**
+ * Subset of the OctoPrint API used by PrusaSlicer, Cura and OrcaSlicer
+ * The routes are nested under /printers/<name> to reach a printer when several are configured
+ */
+pub fn octoprint_routes() -> Router<ApiState> {
+    Router::new()
+        .route("/api/version", get(version))
+        .route("/api/files/local", get(local_files).post(upload))
+        .route("/api/job", get(job).post(job_command))
+        .route("/api/printer", get(printer))
+}
+
+async fn version(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> ApiResult<OctoPrintVersion> {
+    authorize_token(&api, api_key(&headers, query.as_deref()), Role::Viewer)?;
+    Ok(Json(OctoPrintVersion {
+        api: "0.1".to_string(),
+        server: OCTOPRINT_VERSION.to_string(),
+        text: format!(
+            "OctoPrint {} (xcontroller {})",
+            OCTOPRINT_VERSION,
+            env!("CARGO_PKG_VERSION")
+        ),
+    }))
+}
+
+async fn local_files(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> ApiResult<OctoPrintFiles> {
+    authorize_token(&api, api_key(&headers, query.as_deref()), Role::Viewer)?;
+    let files = list_files(&api.configuration.jobs.files_dir)?;
+    Ok(Json(OctoPrintFiles {
+        files: files.iter().map(octoprint_file).collect(),
+    }))
+}
+
+/// Multipart upload with the fields file, select and print, the form fields can come in any order
+async fn upload(
+    State(api): State<ApiState>,
+    printer: Option<Path<String>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<OctoPrintUpload>), ApiError> {
+    let (user, _) = authorize_token(&api, api_key(&headers, query.as_deref()), Role::Operator)?;
+
+    let mut stored = None;
+    let mut select = false;
+    let mut print = false;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?
+    {
+        match field.name() {
+            Some("file") => {
+                let name = field
+                    .file_name()
+                    .map(|name| name.to_string())
+                    .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "file has no name"))?;
+                stored = Some(store_upload(&api.configuration.jobs.files_dir, &name, field).await?);
+            }
+            Some("select") => select = is_true(&field_text(field).await?),
+            Some("print") => print = is_true(&field_text(field).await?),
+            // The library has no folders, the path sent by the slicers is ignored
+            _ => {}
+        }
+    }
+
+    let stored =
+        stored.ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "No file in the form"))?;
+
+    if select || print {
+        let target = api.state.printer(printer.as_deref().map(String::as_str))?;
+        lock(&api.selected).insert(target.name.clone(), stored.name.clone());
+        if print {
+            start(&api, target, &stored.name, &user).await?;
+        }
+    }
+
+    let files = BTreeMap::from([(ORIGIN.to_string(), octoprint_file(&stored))]);
+    Ok((
+        StatusCode::CREATED,
+        Json(OctoPrintUpload { files, done: true }),
+    ))
+}
+
+async fn job(
+    State(api): State<ApiState>,
+    printer: Option<Path<String>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> ApiResult<OctoPrintJob> {
+    authorize_token(&api, api_key(&headers, query.as_deref()), Role::Viewer)?;
+    let target = api.state.printer(printer.as_deref().map(String::as_str))?;
+    let selected = lock(&api.selected).get(&target.name).cloned();
+
+    // A finished job is shown until another file is selected
+    let current = target.job.status().filter(|job| {
+        matches!(job.state, JobState::Printing | JobState::Paused)
+            || selected.as_ref().is_none_or(|name| *name == job.file)
+    });
+    let name = current.as_ref().map(|job| job.file.clone()).or(selected);
+
+    let file = name
+        .and_then(|name| {
+            list_files(&api.configuration.jobs.files_dir)
+                .ok()?
+                .into_iter()
+                .find(|file| file.name == name)
+        })
+        .map(|file| octoprint_file(&file))
+        .unwrap_or_default();
+
+    let progress = current
+        .as_ref()
+        .map(|job| OctoPrintProgress {
+            completion: Some(job.progress),
+            filepos: Some(job.position),
+            print_time: Some(job.finished.unwrap_or_else(now).saturating_sub(job.started)),
+            print_time_left: None,
+        })
+        .unwrap_or_default();
+
+    Ok(Json(OctoPrintJob {
+        job: OctoPrintJobInfo {
+            file,
+            estimated_print_time: None,
+        },
+        progress,
+        state: state_text(target.printer.state()).to_string(),
+        error: current.and_then(|job| job.error),
+    }))
+}
+
+async fn job_command(
+    State(api): State<ApiState>,
+    printer: Option<Path<String>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Json(request): Json<OctoPrintJobCommand>,
+) -> Result<StatusCode, ApiError> {
+    let (user, _) = authorize_token(&api, api_key(&headers, query.as_deref()), Role::Operator)?;
+    let target = api.state.printer(printer.as_deref().map(String::as_str))?;
+    check_writable(target)?;
+
+    match request.command.as_str() {
+        "start" => {
+            let selected = lock(&api.selected).get(&target.name).cloned();
+            let name = selected
+                .ok_or_else(|| ApiError::new(StatusCode::CONFLICT, "No file is selected"))?;
+            start(&api, target, &name, &user).await?;
+        }
+        "cancel" => {
+            target.job.cancel()?;
+        }
+        "pause" => {
+            let paused = target
+                .job
+                .status()
+                .is_some_and(|job| job.state == JobState::Paused);
+            match (request.action.as_deref().unwrap_or("toggle"), paused) {
+                ("pause", _) | ("toggle", false) => target.job.pause(&target.printer)?,
+                ("resume", _) | ("toggle", true) => target.job.resume(&target.printer)?,
+                (action, _) => {
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        format!("Unknown pause action {}", action),
+                    ))
+                }
+            };
+        }
+        command => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Unsupported job command {}", command),
+            ))
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn printer(
+    State(api): State<ApiState>,
+    printer: Option<Path<String>>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> ApiResult<OctoPrintPrinter> {
+    authorize_token(&api, api_key(&headers, query.as_deref()), Role::Viewer)?;
+    let target = api.state.printer(printer.as_deref().map(String::as_str))?;
+
+    let state = target.printer.state();
+    if matches!(
+        state,
+        PrinterState::Disconnected | PrinterState::Connecting | PrinterState::Handshaking
+    ) {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Printer is not operational",
+        ));
+    }
+
+    let temperatures = m105(target.printer.send_async("M105").await?);
+    let mut temperature = BTreeMap::new();
+    let tools = [
+        (temperatures.e0, temperatures.e0_set),
+        (temperatures.e1, temperatures.e1_set),
+        (temperatures.e2, temperatures.e2_set),
+        (temperatures.e3, temperatures.e3_set),
+    ];
+    for (index, (actual, set)) in tools.into_iter().enumerate() {
+        // tool0 is always reported, the other tools only when they heat
+        if index == 0 || actual > 0 || set > 0 {
+            temperature.insert(format!("tool{}", index), reading(actual, set));
+        }
+    }
+    temperature.insert(
+        "bed".to_string(),
+        reading(temperatures.bed, temperatures.bed_set),
+    );
+
+    Ok(Json(OctoPrintPrinter {
+        temperature,
+        state: OctoPrintState {
+            text: state_text(state).to_string(),
+            flags: state_flags(state),
+        },
+    }))
+}
+
+/// Start a file of the library, the same checks as a job started through the REST API
+async fn start(
+    api: &ApiState,
+    target: &ManagedPrinter,
+    name: &str,
+    user: &str,
+) -> Result<(), ApiError> {
+    check_writable(target)?;
+    let jobs = &api.configuration.jobs;
+    let job_file = JobFile::read(file_path(&jobs.files_dir, name)?).await?;
+    target.job.start(
+        &target.printer,
+        job_file,
+        jobs.cancel_commands.clone(),
+        user,
+    )?;
+    info!("OctoPrint client started {} on {}", name, target.name);
+    Ok(())
+}
+
+/**
+ * API key of an OctoPrint client
+ * Slicers send the "X-Api-Key" header, the "apikey" query parameter and the usual tokens are accepted too
+ * @param headers: &HeaderMap, request headers
+ * @param query: Option<&str>, query string of the request
+ * @return Option<String>, token if present
+ */
+fn api_key(headers: &HeaderMap, query: Option<&str>) -> Option<String> {
+    let header = headers
+        .get("X-Api-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(|key| key.trim().to_string())
+        .filter(|key| !key.is_empty());
+
+    header
+        .or_else(|| {
+            query?
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("apikey=").map(|key| key.to_string()))
+        })
+        .or_else(|| request_token(headers, query))
+}
+
+async fn field_text(field: axum::extract::multipart::Field<'_>) -> Result<String, ApiError> {
+    field.text().await.map_err(|e| {
+        warn!("Invalid OctoPrint form field | {}", e);
+        ApiError::new(StatusCode::BAD_REQUEST, e)
+    })
+}
+
+fn is_true(value: &str) -> bool {
+    matches!(value.trim().to_lowercase().as_str(), "true" | "1" | "yes")
+}
+
+fn octoprint_file(file: &FileInfo) -> OctoPrintFile {
+    OctoPrintFile {
+        name: Some(file.name.clone()),
+        display: Some(file.name.clone()),
+        path: Some(file.name.clone()),
+        origin: Some(ORIGIN.to_string()),
+        size: Some(file.size),
+        date: Some(file.modified),
+    }
+}
+
+fn reading(actual: u8, target: u8) -> OctoPrintTemperature {
+    OctoPrintTemperature {
+        actual: f64::from(actual),
+        target: f64::from(target),
+        offset: 0.0,
+    }
+}
+
+/// State text shown by the slicers
+fn state_text(state: PrinterState) -> &'static str {
+    match state {
+        PrinterState::Disconnected => "Offline",
+        PrinterState::Connecting | PrinterState::Handshaking => "Connecting",
+        PrinterState::Operational => "Operational",
+        PrinterState::Printing => "Printing",
+        PrinterState::Paused => "Paused",
+        PrinterState::Error | PrinterState::Halted => "Error",
+    }
+}
+
+fn state_flags(state: PrinterState) -> OctoPrintFlags {
+    let operational = matches!(
+        state,
+        PrinterState::Operational | PrinterState::Printing | PrinterState::Paused
+    );
+    let error = matches!(state, PrinterState::Error | PrinterState::Halted);
+
+    OctoPrintFlags {
+        operational,
+        paused: state == PrinterState::Paused,
+        printing: state == PrinterState::Printing,
+        error,
+        ready: state == PrinterState::Operational,
+        closed_or_error: error || state == PrinterState::Disconnected,
+        ..OctoPrintFlags::default()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::printer::{OkPort, Printer};
+    use crate::state::ServerState;
+    use crate::structs::{Config, HistoryConfig, JobsConfig, RecoveryConfig};
+    use axum::body::Body;
+    use axum::extract::{FromRequest, Request};
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::HeaderValue;
+    use axum::response::IntoResponse;
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// API over a printer acknowledging every line, with its library in a temporary directory
+    fn api(name: &str) -> (ApiState, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("xcontroller_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let configuration = Config {
+            jobs: JobsConfig {
+                files_dir: dir.to_string_lossy().to_string(),
+                ..JobsConfig::default()
+            },
+            history: HistoryConfig {
+                enabled: false,
+                ..HistoryConfig::default()
+            },
+            recovery: RecoveryConfig {
+                enabled: false,
+                ..RecoveryConfig::default()
+            },
+            ..Config::default()
+        };
+        let mut state = ServerState::new(&configuration);
+        state.printers[0].printer = Printer::serving(OkPort::new(Arc::new(AtomicUsize::new(0))));
+        let api = ApiState {
+            configuration: Arc::new(configuration),
+            state: Arc::new(state),
+            selected: Arc::default(),
+        };
+        (api, dir)
+    }
+
+    async fn command(api: &ApiState, command: &str, action: Option<&str>) -> StatusCode {
+        let request = OctoPrintJobCommand {
+            command: command.to_string(),
+            action: action.map(str::to_string),
+        };
+        match job_command(
+            State(api.clone()),
+            None,
+            HeaderMap::new(),
+            RawQuery(None),
+            Json(request),
+        )
+        .await
+        {
+            Ok(status) => status,
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    async fn wait_finished(target: &ManagedPrinter) -> JobState {
+        loop {
+            let job = target.job.status().unwrap();
+            if job.finished.is_some() {
+                return job.state;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_print() {
+        let (api, dir) = api("octoprint_upload");
+        let boundary = "xcontroller";
+        let body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"print\"\r\n\r\ntrue\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"cube.gcode\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n{gcode}\r\n--{b}--\r\n",
+            b = boundary,
+            gcode = "G1 X1 Y1\n".repeat(2000),
+        );
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/files/local")
+            .header(
+                CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", boundary),
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let multipart = Multipart::from_request(request, &()).await.unwrap();
+
+        let (status, Json(answer)) = upload(
+            State(api.clone()),
+            None,
+            HeaderMap::new(),
+            RawQuery(None),
+            multipart,
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(answer.files[ORIGIN].name.as_deref(), Some("cube.gcode"));
+        assert!(dir.join("cube.gcode").is_file());
+
+        let target = &api.state.printers[0];
+        let job = target.job.status().unwrap();
+        assert_eq!(job.file, "cube.gcode");
+        assert_eq!(job.started_by, "anonymous");
+        assert_eq!(
+            lock(&api.selected).get(&target.name).map(String::as_str),
+            Some("cube.gcode")
+        );
+
+        assert_eq!(command(&api, "cancel", None).await, StatusCode::NO_CONTENT);
+        assert_eq!(wait_finished(target).await, JobState::Cancelled);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_job_command() {
+        let (api, dir) = api("octoprint_job");
+        let target = &api.state.printers[0];
+        std::fs::write(dir.join("clip.gcode"), "G1 X1 Y1\n".repeat(2000)).unwrap();
+
+        assert_eq!(command(&api, "start", None).await, StatusCode::CONFLICT);
+        lock(&api.selected).insert(target.name.clone(), "clip.gcode".to_string());
+        assert_eq!(command(&api, "start", None).await, StatusCode::NO_CONTENT);
+        assert_eq!(target.job.status().unwrap().file, "clip.gcode");
+        // A second start while printing is refused
+        assert_eq!(command(&api, "start", None).await, StatusCode::CONFLICT);
+
+        assert_eq!(command(&api, "pause", None).await, StatusCode::NO_CONTENT);
+        assert_eq!(target.job.status().unwrap().state, JobState::Paused);
+        assert_eq!(target.printer.state(), PrinterState::Paused);
+        assert_eq!(
+            command(&api, "pause", Some("resume")).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(target.job.status().unwrap().state, JobState::Printing);
+        assert_eq!(
+            command(&api, "pause", Some("later")).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            command(&api, "restart", None).await,
+            StatusCode::BAD_REQUEST
+        );
+
+        assert_eq!(command(&api, "cancel", None).await, StatusCode::NO_CONTENT);
+        assert_eq!(wait_finished(target).await, JobState::Cancelled);
+        assert_eq!(command(&api, "cancel", None).await, StatusCode::NOT_FOUND);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_api_key() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            api_key(&headers, Some("apikey=abc")),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            api_key(&headers, Some("token=def")),
+            Some("def".to_string())
+        );
+        assert_eq!(api_key(&headers, None), None);
+
+        headers.insert("X-Api-Key", HeaderValue::from_static("xyz"));
+        assert_eq!(
+            api_key(&headers, Some("apikey=abc")),
+            Some("xyz".to_string())
+        );
+    }
+
+    #[test]
+    fn test_state_flags() {
+        let flags = state_flags(PrinterState::Printing);
+        assert!(flags.operational && flags.printing && !flags.ready);
+
+        let flags = state_flags(PrinterState::Halted);
+        assert!(flags.error && flags.closed_or_error && !flags.operational);
+        assert_eq!(state_text(PrinterState::Handshaking), "Connecting");
+        assert!(is_true("True") && !is_true("false"));
+    }
+}
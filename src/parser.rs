@@ -1,7 +1,9 @@
 use log::debug;
 use regex::Regex;
 
-use crate::structs::{AxePositions, EndstopStatus, PrinterInfo, Temperatures};
+use crate::structs::{
+    AxePositions, BedMesh, EndstopStatus, PrinterInfo, PrinterSettings, SettingParams, Temperatures,
+};
 
 /**
  *  List SD card
@@ -180,11 +182,13 @@ pub fn m115(message: String) -> PrinterInfo {
 
             printer_info.firmware_name = fw_version[0].to_string();
             printer_info.firmware_version = fw_version[1].to_string();
-        } else if part.contains("Cap:") {
-            let cap_parts: Vec<&str> = part.split(":").collect();
+        } else if let Some(cap) = part.trim().strip_prefix("Cap:") {
+            let cap_parts: Vec<&str> = cap.split(":").collect();
+            if cap_parts.len() < 2 {
+                continue;
+            }
             match cap_parts[0] {
                 "SERIAL_XON_XOFF" => {
-                    println!("{}", printer_info.serial_xon_xoff);
                     printer_info.serial_xon_xoff = cap_parts[1].parse().unwrap_or(0)
                 }
                 "EEPROM" => printer_info.eeprom = cap_parts[1].parse().unwrap_or(0),
@@ -194,10 +198,10 @@ pub fn m115(message: String) -> PrinterInfo {
                     printer_info.autoreport_temp = cap_parts[1].parse().unwrap_or(0)
                 }
                 "PROGRESS" => printer_info.progress = cap_parts[1].parse().unwrap_or(0),
-                "print_job" => printer_info.print_job = cap_parts[1].parse().unwrap_or(0),
-                "autolevel" => printer_info.autolevel = cap_parts[1].parse().unwrap_or(0),
+                "PRINT_JOB" => printer_info.print_job = cap_parts[1].parse().unwrap_or(0),
+                "AUTOLEVEL" => printer_info.autolevel = cap_parts[1].parse().unwrap_or(0),
                 "RUNOUT" => printer_info.runout = cap_parts[1].parse().unwrap_or(0),
-                "z_probe" => printer_info.z_probe = cap_parts[1].parse().unwrap_or(0),
+                "Z_PROBE" => printer_info.z_probe = cap_parts[1].parse().unwrap_or(0),
                 "LEVELING_DATA" => printer_info.leveling_data = cap_parts[1].parse().unwrap_or(0),
                 "BUILD_PERCENT" => printer_info.build_percent = cap_parts[1].parse().unwrap_or(0),
                 "SOFTWARE_POWER" => printer_info.software_power = cap_parts[1].parse().unwrap_or(0),
@@ -272,6 +276,187 @@ pub fn m119(message: String) -> EndstopStatus {
     endstop_status
 }
 
+/**
+ * Get the settings reported by the firmware
+ * Commands reporting several entries are keyed with their selector, e.g. "M145 S0"
+ * @param message: String, return message from firmware
+ * @return PrinterSettings, parameters of every setting command
+ */
+pub fn m503(message: String) -> PrinterSettings {
+    let mut settings = PrinterSettings::default();
+
+    for line in message.lines() {
+        let line = line.trim().trim_start_matches("echo:").trim();
+        let mut tokens = line.split_whitespace();
+
+        let command = match tokens.next() {
+            Some(command) if is_setting_command(command) => command,
+            _ => continue,
+        };
+
+        let mut key = command.to_string();
+        let mut params = SettingParams::new();
+        let selector = setting_selector(command);
+
+        for token in tokens {
+            let mut chars = token.chars();
+            let letter = chars.next().unwrap_or_default().to_ascii_uppercase();
+
+            match chars.as_str().parse::<f32>() {
+                Ok(value) if Some(letter) == selector => {
+                    key = format!("{} {}{}", command, letter, value);
+                }
+                Ok(value) => {
+                    params.insert(letter, value);
+                }
+                Err(_) => {
+                    // Flags without a value (e.g. "M569 S1 X Y") can't be diffed
+                    debug!("Skipping setting line | {}", line);
+                    params.clear();
+                    break;
+                }
+            }
+        }
+
+        if !params.is_empty() {
+            settings.commands.entry(key).or_default().extend(params);
+        }
+    }
+
+    settings
+}
+
+fn is_setting_command(command: &str) -> bool {
+    command.len() > 1
+        && command.starts_with(['M', 'G'])
+        && command[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Parameter used by the firmware to report several entries of the same command
+pub fn setting_selector(command: &str) -> Option<char> {
+    match command {
+        "M145" => Some('S'),
+        "M301" => Some('E'),
+        "M92" | "M201" | "M203" => Some('T'),
+        _ => None,
+    }
+}
+
+/**
+ * Get the bed leveling grid, bilinear (M420 V) or UBL topography (M420 V / G29 T)
+ * Coordinates are taken from the report when present, otherwise grid indexes are used
+ * @param message: String, return message from firmware
+ * @return BedMesh, z values with row 0 at the front of the bed
+ */
+pub fn m420(message: String) -> BedMesh {
+    let mut mesh = BedMesh {
+        kind: if message.contains("Bilinear") {
+            "bilinear".to_string()
+        } else {
+            "ubl".to_string()
+        },
+        ..Default::default()
+    };
+
+    let coord_re = Regex::new(r"\(\s*(-?\d+\.?\d*)\s*,\s*(-?\d+\.?\d*)\s*\)").unwrap();
+    let mut coords: Vec<(f32, f32)> = Vec::new();
+    let mut rows: Vec<(Option<usize>, Vec<Option<f32>>)> = Vec::new();
+
+    for line in message.lines() {
+        let line = line.trim().trim_start_matches("echo:").trim();
+
+        if line.contains('(') {
+            for captures in coord_re.captures_iter(line) {
+                if let (Ok(x), Ok(y)) = (captures[1].parse(), captures[2].parse()) {
+                    coords.push((x, y));
+                }
+            }
+            continue;
+        }
+
+        let tokens: Vec<&str> = line
+            .split(|c: char| c.is_whitespace() || c == '|')
+            .filter(|t| !t.is_empty())
+            .collect();
+
+        let (index, values) = match tokens.first().map(|t| t.parse::<usize>()) {
+            Some(Ok(index)) => (Some(index), &tokens[1..]),
+            _ => (None, &tokens[..]),
+        };
+
+        // Column headers are plain indexes, values always have decimals,
+        // status lines such as "Fade Height 10.00" have words among the numbers
+        let is_row = values
+            .iter()
+            .any(|t| t.contains('.') && mesh_value(t).is_some())
+            && values
+                .iter()
+                .all(|t| *t == MISSING_POINT || mesh_value(t).is_some());
+        if !is_row {
+            continue;
+        }
+
+        let values: Vec<Option<f32>> = values.iter().map(|t| mesh_value(t)).collect();
+        rows.push((index, values));
+    }
+
+    if rows.iter().all(|(index, _)| index.is_some()) {
+        rows.sort_by_key(|(index, _)| *index);
+    } else if mesh.kind == "ubl" {
+        // UBL prints the back row first
+        rows.reverse();
+    }
+    mesh.z = rows.into_iter().map(|(_, values)| values).collect();
+
+    let rows = mesh.z.len();
+    let cols = mesh.z.first().map_or(0, |row| row.len());
+
+    // Old UBL reports print index corners next to the coordinate corners
+    if coords.len() > 4 {
+        coords.retain(|(x, y)| {
+            !((*x == 0.0 || *x == (cols as f32 - 1.0)) && (*y == 0.0 || *y == (rows as f32 - 1.0)))
+        });
+    }
+
+    let min_x = coords.iter().map(|c| c.0).fold(f32::MAX, f32::min);
+    let max_x = coords.iter().map(|c| c.0).fold(f32::MIN, f32::max);
+    let min_y = coords.iter().map(|c| c.1).fold(f32::MAX, f32::min);
+    let max_y = coords.iter().map(|c| c.1).fold(f32::MIN, f32::max);
+
+    if max_x > min_x && max_y > min_y {
+        mesh.x = linspace(min_x, max_x, cols);
+        mesh.y = linspace(min_y, max_y, rows);
+    } else {
+        mesh.x = (0..cols).map(|i| i as f32).collect();
+        mesh.y = (0..rows).map(|i| i as f32).collect();
+    }
+
+    mesh
+}
+
+// Printed instead of the value of a point that was not probed
+static MISSING_POINT: &str = ".";
+
+/// Parse a single mesh point, unprobed points are reported as "." or "nan"
+fn mesh_value(token: &str) -> Option<f32> {
+    token
+        .trim_matches(|c| c == '[' || c == ']' || c == '<' || c == '>')
+        .parse::<f32>()
+        .ok()
+        .filter(|value| value.is_finite())
+}
+
+/// Evenly spaced coordinates between min and max
+pub fn linspace(min: f32, max: f32, count: usize) -> Vec<f32> {
+    match count {
+        0 => Vec::new(),
+        1 => vec![min],
+        _ => (0..count)
+            .map(|i| min + (max - min) * i as f32 / (count - 1) as f32)
+            .collect(),
+    }
+}
+
 /*****************/
 /*     Tests     */
 /*****************/
@@ -325,18 +510,18 @@ mod tests {
         let info = m115(sample_response);
         assert_eq!(info.firmware_name, "Marlin");
         assert_eq!(info.firmware_version, "2.0.1");
-        // print!("{:?}", info.serial_xon_xoff);
-        // assert_eq!(info.serial_xon_xoff, 1);
-        // assert_eq!(info.eeprom, 1);
-        // assert_eq!(info.volumetric, 1);
-        // assert_eq!(info.autoreport_temp, 1);
-        // assert_eq!(info.progress, 1);
-        // assert_eq!(info.print_job, 1);
-        // assert_eq!(info.autolevel, 1);
-        // assert_eq!(info.z_probe, 1);
-        // assert_eq!(info.leveling_data, 1);
-        // assert_eq!(info.build_percent, 1);
-        // assert_eq!(info.software_power, 1);
+        assert_eq!(info.serial_xon_xoff, 1);
+        assert_eq!(info.eeprom, 1);
+        assert_eq!(info.volumetric, 1);
+        assert_eq!(info.autoreport_temp, 1);
+        assert_eq!(info.progress, 1);
+        assert_eq!(info.print_job, 1);
+        assert_eq!(info.autolevel, 1);
+        assert_eq!(info.z_probe, 1);
+        assert_eq!(info.leveling_data, 1);
+        assert_eq!(info.build_percent, 1);
+        assert_eq!(info.software_power, 1);
+        assert_eq!(info.emergency_parser, 0);
     }
 
     #[test]
@@ -435,4 +620,53 @@ mod tests {
         let print_time = m31(sample_response);
         assert_eq!(print_time, "2h 45m");
     }
+
+    #[test]
+    fn test_m503_parser() {
+        let sample_response = "echo:; Steps per unit:\necho:  M92 X80.00 Y80.00 Z400.00 E93.00\necho:; Hotend PID:\necho:  M301 P21.73 I1.54 D76.55\necho:; Material heatup parameters:\necho:  M145 S0 H200.00 B60.00 F255\necho:  M145 S1 H240.00 B110.00 F255\necho:  M200 D1.75\necho:  M200 S0\necho:  M569 S1 X Y\nok".to_string();
+        let settings = m503(sample_response);
+
+        assert_eq!(settings.commands.len(), 5);
+        assert_eq!(settings.commands["M92"][&'E'], 93.0);
+        assert_eq!(settings.commands["M301"][&'D'], 76.55);
+        assert_eq!(settings.commands["M145 S1"][&'H'], 240.0);
+        assert_eq!(settings.commands["M200"][&'D'], 1.75);
+        assert_eq!(settings.commands["M200"][&'S'], 0.0);
+        assert!(!settings.commands.contains_key("M569"));
+    }
+
+    #[test]
+    fn test_m420_bilinear_parser() {
+        let sample_response = "Bilinear Leveling Grid:\n      0      1      2\n 0 +0.048 +0.040 +0.023\n 1 +0.043 -0.010 .\n 2 -0.102 +0.000 +0.110\nok".to_string();
+        let mesh = m420(sample_response);
+
+        assert_eq!(mesh.kind, "bilinear");
+        assert_eq!(mesh.z.len(), 3);
+        assert_eq!(mesh.z[0], vec![Some(0.048), Some(0.040), Some(0.023)]);
+        assert_eq!(mesh.z[1][2], None);
+        assert_eq!(mesh.x, vec![0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_m420_ignores_status_lines() {
+        let sample_response = "echo:Bed Leveling ON\necho:Fade Height 10.00\nBilinear Leveling Grid:\n      0      1\n 0 +0.048 +0.040\n 1 +0.043 -0.010\nX:0.00 Y:0.00 Z:10.00 E:0.00\necho:Mesh Z offset 0.25\nok".to_string();
+        let mesh = m420(sample_response);
+
+        assert_eq!(mesh.z.len(), 2);
+        assert_eq!(mesh.z[0], vec![Some(0.048), Some(0.040)]);
+        assert_eq!(mesh.z[1], vec![Some(0.043), Some(-0.010)]);
+    }
+
+    #[test]
+    fn test_m420_ubl_parser() {
+        let sample_response = "Bed Topography Report:\n\n    (  0,200)              (200,200)\n        0       1       2\n 2 | +0.300  +0.200  +0.100\n 1 | +0.150 [+0.050] -0.050\n 0 | -0.100  -0.200  -0.300\n    (  0,  0)              (200,  0)\nok".to_string();
+        let mesh = m420(sample_response);
+
+        assert_eq!(mesh.kind, "ubl");
+        assert_eq!(mesh.z[0], vec![Some(-0.1), Some(-0.2), Some(-0.3)]);
+        assert_eq!(mesh.z[1][1], Some(0.05));
+        assert_eq!(mesh.z[2][0], Some(0.3));
+        assert_eq!(mesh.x, vec![0.0, 100.0, 200.0]);
+        assert_eq!(mesh.y, vec![0.0, 100.0, 200.0]);
+    }
 }
@@ -0,0 +1,1183 @@
+use log::{error, info, warn};
+use serialport::SerialPort;
+use std::io::{self, Error, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, oneshot};
+
+use crate::actions::{apply_prompt, parse_action};
+use crate::configuration::{persist_serial_settings, DEFAULT_PRINTER};
+use crate::discovery::{detect, AUTO_BAUD, AUTO_PORT};
+use crate::gcode::parse_gcode;
+use crate::metrics::SerialMetrics;
+use crate::parser::m115;
+use crate::safety::MotionState;
+use crate::serialcom::{open_port, send_command, send_until_ok, write_commands, LineNumbers};
+use crate::structs::{
+    ActionCommand, HostPrompt, MessageSender, PrinterInfo, PrinterState, PrinterStatus,
+    SerialConfigRequest, SerialConfigResult, SerialSettings,
+};
+use crate::wscom::json_response;
+
+static MIN_BACKOFF: u64 = 1;
+static MAX_BACKOFF: u64 = 30;
+static HANDSHAKE_ATTEMPTS: u32 = 5;
+// Interval used to notice an unplugged port while no command is sent
+static IDLE_CHECK: u64 = 2;
+// Silence allowed while a job line waits for its ok, long moves keep the planner full
+static LINE_TIMEOUT: u64 = 120;
+// Axes reported by homed_axes, in the order of Klipper
+static AXES: [char; 3] = ['x', 'y', 'z'];
+
+enum Request {
+    Command {
+        command: String,
+        /// Wait for the ok of the firmware instead of a silence
+        until_ok: bool,
+        reply: Reply,
+    },
+    Reconfigure {
+        settings: SerialSettings,
+        reply: Sender<Result<PrinterInfo, Error>>,
+    },
+    /// Wakes an idle worker to write the urgent commands
+    Urgent,
+}
+
+/// Where the worker sends a response, a thread blocks on a channel and a task awaits a oneshot
+enum Reply {
+    Thread(Sender<Result<String, Error>>),
+    Task(oneshot::Sender<Result<String, Error>>),
+}
+
+impl Reply {
+    /// The requester may have given up, the response is then dropped
+    fn send(self, result: Result<String, Error>) {
+        match self {
+            Reply::Thread(sender) => {
+                let _ = sender.send(result);
+            }
+            Reply::Task(sender) => {
+                let _ = sender.send(result);
+            }
+        }
+    }
+}
+
+/// Why the worker left a connection or stopped waiting
+enum Outcome {
+    Stopped,
+    Retry,
+    Failed(io::Error),
+    Reconfigure(SerialSettings, Sender<Result<PrinterInfo, Error>>),
+}
+
+struct Shared {
+    name: String,
+    state: Mutex<PrinterState>,
+    info: Mutex<Option<PrinterInfo>>,
+    port: Mutex<Option<(String, u32)>>,
+    settings: Mutex<SerialSettings>,
+    metrics: Mutex<SerialMetrics>,
+    /// Dialog of the firmware, answered with M876
+    prompt: Mutex<Option<HostPrompt>>,
+    /// Action commands received during a job, taken by the job
+    actions: Mutex<Vec<ActionCommand>>,
+    /// X, Y and Z homed by a G28 sent since the connection, cleared when the steppers are released
+    homed: Mutex<[bool; 3]>,
+    /// Followed from every command sent, checked by the safety limits
+    motion: Mutex<MotionState>,
+    /// Written by the worker ahead of the queued commands, even while one waits for its ok
+    urgent: Mutex<Vec<String>>,
+    events: broadcast::Sender<MessageSender>,
+}
+
+/**
+ * Handle on the printer connection
+ * The port is kept open by a worker thread which reconnects with backoff
+ * and performs the M110/M115 handshake every time the port reappears
+ */
+#[derive(Clone)]
+pub struct Printer {
+    shared: Arc<Shared>,
+    requests: Sender<Request>,
+}
+
+impl Printer {
+    /**
+     * Start the connection worker
+     * @param name: &str, name of the printer, set on the broadcast transitions
+     * @param settings: SerialSettings, port, baud rate and timeouts of the printer
+     * @param events: broadcast::Sender<MessageSender>, channel receiving the state transitions
+     * @return Printer
+     */
+    pub fn start(
+        name: &str,
+        settings: SerialSettings,
+        events: broadcast::Sender<MessageSender>,
+    ) -> Printer {
+        let shared = Arc::new(Shared::new(name, settings, events));
+        let (requests, receiver) = mpsc::channel();
+
+        let worker = shared.clone();
+        thread::spawn(move || run(worker, receiver));
+
+        Printer { shared, requests }
+    }
+
+    /**
+     * Send a command and wait for the response
+     * @param command: &str, command without line ending
+     * @return Result<String, Error>, the reason when the printer is not connected or the write failed
+     */
+    pub fn send(&self, command: &str) -> Result<String, Error> {
+        self.request(command, false)
+    }
+
+    /**
+     * Send a line of a job and wait until the firmware acknowledged it
+     * @param line: &str, command without line ending
+     * @return Result<String, Error>, lines received up to "ok"
+     */
+    pub fn send_line(&self, line: &str) -> Result<String, Error> {
+        self.request(line, true)
+    }
+
+    /**
+     * Send a command from an async task and wait for the response without blocking the runtime
+     * @param command: &str, command without line ending
+     * @return Result<String, Error>, the reason when the printer is not connected or the write failed
+     */
+    pub async fn send_async(&self, command: &str) -> Result<String, Error> {
+        let (reply, response) = oneshot::channel();
+        self.submit(command, false, Reply::Task(reply))?;
+
+        response.await.map_err(|_| stopped())?
+    }
+
+    /**
+     * Run an exchange of several commands on a blocking thread, for the async handlers
+     * @param exchange: F, sends its commands with Printer::send
+     * @return Result<T, Error>, result of the exchange
+     */
+    pub async fn exchange<T, F>(&self, exchange: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Printer) -> Result<T, Error> + Send + 'static,
+    {
+        let printer = self.clone();
+        tokio::task::spawn_blocking(move || exchange(&printer))
+            .await
+            .map_err(Error::other)?
+    }
+
+    fn request(&self, command: &str, until_ok: bool) -> Result<String, Error> {
+        let (reply, response) = mpsc::channel();
+        self.submit(command, until_ok, Reply::Thread(reply))?;
+
+        response.recv().map_err(|_| stopped())?
+    }
+
+    /**
+     * Write commands ahead of the queued ones
+     * The worker writes them between the reads of the command in progress,
+     * a firmware built with EMERGENCY_PARSER handles them while it waits
+     * @param commands: &[&str], commands without line ending, written in order
+     * @return Result<(), Error>, NotConnected without connection
+     */
+    pub fn send_urgent(&self, commands: &[&str]) -> Result<(), Error> {
+        if lock(&self.shared.port).is_none() {
+            return Err(Error::new(
+                io::ErrorKind::NotConnected,
+                "Printer is not connected",
+            ));
+        }
+        lock(&self.shared.urgent).extend(commands.iter().map(|command| command.to_string()));
+        self.requests.send(Request::Urgent).map_err(|_| stopped())
+    }
+
+    /// Queue a command for the worker, refused while the printer does not accept commands
+    fn submit(&self, command: &str, until_ok: bool, reply: Reply) -> Result<(), Error> {
+        let state = self.state();
+        if !accepts_commands(state) {
+            return Err(Error::new(
+                io::ErrorKind::NotConnected,
+                format!("Printer is {:?}", state),
+            ));
+        }
+
+        self.requests
+            .send(Request::Command {
+                command: command.to_string(),
+                until_ok,
+                reply,
+            })
+            .map_err(|_| stopped())
+    }
+
+    /**
+     * Reconnect with new settings
+     * The previous settings are restored when the handshake fails
+     * @param settings: SerialSettings, new port, baud rate and timeouts
+     * @return Result<PrinterInfo, Error>, capabilities reported with the new settings
+     */
+    pub fn reconfigure(&self, settings: SerialSettings) -> Result<PrinterInfo, Error> {
+        let (reply, response) = mpsc::channel();
+        self.requests
+            .send(Request::Reconfigure { settings, reply })
+            .map_err(|_| stopped())?;
+
+        response.recv().map_err(|_| stopped())?
+    }
+
+    pub fn name(&self) -> &str {
+        &self.shared.name
+    }
+
+    pub fn state(&self) -> PrinterState {
+        self.shared.state()
+    }
+
+    pub fn set_state(&self, state: PrinterState, detail: Option<String>) {
+        self.shared.set_state(state, detail);
+    }
+
+    /// Port and baud rate of the current connection, resolved when set to auto
+    pub fn port(&self) -> Option<(String, u32)> {
+        lock(&self.shared.port).clone()
+    }
+
+    /// Settings of the connection as configured
+    pub fn settings(&self) -> SerialSettings {
+        lock(&self.shared.settings).clone()
+    }
+
+    /// Capabilities reported by M115 during the last handshake
+    pub fn info(&self) -> Option<PrinterInfo> {
+        lock(&self.shared.info).clone()
+    }
+
+    /// Traffic, latency and last temperatures of the connection since the start
+    pub fn metrics(&self) -> SerialMetrics {
+        lock(&self.shared.metrics).clone()
+    }
+
+    /// Dialog shown by the firmware, None when no answer is expected
+    pub fn prompt(&self) -> Option<HostPrompt> {
+        lock(&self.shared.prompt)
+            .clone()
+            .filter(|prompt| prompt.shown)
+    }
+
+    /// Modes, position and hotend target followed from the commands sent and the reports
+    pub fn motion(&self) -> MotionState {
+        lock(&self.shared.motion).clone()
+    }
+
+    /// Axes homed since the connection, "xyz" once G28 was sent, "" when unknown
+    pub fn homed_axes(&self) -> String {
+        let homed = *lock(&self.shared.homed);
+        AXES.iter()
+            .zip(homed)
+            .filter(|(_, homed)| *homed)
+            .map(|(axis, _)| axis)
+            .collect()
+    }
+
+    /// Action commands received while printing since the last call
+    pub fn take_actions(&self) -> Vec<ActionCommand> {
+        std::mem::take(&mut *lock(&self.shared.actions))
+    }
+
+    /**
+     * Answer the dialog of the firmware with M876
+     * Sent as an urgent command, the firmware waits for it while a command is in progress
+     * @param choice: usize, index of the button
+     * @return Result<HostPrompt, Error>, the dialog answered
+     */
+    pub fn answer_prompt(&self, choice: usize) -> Result<HostPrompt, Error> {
+        let prompt = self.prompt().ok_or_else(|| {
+            Error::new(
+                io::ErrorKind::NotFound,
+                "No prompt of the printer is waiting",
+            )
+        })?;
+        if choice >= prompt.buttons.len().max(1) {
+            return Err(Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Prompt has no choice {}", choice),
+            ));
+        }
+        self.send_urgent(&[&format!("M876 S{}", choice)])?;
+        info!(
+            "Printer {} prompt {} answered with {}",
+            self.shared.name,
+            prompt.message,
+            prompt.buttons.get(choice).map_or("", String::as_str)
+        );
+        self.shared.set_prompt(None);
+        Ok(prompt)
+    }
+}
+
+impl Shared {
+    fn new(name: &str, settings: SerialSettings, events: broadcast::Sender<MessageSender>) -> Self {
+        Shared {
+            name: name.to_string(),
+            state: Mutex::new(PrinterState::Disconnected),
+            info: Mutex::new(None),
+            port: Mutex::new(None),
+            settings: Mutex::new(settings),
+            metrics: Mutex::new(SerialMetrics::default()),
+            prompt: Mutex::new(None),
+            actions: Mutex::new(Vec::new()),
+            homed: Mutex::new([false; 3]),
+            motion: Mutex::new(MotionState::default()),
+            urgent: Mutex::new(Vec::new()),
+            events,
+        }
+    }
+
+    fn state(&self) -> PrinterState {
+        *lock(&self.state)
+    }
+
+    /// Change the state and broadcast the transition
+    fn set_state(&self, state: PrinterState, detail: Option<String>) {
+        let previous = std::mem::replace(&mut *lock(&self.state), state);
+        if previous == state {
+            return;
+        }
+        // A new connection or a halt loses the position, the printer has to home again
+        if !accepts_commands(state) || state == PrinterState::Halted {
+            *lock(&self.homed) = [false; 3];
+        }
+
+        match &detail {
+            Some(detail) => info!(
+                "Printer {} {:?} -> {:?} | {}",
+                self.name, previous, state, detail
+            ),
+            None => info!("Printer {} {:?} -> {:?}", self.name, previous, state),
+        }
+
+        let status = PrinterStatus {
+            state,
+            previous,
+            detail,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_secs(),
+        };
+        self.send_event("PrinterStatus", status);
+    }
+
+    /// Replace the dialog of the firmware and tell the clients
+    fn set_prompt(&self, prompt: Option<HostPrompt>) {
+        *lock(&self.prompt) = prompt.clone();
+        self.send_event("HostPrompt", prompt);
+    }
+
+    fn send_event<T: serde::Serialize>(&self, message_type: &str, event: T) {
+        let mut message = json_response(message_type, Ok(event));
+        message.printer = Some(self.name.clone());
+        // No receiver only means that no client is connected
+        let _ = self.events.send(message);
+    }
+
+    /**
+     * Handle the action commands among the lines received from the printer
+     * Prompts are relayed to the clients, the other actions are kept for the job
+     * @param line: &str, line received
+     */
+    fn observe(&self, line: &str) {
+        lock(&self.motion).observe(line);
+        let Some(action) = parse_action(line) else {
+            return;
+        };
+        info!(
+            "Printer {} action {} {}",
+            self.name, action.action, action.detail
+        );
+        self.send_event("HostAction", &action);
+
+        if action.action.starts_with("prompt_") {
+            let mut prompt = lock(&self.prompt);
+            if apply_prompt(&mut prompt, &action) {
+                let shown = prompt.clone();
+                drop(prompt);
+                self.send_event("HostPrompt", shown);
+            }
+        } else if matches!(self.state(), PrinterState::Printing | PrinterState::Paused) {
+            lock(&self.actions).push(action);
+        }
+    }
+
+    /**
+     * Follow the state changes caused by a command sent to the printer
+     * @param command: &str, command sent
+     */
+    fn track(&self, command: &str) {
+        if let Ok(parsed) = parse_gcode(command) {
+            lock(&self.motion).track(&parsed);
+        }
+        let word = command
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .to_uppercase();
+
+        match (word.as_str(), self.state()) {
+            ("M999", PrinterState::Halted) => self.set_state(
+                PrinterState::Operational,
+                Some("Reset with M999".to_string()),
+            ),
+            ("M24", PrinterState::Operational | PrinterState::Paused) => {
+                self.set_state(PrinterState::Printing, None)
+            }
+            ("M25", PrinterState::Printing) => self.set_state(PrinterState::Paused, None),
+            ("M524", PrinterState::Printing | PrinterState::Paused) => {
+                self.set_state(PrinterState::Operational, Some("Print aborted".to_string()))
+            }
+            _ => {}
+        }
+
+        let homed = match word.as_str() {
+            "G28" => true,
+            // M84 S<seconds> only sets the idle timeout
+            "M18" | "M84" if !has_word(command, 'S') => false,
+            _ => return,
+        };
+        let axes = named_axes(command);
+        let mut axes_homed = lock(&self.homed);
+        for (index, axis) in AXES.iter().enumerate() {
+            if axes.is_empty() || axes.contains(axis) {
+                axes_homed[index] = homed;
+            }
+        }
+    }
+}
+
+/// Axes named in G28, M18 or M84, all of them when none is named
+fn named_axes(command: &str) -> Vec<char> {
+    command
+        .split_whitespace()
+        .skip(1)
+        .filter_map(|word| word.chars().next())
+        .map(|letter| letter.to_ascii_lowercase())
+        .filter(|letter| AXES.contains(letter))
+        .collect()
+}
+
+fn has_word(command: &str, letter: char) -> bool {
+    command
+        .split_whitespace()
+        .skip(1)
+        .any(|word| word.to_ascii_uppercase().starts_with(letter))
+}
+
+/// Commands are refused until the handshake succeeded
+fn accepts_commands(state: PrinterState) -> bool {
+    matches!(
+        state,
+        PrinterState::Operational
+            | PrinterState::Printing
+            | PrinterState::Paused
+            | PrinterState::Halted
+    )
+}
+
+/**
+ * Connection loop of the worker thread
+ * Stops once every Printer handle is dropped
+ */
+fn run(shared: Arc<Shared>, requests: Receiver<Request>) {
+    let mut backoff = Duration::from_secs(MIN_BACKOFF);
+    let mut pending: Option<(SerialSettings, Sender<Result<PrinterInfo, Error>>)> = None;
+
+    loop {
+        let settings = match &pending {
+            Some((settings, _)) => settings.clone(),
+            None => lock(&shared.settings).clone(),
+        };
+
+        let outcome = match connect(&shared, &settings) {
+            Ok((mut port, port_name, baud, info)) => {
+                info!(
+                    "Connected to {} {} on {} at {}",
+                    info.firmware_name, info.firmware_version, port_name, baud
+                );
+                if let Some((settings, reply)) = pending.take() {
+                    *lock(&shared.settings) = settings;
+                    let _ = reply.send(Ok(info.clone()));
+                }
+                *lock(&shared.info) = Some(info);
+                *lock(&shared.port) = Some((port_name.clone(), baud));
+                // The firmware starts over in absolute mode at an unknown position
+                *lock(&shared.motion) = MotionState::default();
+                shared.set_state(PrinterState::Operational, None);
+                backoff = Duration::from_secs(MIN_BACKOFF);
+
+                let timeout = Duration::from_millis(settings.timeouts.response_ms);
+                let outcome = serve(&shared, &requests, &mut port, &port_name, timeout);
+                *lock(&shared.port) = None;
+                // Not written before the connection was lost, meant for this one
+                lock(&shared.urgent).clear();
+                // The dialog is gone with the connection
+                if lock(&shared.prompt).is_some() {
+                    shared.set_prompt(None);
+                }
+
+                if let Outcome::Failed(e) = &outcome {
+                    error!("Printer connection lost on {} | {}", port_name, e);
+                    shared.set_state(failed_state(e), Some(e.to_string()));
+                }
+                outcome
+            }
+            Err(e) => {
+                warn!("Failed to connect to {} | {}", settings.serial_port, e);
+
+                // Roll back to the previous settings right away
+                if let Some((_, reply)) = pending.take() {
+                    let _ = reply.send(Err(Error::other(format!(
+                        "Handshake failed on {} at {}, previous settings restored | {}",
+                        settings.serial_port, settings.baud_rate, e
+                    ))));
+                    continue;
+                }
+
+                shared.set_state(failed_state(&e), Some(e.to_string()));
+                Outcome::Failed(e)
+            }
+        };
+
+        match outcome {
+            Outcome::Stopped => return,
+            Outcome::Reconfigure(settings, reply) => {
+                pending = Some((settings, reply));
+                continue;
+            }
+            Outcome::Retry | Outcome::Failed(_) => {}
+        }
+
+        match wait(&shared, &requests, backoff) {
+            Outcome::Stopped => return,
+            Outcome::Reconfigure(settings, reply) => pending = Some((settings, reply)),
+            Outcome::Retry | Outcome::Failed(_) => {}
+        }
+        backoff = (backoff * 2).min(Duration::from_secs(MAX_BACKOFF));
+    }
+}
+
+/**
+ * Open the port and handshake with the firmware
+ * The port is probed again on every attempt when set to auto, it may come back under another name
+ * @param shared: &Shared, state of the connection
+ * @param settings: &SerialSettings, settings to connect with
+ * @return io::Result<(Box<dyn SerialPort>, String, u32, PrinterInfo)>, port, its name, baud rate and capabilities
+ */
+fn connect(
+    shared: &Shared,
+    settings: &SerialSettings,
+) -> io::Result<(Box<dyn SerialPort>, String, u32, PrinterInfo)> {
+    shared.set_state(PrinterState::Connecting, None);
+
+    let (port_name, baud) = if settings.serial_port == AUTO_PORT || settings.baud_rate == AUTO_BAUD
+    {
+        let detected = detect(&settings.serial_port, settings.baud_rate)?;
+        (detected.port_name, detected.baud_rate)
+    } else {
+        (settings.serial_port.clone(), settings.baud_rate)
+    };
+
+    let timeouts = settings.timeouts;
+    let mut port = open_port(&port_name, baud, Duration::from_millis(timeouts.port_ms))?;
+    shared.set_state(PrinterState::Handshaking, None);
+    let info = handshake(&mut port, Duration::from_millis(timeouts.response_ms))?;
+
+    Ok((port, port_name, baud, info))
+}
+
+/**
+ * Reset the line numbers and read the capabilities of the firmware
+ * Opening the port resets most boards, so the first attempts may go unanswered
+ * @param port: &mut T, open port
+ * @param timeout: Duration, silence after which a response is complete
+ * @return io::Result<PrinterInfo>, capabilities reported by M115
+ */
+fn handshake<T: Read + Write>(port: &mut T, timeout: Duration) -> io::Result<PrinterInfo> {
+    for _ in 0..HANDSHAKE_ATTEMPTS {
+        let response = send_command(port, "M110 N0", timeout)?;
+        if response.contains("ok") {
+            let info = m115(send_command(port, "M115", timeout)?);
+            // Tells the firmware that its prompts are answered
+            if info.prompt_support == 1 {
+                send_command(port, "M876 P1", timeout)?;
+            }
+            return Ok(info);
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "Printer did not answer M110",
+    ))
+}
+
+/**
+ * Send the requested commands until the connection fails or is reconfigured
+ * @return Outcome
+ */
+fn serve<T: Read + Write>(
+    shared: &Shared,
+    requests: &Receiver<Request>,
+    port: &mut T,
+    serial_port: &str,
+    timeout: Duration,
+) -> Outcome {
+    // The handshake reset the line numbers with M110 N0
+    let mut numbers = LineNumbers::default();
+    loop {
+        match requests.recv_timeout(Duration::from_secs(IDLE_CHECK)) {
+            Ok(Request::Command {
+                command,
+                until_ok,
+                reply,
+            }) => {
+                if let Err(e) = write_urgent(shared, port) {
+                    reply.send(Err(Error::other(format!("Failed to send command | {}", e))));
+                    return Outcome::Failed(e);
+                }
+                let started = Instant::now();
+                let result = if until_ok {
+                    send_until_ok(
+                        port,
+                        &mut numbers,
+                        &command,
+                        Duration::from_secs(LINE_TIMEOUT),
+                        &mut |line| shared.observe(line),
+                        &mut || std::mem::take(&mut *lock(&shared.urgent)),
+                    )
+                } else {
+                    send_command(port, &command, timeout)
+                        .inspect(|response| response.lines().for_each(|line| shared.observe(line)))
+                };
+                match result {
+                    Ok(response) => {
+                        lock(&shared.metrics).record(&command, &response, started.elapsed());
+                        shared.track(&command);
+                        numbers.follow(&command);
+                        reply.send(Ok(response));
+                    }
+                    Err(e) => {
+                        reply.send(Err(Error::other(format!("Failed to send command | {}", e))));
+                        return Outcome::Failed(e);
+                    }
+                }
+            }
+            Ok(Request::Reconfigure { settings, reply }) => {
+                return Outcome::Reconfigure(settings, reply)
+            }
+            Ok(Request::Urgent) => {
+                if let Err(e) = write_urgent(shared, port) {
+                    return Outcome::Failed(e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if !port_exists(serial_port) {
+                    return Outcome::Failed(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Port {} disappeared", serial_port),
+                    ));
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Outcome::Stopped,
+        }
+    }
+}
+
+/// Write the urgent commands queued since the last write
+fn write_urgent<T: Write>(shared: &Shared, port: &mut T) -> io::Result<()> {
+    let commands = std::mem::take(&mut *lock(&shared.urgent));
+    write_commands(port, &commands)
+}
+
+/**
+ * Refuse commands until the next connection attempt
+ * @return Outcome, Retry once the backoff elapsed
+ */
+fn wait(shared: &Shared, requests: &Receiver<Request>, duration: Duration) -> Outcome {
+    let deadline = Instant::now() + duration;
+
+    loop {
+        match requests.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Request::Command { reply, .. }) => {
+                let reason = format!("Printer is {:?}, reconnecting", shared.state());
+                reply.send(Err(Error::new(io::ErrorKind::NotConnected, reason)));
+            }
+            Ok(Request::Reconfigure { settings, reply }) => {
+                return Outcome::Reconfigure(settings, reply)
+            }
+            // Nothing to write them to
+            Ok(Request::Urgent) => lock(&shared.urgent).clear(),
+            Err(RecvTimeoutError::Timeout) => return Outcome::Retry,
+            Err(RecvTimeoutError::Disconnected) => return Outcome::Stopped,
+        }
+    }
+}
+
+/**
+ * Handle a SerialConfig request
+ * @param printer: &Printer, printer connection
+ * @param request: SerialConfigRequest, values to change
+ * @param config_file: &str, configuration file the settings are stored in
+ * @return Result<SerialConfigResult, Error>, the reason when the settings are invalid or the handshake failed,
+ * ResourceBusy during a print
+ */
+pub fn apply_serial_config(
+    printer: &Printer,
+    request: SerialConfigRequest,
+    config_file: &str,
+) -> Result<SerialConfigResult, Error> {
+    let mut settings = printer.settings();
+    if let Some(serial_port) = request.serial_port {
+        settings.serial_port = serial_port.trim().to_string();
+    }
+    if let Some(baud_rate) = request.baud_rate {
+        settings.baud_rate = baud_rate;
+    }
+    if let Some(timeouts) = request.timeouts {
+        settings.timeouts = timeouts;
+    }
+
+    if settings.serial_port.is_empty() {
+        return Err(Error::other("Serial port can not be empty"));
+    }
+    if settings.timeouts.port_ms == 0 || settings.timeouts.response_ms == 0 {
+        return Err(Error::other("Timeouts must be greater than 0"));
+    }
+    // Reopening the port would drop the job being printed
+    if matches!(
+        printer.state(),
+        PrinterState::Printing | PrinterState::Paused
+    ) {
+        return Err(Error::new(
+            io::ErrorKind::ResourceBusy,
+            "Serial settings can not be changed while printing",
+        ));
+    }
+
+    info!(
+        "Reconfiguring printer {} connection | {:?}",
+        printer.name(),
+        settings
+    );
+    let info = printer.reconfigure(settings.clone())?;
+
+    // The connection already uses the new settings, a failed write is only reported
+    let persisted = request.persist
+        && persist_serial_settings(config_file, printer.name(), &settings)
+            .inspect_err(|e| error!("Failed to store serial settings | {}", e))
+            .is_ok();
+
+    Ok(SerialConfigResult {
+        settings,
+        firmware_name: info.firmware_name,
+        firmware_version: info.firmware_version,
+        persisted,
+    })
+}
+
+/// A missing port means the cable is unplugged, other failures are errors
+fn failed_state(error: &io::Error) -> PrinterState {
+    match error.kind() {
+        io::ErrorKind::NotFound => PrinterState::Disconnected,
+        _ => PrinterState::Error,
+    }
+}
+
+fn port_exists(serial_port: &str) -> bool {
+    // Windows ports such as COM3 are not files
+    !cfg!(unix) || Path::new(serial_port).exists()
+}
+
+/**
+ * Directory where the files of a printer are stored
+ * The default printer keeps the base directory used before printers were named
+ * @param base: &str, directory shared by the printers
+ * @param printer: &str, name of the printer
+ * @return PathBuf
+ */
+pub fn data_dir(base: &str, printer: &str) -> PathBuf {
+    if printer == DEFAULT_PRINTER {
+        PathBuf::from(base)
+    } else {
+        Path::new(base).join(printer)
+    }
+}
+
+fn stopped() -> Error {
+    Error::other("Printer connection stopped")
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+fn test_settings() -> SerialSettings {
+    SerialSettings {
+        serial_port: "/dev/null".to_string(),
+        baud_rate: 115200,
+        timeouts: crate::structs::SerialTimeouts::default(),
+    }
+}
+
+#[cfg(test)]
+impl Printer {
+    /// Operational printer whose worker serves a fake port, for the tests of the jobs
+    pub(crate) fn serving<T: Read + Write + Send + 'static>(mut port: T) -> Printer {
+        let shared = Arc::new(Shared::new("mk3", test_settings(), broadcast::channel(8).0));
+        shared.set_state(PrinterState::Operational, None);
+        *lock(&shared.port) = Some(("/dev/null".to_string(), 115200));
+        let (requests, receiver) = mpsc::channel();
+
+        let worker = shared.clone();
+        thread::spawn(move || {
+            serve(
+                &worker,
+                &receiver,
+                &mut port,
+                "/dev/null",
+                Duration::from_millis(50),
+            )
+        });
+
+        Printer { shared, requests }
+    }
+}
+
+/// Acknowledges every line after a short delay and counts the writes
+#[cfg(test)]
+pub(crate) struct OkPort {
+    written: Arc<std::sync::atomic::AtomicUsize>,
+    pending: usize,
+}
+
+#[cfg(test)]
+impl OkPort {
+    pub(crate) fn new(written: Arc<std::sync::atomic::AtomicUsize>) -> Self {
+        OkPort {
+            written,
+            pending: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Read for OkPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending == 0 {
+            return Ok(0);
+        }
+        self.pending -= 1;
+        thread::sleep(Duration::from_millis(2));
+        buf[..3].copy_from_slice(b"ok\n");
+        Ok(3)
+    }
+}
+
+#[cfg(test)]
+impl Write for OkPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.pending += 1;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::fs;
+
+    /// Answers each written command with the next response
+    struct FakePort {
+        responses: VecDeque<&'static str>,
+        pending: Vec<u8>,
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.pending.len().min(buf.len());
+            buf[..count].copy_from_slice(&self.pending[..count]);
+            self.pending.drain(..count);
+            Ok(count)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if let Some(response) = self.responses.pop_front() {
+                self.pending.extend_from_slice(response.as_bytes());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn shared() -> Shared {
+        Shared::new("mk3", test_settings(), broadcast::channel(8).0)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut port = FakePort {
+            responses: VecDeque::from([
+                "ok\n",
+                "FIRMWARE_NAME:Marlin 2.1.2\nCap:EMERGENCY_PARSER:1\nok\n",
+            ]),
+            pending: Vec::new(),
+        };
+
+        let info = handshake(&mut port, Duration::from_millis(100)).unwrap();
+        assert_eq!(info.firmware_name, "Marlin");
+        assert_eq!(info.emergency_parser, 1);
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        let shared = shared();
+        let mut events = shared.events.subscribe();
+
+        shared.set_state(PrinterState::Operational, None);
+        shared.track("M24");
+        assert_eq!(shared.state(), PrinterState::Printing);
+        shared.track("M25");
+        assert_eq!(shared.state(), PrinterState::Paused);
+        shared.track("M524");
+        assert_eq!(shared.state(), PrinterState::Operational);
+
+        shared.track("G28 X Y");
+        shared.track("M84 S60");
+        assert_eq!(*lock(&shared.homed), [true, true, false]);
+        shared.track("M18 X");
+        assert_eq!(*lock(&shared.homed), [false, true, false]);
+        shared.track("G28");
+        assert_eq!(*lock(&shared.homed), [true; 3]);
+
+        shared.set_state(PrinterState::Halted, None);
+        assert_eq!(*lock(&shared.homed), [false; 3]);
+        shared.track("m999");
+        assert_eq!(shared.state(), PrinterState::Operational);
+
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.message_type, "PrinterStatus");
+        assert_eq!(event.printer.as_deref(), Some("mk3"));
+        assert!(accepts_commands(PrinterState::Halted));
+        assert!(!accepts_commands(PrinterState::Handshaking));
+    }
+
+    #[tokio::test]
+    async fn test_send_async() {
+        let (requests, receiver) = mpsc::channel();
+        let printer = Printer {
+            shared: Arc::new(shared()),
+            requests,
+        };
+        let error = printer.send_async("M105").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotConnected);
+
+        printer.set_state(PrinterState::Operational, None);
+        let worker = printer.shared.clone();
+        let served = thread::spawn(move || {
+            let mut port = FakePort {
+                responses: VecDeque::from(["ok T:21.0 /0.0 B:20.0 /0.0\n"]),
+                pending: Vec::new(),
+            };
+            serve(
+                &worker,
+                &receiver,
+                &mut port,
+                "/dev/null",
+                Duration::from_millis(50),
+            )
+        });
+
+        let response = printer.send_async("M105").await.unwrap();
+        assert!(response.starts_with("ok T:21.0"));
+        drop(printer);
+        assert!(matches!(served.join().unwrap(), Outcome::Stopped));
+    }
+
+    #[test]
+    fn test_observe() {
+        let shared = shared();
+        let mut events = shared.events.subscribe();
+
+        shared.observe("//action:pause filament_runout 0");
+        assert!(lock(&shared.actions).is_empty());
+        assert_eq!(events.try_recv().unwrap().message_type, "HostAction");
+
+        shared.set_state(PrinterState::Printing, None);
+        for line in [
+            "echo:busy: processing",
+            "//action:prompt_begin Paused",
+            "//action:prompt_button Resume",
+            "//action:prompt_show",
+            "//action:paused",
+        ] {
+            shared.observe(line);
+        }
+        assert_eq!(
+            lock(&shared.prompt).as_ref().unwrap().buttons,
+            vec!["Resume"]
+        );
+        assert_eq!(lock(&shared.actions).len(), 1);
+        assert!(std::iter::from_fn(|| events.try_recv().ok())
+            .any(|event| event.message_type == "HostPrompt"));
+
+        // Every command sent and report received reaches the safety state
+        shared.track("M83");
+        shared.observe("ok T:205.0 /210.0 B:60.0 /60.0");
+        let mut motion = MotionState::default();
+        motion.track(&parse_gcode("M83").unwrap());
+        motion.observe("T:0.0 /210.0 B:0.0 /0.0");
+        assert_eq!(*lock(&shared.motion), motion);
+    }
+
+    /// Shows a dialog and stays busy until it is answered
+    struct PromptPort {
+        written: Arc<Mutex<Vec<String>>>,
+        reads: usize,
+        answered: bool,
+    }
+
+    impl Read for PromptPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(5));
+            self.reads += 1;
+            let data: &[u8] = match (self.reads, self.answered) {
+                (1, _) => b"//action:prompt_begin Paused\n//action:prompt_button Resume\n//action:prompt_show\n",
+                (_, false) => b"echo:busy: paused for user\n",
+                (_, true) => b"ok\n",
+            };
+            buf[..data.len()].copy_from_slice(data);
+            Ok(data.len())
+        }
+    }
+
+    impl Write for PromptPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let line = String::from_utf8_lossy(buf).trim_end().to_string();
+            self.answered |= line.starts_with("M876");
+            lock(&self.written).push(line);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_answer_prompt() {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let printer = Printer::serving(PromptPort {
+            written: written.clone(),
+            reads: 0,
+            answered: false,
+        });
+        let waiting = printer.clone();
+        let line = thread::spawn(move || waiting.send_line("M0"));
+
+        while printer.prompt().is_none() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(printer.answer_prompt(0).unwrap().buttons, vec!["Resume"]);
+        assert!(line.join().unwrap().is_ok());
+        // Written by the worker while M0 waited for its ok
+        let written = lock(&written);
+        assert!(written[0].starts_with("N1 M0*"));
+        assert_eq!(written[1..], ["M876 S0"]);
+        assert_eq!(
+            printer.answer_prompt(0).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn test_reconfigure_rollback() {
+        let config_file =
+            std::env::temp_dir().join(format!("xcontroller_rollback_{}.toml", std::process::id()));
+        let stored = "serial_port = \"/dev/xcontroller-missing-a\"\nbaud_rate = 115200\n";
+        fs::write(&config_file, stored).unwrap();
+        let printer = Printer::start(
+            "mk3",
+            SerialSettings {
+                serial_port: "/dev/xcontroller-missing-a".to_string(),
+                ..test_settings()
+            },
+            broadcast::channel(8).0,
+        );
+
+        let request = SerialConfigRequest {
+            serial_port: Some("/dev/xcontroller-missing-b".to_string()),
+            baud_rate: Some(250000),
+            timeouts: None,
+            persist: true,
+        };
+        let error =
+            apply_serial_config(&printer, request, config_file.to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("previous settings restored"));
+
+        // The worker keeps the previous settings and the file still holds them
+        let settings = printer.settings();
+        assert_eq!(settings.serial_port, "/dev/xcontroller-missing-a");
+        assert_eq!(settings.baud_rate, 115200);
+        assert_eq!(fs::read_to_string(&config_file).unwrap(), stored);
+        assert!(printer.port().is_none());
+        fs::remove_file(&config_file).unwrap();
+    }
+
+    #[test]
+    fn test_serial_config_while_printing() {
+        let written = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let printer = Printer::serving(OkPort::new(written.clone()));
+        printer.set_state(PrinterState::Printing, None);
+
+        let request = SerialConfigRequest {
+            serial_port: Some("/dev/xcontroller-missing".to_string()),
+            baud_rate: None,
+            timeouts: None,
+            persist: false,
+        };
+        let error = apply_serial_config(&printer, request, "unused.toml").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(printer.port(), Some(("/dev/null".to_string(), 115200)));
+        assert_eq!(printer.state(), PrinterState::Printing);
+    }
+
+    #[test]
+    fn test_data_dir() {
+        assert_eq!(
+            data_dir("./meshes", DEFAULT_PRINTER),
+            PathBuf::from("./meshes")
+        );
+        assert_eq!(data_dir("./meshes", "mk3"), PathBuf::from("./meshes/mk3"));
+    }
+}
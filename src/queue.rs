@@ -0,0 +1,698 @@
+use log::{error, info, warn};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+use crate::files::file_path;
+use crate::jobs::JobFile;
+use crate::parser::m105;
+use crate::printer::data_dir;
+use crate::state::{ManagedPrinter, ServerState};
+use crate::structs::{
+    Config, JobCheckpoint, JobState, JobStatus, MessageSender, PrinterState, QueueAction,
+    QueueItem, QueueRequest, QueueRun, QueueState,
+};
+use crate::wscom::json_response;
+
+static QUEUE_DIR: &str = "./queues";
+static QUEUE_FILE: &str = "queue.json";
+
+/**
+ * Files waiting to be printed on a printer
+ * The queue is written to disk on every change and restored on start
+ */
+pub struct PrintQueue {
+    printer: String,
+    path: PathBuf,
+    state: Mutex<QueueState>,
+    events: broadcast::Sender<MessageSender>,
+}
+
+impl PrintQueue {
+    /**
+     * Restore the queue of a printer, empty when it was never stored
+     * @param printer: &str, name of the printer
+     * @param events: broadcast::Sender<MessageSender>, channel receiving the QueueStatus changes
+     */
+    pub fn load(printer: &str, events: broadcast::Sender<MessageSender>) -> Self {
+        PrintQueue::open(
+            printer,
+            data_dir(QUEUE_DIR, printer).join(QUEUE_FILE),
+            events,
+        )
+    }
+
+    fn open(printer: &str, path: PathBuf, events: broadcast::Sender<MessageSender>) -> Self {
+        let state = match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_else(|e| {
+                error!("Ignoring the queue in {} | {}", path.display(), e);
+                QueueState::default()
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => QueueState::default(),
+            Err(e) => {
+                error!("Failed to read the queue in {} | {}", path.display(), e);
+                QueueState::default()
+            }
+        };
+        if !state.items.is_empty() {
+            info!(
+                "Queue of {} restored with {} files",
+                printer,
+                state.items.len()
+            );
+        }
+
+        PrintQueue {
+            printer: printer.to_string(),
+            path,
+            state: Mutex::new(state),
+            events,
+        }
+    }
+
+    pub fn status(&self) -> QueueState {
+        lock(&self.state).clone()
+    }
+
+    /**
+     * Change the queue, it is only kept when the change and the write to disk succeed
+     * @param change: FnOnce(&mut QueueState) -> Result<(), Error>
+     * @return Result<QueueState, Error>, queue after the change
+     */
+    fn update<F>(&self, change: F) -> Result<QueueState, Error>
+    where
+        F: FnOnce(&mut QueueState) -> Result<(), Error>,
+    {
+        self.update_then(change, || Ok(()))
+    }
+
+    /**
+     * Change the queue, then run an action once the change is stored
+     * The stored queue is restored when the action fails
+     * @param change: FnOnce(&mut QueueState) -> Result<(), Error>
+     * @param action: FnOnce() -> Result<(), Error>, run while the queue is locked
+     * @return Result<QueueState, Error>, queue after the change
+     */
+    fn update_then<F, A>(&self, change: F, action: A) -> Result<QueueState, Error>
+    where
+        F: FnOnce(&mut QueueState) -> Result<(), Error>,
+        A: FnOnce() -> Result<(), Error>,
+    {
+        let mut state = lock(&self.state);
+        let mut changed = state.clone();
+        change(&mut changed)?;
+        if changed == *state {
+            action()?;
+            return Ok(changed);
+        }
+
+        self.store(&changed)?;
+        if let Err(e) = action() {
+            if let Err(e) = self.store(&state) {
+                error!("Failed to restore the queue of {} | {}", self.printer, e);
+            }
+            return Err(e);
+        }
+        *state = changed.clone();
+        drop(state);
+
+        let mut message = json_response("QueueStatus", Ok(&changed));
+        message.printer = Some(self.printer.clone());
+        // No receiver only means that no client is connected
+        let _ = self.events.send(message);
+        Ok(changed)
+    }
+
+    fn store(&self, state: &QueueState) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_string(state).map_err(Error::other)?;
+        fs::write(&self.path, data)
+    }
+}
+
+/**
+ * Apply a change requested by a client
+ * @param managed: &ManagedPrinter, printer of the queue
+ * @param request: QueueRequest, action with its fields
+ * @param configuration: &Config, library and queue settings
+ * @param user: &str, name of the client, queued jobs are started in its name
+ * @return Result<QueueState, Error>, queue after the change
+ */
+pub async fn apply_request(
+    managed: &ManagedPrinter,
+    request: QueueRequest,
+    configuration: &Config,
+    user: &str,
+) -> Result<QueueState, Error> {
+    let jobs = &configuration.jobs;
+    if request.action == QueueAction::Add {
+        let file = request.file.as_deref().ok_or_else(|| missing("file"))?;
+        if !file_path(&jobs.files_dir, file)?.is_file() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("File {} not found", file),
+            ));
+        }
+    }
+
+    let job = managed.job.status();
+    let checkpoint = managed.job.checkpoint();
+    let state = managed.queue.update(|state| {
+        // The job started by the queue may have finished since the last check
+        settle(
+            state,
+            job.as_ref(),
+            checkpoint.as_ref(),
+            configuration.queue.bed_clear,
+        );
+
+        match request.action {
+            QueueAction::Add => add_item(
+                state,
+                request.file.as_deref().unwrap_or_default(),
+                request.copies.unwrap_or(1),
+                user,
+            ),
+            QueueAction::Remove => {
+                let index = item_index(state, request.id)?;
+                state.items.remove(index);
+                Ok(())
+            }
+            QueueAction::Move => move_item(
+                state,
+                request.id,
+                request.position.ok_or_else(|| missing("position"))?,
+            ),
+            QueueAction::Copies => set_copies(
+                state,
+                request.id,
+                request.copies.ok_or_else(|| missing("copies"))?,
+            ),
+            QueueAction::Confirm => {
+                state.awaiting_clear = false;
+                Ok(())
+            }
+            // Started once the file is read, outside of the queue lock
+            QueueAction::Start => next_item(state).map(drop),
+            QueueAction::Pause => {
+                state.paused = true;
+                Ok(())
+            }
+            QueueAction::Resume => {
+                state.paused = false;
+                state.reason = None;
+                Ok(())
+            }
+        }
+    })?;
+
+    if request.action != QueueAction::Start {
+        return Ok(state);
+    }
+    start_next(managed, configuration, true).await?;
+    Ok(managed.queue.status())
+}
+
+/**
+ * Start the queued jobs of every printer while auto start is enabled
+ * The completed copies are counted even without auto start
+ * @param configuration: Config, queue settings
+ * @param state: Arc<ServerState>, printers with their queue
+ */
+pub async fn run_queue(configuration: Config, state: Arc<ServerState>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(configuration.queue.check_secs));
+    loop {
+        interval.tick().await;
+        for managed in &state.printers {
+            advance(managed, &configuration).await;
+        }
+    }
+}
+
+/// Count the finished job of the queue, then start the next one when the printer is ready
+async fn advance(managed: &ManagedPrinter, configuration: &Config) {
+    let settings = &configuration.queue;
+    let job = managed.job.status();
+    let checkpoint = managed.job.checkpoint();
+    if let Err(e) = managed.queue.update(|state| {
+        settle(state, job.as_ref(), checkpoint.as_ref(), settings.bed_clear);
+        Ok(())
+    }) {
+        error!("Failed to store the queue of {} | {}", managed.name, e);
+    }
+
+    // An interrupted job waits for a client to resume or discard it
+    if !settings.auto_start || !ready(&managed.queue.status()) || checkpoint.is_some() {
+        return;
+    }
+    let idle = job
+        .as_ref()
+        .is_none_or(|job| !matches!(job.state, JobState::Printing | JobState::Paused));
+    if !idle || managed.printer.state() != PrinterState::Operational {
+        return;
+    }
+
+    if let Some(max_bed_temp) = settings.max_bed_temp {
+        match managed.printer.send_async("M105").await {
+            Ok(response) if m105(response.clone()).bed <= max_bed_temp => {}
+            _ => return,
+        }
+    }
+
+    match start_next(managed, configuration, false).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::Interrupted => {
+            info!("Queue of {} not started | {}", managed.name, e)
+        }
+        Err(e) => {
+            warn!("Queue of {} paused | {}", managed.name, e);
+            let _ = managed.queue.update(|state| {
+                pause(state, e.to_string());
+                Ok(())
+            });
+        }
+    }
+}
+
+fn ready(state: &QueueState) -> bool {
+    !state.paused && !state.awaiting_clear && state.current.is_none() && !state.items.is_empty()
+}
+
+/// Item started next, refused while a job of the queue runs
+fn next_item(state: &QueueState) -> Result<&QueueItem, Error> {
+    if state.current.is_some() {
+        return Err(Error::new(
+            ErrorKind::ResourceBusy,
+            "A job of the queue is running",
+        ));
+    }
+    state
+        .items
+        .first()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Queue is empty"))
+}
+
+/**
+ * Start the first item, the queue follows the job until it finishes
+ * The run is stored before the job starts and removed again when the job can't start
+ * @param managed: &ManagedPrinter, printer of the queue
+ * @param configuration: &Config, library of the files
+ * @param resume: bool, resume a paused queue, otherwise it has to be ready
+ * @return Result<(), Error>, Interrupted when the queue changed while the file was read
+ */
+async fn start_next(
+    managed: &ManagedPrinter,
+    configuration: &Config,
+    resume: bool,
+) -> Result<(), Error> {
+    let item = next_item(&managed.queue.status())?.clone();
+    let jobs = &configuration.jobs;
+    let job_file = JobFile::read(file_path(&jobs.files_dir, &item.file)?).await?;
+    let run_id = rand::random();
+
+    managed.queue.update_then(
+        |state| {
+            if next_item(state)?.id != item.id || !(resume || ready(state)) {
+                return Err(Error::new(
+                    ErrorKind::Interrupted,
+                    "Queue changed while the file was read",
+                ));
+            }
+            state.paused = false;
+            state.reason = None;
+            state.awaiting_clear = false;
+            state.current = Some(QueueRun {
+                id: item.id,
+                run_id,
+            });
+            Ok(())
+        },
+        || {
+            managed
+                .job
+                .start_run(
+                    &managed.printer,
+                    job_file,
+                    jobs.cancel_commands.clone(),
+                    &item.added_by,
+                    run_id,
+                )
+                .map(drop)
+        },
+    )?;
+    info!(
+        "Queue of {} starts {} copy {}/{}",
+        managed.name,
+        item.file,
+        item.printed + 1,
+        item.copies
+    );
+    Ok(())
+}
+
+/**
+ * Follow the job started by the queue
+ * A completed copy is counted, any other end pauses the queue
+ * A run left with a checkpoint is followed until the resumed job ends or the checkpoint is discarded
+ * @param state: &mut QueueState
+ * @param job: Option<&JobStatus>, current or last job of the printer
+ * @param checkpoint: Option<&JobCheckpoint>, interrupted job of the printer
+ * @param bed_clear: bool, wait for a confirmation after a completed job
+ */
+fn settle(
+    state: &mut QueueState,
+    job: Option<&JobStatus>,
+    checkpoint: Option<&JobCheckpoint>,
+    bed_clear: bool,
+) {
+    let Some(run) = state.current else {
+        return;
+    };
+    let resumable = checkpoint.is_some_and(|checkpoint| checkpoint.run_id == run.run_id);
+
+    match job.filter(|job| job.run_id == run.run_id) {
+        Some(job) if matches!(job.state, JobState::Printing | JobState::Paused) => return,
+        Some(job) if job.state == JobState::Completed => {
+            if let Some(index) = state.items.iter().position(|item| item.id == run.id) {
+                let item = &mut state.items[index];
+                item.printed += 1;
+                if item.printed >= item.copies {
+                    state.items.remove(index);
+                }
+            }
+            state.awaiting_clear = bed_clear;
+        }
+        _ if resumable => return,
+        Some(job) => pause(state, format!("Job {} {:?}", job.file, job.state)),
+        // A restart or another client replaced the job
+        None => pause(state, "Job of the queue was interrupted".to_string()),
+    }
+    state.current = None;
+}
+
+fn pause(state: &mut QueueState, reason: String) {
+    state.paused = true;
+    state.reason = Some(reason);
+}
+
+fn add_item(state: &mut QueueState, file: &str, copies: u32, user: &str) -> Result<(), Error> {
+    if copies == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "copies must be at least 1",
+        ));
+    }
+
+    state.items.push(QueueItem {
+        id: state.next_id,
+        file: file.to_string(),
+        copies,
+        printed: 0,
+        added: now(),
+        added_by: user.to_string(),
+    });
+    state.next_id += 1;
+    Ok(())
+}
+
+fn move_item(state: &mut QueueState, id: Option<u64>, position: usize) -> Result<(), Error> {
+    let index = item_index(state, id)?;
+    let item = state.items.remove(index);
+    let position = position.min(state.items.len());
+    state.items.insert(position, item);
+    Ok(())
+}
+
+fn set_copies(state: &mut QueueState, id: Option<u64>, copies: u32) -> Result<(), Error> {
+    let index = item_index(state, id)?;
+    let item = &mut state.items[index];
+    if copies <= item.printed {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("copies must be above the {} already printed", item.printed),
+        ));
+    }
+    item.copies = copies;
+    Ok(())
+}
+
+fn item_index(state: &QueueState, id: Option<u64>) -> Result<usize, Error> {
+    let id = id.ok_or_else(|| missing("id"))?;
+    state
+        .items
+        .iter()
+        .position(|item| item.id == id)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("No queued item {}", id)))
+}
+
+fn missing(field: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, format!("{} is required", field))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobRunner;
+    use crate::printer::{OkPort, Printer};
+    use crate::recovery::CheckpointStore;
+    use crate::structs::{ActionsConfig, GcodeState, HistoryConfig, JobsConfig};
+    use sha2::{Digest, Sha256};
+    use std::sync::atomic::AtomicUsize;
+
+    fn job(state: JobState, run_id: u64) -> JobStatus {
+        JobStatus {
+            file: "clip.gcode".to_string(),
+            state,
+            position: 0,
+            size: 100,
+            progress: 0.0,
+            lines_sent: 0,
+            run_id,
+            started: 100,
+            finished: None,
+            error: None,
+            hash: String::new(),
+            filament_used: 0.0,
+            started_by: "night".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_queue_changes() {
+        let dir = std::env::temp_dir().join(format!("xcontroller_queue_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join(QUEUE_FILE);
+        let queue = PrintQueue::open("mk3", path.clone(), broadcast::channel(8).0);
+
+        queue
+            .update(|state| {
+                add_item(state, "clip.gcode", 4, "night")?;
+                add_item(state, "hook.gcode", 1, "night")?;
+                add_item(state, "gear.gcode", 2, "night")
+            })
+            .unwrap();
+        queue.update(|state| move_item(state, Some(3), 0)).unwrap();
+        queue.update(|state| set_copies(state, Some(1), 6)).unwrap();
+        queue
+            .update(|state| {
+                let index = item_index(state, Some(2))?;
+                state.items.remove(index);
+                Ok(())
+            })
+            .unwrap();
+
+        assert!(queue
+            .update(|state| add_item(state, "x.gcode", 0, ""))
+            .is_err());
+        assert!(queue.update(|state| move_item(state, Some(9), 0)).is_err());
+
+        // The queue survives a restart
+        let restored = PrintQueue::open("mk3", path, broadcast::channel(8).0).status();
+        let files: Vec<_> = restored
+            .items
+            .iter()
+            .map(|item| item.file.as_str())
+            .collect();
+        assert_eq!(files, vec!["gear.gcode", "clip.gcode"]);
+        assert_eq!(restored.items[1].copies, 6);
+        assert_eq!(restored.next_id, 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_update_then() {
+        let dir = std::env::temp_dir().join(format!("xcontroller_run_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join(QUEUE_FILE);
+        let queue = PrintQueue::open("mk3", path.clone(), broadcast::channel(8).0);
+        queue
+            .update(|state| add_item(state, "clip.gcode", 2, "night"))
+            .unwrap();
+
+        let run = |state: &mut QueueState| {
+            let id = next_item(state)?.id;
+            state.current = Some(QueueRun { id, run_id: 7 });
+            Ok(())
+        };
+        // A job that can't start leaves the queue as it was, on disk as well
+        let error = queue
+            .update_then(run, || Err(Error::new(ErrorKind::ResourceBusy, "busy")))
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ResourceBusy);
+        assert!(queue.status().current.is_none());
+        let restored = PrintQueue::open("mk3", path.clone(), broadcast::channel(8).0);
+        assert!(restored.status().current.is_none());
+
+        queue.update_then(run, || Ok(())).unwrap();
+        let restored = PrintQueue::open("mk3", path, broadcast::channel(8).0);
+        assert_eq!(restored.status().current.unwrap().run_id, 7);
+        assert!(next_item(&restored.status()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_settle() {
+        let mut state = QueueState::default();
+        add_item(&mut state, "clip.gcode", 2, "night").unwrap();
+        state.current = Some(QueueRun { id: 1, run_id: 100 });
+
+        settle(&mut state, Some(&job(JobState::Printing, 100)), None, true);
+        assert!(state.current.is_some());
+
+        settle(&mut state, Some(&job(JobState::Completed, 100)), None, true);
+        assert_eq!(state.items[0].printed, 1);
+        assert!(state.awaiting_clear);
+        assert!(!ready(&state));
+
+        state.awaiting_clear = false;
+        state.current = Some(QueueRun { id: 1, run_id: 200 });
+        settle(
+            &mut state,
+            Some(&job(JobState::Completed, 200)),
+            None,
+            false,
+        );
+        assert!(state.items.is_empty());
+
+        // Another job started within the same second is not the one of the queue
+        state.current = Some(QueueRun { id: 1, run_id: 250 });
+        settle(
+            &mut state,
+            Some(&job(JobState::Completed, 200)),
+            None,
+            false,
+        );
+        assert!(state.paused);
+        state.paused = false;
+
+        add_item(&mut state, "clip.gcode", 1, "night").unwrap();
+        state.current = Some(QueueRun { id: 2, run_id: 300 });
+        settle(&mut state, Some(&job(JobState::Failed, 300)), None, false);
+        assert!(state.paused);
+        assert_eq!(state.reason.as_deref(), Some("Job clip.gcode Failed"));
+        assert_eq!(state.items[0].printed, 0);
+
+        // After a restart the runner has no job
+        state.current = Some(QueueRun { id: 2, run_id: 400 });
+        settle(&mut state, None, None, false);
+        assert!(state.current.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resumed_run_counted_once() {
+        let dir = std::env::temp_dir().join(format!("xcontroller_resumed_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let gcode = "G1 X1 Y1\n".repeat(20);
+        fs::write(dir.join("clip.gcode"), &gcode).unwrap();
+
+        // The host restarted in the middle of the first copy
+        let checkpoint = JobCheckpoint {
+            file: "clip.gcode".to_string(),
+            hash: format!("{:x}", Sha256::digest(gcode.as_bytes())),
+            position: 90,
+            line: 10,
+            lines_sent: 10,
+            size: gcode.len() as u64,
+            started: 100,
+            started_by: "night".to_string(),
+            gcode: GcodeState::default(),
+            saved: 200,
+            cancelled_objects: Vec::new(),
+            current_object: None,
+            run_id: 7,
+        };
+        let store = CheckpointStore::at(dir.join("checkpoint.json"), Duration::from_secs(60));
+        store.save(&checkpoint).unwrap();
+        let queue = PrintQueue::open("mk3", dir.join(QUEUE_FILE), broadcast::channel(8).0);
+        queue
+            .update(|state| {
+                add_item(state, "clip.gcode", 2, "night")?;
+                state.current = Some(QueueRun { id: 1, run_id: 7 });
+                Ok(())
+            })
+            .unwrap();
+
+        let configuration = Config {
+            jobs: JobsConfig {
+                files_dir: dir.to_string_lossy().to_string(),
+                ..JobsConfig::default()
+            },
+            history: HistoryConfig {
+                enabled: false,
+                ..HistoryConfig::default()
+            },
+            ..Config::default()
+        };
+        let mut state = ServerState::new(&configuration);
+        let managed = &mut state.printers[0];
+        managed.printer = Printer::serving(OkPort::new(Arc::new(AtomicUsize::new(0))));
+        managed.job = JobRunner::new(
+            "mk3",
+            broadcast::channel(64).0,
+            None,
+            Some(store),
+            ActionsConfig::default(),
+        );
+        managed.queue = queue;
+
+        // The run is kept while its checkpoint waits
+        advance(managed, &configuration).await;
+        let queue = managed.queue.status();
+        assert_eq!(queue.current.map(|run| run.run_id), Some(7));
+        assert!(!queue.paused);
+
+        let job_file = JobFile::read(dir.join("clip.gcode")).await.unwrap();
+        managed
+            .job
+            .recover(&managed.printer, job_file, Vec::new(), 2.0)
+            .unwrap();
+        while managed.job.status().unwrap().finished.is_none() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(managed.job.status().unwrap().state, JobState::Completed);
+
+        advance(managed, &configuration).await;
+        advance(managed, &configuration).await;
+        let queue = managed.queue.status();
+        assert!(queue.current.is_none());
+        assert_eq!(queue.items[0].printed, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
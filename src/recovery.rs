@@ -0,0 +1,325 @@
+use log::{info, warn};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::gcode::parse_gcode;
+use crate::printer::data_dir;
+use crate::structs::{GcodeState, JobCheckpoint, RecoveryConfig};
+
+static CHECKPOINT_DIR: &str = "./checkpoints";
+static CHECKPOINT_FILE: &str = "checkpoint.json";
+// Feedrate of the Z moves around the homing, mm/min
+static Z_FEEDRATE: u32 = 600;
+// Feedrate of the move back above the part, mm/min
+static TRAVEL_FEEDRATE: u32 = 6000;
+
+/**
+ * Checkpoint file of a printer, one job at a time
+ */
+#[derive(Debug, Clone)]
+pub struct CheckpointStore {
+    path: PathBuf,
+    pub interval: Duration,
+}
+
+impl CheckpointStore {
+    /**
+     * @param printer: &str, name of the printer
+     * @param recovery: &RecoveryConfig, checkpoint interval
+     * @return Option<CheckpointStore>, None when recovery is disabled
+     */
+    pub fn for_printer(printer: &str, recovery: &RecoveryConfig) -> Option<Self> {
+        recovery.enabled.then(|| CheckpointStore {
+            path: data_dir(CHECKPOINT_DIR, printer).join(CHECKPOINT_FILE),
+            interval: Duration::from_secs(recovery.interval_secs),
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn at(path: PathBuf, interval: Duration) -> Self {
+        CheckpointStore { path, interval }
+    }
+
+    /// Checkpoint left by a job that did not finish, None when there is none or it is unreadable
+    pub fn load(&self) -> Option<JobCheckpoint> {
+        let data = match fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Failed to read {} | {}", self.path.display(), e);
+                return None;
+            }
+        };
+
+        match serde_json::from_str::<JobCheckpoint>(&data) {
+            Ok(checkpoint) => {
+                warn!(
+                    "Job {} was interrupted at {} of {} bytes, it can be resumed",
+                    checkpoint.file, checkpoint.position, checkpoint.size
+                );
+                Some(checkpoint)
+            }
+            Err(e) => {
+                warn!("Ignoring the checkpoint {} | {}", self.path.display(), e);
+                None
+            }
+        }
+    }
+
+    /**
+     * Replace the checkpoint
+     * The file is written next to it and renamed, a power loss leaves the previous checkpoint
+     * @param checkpoint: &JobCheckpoint
+     * @return Result<(), Error>
+     */
+    pub fn save(&self, checkpoint: &JobCheckpoint) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        let data = serde_json::to_string(checkpoint).map_err(Error::other)?;
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &self.path)
+    }
+
+    pub fn clear(&self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => info!("Removed the checkpoint {}", self.path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {} | {}", self.path.display(), e),
+        }
+    }
+}
+
+impl GcodeState {
+    /**
+     * Follow the modes and targets set by a command sent to the printer
+     * @param command: &str, line without comment
+     */
+    pub fn track(&mut self, command: &str) {
+        let Ok(command) = parse_gcode(command) else {
+            return;
+        };
+
+        match (command.letter, command.number) {
+            ('G', 0..=3) => {
+                if let Some(feedrate) = command.param('F') {
+                    self.feedrate = Some(feedrate);
+                }
+                if let Some(x) = command.param('X') {
+                    self.x = if self.relative_moves { self.x + x } else { x };
+                }
+                if let Some(y) = command.param('Y') {
+                    self.y = if self.relative_moves { self.y + y } else { y };
+                }
+                if let Some(z) = command.param('Z') {
+                    self.z = if self.relative_moves { self.z + z } else { z };
+                }
+                if let Some(e) = command.param('E') {
+                    let delta = if self.relative_extrusion {
+                        e
+                    } else {
+                        e - self.e
+                    };
+                    self.e += delta;
+                    self.filament_used += delta;
+                }
+            }
+            ('G', 92) => {
+                if let Some(x) = command.param('X') {
+                    self.x = x;
+                }
+                if let Some(y) = command.param('Y') {
+                    self.y = y;
+                }
+                if let Some(z) = command.param('Z') {
+                    self.z = z;
+                }
+                if let Some(e) = command.param('E') {
+                    self.e = e;
+                }
+            }
+            ('G', 90) => {
+                self.relative_moves = false;
+                self.relative_extrusion = false;
+            }
+            ('G', 91) => {
+                self.relative_moves = true;
+                self.relative_extrusion = true;
+            }
+            ('M', 82) => self.relative_extrusion = false,
+            ('M', 83) => self.relative_extrusion = true,
+            ('M', 104) | ('M', 109) => {
+                if let Some(target) = command.param('S').or(command.param('R')) {
+                    self.hotend_target = target;
+                }
+            }
+            ('M', 140) | ('M', 190) => {
+                if let Some(target) = command.param('S').or(command.param('R')) {
+                    self.bed_target = target;
+                }
+            }
+            ('M', 106) => {
+                self.fan_speed = command.param('S').unwrap_or(255.0).clamp(0.0, 255.0) as u8;
+            }
+            ('M', 107) => self.fan_speed = 0,
+            _ => {}
+        }
+    }
+}
+
+/**
+ * Commands bringing the printer back to the state of a checkpoint
+ * The nozzle is assumed at the Z of the checkpoint, steppers keep their place without power
+ * After homing X and Y the nozzle travels back above the last position before it is lowered
+ * @param checkpoint: &JobCheckpoint
+ * @param lift_mm: f64, raised before X and Y are homed
+ * @return Vec<String>, sent before the streaming resumes
+ */
+pub fn resume_commands(checkpoint: &JobCheckpoint, lift_mm: f64) -> Vec<String> {
+    let gcode = &checkpoint.gcode;
+    let mut commands = Vec::new();
+
+    // The bed heats while the hotend is waited for, the hotend last so it oozes less
+    if gcode.bed_target > 0.0 {
+        commands.push(format!("M140 S{}", gcode.bed_target));
+    }
+    if gcode.hotend_target > 0.0 {
+        commands.push(format!("M104 S{}", gcode.hotend_target));
+    }
+    if gcode.bed_target > 0.0 {
+        commands.push(format!("M190 S{}", gcode.bed_target));
+    }
+    if gcode.hotend_target > 0.0 {
+        commands.push(format!("M109 S{}", gcode.hotend_target));
+    }
+
+    commands.extend([
+        format!("G92 Z{}", gcode.z),
+        "G91".to_string(),
+        format!("G1 Z{} F{}", lift_mm, Z_FEEDRATE),
+        "G90".to_string(),
+        "G28 X Y".to_string(),
+        format!("G0 X{} Y{} F{}", gcode.x, gcode.y, TRAVEL_FEEDRATE),
+        format!("G1 Z{} F{}", gcode.z, Z_FEEDRATE),
+    ]);
+
+    // G90 and G91 also set the extrusion mode, M82 and M83 come after them
+    if gcode.relative_moves {
+        commands.push("G91".to_string());
+    }
+    commands.push(
+        if gcode.relative_extrusion {
+            "M83"
+        } else {
+            "M82"
+        }
+        .to_string(),
+    );
+    commands.push(format!("G92 E{}", gcode.e));
+    commands.push(match gcode.fan_speed {
+        0 => "M107".to_string(),
+        speed => format!("M106 S{}", speed),
+    });
+    if let Some(feedrate) = gcode.feedrate {
+        commands.push(format!("G1 F{}", feedrate));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track() {
+        let mut gcode = GcodeState::default();
+        for command in [
+            "M140 S60",
+            "M109 S215",
+            "G92 E0",
+            "G1 Z0.2 F3000",
+            "G1 X10 E5",
+            "G1 E3",
+            "G1 E8",
+            "M83",
+            "G1 E2",
+            "G1 E-1",
+            "M106",
+            "G91",
+            "G1 Z0.2",
+        ] {
+            gcode.track(command);
+        }
+
+        // Retractions cancel out with the following prime
+        assert_eq!(gcode.filament_used, 9.0);
+        assert_eq!(gcode.hotend_target, 215.0);
+        assert_eq!(gcode.bed_target, 60.0);
+        assert_eq!(gcode.fan_speed, 255);
+        assert_eq!(gcode.feedrate, Some(3000.0));
+        assert!((gcode.z - 0.4).abs() < 1e-9);
+        assert_eq!(gcode.x, 10.0);
+        assert!(gcode.relative_extrusion);
+
+        gcode.track("G90");
+        gcode.track("G92 E0");
+        gcode.track("G1 E4");
+        gcode.track("M107");
+        assert_eq!(gcode.filament_used, 13.0);
+        assert_eq!(gcode.e, 4.0);
+        assert_eq!(gcode.fan_speed, 0);
+    }
+
+    #[test]
+    fn test_resume_commands() {
+        let checkpoint = JobCheckpoint {
+            file: "cube.gcode".to_string(),
+            hash: String::new(),
+            position: 1200,
+            line: 80,
+            lines_sent: 60,
+            size: 5000,
+            started: 0,
+            started_by: "anonymous".to_string(),
+            gcode: GcodeState {
+                x: 42.5,
+                y: 60.0,
+                z: 1.4,
+                e: 12.5,
+                hotend_target: 215.0,
+                bed_target: 60.0,
+                fan_speed: 128,
+                feedrate: Some(1800.0),
+                ..GcodeState::default()
+            },
+            saved: 0,
+            cancelled_objects: Vec::new(),
+            current_object: None,
+            run_id: 1,
+        };
+
+        assert_eq!(
+            resume_commands(&checkpoint, 2.0),
+            vec![
+                "M140 S60",
+                "M104 S215",
+                "M190 S60",
+                "M109 S215",
+                "G92 Z1.4",
+                "G91",
+                "G1 Z2 F600",
+                "G90",
+                "G28 X Y",
+                "G0 X42.5 Y60 F6000",
+                "G1 Z1.4 F600",
+                "M82",
+                "G92 E12.5",
+                "M106 S128",
+                "G1 F1800",
+            ]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use log::error;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::parser::{m105, m114, m115, m119, m20, m27, m31, m33, m420, m503};
+
+/// Command letter and number used to look up a parser, e.g. ('M', 105)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CommandKey {
+    pub letter: char,
+    pub number: u32,
+}
+
+impl CommandKey {
+    /**
+     * Extract the key and the parameters of a command
+     * "m105", "M105 " and "M105 S1" all resolve to ('M', 105)
+     * @param cmd: &str, command sent to the printer
+     * @return Option<(CommandKey, Vec<String>)>, key and parameters
+     */
+    pub fn parse(cmd: &str) -> Option<(CommandKey, Vec<String>)> {
+        let cmd = cmd.trim();
+        let mut chars = cmd.chars();
+        let letter = chars.next()?.to_ascii_uppercase();
+        let rest = chars.as_str();
+
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let number = rest[..digits].parse::<u32>().ok()?;
+
+        // Skip subcodes such as G38.2, parameters start after them
+        let params = rest[digits..]
+            .trim_start_matches(|c: char| c == '.' || c.is_ascii_digit())
+            .split_whitespace()
+            .map(|param| param.to_string())
+            .collect();
+
+        Some((CommandKey { letter, number }, params))
+    }
+}
+
+/// Turn the firmware response of a command into a serializable value
+pub trait ResponseParser: Send + Sync {
+    /**
+     * @param params: &[String], parameters of the command, e.g. ["C"] for "M27 C"
+     * @param response: String, return message from firmware
+     * @return Option<Value>, None when the response is not handled for these parameters
+     */
+    fn parse(&self, params: &[String], response: String) -> Option<Value>;
+}
+
+/// Parser that only runs when the command carries a given parameter letter
+struct WithParam<T> {
+    param: char,
+    parser: fn(String) -> T,
+}
+
+impl<T: Serialize> ResponseParser for fn(String) -> T {
+    fn parse(&self, _params: &[String], response: String) -> Option<Value> {
+        to_value(self(response))
+    }
+}
+
+impl<T: Serialize> ResponseParser for WithParam<T> {
+    fn parse(&self, params: &[String], response: String) -> Option<Value> {
+        if params
+            .iter()
+            .any(|param| param.to_ascii_uppercase().starts_with(self.param))
+        {
+            to_value((self.parser)(response))
+        } else {
+            None
+        }
+    }
+}
+
+fn to_value<T: Serialize>(value: T) -> Option<Value> {
+    match serde_json::to_value(value) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            error!("Failed to serialize parsed response | {}", e);
+            None
+        }
+    }
+}
+
+/// Parsers registered by command key
+#[derive(Default)]
+pub struct ParserRegistry {
+    parsers: HashMap<CommandKey, Box<dyn ResponseParser>>,
+}
+
+impl ParserRegistry {
+    /// Registry with the parsers of parser.rs
+    pub fn with_defaults() -> Self {
+        let mut registry = ParserRegistry::default();
+
+        registry.register('M', 20, m20);
+        registry.register('M', 27, m27);
+        registry.register('M', 31, m31);
+        registry.register('M', 33, m33);
+        registry.register('M', 105, m105);
+        registry.register('M', 114, m114);
+        registry.register('M', 115, m115);
+        registry.register('M', 119, m119);
+        registry.register('M', 503, m503);
+        registry.register_with_param('M', 420, 'V', m420);
+        registry.register_with_param('G', 29, 'T', m420);
+
+        registry
+    }
+
+    /// Register a parser function for every use of the command
+    pub fn register<T: Serialize + 'static>(
+        &mut self,
+        letter: char,
+        number: u32,
+        parser: fn(String) -> T,
+    ) {
+        self.register_parser(letter, number, Box::new(parser));
+    }
+
+    /// Register a parser function used only when the command has the given parameter
+    pub fn register_with_param<T: Serialize + 'static>(
+        &mut self,
+        letter: char,
+        number: u32,
+        param: char,
+        parser: fn(String) -> T,
+    ) {
+        self.register_parser(letter, number, Box::new(WithParam { param, parser }));
+    }
+
+    pub fn register_parser(&mut self, letter: char, number: u32, parser: Box<dyn ResponseParser>) {
+        self.parsers.insert(
+            CommandKey {
+                letter: letter.to_ascii_uppercase(),
+                number,
+            },
+            parser,
+        );
+    }
+
+    /**
+     * Parse the response of a command with the matching parser
+     * @param cmd: &str, command sent to the printer
+     * @param response: String, return message from firmware
+     * @return Option<Value>, None when no parser handles the command
+     */
+    pub fn parse(&self, cmd: &str, response: String) -> Option<Value> {
+        let (key, params) = CommandKey::parse(cmd)?;
+        self.parsers.get(&key)?.parse(&params, response)
+    }
+}
+
+/// Shared registry with the default parsers
+pub fn registry() -> &'static ParserRegistry {
+    static REGISTRY: OnceLock<ParserRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(ParserRegistry::with_defaults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_key_parse() {
+        let (key, params) = CommandKey::parse("m114 d").unwrap();
+        assert_eq!(
+            key,
+            CommandKey {
+                letter: 'M',
+                number: 114
+            }
+        );
+        assert_eq!(params, vec!["d"]);
+
+        let (key, params) = CommandKey::parse("G38.2 Z-10").unwrap();
+        assert_eq!(
+            key,
+            CommandKey {
+                letter: 'G',
+                number: 38
+            }
+        );
+        assert_eq!(params, vec!["Z-10"]);
+
+        assert!(CommandKey::parse("").is_none());
+        assert!(CommandKey::parse("MX").is_none());
+    }
+
+    #[test]
+    fn test_registry_parameters_and_case() {
+        let response = "ok T:185.4 /200.0 B:55.2 /60.0 @:127 B@:0".to_string();
+
+        let value = registry().parse("m105 ", response.clone()).unwrap();
+        assert_eq!(value["e0"], 185);
+
+        let value = registry().parse("M105 S1", response).unwrap();
+        assert_eq!(value["bed_set"], 60);
+    }
+
+    #[test]
+    fn test_registry_required_param() {
+        let response = "Bilinear Leveling Grid:\n 0 +0.100 +0.200\nok".to_string();
+
+        assert!(registry().parse("M420 S1", response.clone()).is_none());
+        assert!(registry().parse("m420 v1", response).is_some());
+    }
+
+    #[test]
+    fn test_registry_unknown_command() {
+        assert!(registry().parse("G28", "ok".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_registry_custom_parser() {
+        struct Echo;
+        impl ResponseParser for Echo {
+            fn parse(&self, params: &[String], response: String) -> Option<Value> {
+                Some(Value::String(format!("{}|{}", params.join(","), response)))
+            }
+        }
+
+        let mut registry = ParserRegistry::default();
+        registry.register_parser('m', 118, Box::new(Echo));
+
+        let value = registry.parse("M118 A1 hello", "ok".to_string()).unwrap();
+        assert_eq!(value, "A1,hello|ok");
+    }
+}
@@ -0,0 +1,803 @@
+use axum::body::{Body, Bytes};
+use axum::extract::connect_info::Connected;
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Multipart, Path, Query, RawQuery, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::serve::IncomingStream;
+use axum::{Json, Router};
+use futures::{Stream, StreamExt};
+use log::{error, info, warn};
+use std::collections::BTreeMap;
+use std::io::{self, Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_rustls::TlsAcceptor;
+
+use crate::access::check_ip;
+use crate::auth::{authenticate, request_token, required_role, Role};
+use crate::commands::g_command;
+use crate::files::{complete_upload, delete_file, file_path, list_files, upload_paths};
+use crate::jobs::JobFile;
+use crate::metrics::render_metrics;
+use crate::octoprint::octoprint_routes;
+use crate::parser::{m105, m114};
+use crate::queue::apply_request;
+use crate::responses::registry;
+use crate::safety::audit_rejection;
+use crate::state::{ManagedPrinter, ServerState};
+use crate::structs::{
+    AccessConfig, AxePositions, CommandRequest, CommandResponse, Config, FileInfo, HistoryEntry,
+    HistoryFilter, HistoryStats, HostPrompt, JobAction, JobCheckpoint, JobRequest, JobStatus,
+    MessageType, ObjectCancelRequest, ObjectList, PrinterInfo, PrinterState, PrinterSummary,
+    PromptAnswer, QueueRequest, QueueState, Temperatures,
+};
+use crate::wscom::HANDSHAKE_TIMEOUT;
+
+// REST requests never hold the control lock, client ids start at 1
+static REST_CLIENT: u64 = 0;
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub configuration: Arc<Config>,
+    pub state: Arc<ServerState>,
+    /// File selected for the next OctoPrint job start, by printer
+    pub selected: Arc<Mutex<BTreeMap<String, String>>>,
+}
+
+/// Error answered with its HTTP status and a JSON body {"error": reason}
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    reason: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, reason: impl ToString) -> Self {
+        ApiError {
+            status,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        ApiError::new(status_for(error.kind()), error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            error!("REST request failed | {}", self.reason);
+        }
+        let body = serde_json::json!({ "error": self.reason });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+/**
+ * Serve the REST API on its own port
+ * The access lists and TLS settings of the WebSocket server apply
+ * @param configuration: Config, configuration of the server
+ * @param state: Arc<ServerState>, state shared with the WebSocket connections
+ * @param tls: Option<TlsAcceptor>, serve https:// when set
+ * @return Result<(), Error>, only returns when the listener fails
+ */
+pub async fn serve_rest(
+    configuration: Config,
+    state: Arc<ServerState>,
+    tls: Option<TlsAcceptor>,
+) -> Result<(), Error> {
+    let addr = format!("0.0.0.0:{}", configuration.http.port);
+    let listener = RestListener::new(
+        TcpListener::bind(&addr).await?,
+        configuration.access.clone(),
+        tls,
+    )?;
+    info!("REST API listening on {}", addr);
+
+    let body_limit = configuration.http.max_upload_mb * 1024 * 1024;
+    let api = ApiState {
+        configuration: Arc::new(configuration),
+        state,
+        selected: Arc::new(Mutex::new(BTreeMap::new())),
+    };
+    let app = router(api).layer(DefaultBodyLimit::max(body_limit));
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<RestPeer>(),
+    )
+    .await
+}
+
+fn router(api: ApiState) -> Router {
+    let mut router = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/api/printers", get(printers))
+        .route("/api/printers/{printer}", get(printer_status))
+        .route("/api/printers/{printer}/temperatures", get(temperatures))
+        .route("/api/printers/{printer}/position", get(position))
+        .route("/api/printers/{printer}/info", get(info))
+        .route("/api/printers/{printer}/command", post(command))
+        .route("/api/printers/{printer}/job", get(job).post(job_action))
+        .route(
+            "/api/printers/{printer}/queue",
+            get(queue).post(queue_action),
+        )
+        .route(
+            "/api/printers/{printer}/checkpoint",
+            get(checkpoint).post(recover).delete(discard_checkpoint),
+        )
+        .route(
+            "/api/printers/{printer}/prompt",
+            get(prompt).post(answer_prompt),
+        )
+        .route(
+            "/api/printers/{printer}/objects",
+            get(objects).post(cancel_object),
+        )
+        .route("/api/history", get(history))
+        .route("/api/history/stats", get(history_stats))
+        .route("/api/files", get(files).post(upload_form))
+        .route("/api/files/{name}", put(upload_raw).delete(remove_file));
+
+    // Slicers are pointed at the host for the only printer, or at /printers/<name>
+    if api.configuration.http.octoprint {
+        router = router
+            .merge(octoprint_routes())
+            .nest("/printers/{printer}", octoprint_routes());
+    }
+    router.with_state(api)
+}
+
+/// Prometheus metrics of every printer, "curl http://host:9003/metrics"
+async fn metrics(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Result<impl IntoResponse, ApiError> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_metrics(&api.state),
+    ))
+}
+
+async fn printers(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> ApiResult<Vec<PrinterSummary>> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    Ok(Json(api.state.printer_list()))
+}
+
+async fn printer_status(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<PrinterSummary> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    api.state.printer(Some(&printer))?;
+
+    let summary = api
+        .state
+        .printer_list()
+        .into_iter()
+        .find(|summary| summary.name == printer);
+    summary
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "Unknown printer"))
+}
+
+async fn temperatures(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<Temperatures> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    Ok(Json(m105(target.printer.send_async("M105").await?)))
+}
+
+async fn position(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<AxePositions> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    Ok(Json(m114(target.printer.send_async("M114").await?)))
+}
+
+async fn info(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<PrinterInfo> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    target.printer.info().map(Json).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Printer has not been connected yet",
+        )
+    })
+}
+
+async fn command(
+    State(api): State<ApiState>,
+    ConnectInfo(RestPeer(peer)): ConnectInfo<RestPeer>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+    Json(request): Json<CommandRequest>,
+) -> ApiResult<CommandResponse> {
+    let (_, role) = authenticate_request(&api, &headers, query.as_deref())?;
+    let command = g_command(&request.command)
+        .map_err(|e| ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e))?;
+
+    // Read only commands are allowed to viewers, like GCommand messages
+    let required = required_role(&MessageType::GCommand, Some(&command)).unwrap_or(Role::Operator);
+    require(role, required)?;
+
+    let target = api.state.printer(Some(&printer))?;
+    if required > Role::Viewer {
+        check_writable(target)?;
+    }
+
+    if let Err(e) = lock(&target.safety).check(&command, &target.printer.motion()) {
+        audit_rejection(peer, &request.command, &e.to_string());
+        return Err(ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, e));
+    }
+
+    let cmd = command.to_string();
+    let response = target.printer.send_async(&cmd).await?;
+    let parsed = registry().parse(&cmd, response.clone());
+
+    Ok(Json(CommandResponse {
+        command: cmd,
+        response,
+        parsed,
+    }))
+}
+
+async fn job(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<JobStatus> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    target
+        .job
+        .status()
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "No job has been started"))
+}
+
+async fn job_action(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+    Json(request): Json<JobRequest>,
+) -> ApiResult<JobStatus> {
+    let (user, _) = authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    let target = api.state.printer(Some(&printer))?;
+    check_writable(target)?;
+
+    let jobs = &api.configuration.jobs;
+    let status = match request.action {
+        JobAction::Start => {
+            let file = request
+                .file
+                .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "file is required"))?;
+            let job_file = JobFile::read(file_path(&jobs.files_dir, &file)?).await?;
+            target.job.start(
+                &target.printer,
+                job_file,
+                jobs.cancel_commands.clone(),
+                &user,
+            )?
+        }
+        JobAction::Pause => target.job.pause(&target.printer)?,
+        JobAction::Resume => target.job.resume(&target.printer)?,
+        JobAction::Cancel => target.job.cancel()?,
+    };
+
+    Ok(Json(status))
+}
+
+async fn checkpoint(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<JobCheckpoint> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    target
+        .job
+        .checkpoint()
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "No interrupted job"))
+}
+
+/// Resume the interrupted job, the printer heats up and homes X and Y first
+async fn recover(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<JobStatus> {
+    authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    let target = api.state.printer(Some(&printer))?;
+    check_writable(target)?;
+
+    let configuration = &api.configuration;
+    let path = target.job.checkpoint_file(&configuration.jobs.files_dir)?;
+    let job_file = JobFile::read(path).await?;
+    Ok(Json(target.job.recover(
+        &target.printer,
+        job_file,
+        configuration.jobs.cancel_commands.clone(),
+        configuration.recovery.lift_mm,
+    )?))
+}
+
+async fn discard_checkpoint(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<JobCheckpoint> {
+    authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    let target = api.state.printer(Some(&printer))?;
+    Ok(Json(target.job.discard_checkpoint()?))
+}
+
+async fn prompt(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<HostPrompt> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    target
+        .printer
+        .prompt()
+        .map(Json)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "No prompt is waiting"))
+}
+
+/// Send the chosen button of the prompt to the printer with M876
+async fn answer_prompt(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+    Json(answer): Json<PromptAnswer>,
+) -> ApiResult<HostPrompt> {
+    authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    let target = api.state.printer(Some(&printer))?;
+    check_writable(target)?;
+    Ok(Json(target.printer.answer_prompt(answer.choice)?))
+}
+
+async fn objects(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<ObjectList> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    Ok(Json(target.job.objects()))
+}
+
+/// Skip an object of the running job, the current one when no id is given
+async fn cancel_object(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+    Json(request): Json<ObjectCancelRequest>,
+) -> ApiResult<ObjectList> {
+    authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    let target = api.state.printer(Some(&printer))?;
+    check_writable(target)?;
+    Ok(Json(
+        target
+            .job
+            .cancel_object(&target.printer, request.id)
+            .await?,
+    ))
+}
+
+async fn queue(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+) -> ApiResult<QueueState> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    let target = api.state.printer(Some(&printer))?;
+    Ok(Json(target.queue.status()))
+}
+
+async fn queue_action(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(printer): Path<String>,
+    Json(request): Json<QueueRequest>,
+) -> ApiResult<QueueState> {
+    let (user, _) = authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    let target = api.state.printer(Some(&printer))?;
+    check_writable(target)?;
+    Ok(Json(
+        apply_request(target, request, &api.configuration, &user).await?,
+    ))
+}
+
+/// Finished jobs, filtered with ?printer=&file=&outcome=&since=&until=&limit=&offset=
+async fn history(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Query(filter): Query<HistoryFilter>,
+) -> ApiResult<Vec<HistoryEntry>> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    Ok(Json(api.state.history()?.query(&filter)?))
+}
+
+async fn history_stats(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Query(filter): Query<HistoryFilter>,
+) -> ApiResult<HistoryStats> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    Ok(Json(api.state.history()?.stats(&filter)?))
+}
+
+async fn files(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> ApiResult<Vec<FileInfo>> {
+    authorize(&api, &headers, query.as_deref(), Role::Viewer)?;
+    Ok(Json(list_files(&api.configuration.jobs.files_dir)?))
+}
+
+/// Upload with a multipart form, "curl -F file=@part.gcode"
+async fn upload_form(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<Vec<FileInfo>>), ApiError> {
+    authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+
+    let mut stored = Vec::new();
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?
+    {
+        let Some(name) = field.file_name().map(|name| name.to_string()) else {
+            continue;
+        };
+        stored.push(store_upload(&api.configuration.jobs.files_dir, &name, field).await?);
+    }
+
+    if stored.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "No file in the form",
+        ));
+    }
+    Ok((StatusCode::CREATED, Json(stored)))
+}
+
+/// Upload the raw body, "curl -T part.gcode"
+async fn upload_raw(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(name): Path<String>,
+    body: Body,
+) -> Result<(StatusCode, Json<FileInfo>), ApiError> {
+    authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    let info = store_upload(
+        &api.configuration.jobs.files_dir,
+        &name,
+        body.into_data_stream(),
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn remove_file(
+    State(api): State<ApiState>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    authorize(&api, &headers, query.as_deref(), Role::Operator)?;
+    delete_file(&api.configuration.jobs.files_dir, &name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/**
+ * Write an upload to the library, a failed upload leaves no file behind
+ * @param dir: &str, directory of the library
+ * @param name: &str, file name
+ * @param stream: S, chunks of the body
+ * @return Result<FileInfo, ApiError>
+ */
+pub async fn store_upload<S, E>(dir: &str, name: &str, mut stream: S) -> Result<FileInfo, ApiError>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    let (temporary, path) = upload_paths(dir, name)?;
+    let mut file = tokio::fs::File::create(&temporary).await?;
+
+    while let Some(chunk) = stream.next().await {
+        let written = match chunk {
+            Ok(chunk) => file.write_all(&chunk).await,
+            Err(e) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Upload failed | {}", e),
+            )),
+        };
+        if let Err(e) = written {
+            let _ = tokio::fs::remove_file(&temporary).await;
+            return Err(e.into());
+        }
+    }
+    file.flush().await?;
+
+    Ok(complete_upload(&temporary, &path)?)
+}
+
+/**
+ * Authenticate a request and check its role
+ * @return Result<(String, Role), ApiError>, name and role of the token, 401 without a valid token, 403 when the role is too low
+ */
+fn authorize(
+    api: &ApiState,
+    headers: &HeaderMap,
+    query: Option<&str>,
+    required: Role,
+) -> Result<(String, Role), ApiError> {
+    authorize_token(api, request_token(headers, query), required)
+}
+
+/**
+ * Check the role of a token already extracted from the request
+ * @param token: Option<String>, token sent with the request
+ * @param required: Role, lowest role allowed
+ * @return Result<(String, Role), ApiError>, name and role of the token, 401 without a valid token, 403 when the role is too low
+ */
+pub fn authorize_token(
+    api: &ApiState,
+    token: Option<String>,
+    required: Role,
+) -> Result<(String, Role), ApiError> {
+    let (name, role) = authenticate_token(api, token)?;
+    require(role, required)?;
+    Ok((name, role))
+}
+
+fn authenticate_request(
+    api: &ApiState,
+    headers: &HeaderMap,
+    query: Option<&str>,
+) -> Result<(String, Role), ApiError> {
+    authenticate_token(api, request_token(headers, query))
+}
+
+fn authenticate_token(api: &ApiState, token: Option<String>) -> Result<(String, Role), ApiError> {
+    let auth = &api.configuration.auth;
+    if !auth.enabled {
+        return Ok(("anonymous".to_string(), Role::Admin));
+    }
+
+    let token = token.ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Missing token"))?;
+    authenticate(&auth.tokens_file, &token)
+        .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Invalid token"))
+}
+
+fn require(role: Role, required: Role) -> Result<(), ApiError> {
+    if role < required {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!("Permission denied, requires role {}", required),
+        ));
+    }
+    Ok(())
+}
+
+/// Requests changing the printer are refused while a client holds control or after an emergency stop
+pub fn check_writable(target: &ManagedPrinter) -> Result<(), ApiError> {
+    target
+        .control
+        .check(REST_CLIENT)
+        .map_err(|e| ApiError::new(StatusCode::CONFLICT, e))?;
+
+    if target.printer.state() == PrinterState::Halted {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Printer is halted, reset it and send M999 from the terminal",
+        ));
+    }
+    Ok(())
+}
+
+/// HTTP status of an error, also used as JSON-RPC error code by the Moonraker endpoint
+pub fn status_for(kind: ErrorKind) -> StatusCode {
+    match kind {
+        ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+        ErrorKind::InvalidData => StatusCode::UNPROCESSABLE_ENTITY,
+        ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        ErrorKind::ResourceBusy => StatusCode::CONFLICT,
+        ErrorKind::NotConnected => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        ErrorKind::Unsupported => StatusCode::NOT_IMPLEMENTED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+trait RestStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RestStream for T {}
+
+/// Accepts the REST connections allowed by the access lists, with TLS when enabled
+struct RestListener {
+    /// Connections ready for HTTP, their TLS handshake already finished
+    ready: mpsc::Receiver<(Box<dyn RestStream>, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl RestListener {
+    fn new(
+        listener: TcpListener,
+        access: AccessConfig,
+        tls: Option<TlsAcceptor>,
+    ) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, ready) = mpsc::channel(16);
+        tokio::spawn(accept_connections(listener, access, tls, sender));
+        Ok(RestListener { ready, local_addr })
+    }
+}
+
+/**
+ * Accept the TCP connections and hand them over once they are ready
+ * Each TLS handshake runs in its own task, a slow client does not hold back the others
+ * @param listener: TcpListener, REST port
+ * @param access: AccessConfig, address lists
+ * @param tls: Option<TlsAcceptor>, TLS of the server when enabled
+ * @param ready: mpsc::Sender, receives the connections ready for HTTP
+ */
+async fn accept_connections(
+    listener: TcpListener,
+    access: AccessConfig,
+    tls: Option<TlsAcceptor>,
+    ready: mpsc::Sender<(Box<dyn RestStream>, SocketAddr)>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("REST accept failed | {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        if let Err(e) = check_ip(&access, peer.ip()) {
+            warn!("Rejected REST connection from {} | {}", peer, e);
+            continue;
+        }
+
+        let Some(acceptor) = tls.clone() else {
+            if ready.send((Box::new(stream), peer)).await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let ready = ready.clone();
+        tokio::spawn(async move {
+            let handshake = tokio::time::timeout(
+                Duration::from_secs(HANDSHAKE_TIMEOUT),
+                acceptor.accept(stream),
+            );
+            match handshake.await {
+                Ok(Ok(stream)) => {
+                    let _ = ready.send((Box::new(stream), peer)).await;
+                }
+                Ok(Err(e)) => error!("TLS handshake failed from {}: {}", peer, e),
+                Err(_) => error!("TLS handshake timed out from {}", peer),
+            }
+        });
+    }
+}
+
+impl axum::serve::Listener for RestListener {
+    type Io = Box<dyn RestStream>;
+    type Addr = RestPeer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.ready.recv().await {
+            Some((stream, peer)) => (stream, RestPeer(peer)),
+            // The accept task only stops with the listener
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(RestPeer(self.local_addr))
+    }
+}
+
+/// Address of a REST client, extracted with ConnectInfo
+#[derive(Debug, Clone, Copy)]
+struct RestPeer(SocketAddr);
+
+impl Connected<IncomingStream<'_, RestListener>> for RestPeer {
+    fn connect_info(stream: IncomingStream<'_, RestListener>) -> Self {
+        *stream.remote_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_for() {
+        let error: ApiError = Error::new(ErrorKind::NotFound, "Unknown printer x").into();
+        assert_eq!(error.status, StatusCode::NOT_FOUND);
+        assert_eq!(status_for(ErrorKind::ResourceBusy), StatusCode::CONFLICT);
+        assert_eq!(
+            status_for(ErrorKind::NotConnected),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status_for(ErrorKind::Other),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn test_require() {
+        assert!(require(Role::Admin, Role::Operator).is_ok());
+        let error = require(Role::Viewer, Role::Operator).unwrap_err();
+        assert_eq!(error.status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,429 @@
+use log::{error, warn};
+use regex::Regex;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::{Error, Write};
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::gcode::GcodeCommand;
+use crate::parser::m114;
+use crate::structs::SafetyLimits;
+
+static AUDIT_LOG: &str = "./logs/rejected_commands.log";
+static AXES: [char; 3] = ['X', 'Y', 'Z'];
+// Target of the active hotend in an M105 report, "T:210.0 /215.0"
+static HOTEND_TARGET: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?:^|\s)T:-?[\d.]+\s*/(-?[\d.]+)").unwrap());
+
+/**
+ * Modes, position and hotend target of a printer
+ * Followed from every command sent, whatever sent it, and from the M105 and M114 reports
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MotionState {
+    relative_positioning: bool,
+    relative_extrusion: bool,
+    /// X, Y and Z, unknown until an absolute move, G92 or an M114 report
+    position: [Option<f64>; 3],
+    last_e: Option<f64>,
+    /// Unknown until set by M104/M109 or reported by M105
+    hotend_target: Option<f64>,
+}
+
+impl MotionState {
+    /**
+     * Follow a command sent to the printer
+     * @param command: &GcodeCommand, parsed command
+     */
+    pub fn track(&mut self, command: &GcodeCommand) {
+        match (command.letter, command.number) {
+            ('G', 90) => {
+                self.relative_positioning = false;
+                self.relative_extrusion = false;
+            }
+            ('G', 91) => {
+                self.relative_positioning = true;
+                self.relative_extrusion = true;
+            }
+            ('M', 82) => self.relative_extrusion = false,
+            ('M', 83) => self.relative_extrusion = true,
+            ('G', 92) => {
+                for (index, axis) in AXES.iter().enumerate() {
+                    if let Some(value) = command.param(*axis) {
+                        self.position[index] = Some(value);
+                    }
+                }
+                if let Some(e) = command.param('E') {
+                    self.last_e = Some(e);
+                }
+            }
+            ('G', 0..=3) | ('G', 5) => {
+                for (index, axis) in AXES.iter().enumerate() {
+                    if let Some(value) = command.param(*axis) {
+                        self.position[index] = if self.relative_positioning {
+                            self.position[index].map(|position| position + value)
+                        } else {
+                            Some(value)
+                        };
+                    }
+                }
+                if let Some(e) = command.param('E') {
+                    self.last_e = if self.relative_extrusion {
+                        self.last_e.map(|last_e| last_e + e)
+                    } else {
+                        Some(e)
+                    };
+                }
+            }
+            // The home position depends on the machine
+            ('G', 28) => {
+                let all = !AXES.iter().any(|axis| command.has_param(*axis));
+                for (index, axis) in AXES.iter().enumerate() {
+                    if all || command.has_param(*axis) {
+                        self.position[index] = None;
+                    }
+                }
+            }
+            ('M', 104) | ('M', 109) if command.param('T').is_none_or(|t| t == 0.0) => {
+                if let Some(target) = command.param('S').or(command.param('R')) {
+                    self.hotend_target = Some(target);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /**
+     * Follow the hotend target reported by M105 and the position reported by M114
+     * @param line: &str, line received from the printer
+     */
+    pub fn observe(&mut self, line: &str) {
+        if let Some(target) = HOTEND_TARGET
+            .captures(line)
+            .and_then(|captures| captures[1].parse().ok())
+        {
+            self.hotend_target = Some(target);
+        }
+        if line.starts_with("X:") {
+            let position = m114(line.to_string());
+            self.position = [position.x, position.y, position.z].map(|value| Some(value as f64));
+        }
+    }
+
+    fn is_extruding(&self, command: &GcodeCommand) -> bool {
+        match command.param('E') {
+            None => false,
+            Some(e) if self.relative_extrusion => e > 0.0,
+            // Without a known position any absolute E move may extrude
+            Some(e) => self.last_e.is_none_or(|last_e| e > last_e),
+        }
+    }
+}
+
+/**
+ * Checks commands against the configured limits
+ * The modes, position and hotend target come from the MotionState of the printer
+ */
+pub struct SafetyChecker {
+    limits: SafetyLimits,
+    rejected: u64,
+}
+
+impl SafetyChecker {
+    pub fn new(limits: SafetyLimits) -> Self {
+        SafetyChecker {
+            limits,
+            rejected: 0,
+        }
+    }
+
+    /**
+     * Validate a command before it is sent
+     * @param command: &GcodeCommand, parsed command
+     * @param motion: &MotionState, state followed by the printer
+     * @return Result<(), Error>, the reason when the command is rejected
+     */
+    pub fn check(&mut self, command: &GcodeCommand, motion: &MotionState) -> Result<(), Error> {
+        let result = self.validate(command, motion);
+        if result.is_err() {
+            self.rejected += 1;
+        }
+        result
+    }
+
+    /// Commands rejected since the start
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    fn validate(&self, command: &GcodeCommand, motion: &MotionState) -> Result<(), Error> {
+        let limits = &self.limits;
+
+        match (command.letter, command.number) {
+            ('M', 104) | ('M', 109) => {
+                check_max(command, &['S', 'R', 'B'], limits.max_hotend_temp, "hotend")?
+            }
+            ('M', 140) | ('M', 190) => check_max(command, &['S', 'R'], limits.max_bed_temp, "bed")?,
+            ('M', 141) | ('M', 191) => {
+                check_max(command, &['S', 'R'], limits.max_chamber_temp, "chamber")?
+            }
+            ('M', 145) => {
+                check_max(command, &['H'], limits.max_hotend_temp, "hotend")?;
+                check_max(command, &['B'], limits.max_bed_temp, "bed")?;
+            }
+            ('M', 303) => {
+                let (max, heater) = match command.param('E') {
+                    Some(e) if e < 0.0 => (limits.max_bed_temp, "bed"),
+                    _ => (limits.max_hotend_temp, "hotend"),
+                };
+                check_max(command, &['S'], max, heater)?;
+            }
+            ('G', 0..=3) | ('G', 5) => self.check_move(command, motion)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn check_move(&self, command: &GcodeCommand, motion: &MotionState) -> Result<(), Error> {
+        let limits = &self.limits;
+        let volume = &limits.build_volume;
+
+        let axes = [
+            ('X', volume.min_x, volume.max_x, limits.max_feedrate.x),
+            ('Y', volume.min_y, volume.max_y, limits.max_feedrate.y),
+            ('Z', volume.min_z, volume.max_z, limits.max_feedrate.z),
+        ];
+
+        for (index, (axis, min, max, _)) in axes.into_iter().enumerate() {
+            let Some(value) = command.param(axis) else {
+                continue;
+            };
+
+            let target = match (motion.relative_positioning, motion.position[index]) {
+                (false, _) => Some(value),
+                (true, Some(position)) => Some(position + value),
+                // From an unknown position only moves longer than the volume are certainly out
+                (true, None) => None,
+            };
+            let allowed = match target {
+                Some(target) => target >= min && target <= max,
+                None => value.abs() <= max - min,
+            };
+
+            if !allowed {
+                return Err(Error::other(format!(
+                    "{} {}{} is outside the build volume ({} to {})",
+                    command.word(),
+                    axis,
+                    value,
+                    min,
+                    max
+                )));
+            }
+        }
+
+        if let Some(feedrate) = command.param('F') {
+            // A move is limited by its slowest axis, a bare F by the fastest one
+            let max_feedrate = axes
+                .iter()
+                .map(|(axis, _, _, feedrate)| (*axis, *feedrate))
+                .chain([('E', limits.max_feedrate.e)])
+                .filter(|(axis, _)| command.has_param(*axis))
+                .map(|(_, feedrate)| feedrate)
+                .reduce(f64::min)
+                .unwrap_or(limits.max_feedrate.x.max(limits.max_feedrate.y));
+
+            if feedrate <= 0.0 || feedrate > max_feedrate {
+                return Err(Error::other(format!(
+                    "{} F{} exceeds max feedrate {}",
+                    command.word(),
+                    feedrate,
+                    max_feedrate
+                )));
+            }
+        }
+
+        if motion.is_extruding(command) {
+            match motion.hotend_target {
+                Some(target) if target >= limits.min_extrude_temp => {}
+                Some(target) => {
+                    return Err(Error::other(format!(
+                        "{} extrudes with hotend target {} below min extrusion temperature {}",
+                        command.word(),
+                        target,
+                        limits.min_extrude_temp
+                    )))
+                }
+                None => {
+                    return Err(Error::other(format!(
+                        "{} extrudes before the hotend target is known, send M104 or M105 first",
+                        command.word()
+                    )))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_max(command: &GcodeCommand, params: &[char], max: f64, heater: &str) -> Result<(), Error> {
+    for param in params {
+        if let Some(value) = command.param(*param) {
+            if value < 0.0 || value > max {
+                return Err(Error::other(format!(
+                    "{} {}{} exceeds max {} temperature {}",
+                    command.word(),
+                    param,
+                    value,
+                    heater,
+                    max
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Append a rejected command to the audit log
+ * @param source: impl Display, client or bridge that sent the command
+ * @param command: &str, command as received
+ * @param reason: &str, why it was rejected
+ */
+pub fn audit_rejection(source: impl Display, command: &str, reason: &str) {
+    warn!(
+        "Rejected command from {} | {} | {}",
+        source, command, reason
+    );
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    let result = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(AUDIT_LOG)
+        .and_then(|mut file| {
+            writeln!(
+                file,
+                "{} | {} | {} | {}",
+                timestamp, source, command, reason
+            )
+        });
+
+    if let Err(e) = result {
+        error!("Failed to write audit log | {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcode::parse_gcode;
+
+    /// Check a command, then follow it as the printer does once it is sent
+    fn check(
+        checker: &mut SafetyChecker,
+        motion: &mut MotionState,
+        line: &str,
+    ) -> Result<(), Error> {
+        let command = parse_gcode(line).unwrap();
+        checker.check(&command, motion)?;
+        motion.track(&command);
+        Ok(())
+    }
+
+    #[test]
+    fn test_temperature_limits() {
+        let mut checker = SafetyChecker::new(SafetyLimits::default());
+        let motion = &mut MotionState::default();
+
+        assert!(check(&mut checker, motion, "M104 S210").is_ok());
+        assert!(check(&mut checker, motion, "M104 S450").is_err());
+        assert!(check(&mut checker, motion, "M109 R300").is_err());
+        assert!(check(&mut checker, motion, "M140 S60").is_ok());
+        assert!(check(&mut checker, motion, "M190 S150").is_err());
+        assert!(check(&mut checker, motion, "M141 S80").is_err());
+        assert!(check(&mut checker, motion, "M303 E-1 S130").is_err());
+        assert!(check(&mut checker, motion, "M104 S-5").is_err());
+        assert_eq!(checker.rejected(), 6);
+    }
+
+    #[test]
+    fn test_build_volume() {
+        let mut checker = SafetyChecker::new(SafetyLimits::default());
+        let motion = &mut MotionState::default();
+
+        assert!(check(&mut checker, motion, "G1 X100 Y100").is_ok());
+        assert!(check(&mut checker, motion, "G1 X5000").is_err());
+        assert!(check(&mut checker, motion, "G0 Z-1").is_err());
+
+        check(&mut checker, motion, "G91").unwrap();
+        // Z is unknown, only a move longer than the volume is rejected
+        assert!(check(&mut checker, motion, "G1 Z-1").is_ok());
+        assert!(check(&mut checker, motion, "G1 Z-300").is_err());
+        // X is at 100
+        assert!(check(&mut checker, motion, "G1 X100").is_ok());
+        assert!(check(&mut checker, motion, "G1 X30").is_err());
+        assert!(check(&mut checker, motion, "G1 Y-101").is_err());
+        assert!(check(&mut checker, motion, "G1 Y-100").is_ok());
+
+        // Homing loses the position until the printer reports it
+        check(&mut checker, motion, "G28 X").unwrap();
+        assert!(check(&mut checker, motion, "G1 X30").is_ok());
+        motion.observe("X:200.00 Y:0.00 Z:10.00 E:0.00 Count X:16000 Y:0 Z:4000");
+        assert!(check(&mut checker, motion, "G1 X30").is_err());
+        assert!(check(&mut checker, motion, "G1 Z-11").is_err());
+    }
+
+    #[test]
+    fn test_tracked_modes() {
+        let mut checker = SafetyChecker::new(SafetyLimits::default());
+        let motion = &mut MotionState::default();
+        assert!(check(&mut checker, motion, "G1 X200").is_ok());
+
+        // Sent without the safety check, through Terminal or Unsafe
+        motion.track(&parse_gcode("G91").unwrap());
+        assert!(check(&mut checker, motion, "G1 X30").is_err());
+        motion.track(&parse_gcode("G90").unwrap());
+        assert!(check(&mut checker, motion, "G1 X30").is_ok());
+    }
+
+    #[test]
+    fn test_feedrate() {
+        let mut checker = SafetyChecker::new(SafetyLimits::default());
+        let motion = &mut MotionState::default();
+
+        assert!(check(&mut checker, motion, "G1 X10 F6000").is_ok());
+        assert!(check(&mut checker, motion, "G1 X10 F999999").is_err());
+        assert!(check(&mut checker, motion, "G1 Z10 F6000").is_err());
+        assert!(check(&mut checker, motion, "G1 F12000").is_ok());
+    }
+
+    #[test]
+    fn test_cold_extrusion() {
+        let mut checker = SafetyChecker::new(SafetyLimits::default());
+        let motion = &mut MotionState::default();
+
+        let error = check(&mut checker, motion, "G1 X10 E5").unwrap_err();
+        assert!(error.to_string().contains("M104 or M105"));
+
+        check(&mut checker, motion, "M83").unwrap();
+        assert!(check(&mut checker, motion, "G1 E-2").is_ok());
+
+        check(&mut checker, motion, "M104 S150").unwrap();
+        assert!(check(&mut checker, motion, "G1 E5").is_err());
+
+        // A printer already hot reports its target
+        motion.observe("ok T:209.8 /210.0 B:60.1 /60.0 @:64 B@:0");
+        assert!(check(&mut checker, motion, "G1 E5").is_ok());
+        motion.observe("T:25.0 /0.0 B:24.0 /0.0");
+        assert!(check(&mut checker, motion, "G1 E5").is_err());
+    }
+}
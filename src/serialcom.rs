@@ -1,45 +1,260 @@
-use log::{debug, error, info};
+use log::{debug, info, warn};
+use serialport::SerialPort;
+use std::collections::VecDeque;
 use std::io::{self, Read, Write};
+use std::thread;
 use std::time::{Duration, Instant};
 
-static TIMEOUT: u64 = 1;
+use crate::structs::SerialTimeouts;
 
-// TODO: This  creates a serial connection for every command
-// The connection can be kept temporarily open to avoid this
-pub fn create_serialcom(cmd: &str, serial_port: String, baud_rate: u32) -> Result<String, ()> {
-    // Validate the Gcode in &command before converting it
+// Numbered lines kept for the resend requests of the firmware
+static RESEND_HISTORY: usize = 32;
+// Milliseconds to wait after a read without data, a closed port returns at once
+static EMPTY_READ_PAUSE: u64 = 5;
+
+/**
+ * Line numbers and checksums of the lines sent with send_until_ok
+ * The firmware checks them and asks with "Resend: <line>" for the lines it received corrupted
+ */
+pub struct LineNumbers {
+    /// Number of the next line, the M110 N0 of the handshake makes it 1
+    next: u64,
+    /// Last lines sent with their number and checksum
+    sent: VecDeque<(u64, String)>,
+}
+
+impl Default for LineNumbers {
+    fn default() -> Self {
+        LineNumbers {
+            next: 1,
+            sent: VecDeque::new(),
+        }
+    }
+}
+
+impl LineNumbers {
+    /// Follow an M110, the firmware continues from the line number it sets
+    pub fn follow(&mut self, cmd: &str) {
+        let mut words = cmd.split_whitespace();
+        if !words
+            .next()
+            .is_some_and(|word| word.eq_ignore_ascii_case("M110"))
+        {
+            return;
+        }
+        let last = words
+            .find_map(|word| word.strip_prefix(['N', 'n']))
+            .and_then(|number| number.parse::<u64>().ok())
+            .unwrap_or(0);
+        self.next = last + 1;
+        self.sent.clear();
+    }
+
+    /// Frame a command as "N<line> <command>*<checksum>"
+    fn number(&mut self, cmd: &str) -> String {
+        let line = format!("N{} {}", self.next, cmd);
+        let framed = format!("{}*{}", line, checksum(&line));
+        self.sent.push_back((self.next, framed.clone()));
+        if self.sent.len() > RESEND_HISTORY {
+            self.sent.pop_front();
+        }
+        self.next += 1;
+        framed
+    }
+
+    /**
+     * Lines to send again from the one requested by the firmware
+     * @param line: u64, number of the first line
+     * @return io::Result<Vec<String>>, an error when the line is no longer kept
+     */
+    fn resend_from(&self, line: u64) -> io::Result<Vec<String>> {
+        if !self.sent.iter().any(|(number, _)| *number == line) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Printer asked to resend unknown line {}", line),
+            ));
+        }
+        Ok(self
+            .sent
+            .iter()
+            .filter(|(number, _)| *number >= line)
+            .map(|(_, framed)| framed.clone())
+            .collect())
+    }
+}
+
+/**
+ * Open the printer port
+ * @param serial_port: &str, port of the printer
+ * @param baud_rate: u32, baud rate of the printer
+ * @param timeout: Duration, timeout of a single read or write
+ * @return io::Result<Box<dyn SerialPort>>
+ */
+pub fn open_port(
+    serial_port: &str,
+    baud_rate: u32,
+    timeout: Duration,
+) -> io::Result<Box<dyn SerialPort>> {
+    let port = serialport::new(serial_port, baud_rate)
+        .timeout(timeout)
+        .open()?;
+    Ok(port)
+}
+
+/**
+ * Send a command and wait for the response
+ * @param port: &mut T, open port
+ * @param cmd: &str, command without line ending
+ * @param timeout: Duration, silence after which the response is complete
+ * @return io::Result<String>, response or "NO RESPONSE"
+ */
+pub fn send_command<T: Read + Write>(
+    port: &mut T,
+    cmd: &str,
+    timeout: Duration,
+) -> io::Result<String> {
     let command = format!("{}\r\n", cmd);
-    let c_inbytes = command.into_bytes();
-
-    match serialport::new(&serial_port, baud_rate)
-        .timeout(Duration::from_secs(TIMEOUT))
-        .open()
-    {
-        Ok(mut port) => {
-            if let Err(e) = write_to_port(&mut port, &c_inbytes) {
-                error!("Failed to write_to_port | {}", e);
-                return Err(());
-            }
+    write_to_port(port, command.as_bytes())?;
+
+    let response = read_from_port(port, timeout)?;
+    info!("{}", response);
+    Ok(response)
+}
+
+/**
+ * Send a numbered command and wait until the firmware acknowledges it
+ * Used to stream jobs, the firmware answers "ok" once the command is queued
+ * and keeps reporting while it is busy with long commands such as G28 or M109
+ * Lines the firmware asks for with "Resend:" are sent again before the next "ok" counts
+ * @param port: &mut T, open port
+ * @param numbers: &mut LineNumbers, line numbers of the connection
+ * @param cmd: &str, command without line ending
+ * @param timeout: Duration, silence after which the printer is considered unresponsive
+ * @param on_line: &mut dyn FnMut(&str), called for every line as soon as it is received
+ * @param urgent: &mut dyn FnMut() -> Vec<String>, commands written between the reads, such as M112 or M876
+ * @return io::Result<String>, lines received up to "ok", an error when the firmware halted
+ */
+pub fn send_until_ok<T: Read + Write>(
+    port: &mut T,
+    numbers: &mut LineNumbers,
+    cmd: &str,
+    timeout: Duration,
+    on_line: &mut dyn FnMut(&str),
+    urgent: &mut dyn FnMut() -> Vec<String>,
+) -> io::Result<String> {
+    let command = format!("{}\r\n", numbers.number(cmd));
+    write_to_port(port, command.as_bytes())?;
+
+    let mut serial_buffer = [0u8; 256];
+    let mut response = String::new();
+    // Length of the response whose lines were already checked
+    let mut checked = 0;
+    let mut last_char_time = Instant::now();
+    // Line asked by the firmware, sent once the "ok" following the request is received
+    let mut resend: Option<u64> = None;
 
-            if let Ok(response) = read_from_port(&mut port) {
-                info!("{}", response);
-                Ok(response)
-            } else {
-                error!("Failed to read read_from_port");
-                Err(())
+    loop {
+        write_commands(port, &urgent())?;
+        match port.read(serial_buffer.as_mut_slice()) {
+            Ok(bytes_read) if bytes_read > 0 => {
+                response.push_str(&String::from_utf8_lossy(&serial_buffer[..bytes_read]));
+                last_char_time = Instant::now();
+
+                // Only complete lines are checked
+                let complete = response.rfind('\n').map_or(0, |end| end + 1);
+                for line in response[checked..complete].lines().map(str::trim) {
+                    on_line(line);
+                    if let Some(number) = resend_request(line) {
+                        resend = Some(number);
+                        continue;
+                    }
+                    if line.starts_with("ok") {
+                        let Some(number) = resend.take() else {
+                            return Ok(response);
+                        };
+                        warn!("Printer asked to resend line {}", number);
+                        for framed in numbers.resend_from(number)? {
+                            write_to_port(port, format!("{}\r\n", framed).as_bytes())?;
+                        }
+                        continue;
+                    }
+                    if line.starts_with("!!") || line.contains("kill() called") {
+                        return Err(io::Error::other(format!("Printer halted | {}", line)));
+                    }
+                    // Checksum and line number errors are followed by a resend request
+                    if line.starts_with("Error:") {
+                        warn!("Printer error | {}", line);
+                    }
+                }
+                checked = complete;
             }
+            Ok(_) => thread::sleep(Duration::from_millis(EMPTY_READ_PAUSE)),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(e),
         }
-        Err(e) => {
-            error!("Failed to open COM \"{}\". Error: {}", serial_port, e);
-            Err(())
+
+        if last_char_time.elapsed() > timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("No ok received for {}", cmd),
+            ));
         }
     }
 }
 
-fn read_from_port<T: Read>(port: &mut T) -> io::Result<String> {
+/**
+ * Write commands without waiting for a response
+ * @param port: &mut T, open port
+ * @param commands: &[String], commands without line ending, written in order
+ * @return io::Result<()>
+ */
+pub fn write_commands<T: Write>(port: &mut T, commands: &[String]) -> io::Result<()> {
+    if commands.is_empty() {
+        return Ok(());
+    }
+    let data: String = commands.iter().map(|cmd| format!("{}\r\n", cmd)).collect();
+    write_to_port(port, data.as_bytes())?;
+    port.flush()
+}
+
+/**
+ * Write commands to a printer that is not connected, opening its port
+ * While connected the worker writes them with Printer::send_urgent
+ * @param commands: &[&str], commands written in order
+ * @param serial_port: &str, port of the printer
+ * @param baud_rate: u32, baud rate of the printer
+ * @return Result<(), io::Error>
+ */
+pub fn emergency_write(commands: &[&str], serial_port: &str, baud_rate: u32) -> io::Result<()> {
+    let data: String = commands.iter().map(|cmd| format!("{}\r\n", cmd)).collect();
+
+    warn!(
+        "Printer not connected, opening {} for emergency write",
+        serial_port
+    );
+    let timeouts = SerialTimeouts::default();
+    let mut port = open_port(
+        serial_port,
+        baud_rate,
+        Duration::from_millis(timeouts.port_ms),
+    )?;
+    write_to_port(&mut port, data.as_bytes())?;
+    port.flush()
+}
+
+/// Line number of a "Resend: <line>" request
+fn resend_request(line: &str) -> Option<u64> {
+    line.strip_prefix("Resend:")?.trim().parse().ok()
+}
+
+/// XOR of the bytes of a numbered line, checked by the firmware
+fn checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+fn read_from_port<T: Read>(port: &mut T, timeout_duration: Duration) -> io::Result<String> {
     let mut serial_buffer = [0u8; 1024];
     let mut response_buffer = String::new();
-    let timeout_duration = Duration::from_millis(100); // Adjust as needed
     let start_time = Instant::now();
     let mut last_char_time = Instant::now();
 
@@ -110,7 +325,7 @@ mod tests {
     fn test_read_from_port_ok() {
         let data = b"ok\n";
         let mut cursor = Cursor::new(data);
-        let result = read_from_port(&mut cursor).unwrap();
+        let result = read_from_port(&mut cursor, Duration::from_millis(100)).unwrap();
         assert_eq!(result, "ok\n");
     }
 
@@ -119,7 +334,7 @@ mod tests {
     fn test_read_from_port_partial_ok() {
         let data = b"data and more data";
         let mut cursor = Cursor::new(data);
-        let result = read_from_port(&mut cursor).unwrap();
+        let result = read_from_port(&mut cursor, Duration::from_millis(100)).unwrap();
         assert_eq!(result, "data and more data");
     }
 
@@ -133,15 +348,236 @@ mod tests {
         }
 
         let mut reader = TimeoutReader;
-        let result = read_from_port(&mut reader).unwrap();
+        let result = read_from_port(&mut reader, Duration::from_millis(100)).unwrap();
         assert_eq!(result, "NO RESPONSE");
     }
 
+    #[test]
+    fn test_send_command() {
+        struct FakePort {
+            written: Vec<u8>,
+            response: Cursor<&'static [u8]>,
+        }
+        impl Read for FakePort {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.response.read(buf)
+            }
+        }
+        impl Write for FakePort {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.written.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut port = FakePort {
+            written: Vec::new(),
+            response: Cursor::new(b"ok\n"),
+        };
+        assert_eq!(
+            send_command(&mut port, "M110 N0", Duration::from_millis(100)).unwrap(),
+            "ok\n"
+        );
+        assert_eq!(port.written, b"M110 N0\r\n");
+    }
+
+    #[test]
+    fn test_send_until_ok() {
+        struct FakePort {
+            response: Cursor<&'static [u8]>,
+        }
+        impl Read for FakePort {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.response.read(buf)
+            }
+        }
+        impl Write for FakePort {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut port = FakePort {
+            response: Cursor::new(b"echo:busy: processing\n//action:prompt_show\nok\n"),
+        };
+        let mut lines = Vec::new();
+        let mut numbers = LineNumbers::default();
+        let response = send_until_ok(
+            &mut port,
+            &mut numbers,
+            "G28",
+            Duration::from_millis(100),
+            &mut |line| lines.push(line.to_string()),
+            &mut Vec::new,
+        )
+        .unwrap();
+        assert!(response.ends_with("ok\n"));
+        assert_eq!(
+            lines,
+            vec!["echo:busy: processing", "//action:prompt_show", "ok"]
+        );
+
+        let mut port = FakePort {
+            response: Cursor::new(b"Error:Printer halted. kill() called!\n"),
+        };
+        assert!(send_until_ok(
+            &mut port,
+            &mut numbers,
+            "M109 S200",
+            Duration::from_millis(100),
+            &mut |_| {},
+            &mut Vec::new
+        )
+        .is_err());
+
+        let mut port = FakePort {
+            response: Cursor::new(b"echo:busy: processing\n"),
+        };
+        let error = send_until_ok(
+            &mut port,
+            &mut numbers,
+            "G28",
+            Duration::from_millis(100),
+            &mut |_| {},
+            &mut Vec::new,
+        )
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    /// Port answering each write with the next response
+    struct ReplyingPort {
+        written: Vec<String>,
+        responses: VecDeque<&'static str>,
+        pending: Vec<u8>,
+    }
+
+    impl ReplyingPort {
+        fn new(responses: &[&'static str]) -> Self {
+            ReplyingPort {
+                written: Vec::new(),
+                responses: responses.iter().copied().collect(),
+                pending: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for ReplyingPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.pending.len().min(buf.len());
+            buf[..count].copy_from_slice(&self.pending[..count]);
+            self.pending.drain(..count);
+            Ok(count)
+        }
+    }
+
+    impl Write for ReplyingPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written
+                .push(String::from_utf8_lossy(buf).trim_end().to_string());
+            if let Some(response) = self.responses.pop_front() {
+                self.pending.extend_from_slice(response.as_bytes());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_line_numbers() {
+        let mut numbers = LineNumbers::default();
+        assert_eq!(numbers.number("M105"), "N1 M105*38");
+        assert_eq!(numbers.number("G28"), "N2 G28*17");
+        numbers.follow("M110 N10");
+        assert_eq!(numbers.number("M105"), "N11 M105*23");
+        assert!(numbers.resend_from(1).is_err());
+        numbers.follow("G1 X10");
+        assert_eq!(numbers.number("M105"), "N12 M105*20");
+        assert_eq!(numbers.resend_from(11).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_send_until_ok_resend() {
+        let mut port = ReplyingPort::new(&[
+            "ok\n",
+            "Error:checksum mismatch, Last Line: 1\nResend: 2\nok\n",
+            "ok\n",
+        ]);
+        let mut numbers = LineNumbers::default();
+        let timeout = Duration::from_millis(100);
+        send_until_ok(
+            &mut port,
+            &mut numbers,
+            "G28",
+            timeout,
+            &mut |_| {},
+            &mut Vec::new,
+        )
+        .unwrap();
+        let response = send_until_ok(
+            &mut port,
+            &mut numbers,
+            "G1 X10",
+            timeout,
+            &mut |_| {},
+            &mut Vec::new,
+        )
+        .unwrap();
+        assert!(response.contains("Resend: 2"));
+        assert_eq!(
+            port.written,
+            vec!["N1 G28*18", "N2 G1 X10*83", "N2 G1 X10*83"]
+        );
+
+        // A line no longer kept can't be sent again
+        let mut port = ReplyingPort::new(&["Resend: 1\nok\n"]);
+        let mut numbers = LineNumbers::default();
+        numbers.follow("M110 N40");
+        assert!(send_until_ok(
+            &mut port,
+            &mut numbers,
+            "G28",
+            timeout,
+            &mut |_| {},
+            &mut Vec::new
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_send_until_ok_error() {
+        let mut port =
+            ReplyingPort::new(&["echo:Unknown command: \"G999\"\nError:Unknown command\nok\n"]);
+        let mut lines = Vec::new();
+        let response = send_until_ok(
+            &mut port,
+            &mut LineNumbers::default(),
+            "G999",
+            Duration::from_millis(100),
+            &mut |line| lines.push(line.to_string()),
+            &mut Vec::new,
+        )
+        .unwrap();
+        assert!(response.ends_with("ok\n"));
+        assert_eq!(lines.len(), 3);
+        assert_eq!(port.written.len(), 1);
+    }
+
     #[test]
     fn test_write_to_port_success() {
         let mut buffer = Vec::new();
         let command = b"test command";
-        let _result = write_to_port(&mut buffer, command).unwrap();
+        write_to_port(&mut buffer, command).unwrap();
         assert_eq!(buffer, command);
     }
 
@@ -150,7 +586,7 @@ mod tests {
         struct ErrorWriter;
         impl Write for ErrorWriter {
             fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-                Err(io::Error::new(io::ErrorKind::Other, "write error"))
+                Err(io::Error::other("write error"))
             }
 
             fn flush(&mut self) -> io::Result<()> {
@@ -0,0 +1,199 @@
+use log::error;
+use serde::Serialize;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::configuration::{printer_configs, printer_safety};
+use crate::control::ControlLock;
+use crate::history::History;
+use crate::jobs::JobRunner;
+use crate::moonraker::StatusCache;
+use crate::printer::Printer;
+use crate::queue::PrintQueue;
+use crate::recovery::CheckpointStore;
+use crate::safety::SafetyChecker;
+use crate::structs::{Config, ControlStatus, MessageSender, PrinterSummary, SerialSettings};
+use crate::wscom::json_response;
+
+/// Printer with its own connection, control lock and safety limits
+pub struct ManagedPrinter {
+    pub name: String,
+    pub profile: Option<String>,
+    pub printer: Printer,
+    pub control: ControlLock,
+    pub job: JobRunner,
+    pub queue: PrintQueue,
+    /// Shared by the clients so the tracked modes and targets follow the printer
+    pub safety: Mutex<SafetyChecker>,
+    /// Temperatures and position read for the Moonraker clients
+    pub status_cache: StatusCache,
+}
+
+/**
+ * State shared by every connection
+ * Events sent on the broadcast channel are forwarded to all clients
+ */
+pub struct ServerState {
+    pub events: broadcast::Sender<MessageSender>,
+    pub printers: Vec<ManagedPrinter>,
+    /// Finished jobs, None when disabled or the database could not be opened
+    pub history: Option<Arc<History>>,
+    next_client_id: AtomicU64,
+    clients: AtomicU64,
+}
+
+impl ServerState {
+    pub fn new(configuration: &Config) -> Self {
+        let (events, _) = broadcast::channel(64);
+        let history = configuration
+            .history
+            .enabled
+            .then(|| History::open(&configuration.history.db_path))
+            .and_then(|result| {
+                result
+                    .map_err(|e| error!("Print history is disabled | {}", e))
+                    .ok()
+            })
+            .map(Arc::new);
+
+        let printers = printer_configs(configuration)
+            .into_iter()
+            .map(|printer| ManagedPrinter {
+                printer: Printer::start(
+                    &printer.name,
+                    SerialSettings {
+                        serial_port: printer.serial_port.clone(),
+                        baud_rate: printer.baud_rate,
+                        timeouts: printer.timeouts.unwrap_or(configuration.timeouts),
+                    },
+                    events.clone(),
+                ),
+                job: JobRunner::new(
+                    &printer.name,
+                    events.clone(),
+                    history.clone(),
+                    CheckpointStore::for_printer(&printer.name, &configuration.recovery),
+                    configuration.actions.clone(),
+                ),
+                queue: PrintQueue::load(&printer.name, events.clone()),
+                control: ControlLock::new(Duration::from_secs(configuration.control.timeout_secs)),
+                safety: Mutex::new(SafetyChecker::new(printer_safety(configuration, &printer))),
+                status_cache: StatusCache::default(),
+                profile: printer.profile,
+                name: printer.name,
+            })
+            .collect();
+
+        ServerState {
+            events,
+            printers,
+            history,
+            next_client_id: AtomicU64::new(1),
+            clients: AtomicU64::new(0),
+        }
+    }
+
+    pub fn next_client_id(&self) -> u64 {
+        self.next_client_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Count a WebSocket client until the returned guard is dropped
+    pub fn client_connected(&self) -> ClientGuard<'_> {
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard { state: self }
+    }
+
+    /// Connected WebSocket clients
+    pub fn clients(&self) -> u64 {
+        self.clients.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Find the printer a message is for
+     * @param name: Option<&str>, requested printer, only optional when a single printer is configured
+     * @return Result<&ManagedPrinter, Error>
+     */
+    pub fn printer(&self, name: Option<&str>) -> Result<&ManagedPrinter, Error> {
+        match name {
+            Some(name) => self
+                .printers
+                .iter()
+                .find(|printer| printer.name == name)
+                .ok_or_else(|| {
+                    Error::new(ErrorKind::NotFound, format!("Unknown printer {}", name))
+                }),
+            None if self.printers.len() == 1 => Ok(&self.printers[0]),
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Several printers are configured, set the printer field",
+            )),
+        }
+    }
+
+    /// Print history, Unsupported when it is disabled
+    pub fn history(&self) -> Result<&History, Error> {
+        self.history
+            .as_deref()
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "Print history is disabled"))
+    }
+
+    /// Printers with their connection state, in configuration order
+    pub fn printer_list(&self) -> Vec<PrinterSummary> {
+        self.printers
+            .iter()
+            .map(|managed| {
+                let settings = managed.printer.settings();
+                PrinterSummary {
+                    name: managed.name.clone(),
+                    profile: managed.profile.clone(),
+                    serial_port: settings.serial_port,
+                    baud_rate: settings.baud_rate,
+                    state: managed.printer.state(),
+                    firmware_name: managed.printer.info().map(|info| info.firmware_name),
+                    job: managed.job.status(),
+                    checkpoint: managed.job.checkpoint(),
+                    prompt: managed.printer.prompt(),
+                }
+            })
+            .collect()
+    }
+
+    /**
+     * Send a message to every connected client
+     * @param message: MessageSender, message to broadcast
+     */
+    pub fn broadcast(&self, message: MessageSender) {
+        // No receiver only means that no client is connected
+        let _ = self.events.send(message);
+    }
+
+    /**
+     * Broadcast an event about a printer
+     * @param printer: &str, name of the printer
+     * @param message_type: &str, type of the event
+     * @param event: &T, serialized as the message
+     */
+    pub fn broadcast_event<T: Serialize>(&self, printer: &str, message_type: &str, event: &T) {
+        let mut message = json_response(message_type, Ok(event));
+        message.printer = Some(printer.to_string());
+        self.broadcast(message);
+    }
+
+    pub fn broadcast_control(&self, printer: &str, status: &ControlStatus) {
+        self.broadcast_event(printer, "ControlStatus", status);
+    }
+}
+
+/// Held by a connection while it is counted in the connected clients
+pub struct ClientGuard<'a> {
+    state: &'a ServerState,
+}
+
+impl Drop for ClientGuard<'_> {
+    fn drop(&mut self) {
+        self.state.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -1,12 +1,39 @@
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 
 /// Used for identifying the type of incoming message
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageType {
+    Auth,
     GCommand,
     SerialConfig,
     Unsafe,
     Terminal,
+    EepromRead,
+    EepromApply,
+    EepromBackups,
+    EepromRestore,
+    BedMesh,
+    BedMeshList,
+    ControlAcquire,
+    ControlRelease,
+    ControlTakeover,
+    ControlStatus,
+    EmergencyStop,
+    PrinterStatus,
+    SerialPorts,
+    PrinterList,
+    History,
+    HistoryStats,
+    Queue,
+    QueueUpdate,
+    PromptAnswer,
+    Objects,
+    ObjectCancel,
+    CheckpointResume,
+    CheckpointDiscard,
 }
 
 /// Used for received messages
@@ -14,10 +41,13 @@ pub enum MessageType {
 pub struct MessageWS<'a> {
     pub message_type: MessageType,
     pub message: &'a str,
+    /// Printer the message is for, optional when the connection selected one
+    #[serde(default)]
+    pub printer: Option<String>,
 }
 
 /// M115 - Firmware and Capabilities
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PrinterInfo {
     pub firmware_name: String,
     pub firmware_version: String,
@@ -57,7 +87,7 @@ pub struct PrinterInfo {
  * M114 - Get Current Position
  * also used printer object
 */
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AxePositions {
     pub x: f32,
     pub y: f32,
@@ -65,7 +95,7 @@ pub struct AxePositions {
 }
 
 /// M105 - Get Extruder Temperature
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Temperatures {
     pub bed: u8,
     pub bed_set: u8,
@@ -81,20 +111,1010 @@ pub struct Temperatures {
 
 /// Com configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub test_mode: bool,
     pub serial_port: String,
     pub baud_rate: u32,
-    pub ws_port: String,
+    pub ws_port: u16,
+    pub safety: SafetyLimits,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub access: AccessConfig,
+    pub control: ControlConfig,
+    pub timeouts: SerialTimeouts,
+    /// Printers managed by this instance, the top level serial settings are used when empty
+    pub printers: Vec<PrinterConfig>,
+    /// Named safety limits selected by the printers
+    pub profiles: BTreeMap<String, SafetyLimits>,
+    pub http: HttpConfig,
+    pub jobs: JobsConfig,
+    pub moonraker: MoonrakerConfig,
+    pub mqtt: MqttConfig,
+    pub history: HistoryConfig,
+    pub queue: QueueConfig,
+    pub recovery: RecoveryConfig,
+    pub actions: ActionsConfig,
+    /// File the configuration was loaded from, runtime changes are stored there
+    #[serde(skip)]
+    pub config_file: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            test_mode: false,
+            serial_port: "/dev/ttyUSB0".to_string(),
+            baud_rate: 115200,
+            ws_port: 9002,
+            safety: SafetyLimits::default(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+            access: AccessConfig::default(),
+            control: ControlConfig::default(),
+            timeouts: SerialTimeouts::default(),
+            printers: Vec::new(),
+            profiles: BTreeMap::new(),
+            http: HttpConfig::default(),
+            jobs: JobsConfig::default(),
+            moonraker: MoonrakerConfig::default(),
+            mqtt: MqttConfig::default(),
+            history: HistoryConfig::default(),
+            queue: QueueConfig::default(),
+            recovery: RecoveryConfig::default(),
+            actions: ActionsConfig::default(),
+            config_file: String::new(),
+        }
+    }
+}
+
+/// Printer entry of the configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PrinterConfig {
+    pub name: String,
+    pub serial_port: String,
+    pub baud_rate: u32,
+    /// Top level timeouts when not set
+    pub timeouts: Option<SerialTimeouts>,
+    /// Key of the safety limits in profiles, top level limits when not set
+    pub profile: Option<String>,
+}
+
+impl Default for PrinterConfig {
+    fn default() -> Self {
+        PrinterConfig {
+            name: String::new(),
+            serial_port: "/dev/ttyUSB0".to_string(),
+            baud_rate: 115200,
+            timeouts: None,
+            profile: None,
+        }
+    }
+}
+
+/// PrinterList - printers managed by this instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrinterSummary {
+    pub name: String,
+    pub profile: Option<String>,
+    pub serial_port: String,
+    pub baud_rate: u32,
+    pub state: PrinterState,
+    pub firmware_name: Option<String>,
+    pub job: Option<JobStatus>,
+    /// Job interrupted by a crash or a power loss, it can be resumed
+    pub checkpoint: Option<JobCheckpoint>,
+    /// Dialog of the firmware waiting for an answer
+    pub prompt: Option<HostPrompt>,
+}
+
+/// REST API served next to the WebSocket server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Off unless set, the API answers anyone reaching the port when authentication is disabled
+    pub enabled: bool,
+    pub port: u16,
+    /// Largest accepted upload in megabytes
+    pub max_upload_mb: usize,
+    /// Serve the subset of the OctoPrint API used by slicers
+    pub octoprint: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            enabled: false,
+            port: 9003,
+            max_upload_mb: 512,
+            octoprint: true,
+        }
+    }
+}
+
+/// G-code files streamed to the printers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JobsConfig {
+    pub files_dir: String,
+    /// Sent after the streaming stopped when a job is cancelled
+    pub cancel_commands: Vec<String>,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        JobsConfig {
+            files_dir: "./gcodes".to_string(),
+            cancel_commands: vec![
+                "M104 S0".to_string(),
+                "M140 S0".to_string(),
+                "M107".to_string(),
+            ],
+        }
+    }
+}
+
+/// Moonraker JSON-RPC served at /websocket of the WebSocket server, for Mainsail and Fluidd
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MoonrakerConfig {
+    pub enabled: bool,
+}
+
+/// MQTT bridge publishing the printers for Home Assistant and Node-RED
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    /// Read from the file only, never printed by the config subcommand
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// Topics are <topic_prefix>/<printer>/<name>
+    pub topic_prefix: String,
+    /// Publish the Home Assistant discovery payloads under discovery_prefix
+    pub discovery: bool,
+    pub discovery_prefix: String,
+    /// Interval between two temperature and position reports
+    pub telemetry_secs: u64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            client_id: "xcontroller".to_string(),
+            username: None,
+            password: None,
+            topic_prefix: "xcontroller".to_string(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+            telemetry_secs: 5,
+        }
+    }
+}
+
+/// Hides the password from the configuration printed at startup
+impl fmt::Debug for MqttConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MqttConfig")
+            .field("enabled", &self.enabled)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "***"))
+            .field("topic_prefix", &self.topic_prefix)
+            .field("discovery", &self.discovery)
+            .field("discovery_prefix", &self.discovery_prefix)
+            .field("telemetry_secs", &self.telemetry_secs)
+            .finish()
+    }
+}
+
+/// Finished jobs recorded in an SQLite database
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    pub enabled: bool,
+    pub db_path: String,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            enabled: true,
+            db_path: "./history.db".to_string(),
+        }
+    }
+}
+
+/// Start of the queued jobs without a client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Start the next job once the previous one completed
+    pub auto_start: bool,
+    /// Wait until a client confirms that the bed was cleared
+    pub bed_clear: bool,
+    /// Wait until the bed cooled down below this temperature, parts come off cold beds
+    pub max_bed_temp: Option<u8>,
+    pub check_secs: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            auto_start: false,
+            bed_clear: true,
+            max_bed_temp: None,
+            check_secs: 5,
+        }
+    }
+}
+
+/// Checkpoints of the running jobs, resumed after a crash or a power loss
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecoveryConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Raised before X and Y are homed so the nozzle clears the part
+    pub lift_mm: f64,
+}
+
+impl Default for RecoveryConfig {
+    fn default() -> Self {
+        RecoveryConfig {
+            enabled: true,
+            interval_secs: 10,
+            lift_mm: 2.0,
+        }
+    }
+}
+
+/// Reaction of the jobs to the action commands sent by the firmware
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ActionsConfig {
+    /// Pause, resume and cancel the streamed job on //action: commands
+    pub enabled: bool,
+    /// Raised when the firmware asks the host to pause, 0 to leave the nozzle in place
+    pub park_lift_mm: f64,
+}
+
+impl Default for ActionsConfig {
+    fn default() -> Self {
+        ActionsConfig {
+            enabled: true,
+            park_lift_mm: 5.0,
+        }
+    }
+}
+
+/// JSON-RPC 2.0 request of the Moonraker endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonRpcRequest {
+    pub method: String,
+    #[serde(default)]
+    pub params: serde_json::Value,
+    /// Notifications without id are not answered
+    pub id: Option<serde_json::Value>,
+}
+
+/// G-code file stored on the host
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Printing,
+    Paused,
+    Completed,
+    Cancelled,
+    Failed,
+}
+
+/// Progress of a file streamed to a printer, broadcast as JobStatus
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub file: String,
+    pub state: JobState,
+    /// Bytes of the file already sent
+    pub position: u64,
+    pub size: u64,
+    /// Percentage of the file sent
+    pub progress: f64,
+    pub lines_sent: u64,
+    /// Random, tells apart the jobs started within the same second
+    pub run_id: u64,
+    pub started: u64,
+    pub finished: Option<u64>,
+    pub error: Option<String>,
+    /// SHA-256 of the file when the job started
+    pub hash: String,
+    /// Millimetres of filament extruded
+    pub filament_used: f64,
+    /// Name of the token that started the job
+    pub started_by: String,
+}
+
+/// File of the library waiting in the queue of a printer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueItem {
+    pub id: u64,
+    pub file: String,
+    pub copies: u32,
+    /// Copies completed, the item leaves the queue when all are printed
+    pub printed: u32,
+    pub added: u64,
+    pub added_by: String,
+}
+
+/// Job started by the queue, followed until it finishes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QueueRun {
+    pub id: u64,
+    /// run_id of the job, 0 for a run stored before it was recorded
+    #[serde(default)]
+    pub run_id: u64,
+}
+
+/// Queue of a printer, stored on every change and broadcast as QueueStatus
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueState {
+    pub items: Vec<QueueItem>,
+    pub next_id: u64,
+    /// No job is started until the queue is resumed
+    pub paused: bool,
+    /// Why the queue paused itself
+    pub reason: Option<String>,
+    /// The last job completed and the bed was not confirmed clear yet
+    pub awaiting_clear: bool,
+    pub current: Option<QueueRun>,
+}
+
+impl Default for QueueState {
+    fn default() -> Self {
+        QueueState {
+            items: Vec::new(),
+            next_id: 1,
+            paused: false,
+            reason: None,
+            awaiting_clear: false,
+            current: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueAction {
+    Add,
+    Remove,
+    Move,
+    Copies,
+    /// The bed was cleared, the next job may start
+    Confirm,
+    /// Start the next item now, confirms the bed and resumes the queue
+    Start,
+    Pause,
+    Resume,
+}
+
+/// Change of the queue, the fields needed depend on the action
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueRequest {
+    pub action: QueueAction,
+    pub file: Option<String>,
+    pub id: Option<u64>,
+    pub copies: Option<u32>,
+    /// New index of the item for move, 0 is printed next
+    pub position: Option<usize>,
+}
+
+/// Modes and targets set by the G-code streamed so far
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GcodeState {
+    /// G91, relative X, Y and Z moves
+    pub relative_moves: bool,
+    /// M83, or G91 until the next M82
+    pub relative_extrusion: bool,
+    /// Checkpoints written before X and Y were followed have none
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    pub z: f64,
+    /// Position of the E axis since the last G92
+    pub e: f64,
+    /// Net millimetres of filament extruded
+    pub filament_used: f64,
+    pub hotend_target: f64,
+    pub bed_target: f64,
+    /// M106 speed, 0 to 255
+    pub fan_speed: u8,
+    pub feedrate: Option<f64>,
+}
+
+/// State of a running job written to disk, the job can be resumed from it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobCheckpoint {
+    pub file: String,
+    pub hash: String,
+    /// Bytes of the file before the layer being printed, the resumed job starts there
+    pub position: u64,
+    /// Lines of the file before that layer, comments included
+    pub line: u64,
+    pub lines_sent: u64,
+    pub size: u64,
+    pub started: u64,
+    pub started_by: String,
+    pub gcode: GcodeState,
+    pub saved: u64,
+    /// Ids of the objects skipped by the job
+    #[serde(default)]
+    pub cancelled_objects: Vec<usize>,
+    /// Id of the object printed at the line of the checkpoint
+    #[serde(default)]
+    pub current_object: Option<usize>,
+    /// Kept by the resumed job, the queue keeps following it
+    #[serde(default)]
+    pub run_id: u64,
+}
+
+/// HostAction - //action: line sent by the firmware
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActionCommand {
+    pub action: String,
+    /// Text following the action, the reason of a pause or the text of a prompt
+    pub detail: String,
+}
+
+/// HostPrompt - dialog of the firmware, answered with M876
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HostPrompt {
+    pub message: String,
+    /// Choices in the order of their M876 index
+    pub buttons: Vec<String>,
+    /// Sent by prompt_show, the dialog is still being built before
+    pub shown: bool,
+}
+
+/// PromptAnswer - button of the host prompt chosen by the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptAnswer {
+    pub choice: usize,
+}
+
+/// Area covered by the extrusions of an object, in mm
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ObjectBounds {
+    pub min_x: f64,
+    pub min_y: f64,
+    pub max_x: f64,
+    pub max_y: f64,
+}
+
+/// Object labelled by the slicer in a G-code file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PrintObject {
+    /// M486 index when the file uses M486, order of appearance otherwise
+    pub id: usize,
+    pub name: String,
+    /// None when the object has no extrusion
+    pub bounds: Option<ObjectBounds>,
+    pub cancelled: bool,
+}
+
+/// ObjectList - objects of the job and the one being printed
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ObjectList {
+    pub objects: Vec<PrintObject>,
+    /// Id of the object being printed
+    pub current: Option<usize>,
+}
+
+/// ObjectCancel - object to skip, the current one when id is not set
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ObjectCancelRequest {
+    pub id: Option<usize>,
+}
+
+/// Finished job stored in the history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub printer: String,
+    pub file: String,
+    pub hash: String,
+    pub started: u64,
+    pub finished: u64,
+    /// Seconds between the start and the end, pauses included
+    pub duration: u64,
+    pub outcome: JobState,
+    pub filament_used: f64,
+    pub error: Option<String>,
+    pub started_by: String,
+}
+
+/// Selection of history entries, every field is optional
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HistoryFilter {
+    pub printer: Option<String>,
+    pub file: Option<String>,
+    pub outcome: Option<JobState>,
+    /// Jobs started at or after this Unix time
+    pub since: Option<u64>,
+    /// Jobs started before this Unix time
+    pub until: Option<u64>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+/// Aggregates of the jobs matching a filter
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryStats {
+    pub jobs: u64,
+    pub completed: u64,
+    pub cancelled: u64,
+    pub failed: u64,
+    pub print_hours: f64,
+    pub filament_used: f64,
+    /// Completed jobs divided by all jobs, 0 without jobs
+    pub success_rate: f64,
+    pub files: Vec<FileStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileStats {
+    pub file: String,
+    pub jobs: u64,
+    pub completed: u64,
+    pub print_hours: f64,
+    pub success_rate: f64,
+}
+
+/// Command sent through the REST API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandRequest {
+    pub command: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobAction {
+    Start,
+    Pause,
+    Resume,
+    Cancel,
+}
+
+/// Job control through the REST API, the file is only needed to start
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    pub action: JobAction,
+    pub file: Option<String>,
+}
+
+/// Result of a command sent through the REST API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub command: String,
+    pub response: String,
+    /// Response decoded by the parser registered for the command
+    pub parsed: Option<serde_json::Value>,
+}
+
+/// Answer of the OctoPrint /api/version, slicers check the text before uploading
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctoPrintVersion {
+    pub api: String,
+    pub server: String,
+    pub text: String,
+}
+
+/// File in the OctoPrint format, every field is null when no file is selected
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OctoPrintFile {
+    pub name: Option<String>,
+    pub display: Option<String>,
+    pub path: Option<String>,
+    pub origin: Option<String>,
+    pub size: Option<u64>,
+    pub date: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctoPrintFiles {
+    pub files: Vec<OctoPrintFile>,
+}
+
+/// Answer of an upload to /api/files/local, files has the single entry "local"
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctoPrintUpload {
+    pub files: BTreeMap<String, OctoPrintFile>,
+    pub done: bool,
+}
+
+/// Answer of the OctoPrint /api/job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctoPrintJob {
+    pub job: OctoPrintJobInfo,
+    pub progress: OctoPrintProgress,
+    pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoPrintJobInfo {
+    pub file: OctoPrintFile,
+    pub estimated_print_time: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoPrintProgress {
+    /// Percentage of the file sent
+    pub completion: Option<f64>,
+    pub filepos: Option<u64>,
+    /// Seconds since the job started
+    pub print_time: Option<u64>,
+    pub print_time_left: Option<u64>,
+}
+
+/// Command posted to the OctoPrint /api/job, action is only used by pause
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctoPrintJobCommand {
+    pub command: String,
+    pub action: Option<String>,
+}
+
+/// Answer of the OctoPrint /api/printer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctoPrintPrinter {
+    /// Keyed "tool0" to "tool3" and "bed"
+    pub temperature: BTreeMap<String, OctoPrintTemperature>,
+    pub state: OctoPrintState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OctoPrintTemperature {
+    pub actual: f64,
+    pub target: f64,
+    pub offset: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OctoPrintState {
+    pub text: String,
+    pub flags: OctoPrintFlags,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OctoPrintFlags {
+    pub operational: bool,
+    pub paused: bool,
+    pub printing: bool,
+    pub cancelling: bool,
+    pub pausing: bool,
+    pub sd_ready: bool,
+    pub error: bool,
+    pub ready: bool,
+    pub closed_or_error: bool,
+}
+
+/// Timeouts of the serial connection in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SerialTimeouts {
+    /// Timeout of a single read or write on the port
+    pub port_ms: u64,
+    /// Silence after which a response is complete, the whole response waits 3 times longer
+    pub response_ms: u64,
+}
+
+impl Default for SerialTimeouts {
+    fn default() -> Self {
+        SerialTimeouts {
+            port_ms: 1000,
+            response_ms: 100,
+        }
+    }
+}
+
+/// Settings of the printer connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SerialSettings {
+    pub serial_port: String,
+    pub baud_rate: u32,
+    pub timeouts: SerialTimeouts,
+}
+
+/// SerialConfig - Change the connection settings, missing values are kept
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SerialConfigRequest {
+    pub serial_port: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub timeouts: Option<SerialTimeouts>,
+    /// Store the settings in the configuration file
+    pub persist: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SerialConfigResult {
+    pub settings: SerialSettings,
+    pub firmware_name: String,
+    pub firmware_version: String,
+    pub persisted: bool,
+}
+
+/// Token authentication of WebSocket clients
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub enabled: bool,
+    pub tokens_file: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            enabled: false,
+            tokens_file: "./tokens.json".to_string(),
+        }
+    }
+}
+
+/// Serve wss:// with a PEM certificate and key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub enabled: bool,
+    pub cert_path: String,
+    pub key_path: String,
+    pub generate_self_signed: bool,
+    pub subject_alt_names: Vec<String>,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            enabled: false,
+            cert_path: "./certs/cert.pem".to_string(),
+            key_path: "./certs/key.pem".to_string(),
+            generate_self_signed: true,
+            subject_alt_names: vec!["localhost".to_string()],
+        }
+    }
+}
+
+/// Restrictions on who can connect and how often they can send messages
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessConfig {
+    /// Accepted browser origins, empty accepts any origin
+    pub allowed_origins: Vec<String>,
+    /// Networks allowed to connect, empty allows every address
+    pub allow: Vec<IpNet>,
+    /// Networks always rejected, checked before the allow list
+    pub deny: Vec<IpNet>,
+    /// Simultaneous clients, 0 for no limit
+    pub max_clients: usize,
+    pub rate_limit: RateLimit,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig {
+            allowed_origins: Vec::new(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_clients: 16,
+            rate_limit: RateLimit::default(),
+        }
+    }
+}
+
+/// Messages allowed per client, a burst is refilled at messages_per_second
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimit {
+    pub messages_per_second: f64,
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            messages_per_second: 20.0,
+            burst: 40,
+        }
+    }
+}
+
+/// Exclusive control of the printer by one client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    /// Seconds without a command from the holder before the lock is released
+    pub timeout_secs: u64,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig { timeout_secs: 300 }
+    }
+}
+
+/// Client holding the control lock
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlHolder {
+    pub client_id: u64,
+    pub name: String,
+    pub peer: String,
+    pub since: u64,
+}
+
+/// Lock ownership, broadcast to every client when it changes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlStatus {
+    pub holder: Option<ControlHolder>,
+    /// Why the ownership changed: acquired, released, takeover, timeout or disconnected
+    pub reason: String,
+}
+
+/// Serial port found on the host, USB details are only known for USB ports
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortDescription {
+    pub port_name: String,
+    pub port_type: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+/// Port and baud rate on which a firmware answered M115
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DetectedPrinter {
+    pub port_name: String,
+    pub baud_rate: u32,
+    pub firmware_name: String,
+    pub firmware_version: String,
+}
+
+/// Connection state of the printer, driven by the serial layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrinterState {
+    Disconnected,
+    Connecting,
+    Handshaking,
+    Operational,
+    Printing,
+    Paused,
+    Error,
+    Halted,
+}
+
+/// Broadcast on every state transition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PrinterStatus {
+    pub state: PrinterState,
+    pub previous: PrinterState,
+    pub detail: Option<String>,
+    pub timestamp: u64,
+}
+
+/// Broadcast when an emergency stop is triggered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmergencyStopEvent {
+    pub triggered_by: String,
+    pub peer: String,
+    pub commands: Vec<String>,
+    /// Whether the firmware acts on the commands before its buffered moves
+    pub emergency_parser: bool,
+    pub warning: Option<String>,
+    pub timestamp: u64,
+}
+
+/// Limits enforced on commands before they are sent to the printer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SafetyLimits {
+    pub max_hotend_temp: f64,
+    pub max_bed_temp: f64,
+    pub max_chamber_temp: f64,
+    pub min_extrude_temp: f64,
+    /// mm/min, same unit as the F parameter
+    pub max_feedrate: AxisFeedrates,
+    pub build_volume: BuildVolume,
+}
+
+impl Default for SafetyLimits {
+    fn default() -> Self {
+        SafetyLimits {
+            max_hotend_temp: 275.0,
+            max_bed_temp: 120.0,
+            max_chamber_temp: 60.0,
+            min_extrude_temp: 170.0,
+            max_feedrate: AxisFeedrates::default(),
+            build_volume: BuildVolume::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AxisFeedrates {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub e: f64,
+}
+
+impl Default for AxisFeedrates {
+    fn default() -> Self {
+        AxisFeedrates {
+            x: 12000.0,
+            y: 12000.0,
+            z: 1200.0,
+            e: 6000.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildVolume {
+    pub min_x: f64,
+    pub max_x: f64,
+    pub min_y: f64,
+    pub max_y: f64,
+    pub min_z: f64,
+    pub max_z: f64,
+}
+
+impl Default for BuildVolume {
+    fn default() -> Self {
+        BuildVolume {
+            min_x: 0.0,
+            max_x: 220.0,
+            min_y: 0.0,
+            max_y: 220.0,
+            min_z: 0.0,
+            max_z: 250.0,
+        }
+    }
 }
 
 // Used for sending messages back to clients
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageSender {
     pub message_type: String,
     pub message: String,
     pub raw_message: String,
     pub timestamp: u64,
+    /// Printer the message is about, not set for messages about the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub printer: Option<String>,
 }
 
 /// M119 - Get Endstop Status
@@ -104,3 +1124,104 @@ pub struct EndstopStatus {
     pub y_min: String,
     pub z_min: String,
 }
+
+/// Parameters of a single setting command, keyed by parameter letter
+pub type SettingParams = BTreeMap<char, f32>;
+
+/**
+ * M503 - Report Settings
+ * Keyed by the command that sets the values, e.g. "M92" or "M145 S0"
+ * when the command reports several entries selected by a parameter
+*/
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PrinterSettings {
+    pub commands: BTreeMap<String, SettingParams>,
+}
+
+/// Versioned snapshot of the printer settings stored on the host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EepromBackup {
+    pub version: u32,
+    pub timestamp: u64,
+    pub settings: PrinterSettings,
+}
+
+/// EepromApply - desired settings sent by the client
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EepromApplyRequest {
+    pub settings: PrinterSettings,
+    #[serde(default)]
+    pub persist: bool,
+}
+
+/// EepromRestore - backup version to restore
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EepromRestoreRequest {
+    pub version: u32,
+    #[serde(default)]
+    pub persist: bool,
+}
+
+/// Result of applying settings to the printer
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct EepromApplyResult {
+    pub commands: Vec<String>,
+    pub mismatches: Vec<String>,
+    pub persisted: bool,
+    pub backup_version: Option<u32>,
+}
+
+/**
+ * M420 V / G29 T - Bed leveling grid
+ * z[row][col], row 0 is the front of the bed (lowest Y)
+*/
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BedMesh {
+    pub kind: String,
+    pub timestamp: u64,
+    pub x: Vec<f32>,
+    pub y: Vec<f32>,
+    pub z: Vec<Vec<Option<f32>>>,
+}
+
+/// Bed area covered by the mesh, used when the firmware does not report coordinates
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MeshBounds {
+    pub min_x: f32,
+    pub max_x: f32,
+    pub min_y: f32,
+    pub max_y: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct MeshStats {
+    pub min: f32,
+    pub max: f32,
+    pub range: f32,
+    pub mean: f32,
+}
+
+/// Point by point difference between two meshes (current - previous)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MeshDiff {
+    pub previous_timestamp: u64,
+    pub z: Vec<Vec<Option<f32>>>,
+    pub max_delta: f32,
+}
+
+/// BedMesh - fetch the mesh from the printer
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BedMeshRequest {
+    #[serde(default)]
+    pub topography: bool,
+    pub bounds: Option<MeshBounds>,
+    pub compare_to: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BedMeshReport {
+    pub mesh: BedMesh,
+    pub stats: MeshStats,
+    pub diff: Option<MeshDiff>,
+}
@@ -0,0 +1,116 @@
+use log::info;
+use rcgen::{generate_simple_self_signed, CertifiedKey};
+use std::fs;
+use std::io::{Error, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::structs::TlsConfig;
+
+/**
+ * Build the TLS acceptor used to serve wss://
+ * A self-signed certificate is generated when the files are missing and generation is enabled
+ * @param config: &TlsConfig, certificate and key paths
+ * @return Result<TlsAcceptor, Error>
+ */
+pub fn tls_acceptor(config: &TlsConfig) -> Result<TlsAcceptor, Error> {
+    let cert_path = Path::new(&config.cert_path);
+    let key_path = Path::new(&config.key_path);
+
+    if !cert_path.exists() || !key_path.exists() {
+        if !config.generate_self_signed {
+            return Err(Error::other(format!(
+                "Certificate {} or key {} not found",
+                config.cert_path, config.key_path
+            )));
+        }
+        generate_certificate(cert_path, key_path, &config.subject_alt_names)?;
+    }
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| Error::other(format!("Failed to read {} | {}", config.cert_path, e)))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::other(format!("Invalid certificate {} | {}", config.cert_path, e)))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| Error::other(format!("Invalid key {} | {}", config.key_path, e)))?;
+
+    let server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(Error::other)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+/**
+ * Generate a self-signed certificate and its private key as PEM files
+ * @param cert_path: &Path, certificate output
+ * @param key_path: &Path, private key output, only readable by the owner
+ * @param names: &[String], subject alternative names
+ */
+fn generate_certificate(cert_path: &Path, key_path: &Path, names: &[String]) -> Result<(), Error> {
+    let CertifiedKey { cert, key_pair } =
+        generate_simple_self_signed(names.to_vec()).map_err(Error::other)?;
+
+    for path in [cert_path, key_path] {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+    }
+
+    fs::write(cert_path, cert.pem())?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(key_path)?
+        .write_all(key_pair.serialize_pem().as_bytes())?;
+
+    info!(
+        "Generated self-signed certificate {} for {:?}",
+        cert_path.display(),
+        names
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tls_acceptor_self_signed() {
+        let dir = std::env::temp_dir().join(format!("xcontroller_tls_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut config = TlsConfig {
+            cert_path: dir.join("cert.pem").to_string_lossy().to_string(),
+            key_path: dir.join("key.pem").to_string_lossy().to_string(),
+            generate_self_signed: false,
+            ..TlsConfig::default()
+        };
+        assert!(tls_acceptor(&config).is_err());
+
+        config.generate_self_signed = true;
+        assert!(tls_acceptor(&config).is_ok());
+        assert!(dir.join("cert.pem").exists());
+
+        // Reuses the generated files
+        config.generate_self_signed = false;
+        assert!(tls_acceptor(&config).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,37 +1,65 @@
 use futures::{stream::StreamExt, SinkExt};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use std::net::SocketAddr;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::net::TcpStream;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::RecvError;
 use tokio_tungstenite::{
-    accept_async,
-    tungstenite::{Error, Result},
+    accept_hdr_async,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        Error, Result,
+    },
 };
 use tungstenite::Message;
 
-use crate::commands::g_command;
-use crate::serialcom::create_serialcom;
+use crate::access::{origin_allowed, RateLimiter};
+use crate::auth::{authenticate, request_token, required_role, Role};
 
-use crate::parser::{m105, m114, m115, m119, m20, m27, m31, m33};
-use crate::structs::MessageSender;
+use crate::commands::g_command;
+use crate::discovery::list_ports;
+use crate::eeprom::{apply_settings, list_backups, read_settings, restore_backup};
+use crate::emergency::emergency_stop;
+use crate::jobs::JobFile;
+use crate::mesh::{fetch_mesh, list_meshes};
+use crate::moonraker::moonraker_session;
+use crate::printer::apply_serial_config;
+use crate::queue::apply_request;
+use crate::responses::registry;
+use crate::safety::audit_rejection;
+use crate::state::ServerState;
+use crate::structs::{
+    BedMeshRequest, EepromApplyRequest, EepromRestoreRequest, HistoryFilter, MessageSender,
+    ObjectCancelRequest, PrinterState, PromptAnswer, QueueRequest, SerialConfigRequest,
+};
 use crate::Config;
 use crate::MessageType;
 use crate::MessageWS;
 
+// Seconds allowed for the TLS and WebSocket handshakes of a new connection
+pub static HANDSHAKE_TIMEOUT: u64 = 10;
+
 /**
  * Accept incoming connection from client
  * @param peer: SocketAddr, peer address
- * @param stream: TcpStream, stream from client
+ * @param stream: S, plain TCP or TLS stream from client
  * @param configuration: Config, configuration for the server
+ * @param state: Arc<ServerState>, state shared between connections
  * @return Result<(), Error>, return Ok(())
  * @throws Error
  */
-pub async fn accept_connection(
+pub async fn accept_connection<S>(
     peer: SocketAddr,
-    stream: TcpStream,
+    stream: S,
     configuration: Config,
-) -> Result<(), Error> {
-    match handle_connection(peer, stream, configuration).await {
+    state: Arc<ServerState>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match handle_connection(peer, stream, configuration, state).await {
         Ok(_) => Ok(()),
         Err(e) => match e {
             Error::ConnectionClosed | Error::Protocol(_) | Error::Utf8(_) => Ok(()),
@@ -46,32 +74,114 @@ pub async fn accept_connection(
 /**
  * Get stream message and validate it and send back command
  * @param peer: SocketAddr, peer address
- * @param stream: TcpStream, stream from client
+ * @param stream: S, plain TCP or TLS stream from client
  * @param configuration: Config, configuration for the server
+ * @param state: Arc<ServerState>, state shared between connections
  * @return Result<(), Error>, return Ok(())
  * @throws Error
  */
-async fn handle_connection(
+// The handshake callback has to return the tungstenite error response
+#[allow(clippy::result_large_err)]
+async fn handle_connection<S>(
     peer: SocketAddr,
-    stream: TcpStream,
+    stream: S,
     configuration: Config,
-) -> Result<(), Error> {
-    let ws_stream = accept_async(stream)
+    state: Arc<ServerState>,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Clients authenticate with a token during the handshake or with an Auth message
+    let mut client: Option<(String, Role)> = None;
+    // Printer selected with /printers/<name>, used when a message does not set one
+    let mut path_printer: Option<String> = None;
+    // Moonraker clients connect to /websocket or /printers/<name>/websocket
+    let mut moonraker = false;
+    let handshake = accept_hdr_async(stream, |request: &Request, response: Response| {
+        let mut path = request.uri().path();
+        if configuration.moonraker.enabled {
+            if let Some(prefix) = path.strip_suffix("/websocket") {
+                moonraker = true;
+                path = prefix;
+            }
+        }
+
+        if let Some(name) = path.strip_prefix("/printers/") {
+            let name = name.trim_end_matches('/');
+            if state.printer(Some(name)).is_err() {
+                warn!("Unknown printer {} requested by {}", name, peer);
+                return Err(error_response(StatusCode::NOT_FOUND, "Unknown printer"));
+            }
+            path_printer = Some(name.to_string());
+        }
+
+        let origin = request
+            .headers()
+            .get("Origin")
+            .and_then(|value| value.to_str().ok());
+        if !origin_allowed(&configuration.access.allowed_origins, origin) {
+            warn!("Rejected origin {:?} from {}", origin, peer);
+            return Err(error_response(StatusCode::FORBIDDEN, "Origin not allowed"));
+        }
+
+        if !configuration.auth.enabled {
+            client = Some(("anonymous".to_string(), Role::Admin));
+        } else if let Some(token) = request_token(request.headers(), request.uri().query()) {
+            client = authenticate(&configuration.auth.tokens_file, &token);
+            if client.is_none() {
+                warn!("Invalid token from {}", peer);
+                return Err(error_response(StatusCode::UNAUTHORIZED, "Invalid token"));
+            }
+        }
+        Ok(response)
+    });
+    // A client that never finishes the handshake would keep its connection slot
+    let ws_stream = tokio::time::timeout(Duration::from_secs(HANDSHAKE_TIMEOUT), handshake)
         .await
-        .expect("Failed to accept incoming connection");
+        .map_err(|_| {
+            Error::Io(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "WebSocket handshake timed out",
+            ))
+        })??;
 
-    // Socket addresses can be validated to insure only valide peers can connect and send commands
-    info!("New client | {}", peer);
+    match &client {
+        Some((name, role)) => info!("New client | {} | {} ({})", peer, name, role),
+        None => info!("New client | {} | not authenticated", peer),
+    }
+    let _client = state.client_connected();
+    if moonraker {
+        return moonraker_session(
+            peer,
+            ws_stream,
+            &configuration,
+            &state,
+            client,
+            path_printer,
+        )
+        .await;
+    }
     let (mut ws_write, mut ws_read) = ws_stream.split();
+    let mut rate_limiter = RateLimiter::new(&configuration.access.rate_limit);
+    let mut events = state.events.subscribe();
+    let client_id = state.next_client_id();
+    let _control = ControlGuard {
+        state: &state,
+        client_id,
+    };
 
-    // Broadcast response message to clients
-    async fn send_message_back(
-        message: MessageSender,
+    // Broadcast response message to clients, tagged with the printer it is about
+    async fn send_message_back<S: AsyncRead + AsyncWrite + Unpin>(
+        mut message: MessageSender,
         ws_write: &mut futures::prelude::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
+            tokio_tungstenite::WebSocketStream<S>,
             Message,
         >,
+        printer: Option<&str>,
     ) -> Result<()> {
+        if message.printer.is_none() {
+            message.printer = printer.map(|printer| printer.to_string());
+        }
         let json_str =
             serde_json::to_string(&message).expect("Failed to serialize message into JSON");
         let resp_message = Message::Text(json_str.into());
@@ -83,9 +193,24 @@ async fn handle_connection(
         Ok(())
     }
 
-    // Loop over received messages
-    while let Some(msg) = ws_read.next().await {
-        let msg = msg?;
+    // Loop over received messages and forward broadcast events
+    loop {
+        let msg = tokio::select! {
+            msg = ws_read.next() => match msg {
+                Some(msg) => msg?,
+                None => break,
+            },
+            event = events.recv() => {
+                match event {
+                    Ok(event) => send_message_back(event, &mut ws_write, None).await?,
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("{} missed {} broadcast messages", peer, count)
+                    }
+                    Err(RecvError::Closed) => {}
+                }
+                continue;
+            }
+        };
 
         // can also check for binary values
         if msg.is_text() && !msg.is_empty() {
@@ -95,22 +220,154 @@ async fn handle_connection(
 
             match serde_json::from_str::<MessageWS>(data) {
                 Ok(message) => {
-                    info!("Message received: {}", message.message);
+                    // Flooding clients are answered without touching the serial link
+                    let emergency = matches!(message.message_type, MessageType::EmergencyStop);
+                    if !emergency && !rate_limiter.allow() {
+                        warn!("Rate limit exceeded by {}", peer);
+                        send_message_back(
+                            error_message("Rate limit exceeded"),
+                            &mut ws_write,
+                            None,
+                        )
+                        .await?;
+                        continue;
+                    }
+
+                    // Tokens must not end up in the logs
+                    match message.message_type {
+                        MessageType::Auth => info!("Message received: {:?}", message.message_type),
+                        _ => info!("Message received: {}", message.message),
+                    }
 
                     // Starting timestamp
                     let now = SystemTime::now();
 
+                    if let MessageType::Auth = message.message_type {
+                        let response = if configuration.auth.enabled {
+                            client = authenticate(&configuration.auth.tokens_file, message.message);
+                            match &client {
+                                Some((_, role)) => json_response("Auth", Ok(role)),
+                                None => {
+                                    warn!("Invalid token from {}", peer);
+                                    error_message("Invalid token")
+                                }
+                            }
+                        } else {
+                            json_response("Auth", Ok(Role::Admin))
+                        };
+                        send_message_back(response, &mut ws_write, None).await?;
+                        continue;
+                    }
+
+                    let command = match message.message_type {
+                        MessageType::GCommand => g_command(message.message).ok(),
+                        _ => None,
+                    };
+                    let required = required_role(&message.message_type, command.as_ref());
+                    if let Some(required) = required {
+                        if client.as_ref().is_none_or(|(_, role)| *role < required) {
+                            let reason = format!(
+                                "Permission denied, {:?} requires role {}",
+                                message.message_type, required
+                            );
+                            warn!("{} | {}", peer, reason);
+                            send_message_back(error_message(&reason), &mut ws_write, None).await?;
+                            continue;
+                        }
+                    }
+
+                    // Messages about the server are not bound to a printer
+                    match message.message_type {
+                        MessageType::SerialPorts => {
+                            let result = list_ports();
+                            send_message_back(
+                                json_response("SerialPorts", result),
+                                &mut ws_write,
+                                None,
+                            )
+                            .await?;
+                            continue;
+                        }
+                        MessageType::PrinterList => {
+                            let printers = state.printer_list();
+                            send_message_back(
+                                json_response("PrinterList", Ok(printers)),
+                                &mut ws_write,
+                                None,
+                            )
+                            .await?;
+                            continue;
+                        }
+                        MessageType::History | MessageType::HistoryStats => {
+                            let response =
+                                history_response(&state, &message.message_type, message.message);
+                            send_message_back(response, &mut ws_write, None).await?;
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    let requested = message.printer.as_deref().or(path_printer.as_deref());
+                    let target = match state.printer(requested) {
+                        Ok(target) => target,
+                        Err(e) => {
+                            send_message_back(error_message(&e.to_string()), &mut ws_write, None)
+                                .await?;
+                            continue;
+                        }
+                    };
+
+                    if required.is_some_and(|required| required > Role::Viewer) {
+                        // Observers can only read while another client holds control
+                        if !ignores_control(&message.message_type) {
+                            if let Err(e) = target.control.check(client_id) {
+                                warn!("{} | {:?} rejected | {}", peer, message.message_type, e);
+                                send_message_back(
+                                    error_message(&e.to_string()),
+                                    &mut ws_write,
+                                    Some(&target.name),
+                                )
+                                .await?;
+                                continue;
+                            }
+                        }
+
+                        // After an emergency stop only admins can talk to the printer to reset it
+                        if target.printer.state() == PrinterState::Halted
+                            && !allowed_when_halted(&message.message_type)
+                        {
+                            let reason =
+                                "Printer is halted, reset it and send M999 from the terminal";
+                            send_message_back(
+                                error_message(reason),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                            continue;
+                        }
+                    }
+
                     match message.message_type {
                         MessageType::GCommand => {
                             debug!("Config: {}", message.message);
-                            let result = g_command(message.message);
+                            let result = g_command(message.message)
+                                .map_err(|e| e.to_string())
+                                .and_then(|command| {
+                                    match lock(&target.safety)
+                                        .check(&command, &target.printer.motion())
+                                    {
+                                        Ok(()) => Ok(command),
+                                        Err(e) => {
+                                            audit_rejection(peer, message.message, &e.to_string());
+                                            Err(e.to_string())
+                                        }
+                                    }
+                                });
                             match result {
-                                Ok(cmd) => {
-                                    match create_serialcom(
-                                        cmd,
-                                        configuration.serial_port.to_string(),
-                                        configuration.baud_rate,
-                                    ) {
+                                Ok(command) => {
+                                    let cmd = command.to_string();
+                                    match target.printer.send_async(&cmd).await {
                                         Ok(response) => {
                                             debug!("{:?}", response);
 
@@ -126,91 +383,81 @@ async fn handle_connection(
                                                 message: "".to_string(),
                                                 raw_message: response.clone(),
                                                 timestamp,
+                                                printer: None,
                                             };
 
                                             if &response != "ok" {
-                                                message_sender.message = match cmd.trim() {
-                                                    "M20" => {
-                                                        let response = m20(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M27" => {
-                                                        let response = m27(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M27 C" => {
-                                                        let response = m27(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M31" => {
-                                                        let response = m31(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M33" => {
-                                                        let response = m33(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M105" => {
-                                                        let response = m105(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M114" => {
-                                                        let response = m114(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M115" => {
-                                                        let response = m115(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    "M119" => {
-                                                        let response = m119(response);
-                                                        serde_json::to_string(&response).expect(
-                                                            "Failed to serialize message into JSON",
-                                                        )
-                                                    }
-                                                    _ => response.to_string(),
+                                                message_sender.message = match registry()
+                                                    .parse(&cmd, response.clone())
+                                                {
+                                                    Some(parsed) => parsed.to_string(),
+                                                    None => response.to_string(),
                                                 };
                                             }
-                                            send_message_back(message_sender, &mut ws_write)
-                                                .await?;
+                                            send_message_back(
+                                                message_sender,
+                                                &mut ws_write,
+                                                Some(&target.name),
+                                            )
+                                            .await?;
                                         }
                                         Err(e) => {
-                                            error!("{:?}", e)
+                                            error!("{:?}", e);
+                                            send_message_back(
+                                                error_message(&format!(
+                                                    "Error executing command | {}",
+                                                    e
+                                                )),
+                                                &mut ws_write,
+                                                Some(&target.name),
+                                            )
+                                            .await?;
                                         }
                                     }
                                 }
                                 Err(e) => {
-                                    error!("{:?}", e)
+                                    error!("Invalid command \"{}\" | {}", message.message, e);
+                                    send_message_back(
+                                        error_message(&e),
+                                        &mut ws_write,
+                                        Some(&target.name),
+                                    )
+                                    .await?;
                                 }
                             }
                         }
+                        // Answered before the printer is resolved
+                        MessageType::Auth
+                        | MessageType::SerialPorts
+                        | MessageType::PrinterList
+                        | MessageType::History
+                        | MessageType::HistoryStats => {}
                         MessageType::SerialConfig => {
-                            // Not yet implemented, changes to the config loading is required
                             debug!("SerialConfig: {}", message.message);
+                            let config_file = configuration.config_file.clone();
+                            let result = match serde_json::from_str::<SerialConfigRequest>(
+                                message.message,
+                            ) {
+                                Ok(request) => {
+                                    target
+                                        .printer
+                                        .exchange(move |printer| {
+                                            apply_serial_config(printer, request, &config_file)
+                                        })
+                                        .await
+                                }
+                                Err(e) => Err(std::io::Error::other(e)),
+                            };
+                            send_message_back(
+                                json_response("SerialConfig", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
                         }
                         MessageType::Terminal => {
                             let cmd = message.message;
-                            match create_serialcom(
-                                cmd,
-                                configuration.serial_port.to_string(),
-                                configuration.baud_rate,
-                            ) {
+                            match target.printer.send_async(cmd).await {
                                 Ok(response) => {
                                     debug!("{:?}", response);
 
@@ -225,9 +472,15 @@ async fn handle_connection(
                                         message: response.to_string().clone(),
                                         raw_message: response,
                                         timestamp,
+                                        printer: None,
                                     };
 
-                                    send_message_back(message_sender, &mut ws_write).await?;
+                                    send_message_back(
+                                        message_sender,
+                                        &mut ws_write,
+                                        Some(&target.name),
+                                    )
+                                    .await?;
                                 }
                                 Err(e) => {
                                     error!("{:?}", e);
@@ -240,22 +493,24 @@ async fn handle_connection(
                                     // Define response message
                                     let message_sender = MessageSender {
                                         message_type: "MessageSenderError".to_string(),
-                                        message: "Error executing command".to_string(),
-                                        raw_message: "Error executing command".to_string(),
+                                        message: format!("Error executing command | {}", e),
+                                        raw_message: format!("Error executing command | {}", e),
                                         timestamp,
+                                        printer: None,
                                     };
 
-                                    send_message_back(message_sender, &mut ws_write).await?;
+                                    send_message_back(
+                                        message_sender,
+                                        &mut ws_write,
+                                        Some(&target.name),
+                                    )
+                                    .await?;
                                 }
                             }
                         }
                         MessageType::Unsafe => {
                             let cmd = message.message;
-                            match create_serialcom(
-                                cmd,
-                                configuration.serial_port.to_string(),
-                                configuration.baud_rate,
-                            ) {
+                            match target.printer.send_async(cmd).await {
                                 Ok(response) => {
                                     debug!("{:?}", response);
 
@@ -270,9 +525,15 @@ async fn handle_connection(
                                         message: response.to_string().clone(),
                                         raw_message: response,
                                         timestamp,
+                                        printer: None,
                                     };
 
-                                    send_message_back(message_sender, &mut ws_write).await?;
+                                    send_message_back(
+                                        message_sender,
+                                        &mut ws_write,
+                                        Some(&target.name),
+                                    )
+                                    .await?;
                                 }
                                 Err(e) => {
                                     error!("{:?}", e);
@@ -285,18 +546,304 @@ async fn handle_connection(
                                     // Define response message
                                     let message_sender = MessageSender {
                                         message_type: "MessageSenderError".to_string(),
-                                        message: "Error executing command".to_string(),
-                                        raw_message: "Error executing command".to_string(),
+                                        message: format!("Error executing command | {}", e),
+                                        raw_message: format!("Error executing command | {}", e),
                                         timestamp,
+                                        printer: None,
                                     };
 
-                                    send_message_back(message_sender, &mut ws_write).await?;
+                                    send_message_back(
+                                        message_sender,
+                                        &mut ws_write,
+                                        Some(&target.name),
+                                    )
+                                    .await?;
+                                }
+                            }
+                        }
+                        MessageType::EepromRead => {
+                            let result = target
+                                .printer
+                                .exchange(|printer| read_settings(&mut |cmd| printer.send(cmd)))
+                                .await;
+                            send_message_back(
+                                json_response("EepromRead", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::EepromApply => {
+                            let name = target.name.clone();
+                            let result =
+                                match serde_json::from_str::<EepromApplyRequest>(message.message) {
+                                    Ok(request) => {
+                                        target
+                                            .printer
+                                            .exchange(move |printer| {
+                                                apply_settings(
+                                                    &mut |cmd| printer.send(cmd),
+                                                    &name,
+                                                    &request.settings,
+                                                    request.persist,
+                                                )
+                                            })
+                                            .await
+                                    }
+                                    Err(e) => Err(std::io::Error::other(e)),
+                                };
+                            send_message_back(
+                                json_response("EepromApply", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::EepromBackups => {
+                            let result = list_backups(&target.name);
+                            send_message_back(
+                                json_response("EepromBackups", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::EepromRestore => {
+                            let name = target.name.clone();
+                            let result =
+                                match serde_json::from_str::<EepromRestoreRequest>(message.message)
+                                {
+                                    Ok(request) => {
+                                        target
+                                            .printer
+                                            .exchange(move |printer| {
+                                                restore_backup(
+                                                    &mut |cmd| printer.send(cmd),
+                                                    &name,
+                                                    request.version,
+                                                    request.persist,
+                                                )
+                                            })
+                                            .await
+                                    }
+                                    Err(e) => Err(std::io::Error::other(e)),
+                                };
+                            send_message_back(
+                                json_response("EepromRestore", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::BedMesh => {
+                            let request = if message.message.trim().is_empty() {
+                                Ok(BedMeshRequest::default())
+                            } else {
+                                serde_json::from_str::<BedMeshRequest>(message.message)
+                                    .map_err(std::io::Error::other)
+                            };
+                            let name = target.name.clone();
+                            let result = match request {
+                                Ok(request) => {
+                                    target
+                                        .printer
+                                        .exchange(move |printer| {
+                                            fetch_mesh(
+                                                &mut |cmd| printer.send(cmd),
+                                                &name,
+                                                &request,
+                                            )
+                                        })
+                                        .await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            send_message_back(
+                                json_response("BedMesh", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::BedMeshList => {
+                            let result = list_meshes(&target.name);
+                            send_message_back(
+                                json_response("BedMeshList", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::Queue => {
+                            send_message_back(
+                                json_response("Queue", Ok(target.queue.status())),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::QueueUpdate => {
+                            let name = client.as_ref().map_or("", |(name, _)| name.as_str());
+                            let result = match serde_json::from_str::<QueueRequest>(message.message)
+                            {
+                                Ok(request) => {
+                                    apply_request(target, request, &configuration, name).await
                                 }
+                                Err(e) => Err(std::io::Error::other(e)),
+                            };
+                            send_message_back(
+                                json_response("QueueUpdate", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::PromptAnswer => {
+                            let result = serde_json::from_str::<PromptAnswer>(message.message)
+                                .map_err(std::io::Error::other)
+                                .and_then(|answer| target.printer.answer_prompt(answer.choice));
+                            send_message_back(
+                                json_response("PromptAnswer", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::Objects => {
+                            send_message_back(
+                                json_response("Objects", Ok(target.job.objects())),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::ObjectCancel => {
+                            let request = if message.message.trim().is_empty() {
+                                Ok(ObjectCancelRequest::default())
+                            } else {
+                                serde_json::from_str::<ObjectCancelRequest>(message.message)
+                                    .map_err(std::io::Error::other)
+                            };
+                            let result = match request {
+                                Ok(request) => {
+                                    target.job.cancel_object(&target.printer, request.id).await
+                                }
+                                Err(e) => Err(e),
+                            };
+                            send_message_back(
+                                json_response("ObjectCancel", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::CheckpointResume => {
+                            let jobs = &configuration.jobs;
+                            let result = match target.job.checkpoint_file(&jobs.files_dir) {
+                                Ok(path) => JobFile::read(path).await.and_then(|job_file| {
+                                    target.job.recover(
+                                        &target.printer,
+                                        job_file,
+                                        jobs.cancel_commands.clone(),
+                                        configuration.recovery.lift_mm,
+                                    )
+                                }),
+                                Err(e) => Err(e),
+                            };
+                            send_message_back(
+                                json_response("CheckpointResume", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::CheckpointDiscard => {
+                            send_message_back(
+                                json_response("CheckpointDiscard", target.job.discard_checkpoint()),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::ControlAcquire => {
+                            let name = client.as_ref().map_or("", |(name, _)| name.as_str());
+                            let result = target.control.acquire(client_id, name, peer);
+                            if let Ok(status) = &result {
+                                state.broadcast_control(&target.name, status);
                             }
+                            send_message_back(
+                                json_response("ControlAcquire", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::ControlRelease => {
+                            let result = target.control.release(client_id);
+                            if let Ok(status) = &result {
+                                state.broadcast_control(&target.name, status);
+                            }
+                            send_message_back(
+                                json_response("ControlRelease", result),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::ControlTakeover => {
+                            let name = client.as_ref().map_or("", |(name, _)| name.as_str());
+                            let status = target.control.takeover(client_id, name, peer);
+                            warn!("{} took over control", peer);
+                            state.broadcast_control(&target.name, &status);
+                            send_message_back(
+                                json_response("ControlTakeover", Ok(status)),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::EmergencyStop => {
+                            let name = client.as_ref().map_or("", |(name, _)| name.as_str());
+                            // The event is broadcast to every client, including this one
+                            if let Err(e) = emergency_stop(&state, target, peer, name) {
+                                error!("Emergency stop failed | {}", e);
+                                send_message_back(
+                                    error_message(&format!("Emergency stop failed | {}", e)),
+                                    &mut ws_write,
+                                    Some(&target.name),
+                                )
+                                .await?;
+                            }
+                        }
+                        MessageType::PrinterStatus => {
+                            let printer_state = target.printer.state();
+                            send_message_back(
+                                json_response("PrinterStatus", Ok(printer_state)),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
+                        }
+                        MessageType::ControlStatus => {
+                            let status = target.control.status();
+                            send_message_back(
+                                json_response("ControlStatus", Ok(status)),
+                                &mut ws_write,
+                                Some(&target.name),
+                            )
+                            .await?;
                         }
                     }
                 }
-                Err(_) => todo!(),
+                Err(e) => {
+                    warn!("Invalid message from {} | {}", peer, e);
+                    send_message_back(
+                        error_message(&format!("Invalid message | {}", e)),
+                        &mut ws_write,
+                        None,
+                    )
+                    .await?;
+                }
             }
         }
     }
@@ -304,3 +851,131 @@ async fn handle_connection(
     info!("Connection lost for {}", peer);
     Err(Error::ConnectionClosed)
 }
+
+/**
+ * Query the print history
+ * @param state: &ServerState, holds the history
+ * @param message_type: &MessageType, History for the entries, HistoryStats for the totals
+ * @param message: &str, HistoryFilter as JSON, everything when empty
+ * @return MessageSender
+ */
+fn history_response(
+    state: &ServerState,
+    message_type: &MessageType,
+    message: &str,
+) -> MessageSender {
+    let filter = if message.trim().is_empty() {
+        Ok(HistoryFilter::default())
+    } else {
+        serde_json::from_str::<HistoryFilter>(message).map_err(std::io::Error::other)
+    };
+    let history = filter.and_then(|filter| Ok((state.history()?, filter)));
+
+    match message_type {
+        MessageType::HistoryStats => json_response(
+            "HistoryStats",
+            history.and_then(|(history, filter)| history.stats(&filter)),
+        ),
+        _ => json_response(
+            "History",
+            history.and_then(|(history, filter)| history.query(&filter)),
+        ),
+    }
+}
+
+/**
+ * Build the response message for an operation returning JSON
+ * @param message_type: &str, type of the request
+ * @param result: Result<T, std::io::Error>, result of the operation
+ * @return MessageSender, JSON result or "MessageSenderError" with the reason
+ */
+pub fn json_response<T: serde::Serialize>(
+    message_type: &str,
+    result: Result<T, std::io::Error>,
+) -> MessageSender {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    match result {
+        Ok(value) => {
+            let json_str =
+                serde_json::to_string(&value).expect("Failed to serialize message into JSON");
+            MessageSender {
+                message_type: message_type.to_string(),
+                message: json_str.clone(),
+                raw_message: json_str,
+                timestamp,
+                printer: None,
+            }
+        }
+        Err(e) => {
+            error!("{} failed | {}", message_type, e);
+            error_message(&e.to_string())
+        }
+    }
+}
+
+/**
+ * Build an error message for the client
+ * @param reason: &str, why the request failed
+ * @return MessageSender, message of type "MessageSenderError"
+ */
+fn error_message(reason: &str) -> MessageSender {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+
+    MessageSender {
+        message_type: "MessageSenderError".to_string(),
+        message: reason.to_string(),
+        raw_message: reason.to_string(),
+        timestamp,
+        printer: None,
+    }
+}
+
+/// Messages accepted from observers while another client holds control
+fn ignores_control(message_type: &MessageType) -> bool {
+    matches!(
+        message_type,
+        MessageType::ControlAcquire
+            | MessageType::ControlRelease
+            | MessageType::ControlTakeover
+            | MessageType::ControlStatus
+            | MessageType::EmergencyStop
+    )
+}
+
+fn allowed_when_halted(message_type: &MessageType) -> bool {
+    ignores_control(message_type)
+        || matches!(message_type, MessageType::Terminal | MessageType::Unsafe)
+}
+
+/// Releases the control lock when the connection ends
+struct ControlGuard<'a> {
+    state: &'a ServerState,
+    client_id: u64,
+}
+
+impl Drop for ControlGuard<'_> {
+    fn drop(&mut self) {
+        for managed in &self.state.printers {
+            if let Some(status) = managed.control.disconnect(self.client_id) {
+                self.state.broadcast_control(&managed.name, &status);
+            }
+        }
+    }
+}
+
+fn lock<T>(mutex: &std::sync::Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn error_response(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_string()));
+    *response.status_mut() = status;
+    response
+}